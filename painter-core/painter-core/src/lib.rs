@@ -1,34 +1,181 @@
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 
 use painter_tools::brush_tool::BrushTool;
 use painter_tools::context::EditContext;
+use painter_tools::journal::{document_fingerprint, Journal, JournalContents};
+use painter_tools::selection_tool::SelectionTool;
+use std::path::{Path, PathBuf};
 
+use log::warn;
 use simple_logger::SimpleLogger;
 
-use painter_data::{load_from_reader, write_into};
+use painter_data::brush::PressureCurve;
+use painter_data::image::Image;
+use painter_data::{from_bytes, save_atomic, to_bytes, PainterDataError};
 use painter_render::PainterRenderer;
 
+mod save_job;
+use save_job::SaveJob;
+
 #[pyclass]
 struct PainterCore {}
 
 #[pymethods]
 impl PainterCore {
     #[new]
-    pub fn new(py: Python) -> PyResult<Self> {
+    pub fn new(_py: Python) -> PyResult<Self> {
         SimpleLogger::new().init().unwrap();
 
         Ok(Self {})
     }
 
-    pub fn save(&self, context: EditContext, filename: String) {
-        let buffer = std::fs::File::create(filename).expect("Failed to create file");
-        write_into(&context.image, buffer).expect("Failed to write");
+    /// Saves the image, and starts a new journal of changes made after saving. The
+    /// existing file is only replaced once the new one has been completely written.
+    /// `progress` is called with the fraction saved so far.
+    #[args(progress = "None")]
+    pub fn save(
+        &self,
+        py: Python,
+        context: &mut EditContext,
+        filename: String,
+        progress: Option<PyObject>,
+    ) -> PyResult<()> {
+        let path = Path::new(&filename);
+        let data = save_atomic(
+            &context.image_with_history(),
+            path,
+            &mut progress_callback(py, &progress),
+        )
+        .map_err(data_error_to_py)?;
+        start_journal(context, path, document_fingerprint(&data));
+        Ok(())
+    }
+
+    /// Saves a snapshot of the image on another thread so that editing can continue.
+    /// Call finish_save once the job has finished.
+    pub fn save_in_background(&self, context: &EditContext, filename: String) -> SaveJob {
+        SaveJob::start(
+            context.image_with_history(),
+            PathBuf::from(filename),
+            context.journal_record_count(),
+        )
+    }
+
+    /// Waits for a background save to finish, and moves any changes made since the
+    /// snapshot was taken into the journal for the saved file.
+    pub fn finish_save(
+        &self,
+        py: Python,
+        context: &mut EditContext,
+        job: &mut SaveJob,
+    ) -> PyResult<()> {
+        let fingerprint = job
+            .result(py)
+            .map_err(pyo3::exceptions::PyIOError::new_err)?;
+        match job.saved_journal_records {
+            Some(saved_records) => {
+                if let Err(err) = context.rebase_journal(&job.path, fingerprint, saved_records) {
+                    warn!("Unable to move journal onto saved file: {:?}", err);
+                    start_journal(context, &job.path, fingerprint);
+                }
+            }
+            None => start_journal(context, &job.path, fingerprint),
+        }
+        Ok(())
+    }
+
+    /// Loads an image. Any journal of unsaved changes is discarded - use
+    /// recoverable_changes and recover to get them back.
+    #[args(progress = "None")]
+    pub fn load(
+        &self,
+        py: Python,
+        filename: String,
+        progress: Option<PyObject>,
+    ) -> PyResult<EditContext> {
+        let path = Path::new(&filename);
+        let (data, image) = read_image(py, path, &progress)?;
+        let mut context = EditContext::new_with_saved_image(image);
+        start_journal(&mut context, path, document_fingerprint(&data));
+        Ok(context)
     }
 
-    pub fn load(&self, filename: String) -> EditContext {
-        let buffer = std::fs::File::open(filename).expect("Failed to open file");
-        let image = load_from_reader(buffer).expect("Failed to read");
-        EditContext::new_with_image(image)
+    /// Encodes the image (including it's undo history) in the same format as a file
+    #[args(progress = "None")]
+    pub fn to_bytes(
+        &self,
+        py: Python,
+        context: &EditContext,
+        progress: Option<PyObject>,
+    ) -> PyResult<Py<PyBytes>> {
+        let data = to_bytes(
+            &context.image_with_history(),
+            &mut progress_callback(py, &progress),
+        )
+        .map_err(data_error_to_py)?;
+        Ok(PyBytes::new(py, &data).into())
+    }
+
+    /// Decodes an image created by to_bytes. The image is not associated with a file
+    /// so changes to it are not journaled.
+    #[staticmethod]
+    #[args(progress = "None")]
+    pub fn from_bytes(
+        py: Python,
+        data: &[u8],
+        progress: Option<PyObject>,
+    ) -> PyResult<EditContext> {
+        let image =
+            from_bytes(data, &mut progress_callback(py, &progress)).map_err(data_error_to_py)?;
+        Ok(EditContext::new_with_saved_image(image))
+    }
+
+    /// How many changes were made to the file after it was last saved (probably
+    /// because the app crashed) that can be recovered by `recover`.
+    pub fn recoverable_changes(&self, filename: String) -> usize {
+        let path = Path::new(&filename);
+        let journal = std::fs::read(path)
+            .ok()
+            .and_then(|data| read_matching_journal(path, &data));
+        match journal {
+            Some(contents) => contents.commands.len(),
+            None => 0,
+        }
+    }
+
+    /// Loads an image and reapplies the changes made to it since it was last saved.
+    /// The recovered changes can be undone.
+    #[args(progress = "None")]
+    pub fn recover(
+        &self,
+        py: Python,
+        filename: String,
+        progress: Option<PyObject>,
+    ) -> PyResult<EditContext> {
+        let path = Path::new(&filename);
+        let (data, image) = read_image(py, path, &progress)?;
+        let mut context = EditContext::new_with_saved_image(image);
+        let journal = match read_matching_journal(path, &data) {
+            Some(journal) => journal,
+            None => {
+                start_journal(&mut context, path, document_fingerprint(&data));
+                return Ok(context);
+            }
+        };
+
+        match Journal::resume(path, &journal) {
+            Ok(resumed) => {
+                context.replay(journal.commands);
+                context.set_journal(Some(resumed));
+            }
+            Err(err) => {
+                warn!("Unable to reopen journal: {:?}", err);
+                context.replay(journal.commands);
+                start_journal(&mut context, path, document_fingerprint(&data));
+            }
+        }
+        Ok(context)
     }
 
     #[staticmethod]
@@ -37,6 +184,66 @@ impl PainterCore {
     }
 }
 
+/// Reads and decodes an image file, returning the raw file contents as well
+fn read_image(py: Python, path: &Path, progress: &Option<PyObject>) -> PyResult<(Vec<u8>, Image)> {
+    let data = std::fs::read(path).map_err(|err| {
+        pyo3::exceptions::PyIOError::new_err(format!("Unable to read {:?}: {}", path, err))
+    })?;
+    let image =
+        from_bytes(&data, &mut progress_callback(py, progress)).map_err(data_error_to_py)?;
+    Ok((data, image))
+}
+
+/// Turns an optional python callable into a progress callback. Errors raised by the callable are logged and otherwise ignored.
+fn progress_callback<'a>(py: Python<'a>, progress: &'a Option<PyObject>) -> impl FnMut(f32) + 'a {
+    move |fraction| {
+        if let Some(callback) = progress {
+            if let Err(err) = callback.call1(py, (fraction,)) {
+                warn!("Progress callback failed: {:?}", err);
+            }
+        }
+    }
+}
+
+fn data_error_to_py(err: PainterDataError) -> PyErr {
+    match err {
+        PainterDataError::ReadError(err) | PainterDataError::WriteError(err) => {
+            pyo3::exceptions::PyIOError::new_err(err.to_string())
+        }
+        err => pyo3::exceptions::PyValueError::new_err(format!("{:?}", err)),
+    }
+}
+
+/// Starts a new journal for the document, replacing any existing one. Failing to do so
+/// is not fatal - the image just won't be recoverable after a crash.
+fn start_journal(context: &mut EditContext, path: &Path, fingerprint: u64) {
+    match Journal::create(path, fingerprint) {
+        Ok(journal) => context.set_journal(Some(journal)),
+        Err(err) => {
+            warn!(
+                "Unable to create journal, changes will not be recoverable: {:?}",
+                err
+            );
+            context.set_journal(None);
+        }
+    }
+}
+
+/// Reads the journal for a document if it was written for `data`, the current contents
+/// of the document (ie the document has not been saved since).
+fn read_matching_journal(path: &Path, data: &[u8]) -> Option<JournalContents> {
+    match Journal::read(path) {
+        Ok(Some(contents)) if contents.document_fingerprint == document_fingerprint(data) => {
+            Some(contents)
+        }
+        Ok(_) => None,
+        Err(err) => {
+            warn!("Unable to read journal: {:?}", err);
+            None
+        }
+    }
+}
+
 /// A Python module implemented in Rust. The name of this function must match
 /// the `lib.name` setting in the `Cargo.toml`, else Python will not be able to
 /// import the module.
@@ -44,7 +251,10 @@ impl PainterCore {
 fn painter_core(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PainterCore>()?;
     m.add_class::<BrushTool>()?;
+    m.add_class::<SelectionTool>()?;
     m.add_class::<EditContext>()?;
     m.add_class::<PainterRenderer>()?;
+    m.add_class::<SaveJob>()?;
+    m.add_class::<PressureCurve>()?;
     Ok(())
 }
@@ -0,0 +1,89 @@
+use pyo3::prelude::*;
+
+use painter_data::image::Image;
+use painter_data::{save_atomic, PainterDataError};
+use painter_tools::journal::document_fingerprint;
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// An image being saved on a background thread. Created by PainterCore.save_in_background
+/// and completed with PainterCore.finish_save.
+#[pyclass]
+pub struct SaveJob {
+    pub path: PathBuf,
+
+    /// How many journal records were included in the image being saved
+    pub saved_journal_records: Option<usize>,
+
+    /// The fraction saved so far, stored as the bits of an f32
+    progress: Arc<AtomicU32>,
+
+    thread: Option<JoinHandle<Result<u64, PainterDataError>>>,
+
+    /// Once the thread has finished, the fingerprint of the saved document
+    result: Option<Result<u64, String>>,
+}
+
+impl SaveJob {
+    /// Starts saving a snapshot of an image to `path`
+    pub fn start(image: Image, path: PathBuf, saved_journal_records: Option<usize>) -> Self {
+        let progress = Arc::new(AtomicU32::new(0.0f32.to_bits()));
+        let thread = {
+            let progress = progress.clone();
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let data = save_atomic(&image, &path, &mut |fraction: f32| {
+                    progress.store(fraction.to_bits(), Ordering::Relaxed)
+                })?;
+                Ok(document_fingerprint(&data))
+            })
+        };
+        Self {
+            path,
+            saved_journal_records,
+            progress,
+            thread: Some(thread),
+            result: None,
+        }
+    }
+
+    /// Blocks until the save has finished, returning the fingerprint of the saved
+    /// document.
+    pub fn result(&mut self, py: Python) -> Result<u64, String> {
+        if let Some(thread) = self.thread.take() {
+            let result = py.allow_threads(move || thread.join());
+            self.result = Some(match result {
+                Ok(Ok(fingerprint)) => Ok(fingerprint),
+                Ok(Err(err)) => Err(format!("{:?}", err)),
+                Err(_) => Err("Save thread panicked".to_string()),
+            });
+        }
+        self.result
+            .clone()
+            .expect("Result is set when the thread is joined")
+    }
+}
+
+#[pymethods]
+impl SaveJob {
+    /// The fraction of the image saved so far, from 0.0 to 1.0
+    pub fn progress(&self) -> f32 {
+        f32::from_bits(self.progress.load(Ordering::Relaxed))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        match &self.thread {
+            Some(thread) => thread.is_finished(),
+            None => true,
+        }
+    }
+
+    /// Waits for the save to finish, returning a description of what went wrong if it
+    /// failed.
+    pub fn wait(&mut self, py: Python) -> Option<String> {
+        self.result(py).err()
+    }
+}
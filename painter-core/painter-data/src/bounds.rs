@@ -0,0 +1,114 @@
+//! Axis aligned bounding boxes of strokes in canvas coordinates, so that code that only
+//! cares about part of the canvas can skip strokes without looking at every stamp.
+use crate::stroke::StrokeData;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+}
+
+impl Bounds {
+    pub fn new(min: [f32; 2], max: [f32; 2]) -> Self {
+        Self { min, max }
+    }
+
+    /// Bounds that contain only a single point
+    pub fn point(point: [f32; 2]) -> Self {
+        Self::new(point, point)
+    }
+
+    /// The smallest bounds containing all the points, or None if there are none
+    pub fn around(points: &[[f32; 2]]) -> Option<Self> {
+        let (first, rest) = points.split_first()?;
+        Some(rest.iter().fold(Self::point(*first), |bounds, point| {
+            bounds.union(&Self::point(*point))
+        }))
+    }
+
+    /// The visible area of a canvas with the given aspect ratio (width / height). The
+    /// canvas spans -1 to 1 vertically, and is stretched horizontally by the aspect
+    /// ratio.
+    pub fn canvas(aspect_ratio: f32) -> Self {
+        Self::new([-aspect_ratio, -1.0], [aspect_ratio, 1.0])
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self::new(
+            [self.min[0].min(other.min[0]), self.min[1].min(other.min[1])],
+            [self.max[0].max(other.max[0]), self.max[1].max(other.max[1])],
+        )
+    }
+
+    /// Whether the bounds overlap. Bounds that only touch count as overlapping.
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.min[0] <= other.max[0]
+            && other.min[0] <= self.max[0]
+            && self.min[1] <= other.max[1]
+            && other.min[1] <= self.max[1]
+    }
+
+    pub fn contains_point(&self, point: [f32; 2]) -> bool {
+        (self.min[0]..=self.max[0]).contains(&point[0])
+            && (self.min[1]..=self.max[1]).contains(&point[1])
+    }
+}
+
+/// The bounds of a single square stamp centered on `center`, `half_size` from center
+/// to edge and rotated by `angle`.
+pub fn stamp_bounds(center: [f32; 2], half_size: f32, angle: f32) -> Bounds {
+    let (sin, cos) = angle.sin_cos();
+    let extent = half_size.abs() * (sin.abs() + cos.abs());
+    Bounds::new(
+        [center[0] - extent, center[1] - extent],
+        [center[0] + extent, center[1] + extent],
+    )
+}
+
+impl StrokeData {
+    /// How far from the center to the edge of the stamp at `index`. Stamps are scaled
+    /// by the canvas aspect ratio (width / height) when they are drawn.
+    pub fn stamp_half_size(&self, index: usize, aspect_ratio: f32) -> f32 {
+        self.size * self.size_array.get(index).cloned().unwrap_or(1.0) * aspect_ratio
+    }
+
+    /// The bounds of the stamp at `index`, or None if there is no such stamp
+    pub fn stamp_bounds(&self, index: usize, aspect_ratio: f32) -> Option<Bounds> {
+        let position = self.position_array.get(index)?;
+        let angle = self.angle_array.get(index).cloned().unwrap_or(0.0);
+        Some(stamp_bounds(
+            *position,
+            self.stamp_half_size(index, aspect_ratio),
+            angle,
+        ))
+    }
+
+    /// The bounds of every stamp of the stroke, or None if it has no stamps
+    pub fn bounds(&self, aspect_ratio: f32) -> Option<Bounds> {
+        (0..self.position_array.len())
+            .filter_map(|index| self.stamp_bounds(index, aspect_ratio))
+            .reduce(|bounds, stamp| bounds.union(&stamp))
+    }
+}
+
+#[test]
+fn test_stroke_bounds() {
+    let stroke = StrokeData {
+        angle_array: vec![0.0, std::f32::consts::FRAC_PI_4],
+        size_array: vec![1.0, 0.5],
+        ..crate::stroke::test_stroke(vec![[0.0, 0.0], [1.0, 0.5]], 0.5)
+    };
+    // The second stamp is turned 45 degrees, so reaches further along each axis
+    let corner = 0.25 * std::f32::consts::SQRT_2;
+    let bounds = stroke.bounds(1.0).unwrap();
+    assert_eq!(bounds.min, [-0.5, -0.5]);
+    assert!((bounds.max[0] - (1.0 + corner)).abs() < 1e-5);
+    assert!((bounds.max[1] - (0.5 + corner)).abs() < 1e-5);
+
+    assert!(bounds.intersects(&Bounds::point([0.0, 0.0])));
+    assert!(!bounds.intersects(&Bounds::new([2.0, 2.0], [3.0, 3.0])));
+    assert_eq!(
+        Bounds::around(&[[1.0, -1.0], [-1.0, 2.0]]),
+        Some(Bounds::new([-1.0, -1.0], [1.0, 2.0]))
+    );
+}
@@ -1,12 +1,99 @@
 use pyo3::prelude::*;
 use serde::{Deserialize, Serialize};
 
+/// The number of samples a Bezier curve is stored as
+const BEZIER_CURVE_SAMPLES: usize = 65;
+
+/// How something responds to pressure. Maps a pressure from 0 to 1 to (usually) a value
+/// from 0 to 1.
+///
+/// The curve is stored as its value at evenly spaced pressures, from 0 to 1 inclusive,
+/// and is interpolated linearly between them. This means any shape (including curves
+/// measured from a tablet) can be stored the same way. With fewer than two samples the
+/// curve is a straight line from 0 to 1.
+#[pyclass]
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PressureCurve {
+    samples: Vec<f32>,
+}
+
+impl PressureCurve {
+    pub fn evaluate(&self, pressure: f32) -> f32 {
+        let pressure = pressure.clamp(0.0, 1.0);
+        if self.samples.len() < 2 {
+            return pressure;
+        }
+        let position = pressure * (self.samples.len() - 1) as f32;
+        let index = (position.floor() as usize).min(self.samples.len() - 2);
+        let fraction = position - index as f32;
+        let (from, to) = (self.samples[index], self.samples[index + 1]);
+        from + (to - from) * fraction
+    }
+}
+
+#[pymethods]
+impl PressureCurve {
+    #[staticmethod]
+    pub fn linear() -> Self {
+        Self::default()
+    }
+
+    /// A curve with the given values at evenly spaced pressures from 0 to 1
+    #[staticmethod]
+    pub fn from_samples(samples: Vec<f32>) -> Self {
+        Self { samples }
+    }
+
+    /// A cubic Bezier curve from (0, 0) to (1, 1) with the given (pressure, value)
+    /// control points, as used in the curve editors of most painting programs. The
+    /// pressure of each control point is clamped from 0 to 1 so that the curve has a
+    /// single value at each pressure.
+    #[staticmethod]
+    pub fn from_bezier(control_a: [f32; 2], control_b: [f32; 2]) -> Self {
+        // Calculated in f64, as the curve can be very flat near its ends
+        let xa = f64::from(control_a[0].clamp(0.0, 1.0));
+        let xb = f64::from(control_b[0].clamp(0.0, 1.0));
+        let cubic = |a: f64, b: f64, t: f64| {
+            let u = 1.0 - t;
+            3.0 * u * u * t * a + 3.0 * u * t * t * b + t * t * t
+        };
+        let samples = (0..BEZIER_CURVE_SAMPLES)
+            .map(|index| {
+                let pressure = index as f64 / (BEZIER_CURVE_SAMPLES - 1) as f64;
+                // The pressure increases along the curve, so find where it is reached
+                // by bisection
+                let (mut low, mut high) = (0.0, 1.0);
+                for _ in 0..52 {
+                    let middle = (low + high) / 2.0;
+                    if cubic(xa, xb, middle) < pressure {
+                        low = middle;
+                    } else {
+                        high = middle;
+                    }
+                }
+                let t = (low + high) / 2.0;
+                cubic(control_a[1].into(), control_b[1].into(), t) as f32
+            })
+            .collect();
+        Self { samples }
+    }
+
+    pub fn get_samples(&self) -> Vec<f32> {
+        self.samples.clone()
+    }
+}
+
 #[pyclass]
 #[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
 pub struct PressureSettings {
     pub min_value: f32,
     pub max_value: f32,
+
+    /// How far the value may randomly vary from stamp to stamp, either way
     pub random: f32,
+
+    /// How far the value is from min_value to max_value at each pressure
+    pub curve: PressureCurve,
 }
 
 #[pyclass]
@@ -16,11 +103,59 @@ pub struct Brush {
     pub glyph: Glyph,
     pub size: PressureSettings,
     pub flow: PressureSettings,
+
+    /// How far stamps are moved away from the path of the stroke, in stamp widths
     pub scatter: PressureSettings,
+
+    /// The distance between stamps along the path of the stroke, in stamp widths
     pub gap: PressureSettings,
+
+    /// How far stamps are rotated, in radians
+    pub angle: PressureSettings,
+}
+
+impl Brush {
+    /// The names of the settings that respond to pressure
+    pub const SETTING_NAMES: [&'static str; 5] = ["size", "flow", "scatter", "gap", "angle"];
+
+    pub fn setting_mut(&mut self, name: &str) -> Option<&mut PressureSettings> {
+        match name {
+            "size" => Some(&mut self.size),
+            "flow" => Some(&mut self.flow),
+            "scatter" => Some(&mut self.scatter),
+            "gap" => Some(&mut self.gap),
+            "angle" => Some(&mut self.angle),
+            _ => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Hash, Eq)]
 pub enum Glyph {
     Png(Vec<u8>),
 }
+
+#[test]
+fn test_pressure_curve() {
+    let linear = PressureCurve::linear();
+    assert_eq!(linear.evaluate(0.25), 0.25);
+    assert_eq!(linear.evaluate(2.0), 1.0);
+
+    let sampled = PressureCurve::from_samples(vec![0.0, 0.5, 0.0]);
+    assert_eq!(sampled.evaluate(0.25), 0.25);
+    assert_eq!(sampled.evaluate(0.5), 0.5);
+    assert_eq!(sampled.evaluate(1.0), 0.0);
+    assert_eq!(sampled.evaluate(-1.0), 0.0);
+
+    // Control points on the diagonal give a straight line
+    let straight = PressureCurve::from_bezier([0.25, 0.25], [0.75, 0.75]);
+    for pressure in [0.0, 0.1, 0.5, 0.9, 1.0].iter() {
+        assert!((straight.evaluate(*pressure) - pressure).abs() < 1e-4);
+    }
+    // A soft start stays low, then catches up
+    let soft = PressureCurve::from_bezier([0.5, 0.0], [1.0, 0.5]);
+    assert!(soft.evaluate(0.25) < 0.05);
+    assert!(soft.evaluate(0.75) < 0.75);
+    assert!((soft.evaluate(1.0) - 1.0).abs() < 1e-4);
+    assert_eq!(soft.get_samples().len(), BEZIER_CURVE_SAMPLES);
+}
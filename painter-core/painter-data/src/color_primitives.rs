@@ -23,9 +23,120 @@ impl Color {
             a: self.a * other.a,
         }
     }
+
+    /// How far apart two colors are, ignoring alpha. Black and white are sqrt(3) apart.
+    pub fn rgb_distance(&self, other: &Self) -> f32 {
+        ((self.r - other.r).powi(2) + (self.g - other.g).powi(2) + (self.b - other.b).powi(2))
+            .sqrt()
+    }
+
+    /// The hue (in degrees from 0 to 360), saturation and value of the color
+    pub fn to_hsv(&self) -> [f32; 3] {
+        let max = self.r.max(self.g).max(self.b);
+        let min = self.r.min(self.g).min(self.b);
+        let chroma = max - min;
+        let hue = if chroma <= 0.0 {
+            0.0
+        } else if max == self.r {
+            60.0 * ((self.g - self.b) / chroma).rem_euclid(6.0)
+        } else if max == self.g {
+            60.0 * ((self.b - self.r) / chroma + 2.0)
+        } else {
+            60.0 * ((self.r - self.g) / chroma + 4.0)
+        };
+        let saturation = if max <= 0.0 { 0.0 } else { chroma / max };
+        [hue, saturation, max]
+    }
+
+    /// The color with a hue (in degrees), saturation and value (see to_hsv)
+    pub fn from_hsv(hsv: [f32; 3], a: f32) -> Self {
+        let [hue, saturation, value] = hsv;
+        let chroma = value * saturation;
+        let sector = hue.rem_euclid(360.0) / 60.0;
+        let x = chroma * (1.0 - (sector.rem_euclid(2.0) - 1.0).abs());
+        let (r, g, b) = match sector as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let min = value - chroma;
+        Self {
+            r: r + min,
+            g: g + min,
+            b: b + min,
+            a,
+        }
+    }
 }
 
+/// How something is drawn over what is below it. The number is the opacity.
 #[derive(FromPyObject, PartialEq, Debug, Serialize, Deserialize, Clone)]
 pub enum BlendMode {
     Mix(f32),
+    Multiply(f32),
+    Screen(f32),
+    Add(f32),
+}
+
+impl BlendMode {
+    /// The names used to refer to blend modes from python
+    pub const NAMES: [&'static str; 4] = ["mix", "multiply", "screen", "add"];
+
+    pub fn from_name(name: &str, opacity: f32) -> Option<Self> {
+        match name {
+            "mix" => Some(Self::Mix(opacity)),
+            "multiply" => Some(Self::Multiply(opacity)),
+            "screen" => Some(Self::Screen(opacity)),
+            "add" => Some(Self::Add(opacity)),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mix(_) => "mix",
+            Self::Multiply(_) => "multiply",
+            Self::Screen(_) => "screen",
+            Self::Add(_) => "add",
+        }
+    }
+
+    pub fn opacity(&self) -> f32 {
+        match self {
+            Self::Mix(opacity)
+            | Self::Multiply(opacity)
+            | Self::Screen(opacity)
+            | Self::Add(opacity) => *opacity,
+        }
+    }
+
+    /// The same blend mode with a different opacity
+    pub fn with_opacity(&self, opacity: f32) -> Self {
+        Self::from_name(self.name(), opacity).expect("Name of an existing blend mode")
+    }
+}
+
+#[test]
+fn test_hsv() {
+    let teal = Color {
+        r: 0.0,
+        g: 0.5,
+        b: 0.5,
+        a: 0.75,
+    };
+    let hsv = teal.to_hsv();
+    assert!((hsv[0] - 180.0).abs() < 1e-4);
+    assert!((hsv[1] - 1.0).abs() < 1e-4);
+    assert!((hsv[2] - 0.5).abs() < 1e-4);
+    let round_trip = Color::from_hsv(hsv, teal.a);
+    assert!(round_trip.rgb_distance(&teal) < 1e-4);
+    assert_eq!(round_trip.a, 0.75);
+
+    // Hues wrap around
+    let red = Color::from_hsv([360.0, 1.0, 1.0], 1.0);
+    assert!(red.rgb_distance(&Color::from_hsv([0.0, 1.0, 1.0], 1.0)) < 1e-4);
+    assert_eq!(Color::default().to_hsv(), [0.0, 0.0, 0.0]);
 }
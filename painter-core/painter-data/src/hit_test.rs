@@ -0,0 +1,357 @@
+//! Finding which strokes cover part of the canvas without needing to render it. The
+//! stamps of a stroke are placed the same way the brush shader places them: a square
+//! centered on each point, rotated by the point's angle and textured with the glyph.
+//! A stamp covers a location if the glyph (multiplied by the stroke color's alpha) is
+//! more than ALPHA_THRESHOLD opaque there.
+use std::collections::HashMap;
+
+use crate::bounds::{stamp_bounds, Bounds};
+use crate::brush::Glyph;
+use crate::id_map::{GlyphId, IdMapBase, LayerId, OperationId};
+use crate::image::Image;
+use crate::operation::Operation;
+use crate::stroke::StrokeData;
+
+/// How opaque a stamp has to be at a location to count as covering it. This stops the
+/// faint edges of soft brushes from being selectable.
+pub const ALPHA_THRESHOLD: f32 = 0.05;
+
+/// When testing areas, each glyph is sampled on a grid at most this many texels across
+const MAX_MASK_SAMPLES: usize = 16;
+
+/// A region of the canvas, in canvas coordinates
+#[derive(Debug, Clone, PartialEq)]
+pub enum HitShape {
+    Point([f32; 2]),
+
+    /// Strokes that cover any part of the rectangle
+    Rect {
+        min: [f32; 2],
+        max: [f32; 2],
+    },
+
+    /// Strokes that are entirely inside the polygon
+    Lasso(Vec<[f32; 2]>),
+}
+
+/// The opacity of a glyph, decoded so it can be sampled on the CPU
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlyphMask {
+    width: usize,
+    height: usize,
+    alpha: Vec<f32>,
+}
+
+impl GlyphMask {
+    /// Decodes the alpha channel of a glyph. Glyphs without an alpha channel are
+    /// opaque, as they are when rendered.
+    pub fn from_glyph(glyph: &Glyph) -> Option<Self> {
+        match glyph {
+            Glyph::Png(data) => {
+                let decoder = png::Decoder::new(data.as_slice());
+                let (info, mut reader) = decoder.read_info().ok()?;
+                let mut buf = vec![0; info.buffer_size()];
+                reader.next_frame(&mut buf).ok()?;
+
+                let (color_type, bit_depth) = reader.output_color_type();
+                let bytes_per_channel = match bit_depth {
+                    png::BitDepth::Sixteen => 2,
+                    _ => 1,
+                };
+                let width = info.width as usize;
+                let height = info.height as usize;
+                let alpha = match color_type {
+                    png::ColorType::RGBA | png::ColorType::GrayscaleAlpha => {
+                        let stride = color_type.samples() * bytes_per_channel;
+                        buf.chunks_exact(stride)
+                            .map(|pixel| pixel[stride - bytes_per_channel] as f32 / 255.0)
+                            .collect()
+                    }
+                    _ => vec![1.0; width * height],
+                };
+                Some(Self {
+                    width,
+                    height,
+                    alpha,
+                })
+            }
+        }
+    }
+
+    /// A mask that covers the whole stamp. Used for glyphs that cannot be decoded.
+    pub fn opaque() -> Self {
+        Self {
+            width: 1,
+            height: 1,
+            alpha: vec![1.0],
+        }
+    }
+
+    /// The opacity at a texture coordinate, or 0 outside of the glyph
+    pub fn alpha_at(&self, uv: [f32; 2]) -> f32 {
+        if !(0.0..1.0).contains(&uv[0]) || !(0.0..1.0).contains(&uv[1]) {
+            return 0.0;
+        }
+        let x = (uv[0] * self.width as f32) as usize;
+        let y = (uv[1] * self.height as f32) as usize;
+        self.alpha.get(y * self.width + x).cloned().unwrap_or(0.0)
+    }
+
+    /// Texture coordinates spread over the glyph, with the opacity at each
+    fn samples(&self) -> Vec<([f32; 2], f32)> {
+        let columns = self.width.clamp(1, MAX_MASK_SAMPLES);
+        let rows = self.height.clamp(1, MAX_MASK_SAMPLES);
+        let mut samples = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            for column in 0..columns {
+                let uv = [
+                    (column as f32 + 0.5) / columns as f32,
+                    (row as f32 + 0.5) / rows as f32,
+                ];
+                samples.push((uv, self.alpha_at(uv)));
+            }
+        }
+        samples
+    }
+}
+
+/// A single stamp of a stroke
+struct Stamp {
+    center: [f32; 2],
+    half_size: f32,
+    angle: f32,
+    alpha: f32,
+}
+
+impl Stamp {
+    fn canvas_to_uv(&self, point: [f32; 2]) -> [f32; 2] {
+        let (sin, cos) = self.angle.sin_cos();
+        let dx = point[0] - self.center[0];
+        let dy = point[1] - self.center[1];
+        let x = dx * cos + dy * sin;
+        let y = -dx * sin + dy * cos;
+        [
+            (x / self.half_size + 1.0) / 2.0,
+            (y / self.half_size + 1.0) / 2.0,
+        ]
+    }
+
+    fn uv_to_canvas(&self, uv: [f32; 2]) -> [f32; 2] {
+        let (sin, cos) = self.angle.sin_cos();
+        let x = (uv[0] * 2.0 - 1.0) * self.half_size;
+        let y = (uv[1] * 2.0 - 1.0) * self.half_size;
+        [
+            self.center[0] + x * cos - y * sin,
+            self.center[1] + x * sin + y * cos,
+        ]
+    }
+
+    fn covers(&self, mask: &GlyphMask, point: [f32; 2]) -> bool {
+        self.alpha * mask.alpha_at(self.canvas_to_uv(point)) > ALPHA_THRESHOLD
+    }
+
+    /// The locations this stamp covers, found by sampling the glyph
+    fn covered_points<'a>(&'a self, mask: &'a GlyphMask) -> impl Iterator<Item = [f32; 2]> + 'a {
+        mask.samples()
+            .into_iter()
+            .filter(move |(_, alpha)| alpha * self.alpha > ALPHA_THRESHOLD)
+            .map(move |(uv, _)| self.uv_to_canvas(uv))
+    }
+}
+
+/// The stamps of a stroke. `aspect_ratio` is the width of the canvas divided by it's
+/// height, which the brush shader scales stamps by.
+fn stamps(stroke: &StrokeData, aspect_ratio: f32) -> impl Iterator<Item = Stamp> + '_ {
+    stroke
+        .position_array
+        .iter()
+        .enumerate()
+        .map(move |(index, position)| Stamp {
+            center: *position,
+            half_size: stroke.stamp_half_size(index, aspect_ratio),
+            angle: stroke.angle_array.get(index).cloned().unwrap_or(0.0),
+            alpha: stroke.color.a * stroke.color_array.get(index).map_or(1.0, |color| color.a),
+        })
+}
+
+fn point_in_rect(point: [f32; 2], min: [f32; 2], max: [f32; 2]) -> bool {
+    (min[0]..=max[0]).contains(&point[0]) && (min[1]..=max[1]).contains(&point[1])
+}
+
+/// Even-odd test of whether a point is inside a polygon
+fn point_in_polygon(point: [f32; 2], polygon: &[[f32; 2]]) -> bool {
+    let mut inside = false;
+    let mut previous = match polygon.last() {
+        Some(previous) => *previous,
+        None => return false,
+    };
+    for vertex in polygon.iter() {
+        let crosses = (vertex[1] > point[1]) != (previous[1] > point[1]);
+        if crosses {
+            let x = vertex[0]
+                + (point[1] - vertex[1]) / (previous[1] - vertex[1]) * (previous[0] - vertex[0]);
+            if point[0] < x {
+                inside = !inside;
+            }
+        }
+        previous = *vertex;
+    }
+    inside
+}
+
+/// Whether a stroke drawn with a glyph covers the shape
+pub fn stroke_hits(
+    stroke: &StrokeData,
+    mask: &GlyphMask,
+    shape: &HitShape,
+    aspect_ratio: f32,
+) -> bool {
+    let mut stamps = stamps(stroke, aspect_ratio);
+    match shape {
+        HitShape::Point(point) => stamps.any(|stamp| stamp.covers(mask, *point)),
+        HitShape::Rect { min, max } => stamps.any(|stamp| {
+            // Skip stamps that miss the rectangle entirely
+            let near = stamp_bounds(stamp.center, stamp.half_size, stamp.angle)
+                .intersects(&Bounds::new(*min, *max));
+            // The glyph is only sampled on a grid, so also check the rectangle's corners
+            // and center in case it is smaller than the gaps between samples
+            let center = [(min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0];
+            let corners = [*min, [min[0], max[1]], *max, [max[0], min[1]], center];
+            near && (corners.iter().any(|point| stamp.covers(mask, *point))
+                || stamp
+                    .covered_points(mask)
+                    .any(|point| point_in_rect(point, *min, *max)))
+        }),
+        HitShape::Lasso(polygon) => {
+            let mut covers_anything = false;
+            let inside = stamps.all(|stamp| {
+                stamp.covered_points(mask).all(|point| {
+                    covers_anything = true;
+                    point_in_polygon(point, polygon)
+                })
+            });
+            inside && covers_anything
+        }
+    }
+}
+
+impl HitShape {
+    /// The area a stroke has to overlap to be hit
+    pub fn bounds(&self) -> Option<Bounds> {
+        match self {
+            HitShape::Point(point) => Some(Bounds::point(*point)),
+            HitShape::Rect { min, max } => Some(Bounds::new(*min, *max)),
+            HitShape::Lasso(polygon) => Bounds::around(polygon),
+        }
+    }
+}
+
+impl Image {
+    /// The strokes that cover `shape`, topmost first. If `layer` is given, only strokes
+    /// that are part of that layer's contents are included. Strokes on hidden layers
+    /// and strokes that are not part of the image are never included.
+    pub fn strokes_hit(&self, shape: &HitShape, layer: Option<LayerId>) -> Vec<OperationId> {
+        let area = shape.bounds();
+        self.strokes_hit_filtered(shape, layer, |operation_id| {
+            match (&area, self.operations.get(operation_id)) {
+                (Some(area), Some(Operation::Stroke(stroke))) => stroke
+                    .bounds(self.metadata.aspect_ratio())
+                    .is_some_and(|bounds| bounds.intersects(area)),
+                _ => false,
+            }
+        })
+    }
+
+    /// As strokes_hit, but only strokes for which `may_hit` returns true are tested.
+    /// This allows an index such as SpatialIndex to skip strokes that are nowhere near
+    /// the shape.
+    pub fn strokes_hit_filtered(
+        &self,
+        shape: &HitShape,
+        layer: Option<LayerId>,
+        may_hit: impl Fn(&OperationId) -> bool,
+    ) -> Vec<OperationId> {
+        let in_layer = layer.map(|layer_id| self.layer_operations(&layer_id));
+        let aspect_ratio = self.metadata.aspect_ratio();
+
+        let mut masks: HashMap<GlyphId, GlyphMask> = HashMap::new();
+        let mut hits = Vec::new();
+        for operation_id in self.paint_order().into_iter().rev() {
+            if in_layer
+                .as_ref()
+                .is_some_and(|in_layer| !in_layer.contains(&operation_id))
+            {
+                continue;
+            }
+            if !may_hit(&operation_id) {
+                continue;
+            }
+            let stroke = match self.operations.get(&operation_id) {
+                Some(Operation::Stroke(stroke)) => stroke,
+                _ => continue,
+            };
+            let mask = masks.entry(stroke.glyph).or_insert_with(|| {
+                self.glyphs
+                    .get(&stroke.glyph)
+                    .and_then(GlyphMask::from_glyph)
+                    .unwrap_or_else(GlyphMask::opaque)
+            });
+            if stroke_hits(stroke, mask, shape, aspect_ratio) {
+                hits.push(operation_id);
+            }
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+fn test_png(width: u32, height: u32, alpha: impl Fn(u32, u32) -> u8) -> Vec<u8> {
+    let mut data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut data, width, height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        let mut pixels = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                pixels.extend_from_slice(&[255, 255, 255, alpha(x, y)]);
+            }
+        }
+        writer.write_image_data(&pixels).unwrap();
+    }
+    data
+}
+
+#[test]
+fn test_stroke_hits() {
+    // Only the left half of the glyph is opaque
+    let glyph = Glyph::Png(test_png(4, 4, |x, _| if x < 2 { 255 } else { 0 }));
+    let mask = GlyphMask::from_glyph(&glyph).unwrap();
+    let stroke = StrokeData {
+        angle_array: vec![0.0, std::f32::consts::PI],
+        ..crate::stroke::test_stroke(vec![[0.0, 0.0], [1.0, 0.0]], 0.25)
+    };
+    let hits = |shape: HitShape| stroke_hits(&stroke, &mask, &shape, 2.0);
+
+    // Stamps are 1.0 across. The first covers x from -0.5 to 0, the second is turned
+    // around so covers x from 1.0 to 1.5
+    assert!(hits(HitShape::Point([-0.25, 0.0])));
+    assert!(!hits(HitShape::Point([0.25, 0.0])));
+    assert!(hits(HitShape::Point([1.25, 0.25])));
+    assert!(!hits(HitShape::Point([0.75, 0.0])));
+    assert!(!hits(HitShape::Point([-0.25, 0.75])));
+
+    assert!(hits(HitShape::Rect {
+        min: [-0.1, -0.1],
+        max: [0.1, 0.1]
+    }));
+    assert!(!hits(HitShape::Rect {
+        min: [0.1, -0.1],
+        max: [0.9, 0.1]
+    }));
+
+    let square = |min: f32, max: f32| vec![[min, min], [max, min], [max, max], [min, max]];
+    assert!(hits(HitShape::Lasso(square(-2.0, 2.0))));
+    assert!(!hits(HitShape::Lasso(square(-2.0, 0.9))));
+}
@@ -26,12 +26,16 @@ pub trait IdMapBase {
     /// Inserts an item into the map and returns it's ID
     fn insert(&mut self, item: Self::Value) -> Self::Index;
     fn alter(&mut self, id: Self::Index, item: Self::Value);
+    /// Inserts an item with a given ID, replacing anything already there. Later inserts
+    /// never reuse the ID.
     fn force(&mut self, id: Self::Index, item: Self::Value);
+    /// Removes an item from the map, returning it if it was present. The ID is never reused.
+    fn remove(&mut self, id: &Self::Index) -> Option<Self::Value>;
     fn get(&self, key: &Self::Index) -> Option<&Self::Value>;
     fn get_unchecked(&self, key: &Self::Index) -> &Self::Value;
     fn get_mut(&mut self, key: &Self::Index) -> Option<&mut Self::Value>;
     fn get_mut_unchecked(&mut self, key: &Self::Index) -> &mut Self::Value;
-    fn iter(&self) -> std::collections::hash_map::Iter<Self::Index, Self::Value>;
+    fn iter(&self) -> std::collections::hash_map::Iter<'_, Self::Index, Self::Value>;
 }
 
 /// Making 1000 strokes per second we will run out of ID's
@@ -128,8 +132,15 @@ macro_rules! impl_id_map_struct {
                 self.map.insert(id, item);
             }
             fn force(&mut self, id: Self::Index, item: Self::Value) {
+                if id.val() >= self.id.val() {
+                    self.id = id.clone();
+                    self.id.increment();
+                }
                 self.map.insert(id, item);
             }
+            fn remove(&mut self, id: &Self::Index) -> Option<Self::Value> {
+                self.map.remove(id)
+            }
             fn get(&self, key: &Self::Index) -> Option<&Self::Value> {
                 self.map.get(key)
             }
@@ -142,11 +153,20 @@ macro_rules! impl_id_map_struct {
             fn get_mut_unchecked(&mut self, key: &Self::Index) -> &mut Self::Value {
                 self.map.get_mut(key).expect("IDMap Get Unckecked Failed")
             }
-            fn iter(&self) -> std::collections::hash_map::Iter<Self::Index, Self::Value> {
+            fn iter(&self) -> std::collections::hash_map::Iter<'_, Self::Index, Self::Value> {
                 self.map.iter()
             }
         }
 
+        impl $map_type {
+            /// Rebuilds a map from it's contents and the last ID that was handed out.
+            /// Used to convert old file formats.
+            #[allow(dead_code)] // Not every map has changed format
+            pub(crate) fn from_parts(map: HashMap<$id_type, $value_type>, id: $id_type) -> Self {
+                Self { map, id }
+            }
+        }
+
         #[pymethods]
         impl $map_type {
             fn list_ids(&self) -> Vec<$id_type> {
@@ -4,8 +4,14 @@ use serde::{Deserialize, Serialize};
 use crate::color_primitives::Color;
 //use crate::depgraph::DepGraph;
 
-use crate::id_map::{BrushIdMap, GlyphIdMap, LayerIdMap, OperationId, OperationIdMap};
+use crate::id_map::{
+    BrushIdMap, GlyphId, GlyphIdMap, IdMapBase, LayerId, LayerIdMap, OperationId, OperationIdMap,
+};
+use crate::layer::LayerKind;
+use crate::operation::Operation;
+use crate::PainterDataError;
 use painter_depgraph::DepGraph;
+use std::collections::{BTreeMap, HashSet};
 
 #[pyclass]
 #[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
@@ -17,6 +23,14 @@ pub struct MetaData {
     pub canvas_background_color: Color,
 }
 
+impl MetaData {
+    /// Width divided by height of the canvas
+    pub fn aspect_ratio(&self) -> f32 {
+        let [width, height] = self.preview_canvas_size;
+        width as f32 / height.max(1) as f32
+    }
+}
+
 #[pyclass]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Image {
@@ -36,4 +50,353 @@ pub struct Image {
 
     #[pyo3(get)]
     pub metadata: MetaData,
+
+    /// Extra data stored by specific editors that is not part of the image itself. Keys
+    /// are prefixed with an underscore and an abbreviation of who uses them (eg
+    /// `_sp_undo_history`). Readers that do not know about an extension can ignore it.
+    pub extensions: BTreeMap<String, Vec<u8>>,
+}
+
+/// What was removed by Image::collect_garbage
+#[pyclass]
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct GarbageReport {
+    #[pyo3(get)]
+    pub operations: Vec<OperationId>,
+
+    #[pyo3(get)]
+    pub glyphs: Vec<GlyphId>,
+
+    #[pyo3(get)]
+    pub layers: Vec<LayerId>,
+}
+
+#[pymethods]
+impl GarbageReport {
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty() && self.glyphs.is_empty() && self.layers.is_empty()
+    }
+}
+
+impl Image {
+    /// Stores some extra data in the file. The name must start with an underscore.
+    pub fn set_extension(&mut self, name: &str, data: Vec<u8>) -> Result<(), PainterDataError> {
+        if !name.starts_with('_') {
+            return Err(PainterDataError::InvalidExtensionName(name.to_string()));
+        }
+        self.extensions.insert(name.to_string(), data);
+        Ok(())
+    }
+
+    pub fn extension(&self, name: &str) -> Option<&Vec<u8>> {
+        self.extensions.get(name)
+    }
+
+    pub fn remove_extension(&mut self, name: &str) -> Option<Vec<u8>> {
+        self.extensions.remove(name)
+    }
+
+    /// The operation the image is rendered from (Output(0))
+    pub fn output_operation(&self) -> Option<OperationId> {
+        self.operations
+            .iter()
+            .find(|(_, operation)| **operation == Operation::Output(0))
+            .map(|(id, _)| *id)
+    }
+
+    /// The layer that is blended by an operation, if any
+    pub fn layer_with_blend_operation(&self, operation_id: &OperationId) -> Option<LayerId> {
+        self.layers
+            .iter()
+            .find(|(_, layer)| layer.blend_operation_id == *operation_id)
+            .map(|(id, _)| *id)
+    }
+
+    /// The layer that an operation is part of the contents of, found by following the
+    /// operations that depend on it up to a layer's blend operation.
+    pub fn layer_containing(&self, operation_id: &OperationId) -> Option<LayerId> {
+        let mut visited = HashSet::new();
+        let mut to_visit = vec![*operation_id];
+        while let Some(current) = to_visit.pop() {
+            if !visited.insert(current) {
+                continue;
+            }
+            for (node, edge_index) in self.depgraph.edges_to(&current) {
+                match (self.layer_with_blend_operation(&node), edge_index) {
+                    (Some(layer_id), 0) => return Some(layer_id),
+                    // Layers above in the same stack (the operation is a blend operation
+                    // of a layer in a group)
+                    (Some(_), 1) => to_visit.push(node),
+                    // Layers clipped to this one
+                    (Some(_), _) => {}
+                    (None, _) => to_visit.push(node),
+                }
+            }
+        }
+        None
+    }
+
+    /// The top level layers in the order they are drawn, topmost first. Layers are
+    /// stacked in the depgraph as:
+    /// ```ignore
+    /// Output ---> Top Blend --1--> ... --1--> Bottom Blend --1--> CanvasBase
+    ///                 |                            |
+    ///                 0                            0
+    ///                 V                            V
+    ///            Top Contents               Bottom Contents
+    /// ```
+    /// Layers that are not in the stack are not included.
+    pub fn layer_stack(&self) -> Vec<LayerId> {
+        self.child_layers(None)
+    }
+
+    /// The layers directly inside a group, or the top level layers if `parent` is None,
+    /// topmost first. The contents of a group is a stack of layers like the top level
+    /// one, but on top of an empty GroupBase rather than the CanvasBase:
+    /// ```ignore
+    /// Group Blend --0--> Top Child Blend --1--> ... --1--> GroupBase
+    /// ```
+    pub fn child_layers(&self, parent: Option<LayerId>) -> Vec<LayerId> {
+        let top = match parent {
+            None => self.output_operation(),
+            Some(parent) => match self.layers.get(&parent) {
+                Some(layer) if layer.kind == LayerKind::Group => Some(layer.blend_operation_id),
+                _ => None,
+            },
+        };
+        let mut stack = Vec::new();
+        let mut next = top.and_then(|top| self.depgraph.depends_on(&top)?.first().cloned());
+        while let Some(operation_id) = next {
+            let layer_id = match self.layer_with_blend_operation(&operation_id) {
+                Some(layer_id) => layer_id,
+                None => break,
+            };
+            if stack.contains(&layer_id) {
+                break;
+            }
+            stack.push(layer_id);
+            next = self
+                .depgraph
+                .depends_on(&operation_id)
+                .and_then(|deps| deps.get(1).cloned());
+        }
+        stack
+    }
+
+    /// The group a layer is in, or None for top level layers (and layers that are not
+    /// part of the image)
+    pub fn parent_layer(&self, layer_id: &LayerId) -> Option<LayerId> {
+        self.layers
+            .iter()
+            .filter(|(_, layer)| layer.kind == LayerKind::Group)
+            .map(|(id, _)| *id)
+            .find(|group_id| self.child_layers(Some(*group_id)).contains(layer_id))
+    }
+
+    /// Every layer in the image, with each group followed by it's contents
+    pub fn all_layers(&self) -> Vec<LayerId> {
+        let mut layers = Vec::new();
+        let mut to_visit: Vec<LayerId> = self.layer_stack().into_iter().rev().collect();
+        while let Some(layer_id) = to_visit.pop() {
+            if layers.contains(&layer_id) {
+                continue;
+            }
+            layers.push(layer_id);
+            to_visit.extend(self.child_layers(Some(layer_id)).into_iter().rev());
+        }
+        layers
+    }
+
+    /// The operations that draw the image, in the order they are drawn (so later
+    /// operations are on top). Layers are drawn bottom first, and the contents of
+    /// hidden layers are left out.
+    pub fn paint_order(&self) -> Vec<OperationId> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        // Each operation is visited twice: once to queue it's dependencies, then again
+        // once they have been drawn.
+        let mut to_visit: Vec<(OperationId, bool)> = self
+            .output_operation()
+            .map(|id| (id, false))
+            .into_iter()
+            .collect();
+        while let Some((operation_id, dependencies_drawn)) = to_visit.pop() {
+            if dependencies_drawn {
+                order.push(operation_id);
+                continue;
+            }
+            if !visited.insert(operation_id) {
+                continue;
+            }
+            to_visit.push((operation_id, true));
+
+            let deps = match self.depgraph.depends_on(&operation_id) {
+                Some(deps) => deps,
+                None => continue,
+            };
+            let drawn_deps: Vec<OperationId> = match self.operations.get(&operation_id) {
+                // What is below is drawn before what is composited onto it. Any
+                // further dependencies are clip masks, which are not drawn.
+                Some(Operation::Composite(_)) => {
+                    let hidden = self
+                        .layer_with_blend_operation(&operation_id)
+                        .and_then(|layer_id| self.layers.get(&layer_id))
+                        .is_some_and(|layer| !layer.visible);
+                    let contents = deps.first().filter(|_| !hidden);
+                    deps.get(1).into_iter().chain(contents).cloned().collect()
+                }
+                _ => deps.clone(),
+            };
+            to_visit.extend(drawn_deps.into_iter().rev().map(|id| (id, false)));
+        }
+        order
+    }
+
+    /// Every operation that makes up the contents of a layer, including the contents
+    /// of the layers inside it if it is a group.
+    pub fn layer_operations(&self, layer_id: &LayerId) -> HashSet<OperationId> {
+        let mut operations = HashSet::new();
+        let contents = self
+            .layers
+            .get(layer_id)
+            .and_then(|layer| self.depgraph.depends_on(&layer.blend_operation_id))
+            .and_then(|deps| deps.first().cloned());
+        let mut to_visit: Vec<OperationId> = contents.into_iter().collect();
+        while let Some(operation_id) = to_visit.pop() {
+            if operations.insert(operation_id) {
+                to_visit.extend(
+                    self.depgraph
+                        .depends_on(&operation_id)
+                        .into_iter()
+                        .flatten(),
+                );
+            }
+        }
+        operations
+    }
+
+    /// Removes data that can no longer affect the image:
+    ///  - Operations that no Output operation (indirectly) depends on, including ones
+    ///    that were never placed in the depgraph.
+    ///  - Glyphs that are not used by any remaining stroke.
+    ///  - Layers whose blend operation has been removed.
+    ///
+    /// Brushes are not removed as they are the palette of tools rather than part of
+    /// the drawing.
+    pub fn collect_garbage(&mut self) -> GarbageReport {
+        let outputs: Vec<OperationId> = self
+            .operations
+            .iter()
+            .filter(|(_, operation)| matches!(operation, Operation::Output(_)))
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut report = GarbageReport::default();
+
+        for id in self.depgraph.unreachable_from(&outputs) {
+            self.depgraph.remove(&id);
+        }
+        report.operations = self
+            .operations
+            .iter()
+            .filter(|(id, _)| !self.depgraph.contains(id))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in report.operations.iter() {
+            self.operations.remove(id);
+        }
+
+        let used_glyphs: HashSet<GlyphId> = self
+            .operations
+            .iter()
+            .filter_map(|(_, operation)| match operation {
+                Operation::Stroke(stroke_data) => Some(stroke_data.glyph),
+                _ => None,
+            })
+            .collect();
+        report.glyphs = self
+            .glyphs
+            .iter()
+            .filter(|(id, _)| !used_glyphs.contains(id))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in report.glyphs.iter() {
+            self.glyphs.remove(id);
+        }
+
+        report.layers = self
+            .layers
+            .iter()
+            .filter(|(_, layer)| self.operations.get(&layer.blend_operation_id).is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in report.layers.iter() {
+            self.layers.remove(id);
+        }
+
+        report
+    }
+}
+
+#[test]
+fn test_collect_garbage() {
+    use crate::brush::Glyph;
+    use crate::color_primitives::BlendMode;
+    use crate::id_map::IncrId;
+    use crate::layer::Layer;
+    use crate::stroke::{test_stroke, StrokeData};
+    use crate::template::create_default_image;
+
+    let mut image = create_default_image();
+    let stroke = |glyph| {
+        Operation::Stroke(StrokeData {
+            glyph,
+            ..test_stroke(vec![], 1.0)
+        })
+    };
+
+    // A fresh image has no garbage
+    assert!(image.collect_garbage().is_empty());
+
+    let used_glyph = image.glyphs.insert(Glyph::Png(vec![1]));
+    let unused_glyph = image.glyphs.insert(Glyph::Png(vec![2]));
+    let dead_glyph = image.glyphs.insert(Glyph::Png(vec![3]));
+
+    // A stroke on the background layer is kept
+    let background = image.layers.iter().next().unwrap().1.clone();
+    let background_tip = image
+        .depgraph
+        .depends_on(&background.blend_operation_id)
+        .unwrap()[0];
+    let live_stroke = image.operations.insert(stroke(used_glyph));
+    image.depgraph.operate_on(live_stroke, background_tip);
+
+    // A layer that is no longer composited onto the output
+    let dead_blend = image
+        .operations
+        .insert(Operation::Composite(BlendMode::Mix(1.0)));
+    let dead_stroke = image.operations.insert(stroke(dead_glyph));
+    image.depgraph.insert(dead_blend, vec![dead_stroke]);
+    image.depgraph.insert(dead_stroke, vec![]);
+    let dead_layer = image
+        .layers
+        .insert(Layer::new("Dead".to_string(), dead_blend));
+
+    // An operation that never made it into the depgraph
+    let orphan = image
+        .operations
+        .insert(Operation::Tag("Orphan".to_string()));
+
+    let mut report = image.collect_garbage();
+    report.operations.sort_by_key(|id| id.val());
+    report.glyphs.sort_by_key(|id| id.val());
+
+    assert_eq!(report.operations, vec![dead_blend, dead_stroke, orphan]);
+    assert_eq!(report.glyphs, vec![unused_glyph, dead_glyph]);
+    assert_eq!(report.layers, vec![dead_layer]);
+
+    assert!(image.operations.get(&live_stroke).is_some());
+    assert!(image.glyphs.get(&used_glyph).is_some());
+    assert!(image.depgraph.dangling_dependencies().is_empty());
+    assert!(image.collect_garbage().is_empty());
 }
@@ -3,9 +3,69 @@ use serde::{Deserialize, Serialize};
 
 use crate::id_map::OperationId;
 
+/// What a layer contains
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum LayerKind {
+    /// Operations drawn onto an empty canvas
+    Paint,
+
+    /// A stack of other layers, composited together onto an empty canvas
+    Group,
+}
+
+/// A layer is drawn by it's blend operation, which is a Composite. The opacity and
+/// blend mode of the layer are those of the Composite.
 #[pyclass]
 #[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
 pub struct Layer {
+    #[pyo3(get)]
     pub name: String,
+
+    #[pyo3(get)]
     pub blend_operation_id: OperationId,
+
+    pub kind: LayerKind,
+
+    /// Hidden layers are skipped when compositing
+    #[pyo3(get)]
+    pub visible: bool,
+
+    /// Locked layers cannot be drawn on
+    #[pyo3(get)]
+    pub locked: bool,
+
+    /// Strokes drawn on alpha locked layers only change the color of what is already
+    /// there, not it's transparency.
+    #[pyo3(get)]
+    pub alpha_locked: bool,
+
+    /// Only show the layer where the layer below it is drawn. If the layer below is also
+    /// clipped, the layer at the bottom of the clipping group is used.
+    #[pyo3(get)]
+    pub clip_to_below: bool,
+}
+
+impl Layer {
+    pub fn new(name: String, blend_operation_id: OperationId) -> Self {
+        Self::new_with_kind(name, blend_operation_id, LayerKind::Paint)
+    }
+
+    pub fn new_with_kind(name: String, blend_operation_id: OperationId, kind: LayerKind) -> Self {
+        Self {
+            name,
+            blend_operation_id,
+            kind,
+            visible: true,
+            locked: false,
+            alpha_locked: false,
+            clip_to_below: false,
+        }
+    }
+}
+
+#[pymethods]
+impl Layer {
+    pub fn is_group(&self) -> bool {
+        self.kind == LayerKind::Group
+    }
 }
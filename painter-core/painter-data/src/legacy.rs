@@ -0,0 +1,192 @@
+//! Older versions of the file format, and how to convert them to the current one.
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::brush::{Brush, Glyph, PressureCurve, PressureSettings};
+use crate::color_primitives::{BlendMode, Color};
+use crate::id_map::{
+    BrushId, BrushIdMap, GlyphId, GlyphIdMap, LayerId, LayerIdMap, OperationId, OperationIdMap,
+};
+use crate::image::{Image, MetaData};
+use crate::layer::Layer;
+use crate::operation::Operation;
+use crate::stroke::StrokeData;
+use painter_depgraph::DepGraph;
+
+/// File format version 1. There were no extensions, layers had no properties other
+/// than their name, strokes could not be alpha locked, and brushes could not be
+/// rotated and responded linearly to pressure.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageV1 {
+    pub brushes: BrushIdMapV1,
+    pub glyphs: GlyphIdMap,
+    pub operations: OperationIdMapV1,
+    pub depgraph: DepGraph<OperationId>,
+    pub layers: LayerIdMapV1,
+    pub metadata: MetaData,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BrushIdMapV1 {
+    pub map: HashMap<BrushId, BrushV1>,
+    pub id: BrushId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BrushV1 {
+    pub name: String,
+    pub glyph: Glyph,
+    pub size: PressureSettingsV1,
+    pub flow: PressureSettingsV1,
+    pub scatter: PressureSettingsV1,
+    pub gap: PressureSettingsV1,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PressureSettingsV1 {
+    pub min_value: f32,
+    pub max_value: f32,
+    pub random: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OperationIdMapV1 {
+    pub map: HashMap<OperationId, OperationV1>,
+    pub id: OperationId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum OperationV1 {
+    Stroke(StrokeDataV1),
+    Composite(BlendMode),
+    Output(u32),
+    Tag(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StrokeDataV1 {
+    pub position_array: Vec<[f32; 2]>,
+    pub angle_array: Vec<f32>,
+    pub size: f32,
+    pub size_array: Vec<f32>,
+    pub color: Color,
+    pub color_array: Vec<Color>,
+    pub glyph: GlyphId,
+    pub blend_mode: BlendMode,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LayerIdMapV1 {
+    pub map: HashMap<LayerId, LayerV1>,
+    pub id: LayerId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LayerV1 {
+    pub name: String,
+    pub blend_operation_id: OperationId,
+}
+
+impl From<ImageV1> for Image {
+    fn from(image: ImageV1) -> Self {
+        Self {
+            brushes: image.brushes.into(),
+            glyphs: image.glyphs,
+            operations: image.operations.into(),
+            depgraph: image.depgraph,
+            layers: image.layers.into(),
+            metadata: image.metadata,
+            extensions: BTreeMap::new(),
+        }
+    }
+}
+
+impl From<BrushV1> for Brush {
+    fn from(brush: BrushV1) -> Self {
+        Self {
+            name: brush.name,
+            glyph: brush.glyph,
+            size: brush.size.into(),
+            flow: brush.flow.into(),
+            scatter: brush.scatter.into(),
+            gap: brush.gap.into(),
+            angle: PressureSettings {
+                min_value: 0.0,
+                max_value: 0.0,
+                random: 0.0,
+                curve: PressureCurve::linear(),
+            },
+        }
+    }
+}
+
+impl From<PressureSettingsV1> for PressureSettings {
+    fn from(setting: PressureSettingsV1) -> Self {
+        Self {
+            min_value: setting.min_value,
+            max_value: setting.max_value,
+            random: setting.random,
+            curve: PressureCurve::linear(),
+        }
+    }
+}
+
+impl From<BrushIdMapV1> for BrushIdMap {
+    fn from(brushes: BrushIdMapV1) -> Self {
+        let map = brushes
+            .map
+            .into_iter()
+            .map(|(id, brush)| (id, brush.into()))
+            .collect();
+        Self::from_parts(map, brushes.id)
+    }
+}
+
+impl From<OperationIdMapV1> for OperationIdMap {
+    fn from(operations: OperationIdMapV1) -> Self {
+        let map = operations
+            .map
+            .into_iter()
+            .map(|(id, operation)| (id, operation.into()))
+            .collect();
+        Self::from_parts(map, operations.id)
+    }
+}
+
+impl From<OperationV1> for Operation {
+    fn from(operation: OperationV1) -> Self {
+        match operation {
+            OperationV1::Stroke(stroke) => Operation::Stroke(StrokeData {
+                position_array: stroke.position_array,
+                angle_array: stroke.angle_array,
+                size: stroke.size,
+                size_array: stroke.size_array,
+                color: stroke.color,
+                color_array: stroke.color_array,
+                glyph: stroke.glyph,
+                blend_mode: stroke.blend_mode,
+                alpha_locked: false,
+            }),
+            OperationV1::Composite(blend_mode) => Operation::Composite(blend_mode),
+            OperationV1::Output(id) => Operation::Output(id),
+            OperationV1::Tag(name) => Operation::Tag(name),
+        }
+    }
+}
+
+impl From<LayerV1> for Layer {
+    fn from(layer: LayerV1) -> Self {
+        Layer::new(layer.name, layer.blend_operation_id)
+    }
+}
+
+impl From<LayerIdMapV1> for LayerIdMap {
+    fn from(layers: LayerIdMapV1) -> Self {
+        let map = layers
+            .map
+            .into_iter()
+            .map(|(id, layer)| (id, layer.into()))
+            .collect();
+        Self::from_parts(map, layers.id)
+    }
+}
@@ -1,16 +1,24 @@
+pub mod bounds;
 pub mod brush;
 pub mod color_primitives;
+pub mod hit_test;
 pub mod id_map;
 pub mod image;
 pub mod layer;
+pub mod legacy;
 pub mod operation;
+pub mod progress;
+pub mod spatial_index;
 pub mod stroke;
 pub mod template;
 
-const CURRENT_FORMAT_VERSION: u32 = 1;
+const CURRENT_FORMAT_VERSION: u32 = 2;
 
 const HEAD_MAGIC_STR: &[u8] = b"PAINTER_SVERG";
 
+use progress::{ProgressReader, ProgressWriter};
+use std::path::Path;
+
 #[derive(Debug)]
 pub enum PainterDataError {
     WriteError(std::io::Error),
@@ -19,6 +27,7 @@ pub enum PainterDataError {
     InvalidMagicString,
     UnknownVersion(u32),
     ReadError(std::io::Error),
+    InvalidExtensionName(String),
 }
 
 pub fn write_into<W: std::io::Write>(
@@ -53,6 +62,11 @@ pub fn load_from_reader<R: std::io::Read>(mut reader: R) -> Result<image::Image,
 
     match version_number {
         1 => {
+            let img: legacy::ImageV1 =
+                bincode::deserialize_from(reader).map_err(PainterDataError::DeserializeError)?;
+            Ok(img.into())
+        }
+        2 => {
             let img =
                 bincode::deserialize_from(reader).map_err(PainterDataError::DeserializeError)?;
             Ok(img)
@@ -60,3 +74,246 @@ pub fn load_from_reader<R: std::io::Read>(mut reader: R) -> Result<image::Image,
         number => Err(PainterDataError::UnknownVersion(number)),
     }
 }
+
+/// As write_into, but calls `progress` with the fraction of the image written so far
+pub fn write_with_progress<W: std::io::Write>(
+    image_to_write: &image::Image,
+    writer: W,
+    progress: &mut dyn FnMut(f32),
+) -> Result<(), PainterDataError> {
+    let total = HEAD_MAGIC_STR.len() as u64
+        + 4
+        + bincode::serialized_size(image_to_write).map_err(PainterDataError::SerializeError)?;
+    write_into(image_to_write, ProgressWriter::new(writer, total, progress))
+}
+
+/// As load_from_reader, but calls `progress` with the fraction of `total_size` bytes
+/// read so far
+pub fn load_with_progress<R: std::io::Read>(
+    reader: R,
+    total_size: u64,
+    progress: &mut dyn FnMut(f32),
+) -> Result<image::Image, PainterDataError> {
+    load_from_reader(ProgressReader::new(reader, total_size, progress))
+}
+
+/// Encodes the image in the same format as a file, so that it can be embedded in some
+/// other container (or put on the clipboard)
+pub fn to_bytes(
+    image_to_write: &image::Image,
+    progress: &mut dyn FnMut(f32),
+) -> Result<Vec<u8>, PainterDataError> {
+    let mut data = Vec::new();
+    write_with_progress(image_to_write, &mut data, progress)?;
+    Ok(data)
+}
+
+/// Decodes an image created by to_bytes
+pub fn from_bytes(
+    data: &[u8],
+    progress: &mut dyn FnMut(f32),
+) -> Result<image::Image, PainterDataError> {
+    load_with_progress(data, data.len() as u64, progress)
+}
+
+/// Replaces the file at `path` with `data` such that if anything goes wrong part way
+/// through the original file is left intact. The data is written to a temporary file
+/// in the same directory, which is then renamed over the original.
+pub fn write_file_atomic(path: &Path, data: &[u8]) -> Result<(), PainterDataError> {
+    use std::io::Write;
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| {
+            PainterDataError::WriteError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Path is not a file",
+            ))
+        })?
+        .to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+    let write_temp = || -> std::io::Result<()> {
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    };
+    if let Err(err) = write_temp() {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(PainterDataError::WriteError(err));
+    }
+
+    // Make sure the rename itself is on disk. Not all platforms can open a directory
+    // so this is best-effort.
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Ok(directory) = std::fs::File::open(parent) {
+            let _ = directory.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Saves an image to a file without the risk of corrupting the existing file if
+/// something goes wrong. `progress` is called with the fraction saved so far. Returns
+/// the contents of the saved file.
+pub fn save_atomic(
+    image_to_write: &image::Image,
+    path: &Path,
+    progress: &mut dyn FnMut(f32),
+) -> Result<Vec<u8>, PainterDataError> {
+    // Writing the file is quick compared to encoding it
+    let data = to_bytes(image_to_write, &mut |fraction| progress(fraction * 0.9))?;
+    write_file_atomic(path, &data)?;
+    progress(1.0);
+    Ok(data)
+}
+
+#[test]
+fn test_load_v1() {
+    use id_map::{BrushIdMap, IdMapBase, LayerIdMap, OperationIdMap};
+
+    let image = template::create_default_image();
+    // The easiest way to get IDs is from current maps. The maps store the next ID to be
+    // handed out.
+    let mut operations = OperationIdMap::default();
+    let blend_id = operations.insert(operation::Operation::Output(0));
+    let next_operation_id = operations.insert(operation::Operation::Output(0));
+    let mut layers = LayerIdMap::default();
+    let layer_id = layers.insert(layer::Layer::new("Old".to_string(), blend_id));
+    let next_layer_id = layers.insert(layer::Layer::new("Next".to_string(), blend_id));
+    let (brush_id, brush) = image.brushes.iter().next().unwrap();
+    let mut brushes = BrushIdMap::default();
+    brushes.insert(brush.clone());
+    let next_brush_id = brushes.insert(brush.clone());
+
+    let setting = |setting: &brush::PressureSettings| legacy::PressureSettingsV1 {
+        min_value: setting.min_value,
+        max_value: setting.max_value,
+        random: setting.random,
+    };
+    let legacy_brush = legacy::BrushV1 {
+        name: brush.name.clone(),
+        glyph: brush.glyph.clone(),
+        size: setting(&brush.size),
+        flow: setting(&brush.flow),
+        scatter: setting(&brush.scatter),
+        gap: setting(&brush.gap),
+    };
+    let legacy_image = legacy::ImageV1 {
+        brushes: legacy::BrushIdMapV1 {
+            map: vec![(*brush_id, legacy_brush)].into_iter().collect(),
+            id: next_brush_id,
+        },
+        glyphs: image.glyphs.clone(),
+        operations: legacy::OperationIdMapV1 {
+            map: vec![(
+                blend_id,
+                legacy::OperationV1::Composite(color_primitives::BlendMode::Mix(0.5)),
+            )]
+            .into_iter()
+            .collect(),
+            id: next_operation_id,
+        },
+        depgraph: image.depgraph.clone(),
+        layers: legacy::LayerIdMapV1 {
+            map: vec![(
+                layer_id,
+                legacy::LayerV1 {
+                    name: "Old".to_string(),
+                    blend_operation_id: blend_id,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            id: next_layer_id,
+        },
+        metadata: image.metadata.clone(),
+    };
+    let mut data = HEAD_MAGIC_STR.to_vec();
+    data.extend(1u32.to_le_bytes());
+    bincode::serialize_into(&mut data, &legacy_image).unwrap();
+
+    let loaded = load_from_reader(data.as_slice()).expect("Failed to load V1 file");
+    assert_eq!(
+        loaded.operations.get(&blend_id),
+        Some(&operation::Operation::Composite(
+            color_primitives::BlendMode::Mix(0.5)
+        ))
+    );
+    assert_eq!(loaded.metadata, image.metadata);
+    assert!(loaded.extensions.is_empty());
+
+    let layer = loaded.layers.get(&layer_id).unwrap();
+    assert_eq!(layer, &layer::Layer::new("Old".to_string(), blend_id));
+    assert!(layer.visible);
+    assert_eq!(layer.kind, layer::LayerKind::Paint);
+
+    let loaded_brush = loaded.brushes.get(brush_id).unwrap();
+    assert_eq!(loaded_brush, brush);
+    assert_eq!(loaded_brush.angle.max_value, 0.0);
+    assert_eq!(loaded_brush.size.curve, brush::PressureCurve::linear());
+
+    // New IDs carry on from the old ones
+    let mut operations = loaded.operations.clone();
+    assert_ne!(operations.insert(operation::Operation::Output(0)), blend_id);
+    let mut brushes = loaded.brushes.clone();
+    assert_ne!(brushes.insert(brush.clone()), *brush_id);
+}
+
+#[test]
+fn test_extensions_round_trip() {
+    let mut image = template::create_default_image();
+    image
+        .set_extension("_test_data", vec![1, 2, 3])
+        .expect("Valid name");
+    assert!(image.set_extension("no_underscore", vec![]).is_err());
+
+    let mut data = Vec::new();
+    write_into(&image, &mut data).expect("Failed to write");
+    let loaded = load_from_reader(data.as_slice()).expect("Failed to load");
+    assert_eq!(loaded.extension("_test_data"), Some(&vec![1, 2, 3]));
+}
+
+#[test]
+fn test_bytes_round_trip() {
+    let image = template::create_default_image();
+    let mut reports = Vec::new();
+    let data = to_bytes(&image, &mut |fraction| reports.push(fraction)).unwrap();
+    assert_eq!(reports.last(), Some(&1.0));
+
+    let mut reports = Vec::new();
+    let loaded = from_bytes(&data, &mut |fraction| reports.push(fraction)).unwrap();
+    assert_eq!(loaded.operations, image.operations);
+    assert!(!reports.is_empty());
+
+    assert!(matches!(
+        from_bytes(b"NOT_A_PAINTING", &mut |_| {}),
+        Err(PainterDataError::InvalidMagicString)
+    ));
+}
+
+#[test]
+fn test_save_atomic() {
+    let path = std::env::temp_dir().join(format!("painter_save_test_{}.sveg", std::process::id()));
+    let image = template::create_default_image();
+    std::fs::write(&path, b"old contents").unwrap();
+
+    let data = save_atomic(&image, &path, &mut |_| {}).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+    let loaded = load_from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(loaded.operations, image.operations);
+
+    // A failed save leaves the original file alone
+    let directory_path = std::env::temp_dir();
+    assert!(write_file_atomic(&directory_path.join(""), b"data").is_err());
+    let loaded = load_from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(loaded.operations, image.operations);
+
+    std::fs::remove_file(&path).unwrap();
+}
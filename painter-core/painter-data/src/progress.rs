@@ -0,0 +1,104 @@
+//! Wrappers around readers and writers that report how far through the data they are.
+
+/// Only report progress when it has changed by at least this much, so that the
+/// callback is not called for every few bytes.
+const REPORT_INTERVAL: f32 = 0.01;
+
+/// Reports the fraction of `total` bytes that have been written
+pub struct ProgressWriter<'a, W> {
+    inner: W,
+    done: u64,
+    total: u64,
+    last_reported: f32,
+    callback: &'a mut dyn FnMut(f32),
+}
+
+impl<'a, W> ProgressWriter<'a, W> {
+    pub fn new(inner: W, total: u64, callback: &'a mut dyn FnMut(f32)) -> Self {
+        Self {
+            inner,
+            done: 0,
+            total,
+            last_reported: 0.0,
+            callback,
+        }
+    }
+}
+
+impl<'a, W: std::io::Write> std::io::Write for ProgressWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.done += written as u64;
+        report(
+            self.done,
+            self.total,
+            &mut self.last_reported,
+            self.callback,
+        );
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reports the fraction of `total` bytes that have been read
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    done: u64,
+    total: u64,
+    last_reported: f32,
+    callback: &'a mut dyn FnMut(f32),
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub fn new(inner: R, total: u64, callback: &'a mut dyn FnMut(f32)) -> Self {
+        Self {
+            inner,
+            done: 0,
+            total,
+            last_reported: 0.0,
+            callback,
+        }
+    }
+}
+
+impl<'a, R: std::io::Read> std::io::Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.done += read as u64;
+        report(
+            self.done,
+            self.total,
+            &mut self.last_reported,
+            self.callback,
+        );
+        Ok(read)
+    }
+}
+
+fn report(done: u64, total: u64, last_reported: &mut f32, callback: &mut dyn FnMut(f32)) {
+    let fraction = (done as f64 / total.max(1) as f64).min(1.0) as f32;
+    if fraction - *last_reported >= REPORT_INTERVAL || (fraction >= 1.0 && *last_reported < 1.0) {
+        *last_reported = fraction;
+        callback(fraction);
+    }
+}
+
+#[test]
+fn test_progress_writer() {
+    use std::io::Write;
+
+    let mut reports = Vec::new();
+    let mut callback = |fraction| reports.push(fraction);
+    let mut writer = ProgressWriter::new(Vec::new(), 1000, &mut callback);
+    for _ in 0..1000 {
+        writer.write_all(&[0]).unwrap();
+    }
+    drop(writer);
+
+    // Reported in steps rather than for every byte
+    assert!(reports.len() > 10 && reports.len() <= 100);
+    assert_eq!(*reports.last().unwrap(), 1.0);
+}
@@ -0,0 +1,234 @@
+//! A grid over the canvas recording which cells the bounds of each stroke touch, so
+//! that the strokes near part of the canvas can be found without looking at all of
+//! them. The index does not watch the image: whoever changes strokes has to call
+//! `update` for them (EditContext does this as changes are applied).
+use std::collections::{HashMap, HashSet};
+
+use crate::bounds::Bounds;
+use crate::id_map::{IdMapBase, OperationId};
+use crate::image::Image;
+use crate::operation::Operation;
+
+/// Width and height of a grid cell in canvas coordinates. The canvas is 2 units high.
+const CELL_SIZE: f32 = 0.125;
+
+/// Strokes that would be in more cells than this are kept in a single list instead
+const MAX_CELLS_PER_STROKE: i64 = 256;
+
+type Cell = (i32, i32);
+
+#[derive(Debug, Clone)]
+pub struct SpatialIndex {
+    /// The canvas aspect ratio the bounds were computed with, as stamps scale with it
+    aspect_ratio: f32,
+
+    /// The bounds of every indexed stroke. None for strokes with no stamps.
+    bounds: HashMap<OperationId, Option<Bounds>>,
+
+    cells: HashMap<Cell, HashSet<OperationId>>,
+
+    /// Strokes too large (or too strange) to store in each cell they touch
+    large: HashSet<OperationId>,
+}
+
+impl Default for SpatialIndex {
+    fn default() -> Self {
+        Self {
+            aspect_ratio: 1.0,
+            bounds: HashMap::new(),
+            cells: HashMap::new(),
+            large: HashSet::new(),
+        }
+    }
+}
+
+impl SpatialIndex {
+    /// Indexes every stroke in an image
+    pub fn new(image: &Image) -> Self {
+        let mut index = Self {
+            aspect_ratio: image.metadata.aspect_ratio(),
+            ..Self::default()
+        };
+        let strokes: Vec<OperationId> = image
+            .operations
+            .iter()
+            .filter(|(_, operation)| matches!(operation, Operation::Stroke(_)))
+            .map(|(id, _)| *id)
+            .collect();
+        for operation_id in strokes {
+            index.update(image, &operation_id);
+        }
+        index
+    }
+
+    /// The aspect ratio the index was built for. If the image's aspect ratio changes
+    /// the index has to be built again.
+    pub fn aspect_ratio(&self) -> f32 {
+        self.aspect_ratio
+    }
+
+    /// Brings the entry for an operation up to date with the image, adding it if it is
+    /// a stroke and removing it otherwise.
+    pub fn update(&mut self, image: &Image, operation_id: &OperationId) {
+        self.remove(operation_id);
+        let stroke = match image.operations.get(operation_id) {
+            Some(Operation::Stroke(stroke)) => stroke,
+            _ => return,
+        };
+        let bounds = stroke.bounds(self.aspect_ratio);
+        self.bounds.insert(*operation_id, bounds);
+        if let Some(bounds) = bounds {
+            match cell_range(&bounds) {
+                Some((min, max)) => {
+                    for x in min.0..=max.0 {
+                        for y in min.1..=max.1 {
+                            self.cells.entry((x, y)).or_default().insert(*operation_id);
+                        }
+                    }
+                }
+                None => {
+                    self.large.insert(*operation_id);
+                }
+            }
+        }
+    }
+
+    /// Forgets an operation. Until it is updated again it is treated as being
+    /// anywhere (see may_intersect).
+    pub fn remove(&mut self, operation_id: &OperationId) {
+        let bounds = match self.bounds.remove(operation_id) {
+            Some(Some(bounds)) => bounds,
+            _ => return,
+        };
+        if !self.large.remove(operation_id) {
+            if let Some((min, max)) = cell_range(&bounds) {
+                for x in min.0..=max.0 {
+                    for y in min.1..=max.1 {
+                        if let Some(cell) = self.cells.get_mut(&(x, y)) {
+                            cell.remove(operation_id);
+                            if cell.is_empty() {
+                                self.cells.remove(&(x, y));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, operation_id: &OperationId) -> bool {
+        self.bounds.contains_key(operation_id)
+    }
+
+    /// The bounds of an indexed stroke. None if it is not indexed or has no stamps.
+    pub fn bounds(&self, operation_id: &OperationId) -> Option<Bounds> {
+        self.bounds.get(operation_id).cloned().flatten()
+    }
+
+    /// The indexed strokes whose bounds overlap `area`
+    pub fn query(&self, area: &Bounds) -> HashSet<OperationId> {
+        let mut found: HashSet<OperationId> = self
+            .large
+            .iter()
+            .filter(|id| {
+                self.bounds(id)
+                    .is_some_and(|bounds| bounds.intersects(area))
+            })
+            .cloned()
+            .collect();
+        let candidates: Box<dyn Iterator<Item = &OperationId>> = match cell_range(area) {
+            Some((min, max)) => Box::new(
+                (min.0..=max.0)
+                    .flat_map(move |x| (min.1..=max.1).map(move |y| (x, y)))
+                    .filter_map(|cell| self.cells.get(&cell))
+                    .flatten(),
+            ),
+            // The area is too large to be worth looking at cell by cell
+            None => Box::new(self.cells.values().flatten()),
+        };
+        for operation_id in candidates {
+            if !found.contains(operation_id)
+                && self
+                    .bounds(operation_id)
+                    .is_some_and(|bounds| bounds.intersects(area))
+            {
+                found.insert(*operation_id);
+            }
+        }
+        found
+    }
+
+    /// Whether an operation could draw anything inside `area`. Operations that are not
+    /// indexed (such as strokes changed since they were last updated) could be
+    /// anywhere.
+    pub fn may_intersect(&self, operation_id: &OperationId, area: &Bounds) -> bool {
+        match self.bounds.get(operation_id) {
+            None => true,
+            Some(None) => false,
+            Some(Some(bounds)) => bounds.intersects(area),
+        }
+    }
+}
+
+/// The first and last cells covered by some bounds, or None if there would be too
+/// many of them.
+fn cell_range(bounds: &Bounds) -> Option<(Cell, Cell)> {
+    let to_cell = |value: f32| (value / CELL_SIZE).floor();
+    let min = [to_cell(bounds.min[0]), to_cell(bounds.min[1])];
+    let max = [to_cell(bounds.max[0]), to_cell(bounds.max[1])];
+    let limit = i32::MAX as f32 / 2.0;
+    if min
+        .iter()
+        .chain(max.iter())
+        .any(|value| !value.is_finite() || value.abs() > limit)
+    {
+        return None;
+    }
+    let cell_count = (max[0] as i64 - min[0] as i64 + 1) * (max[1] as i64 - min[1] as i64 + 1);
+    if cell_count > MAX_CELLS_PER_STROKE {
+        return None;
+    }
+    Some((
+        (min[0] as i32, min[1] as i32),
+        (max[0] as i32, max[1] as i32),
+    ))
+}
+
+#[test]
+fn test_spatial_index() {
+    use crate::stroke::test_stroke;
+    use crate::template::create_default_image;
+
+    let mut image = create_default_image();
+    image.metadata.preview_canvas_size = [100, 100];
+    let stroke =
+        |position: [f32; 2], size: f32| Operation::Stroke(test_stroke(vec![position], size));
+    let small = image.operations.insert(stroke([0.5, 0.5], 0.1));
+    let huge = image.operations.insert(stroke([0.0, 0.0], 10.0));
+    let tag = image
+        .operations
+        .insert(Operation::Tag("Not a stroke".to_string()));
+    let mut index = SpatialIndex::new(&image);
+
+    let near_small = Bounds::new([0.35, 0.35], [0.45, 0.45]);
+    let elsewhere = Bounds::new([-0.9, -0.9], [-0.8, -0.8]);
+    assert_eq!(
+        index.query(&near_small),
+        vec![small, huge].into_iter().collect()
+    );
+    assert_eq!(index.query(&elsewhere), vec![huge].into_iter().collect());
+    assert!(!index.may_intersect(&small, &elsewhere));
+    // Operations that are not indexed could be anywhere
+    assert!(!index.contains(&tag));
+    assert!(index.may_intersect(&tag, &elsewhere));
+
+    // Moving a stroke moves it in the index once it is updated
+    image.operations.force(small, stroke([-0.85, -0.85], 0.1));
+    index.update(&image, &small);
+    assert!(index.query(&elsewhere).contains(&small));
+    assert!(!index.query(&near_small).contains(&small));
+
+    index.remove(&huge);
+    assert!(index.query(&near_small).is_empty());
+    assert!(index.may_intersect(&huge, &near_small));
+}
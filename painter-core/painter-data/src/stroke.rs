@@ -17,4 +17,30 @@ pub struct StrokeData {
 
     pub glyph: GlyphId,
     pub blend_mode: BlendMode,
+
+    /// Only change the color of what is painted on, not it's transparency. Set when
+    /// drawing on an alpha locked layer.
+    pub alpha_locked: bool,
+}
+
+/// A stroke with an opaque black, unrotated, full size stamp at each position
+#[cfg(test)]
+pub(crate) fn test_stroke(positions: Vec<[f32; 2]>, size: f32) -> StrokeData {
+    let black = Color {
+        r: 0.0,
+        g: 0.0,
+        b: 0.0,
+        a: 1.0,
+    };
+    StrokeData {
+        angle_array: positions.iter().map(|_| 0.0).collect(),
+        size_array: positions.iter().map(|_| 1.0).collect(),
+        color_array: positions.iter().map(|_| black.clone()).collect(),
+        position_array: positions,
+        size,
+        color: black,
+        glyph: GlyphId::default(),
+        blend_mode: BlendMode::Mix(1.0),
+        alpha_locked: false,
+    }
 }
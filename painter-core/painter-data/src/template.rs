@@ -1,4 +1,4 @@
-use crate::brush::{Brush, Glyph, PressureSettings};
+use crate::brush::{Brush, Glyph, PressureCurve, PressureSettings};
 use crate::color_primitives::{BlendMode, Color};
 // use crate::depgraph::DepGraph;
 use crate::id_map::{BrushIdMap, GlyphIdMap, IdMapBase, LayerIdMap, OperationIdMap};
@@ -6,6 +6,7 @@ use crate::image::{Image, MetaData};
 use crate::layer::Layer;
 use crate::operation::Operation;
 use painter_depgraph::DepGraph;
+use std::collections::BTreeMap;
 
 pub fn create_default_image() -> Image {
     let mut image = Image {
@@ -24,6 +25,7 @@ pub fn create_default_image() -> Image {
                 a: 1.0,
             },
         },
+        extensions: BTreeMap::new(),
     };
 
     let output_op_id = image.operations.insert(Operation::Output(0));
@@ -31,10 +33,9 @@ pub fn create_default_image() -> Image {
         .operations
         .insert(Operation::Composite(BlendMode::Mix(1.0)));
 
-    image.layers.insert(Layer {
-        name: "Background".to_string(),
-        blend_operation_id: background_blend_op_id.clone(),
-    });
+    image
+        .layers
+        .insert(Layer::new("Background".to_string(), background_blend_op_id));
     let canvas_base = image
         .operations
         .insert(Operation::Tag("CanvasBase".to_string()));
@@ -59,21 +60,33 @@ pub fn create_default_image() -> Image {
             min_value: 0.0,
             max_value: 1.0,
             random: 0.0,
+            curve: PressureCurve::linear(),
         },
         flow: PressureSettings {
             min_value: 0.5,
             max_value: 1.0,
             random: 0.0,
+            curve: PressureCurve::linear(),
         },
         scatter: PressureSettings {
             min_value: 0.0,
             max_value: 0.0,
             random: 0.0,
+            curve: PressureCurve::linear(),
         },
+        // A fraction of the stamp's width, so strokes are continuous but don't pile up
+        // stamps where they are drawn slowly
         gap: PressureSettings {
+            min_value: 0.1,
+            max_value: 0.25,
+            random: 0.0,
+            curve: PressureCurve::linear(),
+        },
+        angle: PressureSettings {
             min_value: 0.0,
             max_value: 0.0,
             random: 0.0,
+            curve: PressureCurve::linear(),
         },
     });
 
@@ -31,7 +31,11 @@ fn main() {
         10,
         &mut |op| println!("Loading {:?} into {:?}", op.id, op.addr),
         &mut |op| println!("Deleting {:?} from {:?}", op.id, op.addr),
-        &mut |op, deps| println!("Executing {:?} ({:?}) deps: {:?}", op.id, op.addr, deps),
+        &mut |op| println!("Spilling {:?} from {:?}", op.id, op.addr),
+        &mut |op| println!("Restoring {:?} into {:?}", op.id, op.addr),
+        &mut |op, deps, _mutable_deps| {
+            println!("Executing {:?} ({:?}) deps: {:?}", op.id, op.addr, deps)
+        },
     )
     .expect("Execution Failed");
 }
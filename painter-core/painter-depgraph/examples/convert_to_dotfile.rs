@@ -16,6 +16,6 @@ fn main() {
 
     println!(
         "{}",
-        d.generate_dotgraph(&|x| format!("{}", names.get(x).unwrap()))
+        d.generate_dotgraph(&|x| names.get(x).unwrap().to_string())
     )
 }
@@ -1,7 +1,7 @@
 use painter_depgraph::{default_executor, LocatedOperation, Operation, OperationStage};
 
 fn to_op<I: Clone + std::fmt::Debug>(op: I, addr: usize) -> LocatedOperation<I> {
-    LocatedOperation { id: op, addr: addr }
+    LocatedOperation { id: op, addr }
 }
 
 fn main() {
@@ -37,36 +37,50 @@ fn main() {
     let stages = vec![
         OperationStage {
             operation: (e, 0),
+            spill_before: vec![],
+            restore_before: vec![],
             allocate_before: vec![to_op('E', 0)],
             delete_after: vec![],
         },
         OperationStage {
             operation: (f, 3),
+            spill_before: vec![],
+            restore_before: vec![],
             allocate_before: vec![to_op('F', 3)],
             delete_after: vec![],
         },
         OperationStage {
             operation: (g, 2),
+            spill_before: vec![],
+            restore_before: vec![],
             allocate_before: vec![to_op('G', 2)],
             delete_after: vec![],
         },
         OperationStage {
             operation: (d, 1),
+            spill_before: vec![],
+            restore_before: vec![],
             allocate_before: vec![to_op('D', 1)],
             delete_after: vec![to_op('E', 0)],
         },
         OperationStage {
             operation: (c, 0),
+            spill_before: vec![],
+            restore_before: vec![],
             allocate_before: vec![to_op('C', 0)],
             delete_after: vec![to_op('D', 1), to_op('F', 3)],
         },
         OperationStage {
             operation: (b, 1),
+            spill_before: vec![],
+            restore_before: vec![],
             allocate_before: vec![to_op('B', 1)],
             delete_after: vec![to_op('C', 0)],
         },
         OperationStage {
             operation: (a, 0),
+            spill_before: vec![],
+            restore_before: vec![],
             allocate_before: vec![to_op('A', 0)],
             delete_after: vec![to_op('B', 1), to_op('G', 2)],
         },
@@ -77,7 +91,11 @@ fn main() {
         10,
         &mut |op| println!("Loading {:?} into {:?}", op.id, op.addr),
         &mut |op| println!("Deleting {:?} from {:?}", op.id, op.addr),
-        &mut |op, deps| println!("Executing {:?} ({:?}) deps: {:?}", op.id, op.addr, deps),
+        &mut |op| println!("Spilling {:?} from {:?}", op.id, op.addr),
+        &mut |op| println!("Restoring {:?} into {:?}", op.id, op.addr),
+        &mut |op, deps, _mutable_deps| {
+            println!("Executing {:?} ({:?}) deps: {:?}", op.id, op.addr, deps)
+        },
     )
     .expect("Arrgh!");
 }
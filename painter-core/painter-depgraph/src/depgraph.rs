@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
 
@@ -9,6 +9,30 @@ pub struct DepGraph<I: Hash + Eq + Debug + Clone> {
     nodes: HashMap<I, Vec<I>>,
 }
 
+/// Reasons a checked edit of the depgraph was refused
+#[derive(Debug, PartialEq)]
+pub enum DepGraphError<I> {
+    /// The edit would make an operation depend on itself. Contains the nodes that would
+    /// form the cycle, each depending on the next and the last depending on the first.
+    WouldCreateCycle(Vec<I>),
+
+    /// A node the edit refers to is not in the depgraph
+    UnknownNode(I),
+
+    /// The new node is already in the depgraph
+    NodeAlreadyExists(I),
+
+    /// The node does not depend on the dependency the edit refers to
+    NotADependency { node: I, dependency: I },
+
+    /// The node does not have that many dependencies
+    EdgeOutOfRange { node: I, edge_index: usize },
+
+    /// The node cannot be moved as part of a chain as it does not have exactly one
+    /// dependency, or something outside of the chain depends on it.
+    NotAChain(I),
+}
+
 impl<I: Hash + Eq + Debug + Clone> Default for DepGraph<I> {
     fn default() -> Self {
         Self {
@@ -36,13 +60,7 @@ impl<I: Hash + Eq + Debug + Clone> DepGraph<I> {
     pub fn dependees(&self, operation: &I) -> Vec<&I> {
         self.nodes
             .iter()
-            .filter_map(|(k, v)| {
-                if v.contains(&operation) {
-                    Some(k)
-                } else {
-                    None
-                }
-            })
+            .filter_map(|(k, v)| if v.contains(operation) { Some(k) } else { None })
             .collect()
     }
 
@@ -63,7 +81,10 @@ impl<I: Hash + Eq + Debug + Clone> DepGraph<I> {
                 let mut dep_hasher = DefaultHasher::new();
                 dependant_node.hash(&mut dep_hasher);
                 let dep_hash = dep_hasher.finish();
-                outstr += &format!("    op_{} -> op_{} [label={}];\n", node_hash, dep_hash, edge_id);
+                outstr += &format!(
+                    "    op_{} -> op_{} [label={}];\n",
+                    node_hash, dep_hash, edge_id
+                );
             }
         }
         outstr += "}\n";
@@ -74,7 +95,7 @@ impl<I: Hash + Eq + Debug + Clone> DepGraph<I> {
         self.nodes.contains_key(node)
     }
 
-    pub fn iter_nodes(&self) -> std::collections::hash_map::Keys<I, Vec<I>> {
+    pub fn iter_nodes(&self) -> std::collections::hash_map::Keys<'_, I, Vec<I>> {
         self.nodes.keys()
     }
 
@@ -134,6 +155,544 @@ impl<I: Hash + Eq + Debug + Clone> DepGraph<I> {
             existing_children[index] = new_operation.clone();
         }
     }
+
+    /// As with `insert`, but refuses to make the change if it would create a cycle.
+    /// Dependencies that are not (yet) in the depgraph are allowed.
+    pub fn try_insert(&mut self, operation: I, depends_on: Vec<I>) -> Result<(), DepGraphError<I>> {
+        for dep in depends_on.iter() {
+            if let Some(cycle) = self.cycle_if_depending(&operation, dep) {
+                return Err(DepGraphError::WouldCreateCycle(cycle));
+            }
+        }
+        self.insert(operation, depends_on);
+        Ok(())
+    }
+
+    /// As with `operate_on`, but returns an error rather than panicking if the base does
+    /// not exist or the new operation is already present, and refuses to make the change
+    /// if it would create a cycle.
+    pub fn try_operate_on(&mut self, new_operation: I, base: I) -> Result<(), DepGraphError<I>> {
+        if !self.contains(&base) {
+            return Err(DepGraphError::UnknownNode(base));
+        }
+        if self.contains(&new_operation) {
+            return Err(DepGraphError::NodeAlreadyExists(new_operation));
+        }
+        // The new operation could already be referenced as a dangling dependency, in
+        // which case it depending on base could close a loop.
+        if let Some(cycle) = self.cycle_if_depending(&new_operation, &base) {
+            return Err(DepGraphError::WouldCreateCycle(cycle));
+        }
+        self.operate_on(new_operation, base);
+        Ok(())
+    }
+
+    /// Removes a node from the depgraph, returning what it depended on. Anything that
+    /// depended on the node is left with a dangling dependency, so usually one of
+    /// `remove_and_bridge` or `remove_subgraph` is what you want.
+    pub fn remove(&mut self, node: &I) -> Option<Vec<I>> {
+        self.nodes.remove(node)
+    }
+
+    /// Removes a node, connecting the nodes that depended on it to it's dependencies.
+    /// This is the opposite of `operate_on`. Eg:
+    /// ```ignore
+    ///    A ----> Node -----> Base  ----> C
+    /// ```
+    /// Goes to:
+    /// ```ignore
+    ///    A -----> Base ---------> C
+    /// ```
+    ///
+    /// If the node had several dependencies they are all spliced into the dependees
+    /// dependency list where the node used to be. If it had none, the edges to it are
+    /// removed.
+    pub fn remove_and_bridge(&mut self, node: &I) -> Result<(), DepGraphError<I>> {
+        let bridged = self
+            .nodes
+            .remove(node)
+            .ok_or_else(|| DepGraphError::UnknownNode(node.clone()))?;
+
+        for depends_on in self.nodes.values_mut() {
+            if depends_on.contains(node) {
+                *depends_on = depends_on
+                    .drain(..)
+                    .flat_map(|dep| {
+                        if dep == *node {
+                            bridged.clone()
+                        } else {
+                            vec![dep]
+                        }
+                    })
+                    .collect();
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `root` along with everything it (indirectly) depends on that is not also
+    /// used by something outside of the removed nodes. Edges from other nodes to `root`
+    /// are removed. Returns the nodes that were removed.
+    ///
+    /// Eg removing Layer:
+    /// ```ignore
+    ///                   Stroke2 ---> Stroke1
+    ///                     ^             |
+    ///                     |             V
+    /// Output ---> Layer --+------->  Base
+    /// ```
+    /// Removes Layer, Stroke2 and Stroke1, leaving Output and Base
+    pub fn remove_subgraph(&mut self, root: &I) -> Result<Vec<I>, DepGraphError<I>> {
+        let removed = self.subgraph_to_remove(root)?;
+        for node in removed.iter() {
+            self.nodes.remove(node);
+        }
+        for depends_on in self.nodes.values_mut() {
+            depends_on.retain(|dep| dep != root);
+        }
+        Ok(removed)
+    }
+
+    /// The nodes that `remove_subgraph` would remove, without removing them
+    pub fn subgraph_to_remove(&self, root: &I) -> Result<Vec<I>, DepGraphError<I>> {
+        if !self.contains(root) {
+            return Err(DepGraphError::UnknownNode(root.clone()));
+        }
+
+        // A node can be removed once every node depending on it has been removed
+        let mut remaining_dependees: HashMap<&I, usize> = HashMap::new();
+        for depends_on in self.nodes.values() {
+            let unique_deps: HashSet<&I> = depends_on.iter().collect();
+            for dep in unique_deps {
+                *remaining_dependees.entry(dep).or_default() += 1;
+            }
+        }
+
+        let mut removed: Vec<I> = vec![root.clone()];
+        let mut to_visit: Vec<&I> = vec![root];
+        while let Some(node) = to_visit.pop() {
+            let unique_deps: HashSet<&I> = self.nodes[node].iter().collect();
+            for dep in unique_deps {
+                if dep == root || !self.contains(dep) {
+                    continue;
+                }
+                let count = remaining_dependees
+                    .get_mut(dep)
+                    .expect("All dependencies are counted");
+                *count -= 1;
+                if *count == 0 {
+                    removed.push(dep.clone());
+                    to_visit.push(dep);
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Moves the chain of operations from `top` down to `bottom` so that it operates on
+    /// `new_base` instead of whatever it was on before. The gap left behind is bridged as
+    /// with `remove_and_bridge` and the chain is inserted on top of `new_base` as with
+    /// `operate_on`. Eg:
+    /// ```ignore
+    ///    A ----> Top ----> Bottom ----> OldBase
+    ///    B ----> NewBase
+    /// ```
+    /// Goes to:
+    /// ```ignore
+    ///    A ----> OldBase
+    ///    B ----> Top ----> Bottom ----> NewBase
+    /// ```
+    ///
+    /// Each node in the chain must have exactly one dependency, and nodes other than
+    /// `top` must only be depended upon by the node above them in the chain.
+    /// `top` and `bottom` may be the same node.
+    pub fn move_chain(
+        &mut self,
+        top: &I,
+        bottom: &I,
+        new_base: &I,
+    ) -> Result<(), DepGraphError<I>> {
+        if !self.contains(new_base) {
+            return Err(DepGraphError::UnknownNode(new_base.clone()));
+        }
+
+        let mut chain = vec![top.clone()];
+        loop {
+            let current = chain.last().expect("Chain is not empty");
+            let depends_on = self
+                .depends_on(current)
+                .ok_or_else(|| DepGraphError::UnknownNode(current.clone()))?;
+            if depends_on.len() != 1 || chain.contains(&depends_on[0]) {
+                return Err(DepGraphError::NotAChain(current.clone()));
+            }
+            if current != top && self.dependees(current).len() != 1 {
+                return Err(DepGraphError::NotAChain(current.clone()));
+            }
+            if current == bottom {
+                break;
+            }
+            chain.push(depends_on[0].clone());
+        }
+
+        if let Some(position) = chain.iter().position(|node| node == new_base) {
+            return Err(DepGraphError::WouldCreateCycle(chain[position..].to_vec()));
+        }
+
+        let old_base = self.nodes[bottom][0].clone();
+        for (node, depends_on) in self.nodes.iter_mut() {
+            if chain.contains(node) {
+                continue;
+            }
+            for dep in depends_on.iter_mut() {
+                if dep == top {
+                    *dep = old_base.clone();
+                }
+            }
+        }
+        for (node, depends_on) in self.nodes.iter_mut() {
+            if chain.contains(node) {
+                continue;
+            }
+            for dep in depends_on.iter_mut() {
+                if dep == new_base {
+                    *dep = top.clone();
+                }
+            }
+        }
+        self.nodes.insert(bottom.clone(), vec![new_base.clone()]);
+        Ok(())
+    }
+
+    /// Inserts a new operation "underneath" an existing one, so that the existing
+    /// operation operates on the new one. This is the mirror of `operate_on`. Eg:
+    /// ```ignore
+    ///    A -----> Base ---------> C
+    /// ```
+    /// Goes to:
+    /// ```ignore
+    ///    A ----> Base -----> New Operation  ----> C
+    /// ```
+    /// All of base's dependencies are moved to the new operation.
+    pub fn insert_below(&mut self, new_operation: I, base: &I) -> Result<(), DepGraphError<I>> {
+        let depends_on = self
+            .depends_on(base)
+            .ok_or_else(|| DepGraphError::UnknownNode(base.clone()))?
+            .clone();
+        if self.contains(&new_operation) {
+            return Err(DepGraphError::NodeAlreadyExists(new_operation));
+        }
+        for dep in depends_on.iter() {
+            if let Some(cycle) = self.cycle_if_depending(&new_operation, dep) {
+                return Err(DepGraphError::WouldCreateCycle(cycle));
+            }
+        }
+        self.nodes.insert(new_operation.clone(), depends_on);
+        self.nodes.insert(base.clone(), vec![new_operation]);
+        Ok(())
+    }
+
+    /// Makes `node` depend on `new_dependency` everywhere it used to depend on
+    /// `old_dependency`. The position in the list of dependencies is preserved.
+    pub fn replace_dependency(
+        &mut self,
+        node: &I,
+        old_dependency: &I,
+        new_dependency: I,
+    ) -> Result<(), DepGraphError<I>> {
+        let depends_on = self
+            .depends_on(node)
+            .ok_or_else(|| DepGraphError::UnknownNode(node.clone()))?;
+        if !depends_on.contains(old_dependency) {
+            return Err(DepGraphError::NotADependency {
+                node: node.clone(),
+                dependency: old_dependency.clone(),
+            });
+        }
+        if let Some(cycle) = self.cycle_if_depending(node, &new_dependency) {
+            return Err(DepGraphError::WouldCreateCycle(cycle));
+        }
+
+        for dep in self.nodes.get_mut(node).expect("Checked above").iter_mut() {
+            if dep == old_dependency {
+                *dep = new_dependency.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `node` depends on `dependency`, either directly or through other nodes
+    pub fn depends_on_indirectly(&self, node: &I, dependency: &I) -> bool {
+        node != dependency && self.dependency_path(node, dependency).is_some()
+    }
+
+    /// Returns every (dependee, edge index) pair where the dependee's dependency at that
+    /// index is `dependency`. An operation that uses the same input twice appears twice.
+    pub fn edges_to(&self, dependency: &I) -> Vec<(I, usize)> {
+        self.nodes
+            .iter()
+            .flat_map(|(node, depends_on)| {
+                depends_on
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, dep)| *dep == dependency)
+                    .map(move |(edge_index, _)| (node.clone(), edge_index))
+            })
+            .collect()
+    }
+
+    /// Points edge `edge_index` of `node` at `new_dependency`, returning the dependency
+    /// it used to point at.
+    pub fn set_dependency(
+        &mut self,
+        node: &I,
+        edge_index: usize,
+        new_dependency: I,
+    ) -> Result<I, DepGraphError<I>> {
+        self.check_edge(node, edge_index)?;
+        if let Some(cycle) = self.cycle_if_depending(node, &new_dependency) {
+            return Err(DepGraphError::WouldCreateCycle(cycle));
+        }
+        let depends_on = self.nodes.get_mut(node).expect("Checked above");
+        Ok(std::mem::replace(
+            &mut depends_on[edge_index],
+            new_dependency,
+        ))
+    }
+
+    /// Inserts a new operation on a single edge, so that `node` depends on the new
+    /// operation at `edge_index`, and the new operation depends on what `node` used to.
+    /// Unlike `insert_below` the other dependencies of `node` are left alone. Eg
+    /// inserting on edge 0:
+    /// ```ignore
+    ///    A --0--> B
+    ///    A --1--> C
+    /// ```
+    /// Goes to:
+    /// ```ignore
+    ///    A --0--> New --0--> B
+    ///    A --1--> C
+    /// ```
+    pub fn insert_on_edge(
+        &mut self,
+        new_operation: I,
+        node: &I,
+        edge_index: usize,
+    ) -> Result<(), DepGraphError<I>> {
+        self.check_edge(node, edge_index)?;
+        if self.contains(&new_operation) {
+            return Err(DepGraphError::NodeAlreadyExists(new_operation));
+        }
+        let old_dependency = self.nodes[node][edge_index].clone();
+        if let Some(cycle) = self.cycle_if_depending(&new_operation, &old_dependency) {
+            return Err(DepGraphError::WouldCreateCycle(cycle));
+        }
+        self.nodes
+            .insert(new_operation.clone(), vec![old_dependency]);
+        self.nodes.get_mut(node).expect("Checked above")[edge_index] = new_operation;
+        Ok(())
+    }
+
+    /// Removes edge `edge_index` of `node`, returning the dependency it pointed at.
+    /// Later edges move down by one.
+    pub fn remove_dependency(
+        &mut self,
+        node: &I,
+        edge_index: usize,
+    ) -> Result<I, DepGraphError<I>> {
+        self.check_edge(node, edge_index)?;
+        Ok(self
+            .nodes
+            .get_mut(node)
+            .expect("Checked above")
+            .remove(edge_index))
+    }
+
+    fn check_edge(&self, node: &I, edge_index: usize) -> Result<(), DepGraphError<I>> {
+        let depends_on = self
+            .depends_on(node)
+            .ok_or_else(|| DepGraphError::UnknownNode(node.clone()))?;
+        if edge_index >= depends_on.len() {
+            return Err(DepGraphError::EdgeOutOfRange {
+                node: node.clone(),
+                edge_index,
+            });
+        }
+        Ok(())
+    }
+
+    /// If `node` were to depend on `dependency`, would there be a cycle, and if so
+    /// what nodes would form it.
+    fn cycle_if_depending(&self, node: &I, dependency: &I) -> Option<Vec<I>> {
+        // The path ends back at the node, which is where the cycle starts
+        let path = self.dependency_path(dependency, node)?;
+        let mut cycle = vec![node.clone()];
+        cycle.extend(path.into_iter().take_while(|other| other != node));
+        Some(cycle)
+    }
+
+    /// Finds the shortest chain of dependencies leading from `from` to `to`, including
+    /// both ends. Returns None if `from` does not (indirectly) depend on `to`.
+    fn dependency_path(&self, from: &I, to: &I) -> Option<Vec<I>> {
+        let mut came_from: HashMap<I, I> = HashMap::new();
+        let mut queue: VecDeque<I> = VecDeque::new();
+        queue.push_back(from.clone());
+
+        while let Some(node) = queue.pop_front() {
+            if node == *to {
+                let mut path = vec![node];
+                while let Some(previous) = came_from.get(path.last().expect("Path not empty")) {
+                    path.push(previous.clone());
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for dep in self.depends_on(&node).into_iter().flatten() {
+                if dep != from && !came_from.contains_key(dep) {
+                    came_from.insert(dep.clone(), node.clone());
+                    queue.push_back(dep.clone());
+                }
+            }
+        }
+        None
+    }
+
+    /// Finds all the groups of nodes that (indirectly) depend on themselves. Each group
+    /// is a strongly connected component of the graph, so every node in it depends on
+    /// every other node in it. The order of groups and of nodes within them is arbitrary.
+    pub fn find_cycles(&self) -> Vec<Vec<I>> {
+        // Tarjan's algorithm, but with an explicit stack as layer stacks can be very deep
+        let mut next_index = 0;
+        let mut indices: HashMap<I, usize> = HashMap::new();
+        let mut lowlinks: HashMap<I, usize> = HashMap::new();
+        let mut component_stack: Vec<I> = Vec::new();
+        let mut on_component_stack: HashSet<I> = HashSet::new();
+        let mut cycles = Vec::new();
+
+        for start in self.nodes.keys() {
+            if indices.contains_key(start) {
+                continue;
+            }
+            let mut stack: Vec<(I, usize)> = vec![(start.clone(), 0)];
+            indices.insert(start.clone(), next_index);
+            lowlinks.insert(start.clone(), next_index);
+            next_index += 1;
+            component_stack.push(start.clone());
+            on_component_stack.insert(start.clone());
+
+            while let Some((node, next_dep)) = stack.last().cloned() {
+                let deps = &self.nodes[&node];
+                if let Some(dep) = deps.get(next_dep) {
+                    stack.last_mut().expect("Stack is not empty").1 += 1;
+                    if !self.nodes.contains_key(dep) {
+                        continue;
+                    }
+                    if !indices.contains_key(dep) {
+                        indices.insert(dep.clone(), next_index);
+                        lowlinks.insert(dep.clone(), next_index);
+                        next_index += 1;
+                        component_stack.push(dep.clone());
+                        on_component_stack.insert(dep.clone());
+                        stack.push((dep.clone(), 0));
+                    } else if on_component_stack.contains(dep) {
+                        let lowlink = lowlinks[&node].min(indices[dep]);
+                        lowlinks.insert(node.clone(), lowlink);
+                    }
+                } else {
+                    stack.pop();
+                    let node_lowlink = lowlinks[&node];
+                    if let Some((parent, _)) = stack.last() {
+                        let lowlink = lowlinks[parent].min(node_lowlink);
+                        lowlinks.insert(parent.clone(), lowlink);
+                    }
+                    if node_lowlink == indices[&node] {
+                        let mut component = Vec::new();
+                        loop {
+                            let member = component_stack.pop().expect("Node is on the stack");
+                            on_component_stack.remove(&member);
+                            let is_root = member == node;
+                            component.push(member);
+                            if is_root {
+                                break;
+                            }
+                        }
+                        if component.len() > 1 || deps.contains(&node) {
+                            cycles.push(component);
+                        }
+                    }
+                }
+            }
+        }
+        cycles
+    }
+
+    /// Returns all the nodes that none of the outputs (indirectly) depend on. These
+    /// do not contribute to the result and could be removed.
+    pub fn unreachable_from(&self, outputs: &[I]) -> Vec<I> {
+        let mut reachable: HashSet<&I> = HashSet::new();
+        let mut to_visit: Vec<&I> = outputs.iter().collect();
+        while let Some(node) = to_visit.pop() {
+            if reachable.insert(node) {
+                to_visit.extend(self.depends_on(node).into_iter().flatten());
+            }
+        }
+        self.nodes
+            .keys()
+            .filter(|node| !reachable.contains(node))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns (node, dependency) for every dependency that is not in the depgraph
+    pub fn dangling_dependencies(&self) -> Vec<(I, I)> {
+        self.nodes
+            .iter()
+            .flat_map(|(node, deps)| {
+                deps.iter()
+                    .filter(|dep| !self.nodes.contains_key(dep))
+                    .map(move |dep| (node.clone(), dep.clone()))
+            })
+            .collect()
+    }
+
+    /// Orders all the nodes in the depgraph so that every node comes after all of it's
+    /// dependencies. Dangling dependencies are ignored.
+    /// If this is not possible, returns the nodes that are part of cycles.
+    pub fn topological_order(&self) -> Result<Vec<I>, Vec<I>> {
+        let mut remaining_deps: HashMap<&I, usize> = HashMap::new();
+        let mut dependees: HashMap<&I, Vec<&I>> = HashMap::new();
+        for (node, deps) in self.nodes.iter() {
+            let present_deps: Vec<&I> = deps.iter().filter(|dep| self.contains(dep)).collect();
+            remaining_deps.insert(node, present_deps.len());
+            for dep in present_deps {
+                dependees.entry(dep).or_default().push(node);
+            }
+        }
+
+        let mut ready: Vec<&I> = remaining_deps
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(node, _)| *node)
+            .collect();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(node) = ready.pop() {
+            order.push(node.clone());
+            for dependee in dependees.get(node).into_iter().flatten() {
+                let count = remaining_deps
+                    .get_mut(dependee)
+                    .expect("All nodes have a count");
+                *count -= 1;
+                if *count == 0 {
+                    ready.push(dependee);
+                }
+            }
+        }
+
+        if order.len() == self.nodes.len() {
+            Ok(order)
+        } else {
+            Err(self.find_cycles().into_iter().flatten().collect())
+        }
+    }
 }
 
 #[test]
@@ -204,15 +763,15 @@ fn test_operate_on_with_multi_deps() {
     let d = "D";
     let new = "New";
 
-    graph.insert(base.clone(), vec![c.clone(), d.clone()]);
-    graph.insert(a.clone(), vec![base.clone()]);
-    graph.insert(b.clone(), vec![base.clone()]);
-    graph.insert(c.clone(), vec![]);
-    graph.insert(d.clone(), vec![]);
+    graph.insert(base, vec![c, d]);
+    graph.insert(a, vec![base]);
+    graph.insert(b, vec![base]);
+    graph.insert(c, vec![]);
+    graph.insert(d, vec![]);
 
     // println!("{}", graph.generate_dotgraph(&|x| format!("{}", x)));
 
-    graph.operate_on(new.clone(), base.clone());
+    graph.operate_on(new, base);
 
     // println!("{}", graph.generate_dotgraph(&|x| format!("{}", x)));
 
@@ -226,3 +785,390 @@ fn test_operate_on_with_multi_deps() {
     assert!(!graph.depends_on(&a).expect("Missing Dep").contains(&base));
     assert!(!graph.depends_on(&b).expect("Missing Dep").contains(&base));
 }
+
+#[test]
+fn test_find_cycles() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2]);
+    graph.insert(2, vec![3]);
+    graph.insert(3, vec![4, 6]);
+    graph.insert(4, vec![2]);
+    graph.insert(5, vec![5]);
+    graph.insert(6, vec![]);
+
+    let mut cycles: Vec<Vec<i32>> = graph
+        .find_cycles()
+        .into_iter()
+        .map(|mut cycle| {
+            cycle.sort_unstable();
+            cycle
+        })
+        .collect();
+    cycles.sort();
+    assert_eq!(cycles, vec![vec![2, 3, 4], vec![5]]);
+
+    let mut acyclic = DepGraph::default();
+    acyclic.insert(1, vec![2, 3]);
+    acyclic.insert(2, vec![3]);
+    acyclic.insert(3, vec![]);
+    assert!(acyclic.find_cycles().is_empty());
+}
+
+#[test]
+fn test_find_cycles_long_chain() {
+    // Should not overflow the stack
+    let mut graph = DepGraph::default();
+    for i in 0..10000 {
+        graph.insert(i, vec![i + 1]);
+    }
+    graph.insert(10000, vec![0]);
+    assert_eq!(graph.find_cycles()[0].len(), 10001);
+}
+
+#[test]
+fn test_unreachable_from() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2]);
+    graph.insert(2, vec![]);
+    graph.insert(3, vec![2]);
+    graph.insert(4, vec![3]);
+
+    let mut unreachable = graph.unreachable_from(&[1]);
+    unreachable.sort_unstable();
+    assert_eq!(unreachable, vec![3, 4]);
+
+    assert!(graph.unreachable_from(&[1, 4]).is_empty());
+}
+
+#[test]
+fn test_dangling_dependencies() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2, 3]);
+    graph.insert(2, vec![4]);
+
+    let mut dangling = graph.dangling_dependencies();
+    dangling.sort_unstable();
+    assert_eq!(dangling, vec![(1, 3), (2, 4)]);
+}
+
+#[test]
+fn test_topological_order() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2, 3]);
+    graph.insert(2, vec![3, 3]);
+    graph.insert(3, vec![4]);
+
+    let order = graph.topological_order().expect("Graph is acyclic");
+    let position = |x| order.iter().position(|y| *y == x).unwrap();
+    assert_eq!(order.len(), 3);
+    assert!(position(1) > position(2));
+    assert!(position(2) > position(3));
+}
+
+#[test]
+fn test_topological_order_cycle() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2]);
+    graph.insert(2, vec![3]);
+    graph.insert(3, vec![2]);
+
+    let mut offending = graph.topological_order().unwrap_err();
+    offending.sort_unstable();
+    assert_eq!(offending, vec![2, 3]);
+}
+
+#[test]
+fn test_try_insert() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2]);
+    graph.insert(2, vec![3]);
+    graph.insert(3, vec![]);
+
+    assert_eq!(
+        graph.try_insert(3, vec![1]),
+        Err(DepGraphError::WouldCreateCycle(vec![3, 1, 2]))
+    );
+    assert_eq!(
+        graph.try_insert(3, vec![3]),
+        Err(DepGraphError::WouldCreateCycle(vec![3]))
+    );
+    // Refused edits leave the graph as it was
+    assert_eq!(graph.depends_on(&3), Some(&vec![]));
+
+    assert_eq!(graph.try_insert(3, vec![4]), Ok(()));
+    assert_eq!(graph.try_insert(1, vec![3]), Ok(()));
+    assert_eq!(graph.depends_on(&1), Some(&vec![3]));
+}
+
+#[test]
+fn test_try_operate_on() {
+    let mut graph = DepGraph::default();
+    graph.insert("A", vec!["Base"]);
+    graph.insert("Base", vec!["New"]);
+
+    assert_eq!(
+        graph.try_operate_on("New", "Missing"),
+        Err(DepGraphError::UnknownNode("Missing"))
+    );
+    assert_eq!(
+        graph.try_operate_on("A", "Base"),
+        Err(DepGraphError::NodeAlreadyExists("A"))
+    );
+    // "New" is already depended on by "Base", so depending on "Base" would loop
+    assert_eq!(
+        graph.try_operate_on("New", "Base"),
+        Err(DepGraphError::WouldCreateCycle(vec!["New", "Base"]))
+    );
+
+    graph.insert("Base", vec![]);
+    assert_eq!(graph.try_operate_on("New", "Base"), Ok(()));
+    assert_eq!(graph.depends_on(&"A"), Some(&vec!["New"]));
+}
+
+#[test]
+fn test_remove_and_bridge() {
+    let mut graph = DepGraph::default();
+
+    let a = "A";
+    let b = "B";
+    let node = "Node";
+    let c = "C";
+    let d = "D";
+
+    graph.insert(a, vec![node]);
+    graph.insert(b, vec![c, node]);
+    graph.insert(node, vec![c, d]);
+    graph.insert(c, vec![]);
+    graph.insert(d, vec![]);
+
+    graph.remove_and_bridge(&node).expect("Remove failed");
+
+    assert!(!graph.contains(&node));
+    assert_eq!(graph.depends_on(&a), Some(&vec![c, d]));
+    assert_eq!(graph.depends_on(&b), Some(&vec![c, c, d]));
+    assert!(graph.dangling_dependencies().is_empty());
+
+    assert_eq!(
+        graph.remove_and_bridge(&node),
+        Err(DepGraphError::UnknownNode(node))
+    );
+}
+
+#[test]
+fn test_remove_subgraph() {
+    let mut graph = DepGraph::default();
+
+    let output = "Output";
+    let layer = "Layer";
+    let stroke1 = "Stroke1";
+    let stroke2 = "Stroke2";
+    let base = "Base";
+
+    graph.insert(output, vec![layer]);
+    graph.insert(layer, vec![stroke2, base]);
+    graph.insert(stroke2, vec![stroke1]);
+    graph.insert(stroke1, vec![base]);
+    graph.insert(base, vec![]);
+
+    // Base is still used by the output once the layer has gone
+    graph
+        .replace_dependency(&output, &layer, base)
+        .expect("Replace failed");
+    let mut removed = graph.remove_subgraph(&layer).expect("Remove failed");
+    removed.sort_unstable();
+
+    assert_eq!(removed, vec![layer, stroke1, stroke2]);
+    assert!(graph.contains(&output));
+    assert!(graph.contains(&base));
+    assert_eq!(graph.depends_on(&output), Some(&vec![base]));
+    assert!(graph.dangling_dependencies().is_empty());
+}
+
+#[test]
+fn test_remove_subgraph_removes_edges_to_root() {
+    let mut graph = DepGraph::default();
+
+    graph.insert("A", vec!["Root", "B"]);
+    graph.insert("Root", vec!["B"]);
+    graph.insert("B", vec![]);
+
+    let removed = graph.remove_subgraph(&"Root").expect("Remove failed");
+
+    assert_eq!(removed, vec!["Root"]);
+    assert_eq!(graph.depends_on(&"A"), Some(&vec!["B"]));
+}
+
+#[test]
+fn test_move_chain() {
+    let mut graph = DepGraph::default();
+
+    let a = "A";
+    let b = "B";
+    let top = "Top";
+    let bottom = "Bottom";
+    let old_base = "OldBase";
+    let new_base = "NewBase";
+
+    graph.insert(a, vec![top]);
+    graph.insert(top, vec![bottom]);
+    graph.insert(bottom, vec![old_base]);
+    graph.insert(old_base, vec![]);
+    graph.insert(b, vec![new_base]);
+    graph.insert(new_base, vec![]);
+
+    graph
+        .move_chain(&top, &bottom, &new_base)
+        .expect("Move failed");
+
+    assert_eq!(graph.depends_on(&a), Some(&vec![old_base]));
+    assert_eq!(graph.depends_on(&b), Some(&vec![top]));
+    assert_eq!(graph.depends_on(&top), Some(&vec![bottom]));
+    assert_eq!(graph.depends_on(&bottom), Some(&vec![new_base]));
+    assert!(graph.find_cycles().is_empty());
+}
+
+#[test]
+fn test_move_chain_invalid() {
+    let mut graph = DepGraph::default();
+
+    let a = "A";
+    let top = "Top";
+    let middle = "Middle";
+    let bottom = "Bottom";
+    let base = "Base";
+    let other = "Other";
+
+    graph.insert(a, vec![top]);
+    graph.insert(top, vec![middle]);
+    graph.insert(middle, vec![bottom]);
+    graph.insert(bottom, vec![base]);
+    graph.insert(base, vec![]);
+    graph.insert(other, vec![middle]);
+
+    // Other also depends on Middle
+    assert_eq!(
+        graph.move_chain(&top, &bottom, &a),
+        Err(DepGraphError::NotAChain(middle))
+    );
+
+    graph.insert(other, vec![]);
+    // Moving the chain onto itself
+    assert_eq!(
+        graph.move_chain(&top, &bottom, &middle),
+        Err(DepGraphError::WouldCreateCycle(vec![middle, bottom]))
+    );
+
+    // Failed moves leave the graph untouched
+    assert_eq!(graph.depends_on(&a), Some(&vec![top]));
+    assert_eq!(graph.depends_on(&bottom), Some(&vec![base]));
+
+    // A single node chain
+    graph
+        .move_chain(&bottom, &bottom, &other)
+        .expect("Move failed");
+    assert_eq!(graph.depends_on(&middle), Some(&vec![base]));
+    assert_eq!(graph.depends_on(&bottom), Some(&vec![other]));
+}
+
+#[test]
+fn test_insert_below() {
+    let mut graph = DepGraph::default();
+
+    let a = "A";
+    let base = "Base";
+    let c = "C";
+    let d = "D";
+    let new = "New";
+
+    graph.insert(a, vec![base]);
+    graph.insert(base, vec![c, d]);
+    graph.insert(c, vec![]);
+    graph.insert(d, vec![]);
+
+    graph.insert_below(new, &base).expect("Insert failed");
+
+    assert_eq!(graph.depends_on(&a), Some(&vec![base]));
+    assert_eq!(graph.depends_on(&base), Some(&vec![new]));
+    assert_eq!(graph.depends_on(&new), Some(&vec![c, d]));
+
+    assert_eq!(
+        graph.insert_below(c, &base),
+        Err(DepGraphError::NodeAlreadyExists(c))
+    );
+}
+
+#[test]
+fn test_replace_dependency() {
+    let mut graph = DepGraph::default();
+
+    let a = "A";
+    let b = "B";
+    let c = "C";
+    let d = "D";
+
+    graph.insert(a, vec![b, c, b]);
+    graph.insert(b, vec![]);
+    graph.insert(c, vec![]);
+    graph.insert(d, vec![a]);
+
+    assert_eq!(
+        graph.replace_dependency(&a, &b, d),
+        Err(DepGraphError::WouldCreateCycle(vec![a, d]))
+    );
+    graph.replace_dependency(&a, &b, c).expect("Replace failed");
+    assert_eq!(graph.depends_on(&a), Some(&vec![c, c, c]));
+
+    assert_eq!(
+        graph.replace_dependency(&a, &b, c),
+        Err(DepGraphError::NotADependency {
+            node: a,
+            dependency: b
+        })
+    );
+}
+
+#[test]
+fn test_edge_rewiring() {
+    let mut graph = DepGraph::default();
+
+    let composite = "Composite";
+    let above = "Above";
+    let below = "Below";
+    let new = "New";
+
+    graph.insert(composite, vec![above, below]);
+    graph.insert(above, vec![below]);
+    graph.insert(below, vec![]);
+
+    let mut edges = graph.edges_to(&below);
+    edges.sort_unstable();
+    assert_eq!(edges, vec![(above, 0), (composite, 1)]);
+
+    graph
+        .insert_on_edge(new, &composite, 1)
+        .expect("Insert failed");
+    assert_eq!(graph.depends_on(&composite), Some(&vec![above, new]));
+    assert_eq!(graph.depends_on(&new), Some(&vec![below]));
+    assert_eq!(graph.depends_on(&above), Some(&vec![below]));
+
+    assert_eq!(graph.set_dependency(&composite, 1, below), Ok(new));
+    assert_eq!(graph.depends_on(&composite), Some(&vec![above, below]));
+    assert_eq!(
+        graph.set_dependency(&below, 0, composite),
+        Err(DepGraphError::EdgeOutOfRange {
+            node: below,
+            edge_index: 0
+        })
+    );
+    assert_eq!(
+        graph.set_dependency(&above, 0, composite),
+        Err(DepGraphError::WouldCreateCycle(vec![above, composite]))
+    );
+
+    assert_eq!(graph.remove_dependency(&composite, 0), Ok(above));
+    assert_eq!(graph.depends_on(&composite), Some(&vec![below]));
+
+    assert!(graph.depends_on_indirectly(&composite, &below));
+    assert!(!graph.depends_on_indirectly(&below, &composite));
+    assert!(!graph.depends_on_indirectly(&below, &below));
+}
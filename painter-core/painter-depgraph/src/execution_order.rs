@@ -1,17 +1,28 @@
 use super::depgraph::DepGraph;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
 
 use super::{LocatedOperation, Operation, OperationStage};
-use std::iter::FromIterator;
 
 #[derive(Debug, PartialEq)]
-pub enum OrderCalculationError {
+pub enum OrderCalculationError<I> {
     /// One of the operations depended on a node that is not present in the depgraph
     /// or for some other reason a lookup for a node in the graph failed.
     UnknownDependency,
 
-    /// Was unable to complete execution of the graph for the given memory size
-    ResourceLimitExceeded,
+    /// Was unable to complete execution of the graph for the given memory size, even
+    /// when spilling intermediate results out of memory.
+    ResourceLimitExceeded {
+        /// How many registers the operation that could not be scheduled needed at once
+        required: usize,
+
+        /// How many registers evaluating the whole graph would take without spilling
+        peak_demand: usize,
+
+        /// The memory size that was supplied
+        available: usize,
+    },
 
     /// Something wrong in the implementation (should not be triggerable by
     /// external data - even invalid external data). Hopefully should not occur
@@ -21,193 +32,397 @@ pub enum OrderCalculationError {
     /// the stages
     UnexecutedOperations,
 
-    /// Did not manage to compute execution order within a finite amount of operations.
-    /// This may indicate recursion in the input graph....
-    IterationLimitReached,
+    /// The operations required by the outputs depend on themselves, so there is no order
+    /// they can be run in. Contains the nodes making up the cycle, each depending on the
+    /// next and the last depending on the first.
+    CycleDetected(Vec<I>),
 }
 
-pub fn compute_execution<I: std::fmt::Debug + std::hash::Hash + Eq + Clone>(
+/// Computes the order to run the operations required by `output_nodes` in, and where in
+/// a memory of `memory_size` registers each intermediate result should be stored.
+///
+/// This works similarly to Sethi-Ullman register allocation:
+///  1. The number of registers needed to evaluate each node is computed bottom up.
+///  2. Dependencies are evaluated in order of decreasing register need, so that the
+///     large subtrees are run while there are still plenty of free registers.
+///  3. A register is freed as soon as the last operation reading it has executed.
+///
+/// If at some point there is no free register, the value whose next use is furthest in
+/// the future is spilled out of memory and restored before it is next needed. Output
+/// nodes are never freed or spilled. It is only an error if a single operation cannot
+/// fit itself and all of it's dependencies into memory at once.
+///
+/// Only operations reachable from the output nodes are included in the stages.
+pub fn compute_execution<I: Debug + Hash + Eq + Clone>(
     graph: &DepGraph<I>,
     output_nodes: Vec<I>,
     memory_size: usize,
-) -> Result<Vec<OperationStage<I>>, OrderCalculationError> {
-    let mut stages = Vec::new();
+) -> Result<Vec<OperationStage<I>>, OrderCalculationError<I>> {
+    let reachable = reachable_in_dependency_order(graph, &output_nodes)?;
+    let register_need = compute_register_need(graph, &reachable)?;
+    let evaluation_order = compute_evaluation_order(graph, &output_nodes, &register_need)?;
+    let peak_demand = output_nodes
+        .iter()
+        .filter_map(|output| register_need.get(output))
+        .sum();
+    allocate_registers(
+        graph,
+        &output_nodes,
+        &evaluation_order,
+        memory_size,
+        peak_demand,
+    )
+}
 
-    let mut prev_memory_state: Vec<Option<I>> = vec![None; memory_size];
-    let mut del_after: Vec<LocatedOperation<I>> = Vec::new();
+/// Returns all the nodes reachable from the roots, ordered so that every node comes
+/// after all of it's dependencies.
+fn reachable_in_dependency_order<I: Debug + Hash + Eq + Clone>(
+    graph: &DepGraph<I>,
+    roots: &[I],
+) -> Result<Vec<I>, OrderCalculationError<I>> {
+    let mut order = Vec::new();
+    let mut finished: HashSet<I> = HashSet::new();
+    let mut in_progress: HashSet<I> = HashSet::new();
+
+    for root in roots {
+        if finished.contains(root) {
+            continue;
+        }
+        // Deep layer stacks can be thousands of operations long, so this is done with an
+        // explicit stack of (node, index of next dependency to visit) rather than recursion
+        let mut stack: Vec<(I, usize)> = vec![(root.clone(), 0)];
+        in_progress.insert(root.clone());
+
+        while let Some((node, next_dep)) = stack.last().cloned() {
+            let deps = graph
+                .depends_on(&node)
+                .ok_or(OrderCalculationError::UnknownDependency)?;
 
-    for (addr, output_node) in output_nodes.iter().enumerate() {
-        prev_memory_state[addr] = Some(output_node.clone());
-        del_after.push(LocatedOperation {
-            id: output_node.clone(),
-            addr,
-        });
+            if let Some(dep) = deps.get(next_dep) {
+                stack.last_mut().expect("Stack is not empty").1 += 1;
+                if finished.contains(dep) {
+                    continue;
+                }
+                if in_progress.contains(dep) {
+                    // Following this dependency leads back to a node we are part way
+                    // through visiting, so there is no valid order. The nodes on the
+                    // stack from that node onwards form the cycle.
+                    let cycle_start = stack
+                        .iter()
+                        .position(|(node, _)| node == dep)
+                        .expect("In progress nodes are on the stack");
+                    return Err(OrderCalculationError::CycleDetected(
+                        stack[cycle_start..]
+                            .iter()
+                            .map(|(node, _)| node.clone())
+                            .collect(),
+                    ));
+                }
+                in_progress.insert(dep.clone());
+                stack.push((dep.clone(), 0));
+            } else {
+                stack.pop();
+                in_progress.remove(&node);
+                finished.insert(node.clone());
+                order.push(node);
+            }
+        }
     }
+    Ok(order)
+}
 
-    let tmp_first_stage = OperationStage {
-        operation: (
-            Operation {
-                id: output_nodes[0].clone(),
-                depends_on: vec![],
-            },
-            0,
-        ),
-        allocate_before: vec![],
-        delete_after: del_after,
-    };
-    stages.push(tmp_first_stage.clone());
-
-    let mut remaining_ops: HashSet<I> = HashSet::from_iter(graph.iter_nodes().cloned());
-
-    // We know that if each execution stage runs one operation, then we should not need to loop
-    // more than the number of operations to run.
-    // This gives us a hard end criteria to prevent infinite loops. Something has gone wrong if we reach the end of this
-    // without a break
-    let mut did_break_from_loop = false;
-    for _ in 0..remaining_ops.len() + 1 {
-        let prev_stage = &stages.last().ok_or(OrderCalculationError::InternalError(
-            "Stages is empty!".to_string(),
-        ))?;
-        let prev_alloc_before_ids: Vec<I> = prev_stage
-            .allocate_before
-            .iter()
-            .map(|op| op.id.clone())
-            .collect();
-        let mut new_memory_state: Vec<Option<I>> = prev_memory_state
+/// Computes how many registers are needed to evaluate each node without spilling.
+/// A node with dependencies needing `n1 >= n2 >= ...` registers needs `max(n_i + i)`,
+/// as while evaluating the i'th dependency the results of the previous ones are held.
+/// It also needs space for all it's dependencies plus it's own result.
+///
+/// For shared nodes (where the graph is not a tree) this is an overestimate.
+fn compute_register_need<I: Debug + Hash + Eq + Clone>(
+    graph: &DepGraph<I>,
+    dependency_order: &[I],
+) -> Result<HashMap<I, usize>, OrderCalculationError<I>> {
+    let mut register_need: HashMap<I, usize> = HashMap::new();
+
+    for node in dependency_order {
+        let deps = graph
+            .depends_on(node)
+            .ok_or(OrderCalculationError::UnknownDependency)?;
+        let mut dep_needs = deps
             .iter()
-            .map(|op| {
-                if let Some(op) = op {
-                    if prev_alloc_before_ids.contains(op) {
-                        None
-                    } else {
-                        Some(op.clone())
-                    }
-                } else {
-                    None
-                }
+            .map(|dep| {
+                register_need.get(dep).cloned().ok_or_else(|| {
+                    OrderCalculationError::InternalError(
+                        "Dependency visited after dependee".to_string(),
+                    )
+                })
             })
-            .collect();
+            .collect::<Result<Vec<usize>, OrderCalculationError<I>>>()?;
+        dep_needs.sort_unstable_by(|a, b| b.cmp(a));
 
-        // Op, Dependencies, address
-        let mut candidate_ops: Vec<(I, Vec<I>, usize)> = vec![];
+        let nested_need = dep_needs
+            .iter()
+            .enumerate()
+            .map(|(index, need)| need + index)
+            .max()
+            .unwrap_or(0);
+        register_need.insert(node.clone(), nested_need.max(deps.len() + 1));
+    }
+    Ok(register_need)
+}
 
-        for (index, op) in new_memory_state.iter().enumerate() {
-            if let Some(op) = op {
-                let deps = graph
-                    .depends_on(&op)
-                    .ok_or(OrderCalculationError::UnknownDependency)?;
-                candidate_ops.push((op.clone(), deps.clone(), index));
-            }
+/// Orders the nodes so that dependencies with the largest register need are
+/// evaluated first. Each node appears once, after all of it's dependencies.
+fn compute_evaluation_order<I: Debug + Hash + Eq + Clone>(
+    graph: &DepGraph<I>,
+    output_nodes: &[I],
+    register_need: &HashMap<I, usize>,
+) -> Result<Vec<I>, OrderCalculationError<I>> {
+    let mut order = Vec::new();
+    let mut visited: HashSet<I> = HashSet::new();
+
+    let mut roots: Vec<&I> = output_nodes.iter().collect();
+    roots.sort_by_key(|node| std::cmp::Reverse(register_need.get(node)));
+
+    for root in roots {
+        if visited.contains(root) {
+            continue;
         }
-
-        let all_candidate_operations: Vec<I> = candidate_ops.iter().map(|x| x.0.clone()).collect();
-        for remaining_op in &remaining_ops {
-            // TODO: Likely slow
-            let remaining_op_depends_on = graph
-                .depends_on(&remaining_op)
-                .ok_or(OrderCalculationError::UnknownDependency)?;
-            for rem in remaining_op_depends_on.iter() {
-                if all_candidate_operations.contains(rem) {
-                    candidate_ops.retain(|o| &o.0 != rem);
+        visited.insert(root.clone());
+        let mut stack: Vec<(I, Vec<I>)> =
+            vec![(root.clone(), sorted_by_need(graph, root, register_need)?)];
+
+        while let Some((node, remaining_deps)) = stack.last_mut() {
+            if let Some(dep) = remaining_deps.pop() {
+                if visited.insert(dep.clone()) {
+                    let dep_deps = sorted_by_need(graph, &dep, register_need)?;
+                    stack.push((dep, dep_deps));
                 }
+            } else {
+                order.push(node.clone());
+                stack.pop();
             }
         }
+    }
+    Ok(order)
+}
 
-        if candidate_ops.len() == 0 {
-            if all_candidate_operations.len() != 0 {
-                return Err(OrderCalculationError::InternalError(
-                    "Unable to find candidate operation, but there are still some remaining"
-                        .to_string(),
-                ));
-            }
-            if candidate_ops.len() != 0 {
-                return Err(OrderCalculationError::UnexecutedOperations);
-            }
-            did_break_from_loop = true;
-            break;
+/// The dependencies of a node, with the one that should be evaluated first at the end
+/// of the vector (so that they can be popped off).
+fn sorted_by_need<I: Debug + Hash + Eq + Clone>(
+    graph: &DepGraph<I>,
+    node: &I,
+    register_need: &HashMap<I, usize>,
+) -> Result<Vec<I>, OrderCalculationError<I>> {
+    let mut deps = graph
+        .depends_on(node)
+        .ok_or(OrderCalculationError::UnknownDependency)?
+        .clone();
+    // Stable sort so ties are evaluated in the order they are listed in the graph
+    deps.reverse();
+    deps.sort_by_key(|dep| register_need.get(dep));
+    Ok(deps)
+}
+
+/// Keeps track of what is stored in each register while laying out the stages.
+struct RegisterFile<I: Debug + Hash + Eq + Clone> {
+    slots: Vec<Option<I>>,
+    location: HashMap<I, usize>,
+    spilled: HashSet<I>,
+    pinned: HashSet<I>,
+
+    /// The stage indices at which each value is read
+    uses: HashMap<I, Vec<usize>>,
+
+    /// Registers needed to evaluate the outputs without spilling. Only used for reporting
+    peak_demand: usize,
+}
+
+impl<I: Debug + Hash + Eq + Clone> RegisterFile<I> {
+    fn next_use(&self, value: &I, position: usize) -> usize {
+        self.uses
+            .get(value)
+            .and_then(|uses| uses.iter().find(|use_position| **use_position > position))
+            .cloned()
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Finds a free register, spilling the value that is not needed for the longest
+    /// time if there are none. Values in `protected` are never spilled.
+    fn claim(
+        &mut self,
+        protected: &[I],
+        position: usize,
+        spill_before: &mut Vec<LocatedOperation<I>>,
+    ) -> Result<usize, OrderCalculationError<I>> {
+        if let Some(addr) = self.slots.iter().position(|slot| slot.is_none()) {
+            return Ok(addr);
         }
 
-        // Sort the candidate operations so we pick the best one according to some heuristics
-        candidate_ops.sort_unstable_by(|a, b| {
-            let a_deps = a.1.len();
-            let b_deps = b.1.len();
-            return a_deps.cmp(&b_deps);
+        let victim_addr = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(addr, slot)| slot.as_ref().map(|value| (addr, value)))
+            .filter(|(_addr, value)| !protected.contains(value) && !self.pinned.contains(value))
+            .max_by_key(|(addr, value)| (self.next_use(value, position), std::cmp::Reverse(*addr)))
+            .map(|(addr, _value)| addr)
+            .ok_or_else(|| self.resource_limit_exceeded(protected))?;
+
+        let victim = self.slots[victim_addr].take().ok_or_else(|| {
+            OrderCalculationError::InternalError("Spilled empty slot".to_string())
+        })?;
+        self.location.remove(&victim);
+        self.spilled.insert(victim.clone());
+        spill_before.push(LocatedOperation {
+            id: victim,
+            addr: victim_addr,
         });
+        Ok(victim_addr)
+    }
+
+    fn resource_limit_exceeded(&self, protected: &[I]) -> OrderCalculationError<I> {
+        let distinct_protected: HashSet<&I> = protected.iter().collect();
+        let pinned_in_memory = self
+            .slots
+            .iter()
+            .flatten()
+            .filter(|value| self.pinned.contains(value) && !distinct_protected.contains(value))
+            .count();
+        OrderCalculationError::ResourceLimitExceeded {
+            required: distinct_protected.len() + pinned_in_memory + 1,
+            peak_demand: self.peak_demand,
+            available: self.slots.len(),
+        }
+    }
+
+    fn store(&mut self, value: I, addr: usize) {
+        self.slots[addr] = Some(value.clone());
+        self.location.insert(value, addr);
+    }
+
+    fn free(&mut self, value: &I) -> Result<LocatedOperation<I>, OrderCalculationError<I>> {
+        let addr = self.location.remove(value).ok_or_else(|| {
+            OrderCalculationError::InternalError("Freeing value not in a register".to_string())
+        })?;
+        self.slots[addr] = None;
+        Ok(LocatedOperation {
+            id: value.clone(),
+            addr,
+        })
+    }
+}
+
+fn allocate_registers<I: Debug + Hash + Eq + Clone>(
+    graph: &DepGraph<I>,
+    output_nodes: &[I],
+    evaluation_order: &[I],
+    memory_size: usize,
+    peak_demand: usize,
+) -> Result<Vec<OperationStage<I>>, OrderCalculationError<I>> {
+    let mut uses: HashMap<I, Vec<usize>> = HashMap::new();
+    for (position, node) in evaluation_order.iter().enumerate() {
+        let deps = graph
+            .depends_on(node)
+            .ok_or(OrderCalculationError::UnknownDependency)?;
+        for dep in deps {
+            uses.entry(dep.clone()).or_default().push(position);
+        }
+    }
+    let mut remaining_uses: HashMap<I, usize> =
+        uses.iter().map(|(k, v)| (k.clone(), v.len())).collect();
+
+    let mut registers = RegisterFile {
+        slots: vec![None; memory_size],
+        location: HashMap::new(),
+        spilled: HashSet::new(),
+        pinned: output_nodes.iter().cloned().collect(),
+        uses,
+        peak_demand,
+    };
 
-        let (operation, operation_depends, operation_addr) = candidate_ops
-            .first()
-            .ok_or(OrderCalculationError::InternalError(
-                "No candidate operations at time to pick one".to_string(),
-            ))?
+    let mut stages = Vec::with_capacity(evaluation_order.len());
+
+    for (position, node) in evaluation_order.iter().enumerate() {
+        let deps = graph
+            .depends_on(node)
+            .ok_or(OrderCalculationError::UnknownDependency)?
             .clone();
 
-        remaining_ops.remove(&operation);
-        let mut allocate_before: Vec<LocatedOperation<I>> = Vec::new();
-        let mut delete_after: Vec<LocatedOperation<I>> = Vec::new();
+        let mut spill_before = Vec::new();
+        let mut restore_before = Vec::new();
 
-        for dep in operation_depends.iter() {
-            if !graph.contains(dep) {
-                return Err(OrderCalculationError::UnknownDependency);
-            }
-            let some_dep = Some(dep.clone());
-
-            if !new_memory_state.contains(&some_dep) {
-                let available_slot = new_memory_state
-                    .iter()
-                    .position(|x| x == &None)
-                    .ok_or(OrderCalculationError::ResourceLimitExceeded)?; // # ValueError = Out of memory
-                new_memory_state[available_slot] = some_dep; // Cannot fail as the slot id s determined above
-                delete_after.push(LocatedOperation {
+        for dep in deps.iter() {
+            if registers.spilled.contains(dep) {
+                let addr = registers.claim(&deps, position, &mut spill_before)?;
+                registers.spilled.remove(dep);
+                registers.store(dep.clone(), addr);
+                restore_before.push(LocatedOperation {
                     id: dep.clone(),
-                    addr: available_slot,
+                    addr,
                 });
+            } else if !registers.location.contains_key(dep) {
+                return Err(OrderCalculationError::InternalError(
+                    "Dependency scheduled after dependee".to_string(),
+                ));
             }
         }
 
-        allocate_before.push(LocatedOperation {
-            id: operation.clone(),
-            addr: operation_addr,
-        });
+        let operation_addr = registers.claim(&deps, position, &mut spill_before)?;
+        registers.store(node.clone(), operation_addr);
+
+        let mut delete_after = Vec::new();
+        for dep in deps.iter() {
+            let remaining = remaining_uses.get_mut(dep).ok_or_else(|| {
+                OrderCalculationError::InternalError("Use count missing".to_string())
+            })?;
+            *remaining -= 1;
+            if *remaining == 0 && !registers.pinned.contains(dep) {
+                delete_after.push(registers.free(dep)?);
+            }
+        }
 
-        let stage = OperationStage {
+        stages.push(OperationStage {
             operation: (
                 Operation {
-                    id: operation,
-                    depends_on: operation_depends,
+                    id: node.clone(),
+                    depends_on: deps,
                 },
                 operation_addr,
             ),
-            allocate_before,
+            spill_before,
+            restore_before,
+            allocate_before: vec![LocatedOperation {
+                id: node.clone(),
+                addr: operation_addr,
+            }],
             delete_after,
-        };
-        stages.push(stage);
-
-        prev_memory_state = new_memory_state;
-    }
-
-    if !did_break_from_loop {
-        return Err(OrderCalculationError::IterationLimitReached);
-    }
-
-    stages.reverse();
-    if stages.pop() != Some(tmp_first_stage) {
-        return Err(OrderCalculationError::InternalError(
-            "First stage should have been temporary".to_string(),
-        ));
+        });
     }
 
     Ok(stages)
 }
 
 #[cfg(test)]
-fn position<I: Clone + Eq + std::fmt::Debug>(
-    order: &Vec<OperationStage<I>>,
-    operation: I,
-) -> usize {
-    return order
+fn position<I: Clone + Eq + std::fmt::Debug>(order: &[OperationStage<I>], operation: I) -> usize {
+    order
         .iter()
         .position(|x| x.operation.0.id == operation)
-        .expect("Operation not in execution stages!");
+        .expect("Operation not in execution stages!")
+}
+
+#[cfg(test)]
+fn validate<I: Debug + Hash + Eq + Clone>(stages: Vec<OperationStage<I>>, memory_size: usize) {
+    use super::default_executor;
+    default_executor(
+        stages,
+        memory_size,
+        &mut |_| {},
+        &mut |_| {},
+        &mut |_| {},
+        &mut |_| {},
+        &mut |_, _, _| {},
+    )
+    .expect("Stages failed to execute");
 }
 
 #[test]
@@ -256,12 +471,157 @@ fn test_unknown_dep_2() {
     let mut graph = DepGraph::default();
     graph.insert(1, vec![]);
 
-    let order: Result<_, OrderCalculationError> = compute_execution(&graph, vec![2], 10);
+    let order: Result<_, OrderCalculationError<i32>> = compute_execution(&graph, vec![2], 10);
 
     assert_eq!(order.unwrap_err(), OrderCalculationError::UnknownDependency);
 }
 
 #[test]
 fn test_unexecuted() {
-    todo!("Write remaining tests for the compute_execution function")
+    // Operations that the output does not depend on are not run
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2]);
+    graph.insert(2, vec![]);
+    graph.insert(3, vec![2]);
+
+    let order = compute_execution(&graph, vec![1], 10).expect("Computation Failed");
+
+    assert_eq!(order.len(), 2);
+    assert!(!order.iter().any(|stage| stage.operation.0.id == 3));
+}
+
+#[test]
+fn test_cycle() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2]);
+    graph.insert(2, vec![3]);
+    graph.insert(3, vec![2]);
+
+    let order = compute_execution(&graph, vec![1], 10);
+
+    assert_eq!(
+        order.unwrap_err(),
+        OrderCalculationError::CycleDetected(vec![2, 3])
+    );
+}
+
+#[test]
+fn test_long_chain_reuses_registers() {
+    let mut graph = DepGraph::default();
+    for i in 0..1000 {
+        graph.insert(i, vec![i + 1]);
+    }
+    graph.insert(1000, vec![]);
+
+    let order = compute_execution(&graph, vec![0], 2).expect("Computation Failed");
+
+    assert_eq!(order.len(), 1001);
+    assert!(order.iter().all(|stage| stage.spill_before.is_empty()));
+    validate(order, 2);
+}
+
+#[test]
+fn test_largest_subtree_first() {
+    let mut graph = DepGraph::default();
+    // 1 depends on a single leaf (2) and on a subtree (3) needing more registers
+    graph.insert(1, vec![2, 3]);
+    graph.insert(2, vec![]);
+    graph.insert(3, vec![4, 5]);
+    graph.insert(4, vec![]);
+    graph.insert(5, vec![6]);
+    graph.insert(6, vec![]);
+
+    let order = compute_execution(&graph, vec![1], 10).expect("Computation Failed");
+
+    assert!(position(&order, 3) < position(&order, 2));
+    assert!(position(&order, 1) > position(&order, 2));
+
+    // Without spilling this fits in the Sethi-Ullman number of registers
+    let order = compute_execution(&graph, vec![1], 3).expect("Computation Failed");
+    assert!(order.iter().all(|stage| stage.spill_before.is_empty()));
+    validate(order, 3);
+}
+
+#[test]
+fn test_slot_freed_when_dead() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2]);
+    graph.insert(2, vec![3]);
+    graph.insert(3, vec![]);
+
+    let order = compute_execution(&graph, vec![1], 10).expect("Computation Failed");
+
+    let stage_2 = &order[position(&order, 2)];
+    assert_eq!(stage_2.delete_after.len(), 1);
+    assert_eq!(stage_2.delete_after[0].id, 3);
+    // Output nodes are never freed
+    assert!(order
+        .iter()
+        .all(|stage| stage.delete_after.iter().all(|op| op.id != 1)));
+}
+
+#[test]
+fn test_spills_when_memory_too_small() {
+    let mut graph = DepGraph::default();
+    // A balanced tree needs four registers to evaluate without spilling
+    graph.insert(1, vec![2, 3]);
+    graph.insert(2, vec![4, 5]);
+    graph.insert(3, vec![6, 7]);
+    for leaf in 4..8 {
+        graph.insert(leaf, vec![]);
+    }
+
+    let order = compute_execution(&graph, vec![1], 4).expect("Computation Failed");
+    assert!(order.iter().all(|stage| stage.spill_before.is_empty()));
+
+    let order = compute_execution(&graph, vec![1], 3).expect("Computation Failed");
+    assert_eq!(
+        order
+            .iter()
+            .map(|stage| stage.spill_before.len())
+            .sum::<usize>(),
+        1
+    );
+    assert_eq!(
+        order
+            .iter()
+            .map(|stage| stage.restore_before.len())
+            .sum::<usize>(),
+        1
+    );
+    validate(order, 3);
+}
+
+#[test]
+fn test_shared_dependency() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2, 3]);
+    graph.insert(2, vec![4]);
+    graph.insert(3, vec![4]);
+    graph.insert(4, vec![]);
+
+    let order = compute_execution(&graph, vec![1], 3).expect("Computation Failed");
+
+    assert_eq!(order.len(), 4);
+    validate(order, 3);
+}
+
+#[test]
+fn test_resource_limit_exceeded() {
+    let mut graph = DepGraph::default();
+    graph.insert(1, vec![2, 3, 4]);
+    graph.insert(2, vec![]);
+    graph.insert(3, vec![]);
+    graph.insert(4, vec![]);
+
+    let order = compute_execution(&graph, vec![1], 3);
+
+    assert_eq!(
+        order.unwrap_err(),
+        OrderCalculationError::ResourceLimitExceeded {
+            required: 4,
+            peak_demand: 4,
+            available: 3
+        }
+    );
 }
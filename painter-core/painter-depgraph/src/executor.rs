@@ -15,6 +15,13 @@ pub struct Operation<I: Clone> {
 #[derive(Debug, PartialEq, Clone)]
 pub struct OperationStage<I: Clone + Debug> {
     pub operation: (Operation<I>, usize),
+
+    /// Results that should be moved out of memory (eg to slower storage) to make room
+    /// for this stage. They are restored by a later stage before they are needed again.
+    pub spill_before: Vec<LocatedOperation<I>>,
+
+    /// Previously spilled results that should be moved back into memory for this stage
+    pub restore_before: Vec<LocatedOperation<I>>,
     pub allocate_before: Vec<LocatedOperation<I>>,
     pub delete_after: Vec<LocatedOperation<I>>,
 }
@@ -59,8 +66,22 @@ pub enum ExecutorError {
 
     /// Dependency of the current operation has not yet executed
     DependencyNotExecuted,
+
+    /// Spilling an operation that is not in memory, or has not been executed yet
+    SpillingInvalid,
+
+    /// Restoring an operation that was not spilled
+    RestoringUnspilled,
 }
 
+/// Callback that is run whenever an operation is ready to be executed.
+/// The first parameter is the ID of the operation to execute
+/// The second parameters is a vector of the dependencies (and addresses thereof) for that operation.
+/// The third parameter is a vector of dependencies that will be deleted/unloaded after the operation.
+///      ths is because for some operations it amay be more efficient to execute if it can mutate one of the
+///      dependencies. This third parameter is the list of dependencies it is safe to mutate.
+pub type PerformOperation<'a, I> =
+    dyn FnMut(LocatedOperation<I>, Vec<LocatedOperation<I>>, Vec<LocatedOperation<I>>) + 'a;
 
 /// This executor explicitly errors if anything incorrect is detected, and prints the allocated resources at each stage.
 /// You specify the operations it should execute and the number of registers available to the machine.
@@ -72,13 +93,11 @@ pub fn default_executor<I: Debug + std::cmp::PartialEq + std::hash::Hash + Clone
     load: &mut dyn FnMut(LocatedOperation<I>),
     unload: &mut dyn FnMut(LocatedOperation<I>),
 
-    // Callback that us run whenever an operation is ready to be executed.
-    // The first parameter is the ID of the operation to execute
-    // The second parameters is a vector of the dependencies (and addresses thereof) for that operation.
-    // The third parameter is a vector of dependencies that will be deleted/unloaded after the operation.
-    //      ths is because for some operations it amay be more efficient to execute if it can mutate one of the
-    //      dependencies. This third parameter is the list of dependencies it is safe to mutate.
-    perform_operation: &mut dyn FnMut(LocatedOperation<I>, Vec<LocatedOperation<I>>, Vec<LocatedOperation<I>>),
+    // Called to move an executed result out of memory. The operation is restored back
+    // into memory (possibly at a different address) with `restore` before it is used again.
+    spill: &mut dyn FnMut(LocatedOperation<I>),
+    restore: &mut dyn FnMut(LocatedOperation<I>),
+    perform_operation: &mut PerformOperation<I>,
 ) -> Result<(), ExecutorError> {
     let mut memory: Vec<(Option<I>, bool)> = Vec::with_capacity(register_count);
 
@@ -87,8 +106,46 @@ pub fn default_executor<I: Debug + std::cmp::PartialEq + std::hash::Hash + Clone
     }
 
     let mut memory_map = std::collections::HashMap::new();
+    let mut spilled = std::collections::HashSet::new();
 
     for stage in stages.iter() {
+        // Move things out of memory
+        for spill_op in stage.spill_before.iter() {
+            {
+                let existing = memory
+                    .get(spill_op.addr)
+                    .ok_or(ExecutorError::MemoryHitResourceLimit)?;
+                if existing != &(Some(spill_op.id.clone()), true) {
+                    return Err(ExecutorError::SpillingInvalid);
+                }
+                if memory_map.get(&spill_op.id) != Some(&spill_op.addr) {
+                    return Err(ExecutorError::MemoryMapError);
+                }
+            }
+            memory[spill_op.addr] = (None, false);
+            memory_map.remove(&spill_op.id);
+            spilled.insert(spill_op.id.clone());
+            spill(spill_op.clone());
+        }
+
+        // And back in again
+        for restore_op in stage.restore_before.iter() {
+            {
+                let existing = memory
+                    .get(restore_op.addr)
+                    .ok_or(ExecutorError::MemoryHitResourceLimit)?;
+                if existing != &(None, false) {
+                    return Err(ExecutorError::MemoryOverwrite);
+                }
+                if !spilled.remove(&restore_op.id) {
+                    return Err(ExecutorError::RestoringUnspilled);
+                }
+            }
+            memory[restore_op.addr] = (Some(restore_op.id.clone()), true);
+            memory_map.insert(restore_op.id.clone(), restore_op.addr);
+            restore(restore_op.clone());
+        }
+
         // Allocate Space
         for alloc_op in stage.allocate_before.iter() {
             {
@@ -98,7 +155,7 @@ pub fn default_executor<I: Debug + std::cmp::PartialEq + std::hash::Hash + Clone
                 if existing != &(None, false) {
                     return Err(ExecutorError::MemoryOverwrite);
                 };
-                if memory_map.contains_key(&alloc_op.id) {
+                if memory_map.contains_key(&alloc_op.id) || spilled.contains(&alloc_op.id) {
                     return Err(ExecutorError::OperationReallocated);
                 }
             }
@@ -152,7 +209,6 @@ pub fn default_executor<I: Debug + std::cmp::PartialEq + std::hash::Hash + Clone
             if *already_executed {
                 return Err(ExecutorError::OperationRunTwice);
             }
-
         }
         memory
             .get_mut(*current_operation_addr)
@@ -164,8 +220,7 @@ pub fn default_executor<I: Debug + std::cmp::PartialEq + std::hash::Hash + Clone
                 addr: *current_operation_addr,
             },
             dep_array,
-            stage.delete_after.clone()
-
+            stage.delete_after.clone(),
         );
 
         // Remove Old
@@ -176,11 +231,14 @@ pub fn default_executor<I: Debug + std::cmp::PartialEq + std::hash::Hash + Clone
                 }
                 let (del_cur_allocated, del_already_executed) = &memory
                     .get(del_op.addr)
-                    .ok_or(ExecutorError::MemoryMapErrorInternal)?;
+                    .ok_or(ExecutorError::MemoryHitResourceLimit)?;
 
-                if del_cur_allocated == &None {
+                if del_cur_allocated.is_none() {
                     return Err(ExecutorError::MemoryFreeingEmpty);
                 }
+                if memory_map.get(&del_op.id) != Some(&del_op.addr) {
+                    return Err(ExecutorError::MemoryMapError);
+                }
                 if del_cur_allocated.as_ref() != Some(&del_op.id) {
                     return Err(ExecutorError::MemoryMapErrorInternal);
                 }
@@ -193,14 +251,13 @@ pub fn default_executor<I: Debug + std::cmp::PartialEq + std::hash::Hash + Clone
                 .ok_or(ExecutorError::MemoryHitResourceLimit)? = (None, false);
             unload(del_op.clone());
         }
-
     }
     Ok(())
 }
 
 #[cfg(test)]
 fn to_op<I: Clone + Debug>(op: I, addr: usize) -> LocatedOperation<I> {
-    LocatedOperation { id: op, addr: addr }
+    LocatedOperation { id: op, addr }
 }
 
 #[test]
@@ -212,11 +269,21 @@ fn test_executor_memory_overwrite_detect() {
 
     let stages = vec![OperationStage {
         operation: (a, 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0), to_op('B', 0)],
         delete_after: vec![],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::MemoryOverwrite)
     );
 }
@@ -229,11 +296,21 @@ fn test_executor_reallocate_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a, 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0), to_op('A', 1)],
         delete_after: vec![],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::OperationReallocated)
     );
 }
@@ -246,11 +323,21 @@ fn test_executor_dependency_unallocated_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a, 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0)],
         delete_after: vec![],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::DependencyNotAllocated)
     );
 }
@@ -263,11 +350,21 @@ fn test_executor_dependency_not_executed_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a, 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0), to_op('B', 1)],
         delete_after: vec![],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::DependencyNotExecuted)
     );
 }
@@ -280,11 +377,21 @@ fn test_executor_opertion_not_allocated_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a, 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![],
         delete_after: vec![],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::OperationNotAllocated)
     );
 }
@@ -298,17 +405,29 @@ fn test_executor_opertion_run_twice_detect() {
     let stages = vec![
         OperationStage {
             operation: (a.clone(), 0),
+            spill_before: vec![],
+            restore_before: vec![],
             allocate_before: vec![to_op('A', 0)],
             delete_after: vec![],
         },
         OperationStage {
             operation: (a, 0),
+            spill_before: vec![],
+            restore_before: vec![],
             allocate_before: vec![],
             delete_after: vec![],
         },
     ];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::OperationRunTwice)
     );
 }
@@ -321,11 +440,21 @@ fn test_executor_freeing_empty_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a.clone(), 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0)],
         delete_after: vec![to_op('A', 1)],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::MemoryFreeingEmpty)
     );
 }
@@ -338,11 +467,21 @@ fn test_executor_freeing_unallocated_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a.clone(), 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0)],
         delete_after: vec![to_op('B', 0)],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::MemoryFreeingUnallocated)
     );
 }
@@ -355,11 +494,21 @@ fn test_executor_freeing_unexecuted_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a.clone(), 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0), to_op('B', 1)],
         delete_after: vec![to_op('B', 1)],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::MemoryFreeingUnexecuted)
     );
 }
@@ -372,11 +521,21 @@ fn test_executor_mem_map_error1_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a.clone(), 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 1)],
         delete_after: vec![],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::MemoryMapError)
     );
 }
@@ -389,11 +548,21 @@ fn test_executor_mem_map_error2_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a.clone(), 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0), to_op('B', 1)],
         delete_after: vec![to_op('B', 0)],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::MemoryMapError)
     );
 }
@@ -406,11 +575,21 @@ fn test_executor_memory_limit_allocate_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a.clone(), 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0), to_op('B', 20)],
         delete_after: vec![],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::MemoryHitResourceLimit)
     );
 }
@@ -423,11 +602,21 @@ fn test_executor_memory_limit_operate_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a.clone(), 20),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0)],
         delete_after: vec![],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::MemoryHitResourceLimit)
     );
 }
@@ -440,11 +629,21 @@ fn test_executor_memory_limit_delete_detect() {
     };
     let stages = vec![OperationStage {
         operation: (a.clone(), 0),
+        spill_before: vec![],
+        restore_before: vec![],
         allocate_before: vec![to_op('A', 0)],
         delete_after: vec![to_op('A', 20)],
     }];
     assert_eq!(
-        default_executor(stages, 10, &mut |_| {}, &mut |_| {}, &mut |_, _| {}),
+        default_executor(
+            stages,
+            10,
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_, _, _| {}
+        ),
         Err(ExecutorError::MemoryHitResourceLimit)
     );
 }
@@ -2,6 +2,6 @@ mod depgraph;
 mod execution_order;
 mod executor;
 
-pub use depgraph::DepGraph;
-pub use execution_order::compute_execution;
+pub use depgraph::{DepGraph, DepGraphError};
+pub use execution_order::{compute_execution, OrderCalculationError};
 pub use executor::{default_executor, LocatedOperation, Operation, OperationStage};
@@ -2,6 +2,7 @@ use super::gl_utils;
 use super::quad;
 use super::shader;
 use glow::HasContext;
+use painter_data::bounds::Bounds;
 use painter_data::brush::Glyph;
 use painter_data::stroke::StrokeData;
 
@@ -9,7 +10,6 @@ use log::info;
 
 use std::collections::HashMap;
 
-use png;
 use png::{BitDepth, ColorType};
 
 use super::canvas::Canvas;
@@ -109,8 +109,8 @@ impl BrushRenderer {
     /// Ensures a brushes texture is loaded onto the GPU and returns a reference to it
     pub fn load_glyph_texture(&self, gl: &glow::Context, glyph: &Glyph) -> glow::Texture {
         let mut tex_store = self.brush_texture_store.borrow_mut();
-        if let Some(tex) = tex_store.get(&glyph) {
-            tex.clone()
+        if let Some(tex) = tex_store.get(glyph) {
+            *tex
         } else {
             info!("loading_brush_texture_to_gpu");
             unsafe {
@@ -128,7 +128,7 @@ impl BrushRenderer {
             }
 
             tex_store.insert(glyph.clone(), new_tex);
-            tex_store.get(&glyph).unwrap().clone()
+            *tex_store.get(glyph).unwrap()
         }
     }
 
@@ -139,10 +139,19 @@ impl BrushRenderer {
         glyph: &Glyph,
         canvas: &Canvas,
     ) {
+        // Stamps that are entirely off the canvas are left out
+        let viewport = Bounds::canvas(canvas.aspect_ratio());
+
         // We need all our point data layed out in a flat array
         let mut stroke_data_flat = Vec::with_capacity(stroke.position_array.len() * 4);
         let mut color_data_flat = Vec::with_capacity(stroke.position_array.len() * 4);
         for (point_id, position) in stroke.position_array.iter().enumerate() {
+            let on_canvas = stroke
+                .stamp_bounds(point_id, canvas.aspect_ratio())
+                .is_some_and(|bounds| bounds.intersects(&viewport));
+            if !on_canvas {
+                continue;
+            }
             let size = stroke.size
                 * stroke
                     .size_array
@@ -173,17 +182,31 @@ impl BrushRenderer {
             // stroke_data_flat.push(point.pressure);
             // stroke_data_flat.push(point.time);
         }
+        let stamp_count = stroke_data_flat.len() / 4;
+        if stamp_count == 0 {
+            return;
+        }
         unsafe {
             gl.push_debug_group(glow::DEBUG_SOURCE_APPLICATION, 0, "BrushRenderer");
             gl.bind_vertex_array(Some(self.vertex_array_obj));
             gl.enable(glow::BLEND);
             gl.blend_equation(glow::FUNC_ADD);
-            gl.blend_func_separate(
-                glow::SRC_ALPHA,
-                glow::ONE_MINUS_SRC_ALPHA,
-                glow::SRC1_ALPHA,
-                glow::ONE,
-            );
+            if stroke.alpha_locked {
+                // Keep the alpha of the canvas so only the color changes
+                gl.blend_func_separate(
+                    glow::SRC_ALPHA,
+                    glow::ONE_MINUS_SRC_ALPHA,
+                    glow::ZERO,
+                    glow::ONE,
+                );
+            } else {
+                gl.blend_func_separate(
+                    glow::SRC_ALPHA,
+                    glow::ONE_MINUS_SRC_ALPHA,
+                    glow::SRC1_ALPHA,
+                    glow::ONE,
+                );
+            }
         }
 
         let glyph_texture = self.load_glyph_texture(gl, glyph);
@@ -263,12 +286,7 @@ impl BrushRenderer {
         }
 
         unsafe {
-            gl.draw_arrays_instanced(
-                glow::TRIANGLE_STRIP,
-                0,
-                4,
-                stroke.position_array.len() as i32,
-            );
+            gl.draw_arrays_instanced(glow::TRIANGLE_STRIP, 0, 4, stamp_count as i32);
         }
 
         unsafe {
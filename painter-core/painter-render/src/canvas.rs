@@ -3,6 +3,10 @@ use log::info;
 use std::convert::TryInto;
 
 use super::gl_utils::{color_attachment_int_to_gl, TextureFormat};
+use super::texture_budget::canvas_texture_bytes;
+
+/// The format used for the canvases the depgraph is rendered into
+pub const CANVAS_FORMAT: TextureFormat = TextureFormat::RGBA32F;
 
 pub struct Canvas {
     framebuffer: glow::Framebuffer,
@@ -10,6 +14,8 @@ pub struct Canvas {
     pub texture: glow::Texture,
 }
 
+// Contents are only read through the Debug implementation when reporting errors
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum CanvasError {
     CreateFrameBufferFailed(String),
@@ -49,7 +55,7 @@ impl Canvas {
 
         unsafe {
             // let levels = { (resolution[0] as f32).log2().ceil() as i32 };
-            let format = TextureFormat::RGBA32F;
+            let format = CANVAS_FORMAT;
 
             gl.bind_texture(glow::TEXTURE_2D, Some(texture));
             // For textures that can change size we use TexImage2d
@@ -74,7 +80,7 @@ impl Canvas {
                 0,
             );
 
-            gl.draw_buffers(&vec![attachment]);
+            gl.draw_buffers(&[attachment]);
         }
         Ok(Self {
             framebuffer,
@@ -88,7 +94,7 @@ impl Canvas {
             self.resolution = resolution;
             unsafe {
                 info!("resizing_canvas");
-                let format = TextureFormat::RGBA32F;
+                let format = CANVAS_FORMAT;
 
                 gl.bind_texture(glow::TEXTURE_2D, Some(self.texture));
                 // For textures that can change size we use TexImage2d
@@ -108,6 +114,11 @@ impl Canvas {
         }
     }
 
+    /// Approximate GPU memory used by this canvas in bytes (ignoring mipmaps)
+    pub fn memory_size(&self) -> u64 {
+        canvas_texture_bytes(self.resolution, &CANVAS_FORMAT)
+    }
+
     pub fn aspect_ratio(&self) -> f32 {
         (self.resolution[0] as f32) / (self.resolution[1] as f32)
     }
@@ -119,19 +130,91 @@ impl Canvas {
         }
     }
 
+    pub fn resolution(&self) -> [u32; 2] {
+        self.resolution
+    }
+
     /// Copies all the texture data from `other` into `self`
     pub fn copy_from(&self, gl: &glow::Context, other: &Self) {
+        self.copy_area_from(
+            gl,
+            other,
+            [
+                0,
+                0,
+                self.resolution[0].try_into().unwrap(),
+                self.resolution[1].try_into().unwrap(),
+            ],
+        );
+    }
+
+    /// Copies the pixels in `area` (x, y, width, height) from `other` into the same
+    /// place in `self`
+    pub fn copy_area_from(&self, gl: &glow::Context, other: &Self, area: [i32; 4]) {
         unsafe {
             other.make_active(gl);
 
             gl.bind_texture(glow::TEXTURE_2D, Some(self.texture));
             gl.copy_tex_sub_image_2d(
-                glow::TEXTURE_2D, 
-                0, 
-                0, 0, // Offset
-                0, 0, // Offset #s....
-                self.resolution[0].try_into().unwrap(), 
-                self.resolution[1].try_into().unwrap()
+                glow::TEXTURE_2D,
+                0,
+                area[0],
+                area[1], // Offset
+                area[0],
+                area[1],
+                area[2],
+                area[3],
+            );
+        }
+    }
+
+    /// Frees the GPU resources used by this canvas
+    pub fn delete(self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_framebuffer(self.framebuffer);
+            gl.delete_texture(self.texture);
+        }
+    }
+
+    /// Reads the contents of the canvas back to the CPU in CANVAS_FORMAT
+    pub fn read_pixels(&self, gl: &glow::Context) -> Vec<u8> {
+        let format = CANVAS_FORMAT;
+        let mut pixels = vec![
+            0u8;
+            self.resolution[0] as usize
+                * self.resolution[1] as usize
+                * format.bytes_per_pixel()
+        ];
+        unsafe {
+            self.make_active(gl);
+            gl.read_pixels(
+                0,
+                0,
+                self.resolution[0].try_into().unwrap(),
+                self.resolution[1].try_into().unwrap(),
+                format.to_format(),
+                format.to_type(),
+                glow::PixelPackData::Slice(&mut pixels),
+            );
+        }
+        pixels
+    }
+
+    /// Uploads data (as created by read_pixels) into the canvas
+    pub fn write_pixels(&self, gl: &glow::Context, pixels: &[u8]) {
+        let format = CANVAS_FORMAT;
+        unsafe {
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.texture));
+            gl.tex_sub_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                0,
+                0,
+                self.resolution[0].try_into().unwrap(),
+                self.resolution[1].try_into().unwrap(),
+                format.to_format(),
+                format.to_type(),
+                glow::PixelUnpackData::Slice(pixels),
             );
         }
     }
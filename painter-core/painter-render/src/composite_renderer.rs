@@ -0,0 +1,145 @@
+use super::canvas::Canvas;
+use super::gl_utils::texture_unit_id_to_gl;
+use super::quad;
+use super::shader::SimpleShader;
+use glow::HasContext;
+use painter_data::color_primitives::BlendMode;
+
+/// Blends one canvas over another into a third, as done by Composite operations
+pub struct CompositeRenderer {
+    composite_shader: SimpleShader,
+    uniform_above_texture: glow::UniformLocation,
+    uniform_below_texture: glow::UniformLocation,
+    uniform_clip_texture: glow::UniformLocation,
+    uniform_use_clip: glow::UniformLocation,
+    uniform_opacity: glow::UniformLocation,
+    uniform_blend_mode: glow::UniformLocation,
+    position_buffer: glow::NativeBuffer,
+    vertex_array_obj: glow::NativeVertexArray,
+}
+
+impl CompositeRenderer {
+    pub fn new(gl: &glow::Context) -> Self {
+        let composite_shader = SimpleShader::new(
+            gl,
+            include_str!("resources/composite.vert"),
+            include_str!("resources/composite.frag"),
+            "CompositeRenderer",
+        )
+        .expect("Loading Composite Shader Failed");
+
+        let uniform_above_texture =
+            unsafe { gl.get_uniform_location(composite_shader.program, "aboveTexture") }
+                .expect("Could not find uniform aboveTexture");
+        let uniform_below_texture =
+            unsafe { gl.get_uniform_location(composite_shader.program, "belowTexture") }
+                .expect("Could not find uniform belowTexture");
+        let uniform_clip_texture =
+            unsafe { gl.get_uniform_location(composite_shader.program, "clipTexture") }
+                .expect("Could not find uniform clipTexture");
+        let uniform_use_clip =
+            unsafe { gl.get_uniform_location(composite_shader.program, "useClip") }
+                .expect("Could not find uniform useClip");
+        let uniform_opacity =
+            unsafe { gl.get_uniform_location(composite_shader.program, "opacity") }
+                .expect("Could not find uniform opacity");
+        let uniform_blend_mode =
+            unsafe { gl.get_uniform_location(composite_shader.program, "blendMode") }
+                .expect("Could not find uniform blendMode");
+
+        let vertex_array_obj =
+            unsafe { gl.create_vertex_array() }.expect("Failed creating vertex array");
+        unsafe {
+            gl.bind_vertex_array(Some(vertex_array_obj));
+            gl.object_label(
+                glow::VERTEX_ARRAY,
+                std::mem::transmute(vertex_array_obj),
+                Some("CompositeRenderVertexArray"),
+            );
+        }
+
+        let position_buffer = unsafe { gl.create_buffer() }.expect("Failed creating vertex buffer");
+        unsafe {
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(position_buffer));
+            gl.buffer_data_u8_slice(
+                glow::ARRAY_BUFFER,
+                quad::as_u8_slice(&[0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0, 1.0]),
+                glow::STATIC_DRAW,
+            );
+            assert_eq!(gl.get_error(), glow::NO_ERROR);
+            gl.bind_vertex_array(None);
+        }
+
+        Self {
+            composite_shader,
+            uniform_above_texture,
+            uniform_below_texture,
+            uniform_clip_texture,
+            uniform_use_clip,
+            uniform_opacity,
+            uniform_blend_mode,
+            position_buffer,
+            vertex_array_obj,
+        }
+    }
+
+    /// Draws `above` blended over `below` into `output`, replacing it's contents. If
+    /// `clip` is supplied, `above` is only drawn where `clip` is opaque.
+    pub fn composite(
+        &self,
+        gl: &glow::Context,
+        blend_mode: &BlendMode,
+        above: &Canvas,
+        below: &Canvas,
+        clip: Option<&Canvas>,
+        output: &Canvas,
+    ) {
+        let blend_mode_id = match blend_mode {
+            BlendMode::Mix(_) => 0,
+            BlendMode::Multiply(_) => 1,
+            BlendMode::Screen(_) => 2,
+            BlendMode::Add(_) => 3,
+        };
+
+        unsafe {
+            gl.push_debug_group(glow::DEBUG_SOURCE_APPLICATION, 0, "CompositeRenderer");
+        }
+        output.make_active(gl);
+        self.composite_shader.bind(gl);
+
+        unsafe {
+            gl.disable(glow::BLEND);
+            gl.bind_vertex_array(Some(self.vertex_array_obj));
+            gl.enable_vertex_attrib_array(self.composite_shader.attrib_vertex_positions);
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.position_buffer));
+            gl.vertex_attrib_pointer_f32(
+                self.composite_shader.attrib_vertex_positions,
+                2,
+                glow::FLOAT,
+                false,
+                0,
+                0,
+            );
+
+            for (texture_unit_id, canvas, uniform) in [
+                (0, above, &self.uniform_above_texture),
+                (1, below, &self.uniform_below_texture),
+                // Something has to be bound even if it is not used
+                (2, clip.unwrap_or(above), &self.uniform_clip_texture),
+            ] {
+                gl.active_texture(texture_unit_id_to_gl(texture_unit_id));
+                gl.bind_texture(glow::TEXTURE_2D, Some(canvas.texture));
+                gl.uniform_1_i32(Some(uniform), texture_unit_id as i32);
+            }
+            gl.uniform_1_i32(Some(&self.uniform_use_clip), clip.is_some() as i32);
+            gl.uniform_1_f32(Some(&self.uniform_opacity), blend_mode.opacity());
+            gl.uniform_1_i32(Some(&self.uniform_blend_mode), blend_mode_id);
+
+            gl.draw_arrays(glow::TRIANGLE_STRIP, 0, 4);
+
+            gl.bind_vertex_array(None);
+            gl.active_texture(texture_unit_id_to_gl(0));
+            gl.pop_debug_group();
+        }
+    }
+}
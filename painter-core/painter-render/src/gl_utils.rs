@@ -226,4 +226,27 @@ impl TextureFormat {
             Self::RGBA32UI => glow::UNSIGNED_INT,
         }
     }
+
+    /// The number of bytes a single pixel of this format occupies in memory
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            Self::R8 | Self::R8_SNORM | Self::R8UI | Self::R8I => 1,
+            Self::R16F | Self::R16UI | Self::R16I => 2,
+            Self::RG8 | Self::RG8_SNORM | Self::RG8UI | Self::RG8I => 2,
+            Self::RGB565 | Self::RGB5_A1 | Self::RGBA4 => 2,
+            Self::RGB8 | Self::SRGB8 | Self::RGB8_SNORM | Self::RGB8UI | Self::RGB8I => 3,
+            Self::R32F | Self::R32UI | Self::R32I => 4,
+            Self::RG16F | Self::RG16UI | Self::RG16I => 4,
+            Self::R11F_G11F_B10F | Self::RGB9_E5 => 4,
+            Self::RGBA8 | Self::SRGB8_ALPHA8 | Self::RGBA8_SNORM | Self::RGBA8UI | Self::RGBA8I => {
+                4
+            }
+            Self::RGB10_A2 | Self::RGB10_A2UI => 4,
+            Self::RGB16F | Self::RGB16UI | Self::RGB16I => 6,
+            Self::RG32F | Self::RG32UI | Self::RG32I => 8,
+            Self::RGBA16F | Self::RGBA16UI | Self::RGBA16I => 8,
+            Self::RGB32F | Self::RGB32UI | Self::RGB32I => 12,
+            Self::RGBA32F | Self::RGBA32I | Self::RGBA32UI => 16,
+        }
+    }
 }
@@ -1,3 +1,6 @@
+// The GL object handles used for debug labels are only accessible through transmute
+#![allow(clippy::missing_transmute_annotations)]
+
 use glow::HasContext;
 use libc::RTLD_NOW;
 use log::info;
@@ -6,35 +9,56 @@ use std::ffi;
 
 use log::warn;
 
+use painter_data::bounds::Bounds;
 use painter_data::id_map::IdMapBase;
-use painter_tools::context::EditContext;
+use painter_tools::context::{Damage, EditContext};
 
 use painter_data::id_map::{OperationId, OperationIdMap};
 use painter_data::operation::Operation;
-use painter_depgraph::{compute_execution, default_executor, LocatedOperation};
+use painter_depgraph::{
+    compute_execution, default_executor, LocatedOperation, OrderCalculationError,
+};
 use std::cell::RefCell;
 
 mod brush_renderer;
 mod canvas;
+mod composite_renderer;
 mod framebuffer_state;
 mod gl_utils;
 mod output_renderer;
 mod quad;
 mod shader;
+mod texture_budget;
 
 use brush_renderer::BrushRenderer;
+use composite_renderer::CompositeRenderer;
 use output_renderer::OutputRenderer;
 
-const NUM_TEXTURES: usize = 10;
-
 #[pyclass]
 pub struct PainterRenderer {
     gl: glow::Context,
     brush_renderer: BrushRenderer,
+    composite_renderer: CompositeRenderer,
     output_renderer: OutputRenderer,
     output_framebuffer: Option<framebuffer_state::FrameBufferState>,
 
     gpu_texture_cache: RefCell<std::collections::HashMap<usize, canvas::Canvas>>,
+
+    /// Canvases that did not fit in the GPU texture budget and were read back to the CPU
+    cpu_texture_store: RefCell<std::collections::HashMap<OperationId, Vec<u8>>>,
+
+    /// The maximum number of canvas textures to keep on the GPU at once. When rendering
+    /// needs more than this, intermediate results are spilled to the CPU.
+    #[pyo3(get)]
+    texture_budget: usize,
+
+    /// The finished image from the last render, so that only the parts of it that have
+    /// changed need to be drawn again
+    last_render: Option<canvas::Canvas>,
+
+    /// If set, only this part of the canvas is being drawn again, and strokes outside
+    /// of it are skipped
+    redraw_area: Option<Bounds>,
 }
 
 /// Returns the first zero-index output node in an OperationIdMap
@@ -78,22 +102,70 @@ impl PainterRenderer {
         let gl = create_gl_context();
 
         let brush_renderer = BrushRenderer::new(&gl);
+        let composite_renderer = CompositeRenderer::new(&gl);
         let output_renderer = OutputRenderer::new(&gl);
 
         Ok(Self {
             gl,
             brush_renderer,
+            composite_renderer,
             gpu_texture_cache: RefCell::new(std::collections::HashMap::new()),
+            cpu_texture_store: RefCell::new(std::collections::HashMap::new()),
+            texture_budget: texture_budget::DEFAULT_TEXTURE_BUDGET,
             output_renderer,
             output_framebuffer: None,
+            last_render: None,
+            redraw_area: None,
         })
     }
 
-    fn render(&mut self, context: &EditContext) {
+    /// Never goes below MIN_TEXTURE_BUDGET, as the image could not be rendered at all
+    #[setter]
+    fn set_texture_budget(&mut self, texture_budget: usize) {
+        self.texture_budget = texture_budget.max(texture_budget::MIN_TEXTURE_BUDGET);
+    }
+
+    /// Sets the texture budget to however many canvases of the context's size fit into
+    /// `memory_budget` bytes. Returns the new budget.
+    fn set_texture_budget_from_memory(
+        &mut self,
+        context: &EditContext,
+        memory_budget: u64,
+    ) -> usize {
+        self.texture_budget = texture_budget::texture_budget_from_memory(
+            context.image.metadata.preview_canvas_size,
+            &canvas::CANVAS_FORMAT,
+            memory_budget,
+        );
+        self.texture_budget
+    }
+
+    /// Sets the texture budget to use `memory_fraction` of the currently free video memory
+    /// (including whatever the renderer is already using). This only works if the driver
+    /// supports GL_NVX_gpu_memory_info or GL_ATI_meminfo. Returns the new budget, or None
+    /// if the free memory could not be determined, in which case the budget is unchanged.
+    fn auto_texture_budget(
+        &mut self,
+        context: &EditContext,
+        memory_fraction: f64,
+    ) -> Option<usize> {
+        let free_memory = texture_budget::query_free_video_memory(&self.gl)?;
+        let in_use: u64 = self
+            .gpu_texture_cache
+            .borrow()
+            .values()
+            .map(|canvas| canvas.memory_size())
+            .sum();
+        let memory_budget = ((free_memory + in_use) as f64 * memory_fraction) as u64;
+        Some(self.set_texture_budget_from_memory(context, memory_budget))
+    }
+
+    fn render(&mut self, context: &mut EditContext) -> PyResult<()> {
         // let col = &context.image.metadata.canvas_background_color;
 
-        self.output_framebuffer =
-            Some(framebuffer_state::FrameBufferState::from_current_gl_state(&self.gl));
+        self.output_framebuffer = Some(framebuffer_state::FrameBufferState::from_current_gl_state(
+            &self.gl,
+        ));
 
         // if let Some(canv) = self.tmp_canvas.as_mut() {
         //     canv.resize(&self.gl, context.image.metadata.preview_canvas_size);
@@ -118,76 +190,192 @@ impl PainterRenderer {
         //     );
         // }
 
-        let output_node = get_output_node(&context.image.operations).expect("No Output Node");
-        let order_of_operations =
-            compute_execution(&context.image.depgraph, vec![output_node], NUM_TEXTURES)
-                .expect("Computing order of operation failed");
+        // Only what has changed since the last render is drawn again, unless the canvas
+        // has been resized
+        let resolution = context.image.metadata.preview_canvas_size;
+        let mut damage = context.take_damage();
+        match &mut self.last_render {
+            Some(last_render) if last_render.resolution() == resolution => {}
+            Some(last_render) => {
+                last_render.resize(&self.gl, resolution);
+                damage = Damage::Everything;
+            }
+            None => {
+                self.last_render = Some(
+                    canvas::Canvas::new(&self.gl, resolution, "last_render")
+                        .expect("Creating Canvas Failed"),
+                );
+                damage = Damage::Everything;
+            }
+        }
+        self.redraw_area = match damage {
+            Damage::Area(area) => Some(area),
+            _ => None,
+        };
+        if damage != Damage::Nothing {
+            self.render_image(context)
+                .inspect_err(|_| self.output_framebuffer = None)?;
+        }
+
+        let last_render = self.last_render.as_ref().expect("Created above");
+        self.output_renderer.render(
+            &self.gl,
+            context,
+            &last_render.texture,
+            self.output_framebuffer
+                .as_ref()
+                .expect("No output framebuffer"),
+        );
+        self.output_framebuffer = None;
+        Ok(())
+    }
+}
+
+/// The pixels (x, y, width, height) of a canvas with the given resolution that `area`
+/// covers, with a pixel to spare on each side for filtering. Clamped to the canvas.
+fn pixel_area(area: &Bounds, resolution: [u32; 2]) -> [i32; 4] {
+    let aspect_ratio = resolution[0] as f32 / resolution[1] as f32;
+    // Canvas coordinates span -aspect_ratio to aspect_ratio horizontally and -1 to 1
+    // vertically (see Bounds::canvas)
+    let to_pixels =
+        |value: f32, scale: f32, pixels: u32| (value / scale + 1.0) * 0.5 * pixels as f32;
+    let clamp = |value: f32, pixels: u32| (value as i32).clamp(0, pixels as i32);
+    let min_x = clamp(
+        to_pixels(area.min[0], aspect_ratio, resolution[0]).floor() - 1.0,
+        resolution[0],
+    );
+    let min_y = clamp(
+        to_pixels(area.min[1], 1.0, resolution[1]).floor() - 1.0,
+        resolution[1],
+    );
+    let max_x = clamp(
+        to_pixels(area.max[0], aspect_ratio, resolution[0]).ceil() + 1.0,
+        resolution[0],
+    );
+    let max_y = clamp(
+        to_pixels(area.max[1], 1.0, resolution[1]).ceil() + 1.0,
+        resolution[1],
+    );
+    [min_x, min_y, (max_x - min_x).max(0), (max_y - min_y).max(0)]
+}
+
+#[test]
+fn test_pixel_area() {
+    let resolution = [200, 100];
+    assert_eq!(
+        pixel_area(&Bounds::canvas(2.0), resolution),
+        [0, 0, 200, 100]
+    );
+    // The top right quarter, with a pixel around it
+    assert_eq!(
+        pixel_area(&Bounds::new([0.0, 0.0], [2.0, 1.0]), resolution),
+        [99, 49, 101, 51]
+    );
+    // Entirely off the canvas
+    assert_eq!(
+        pixel_area(&Bounds::new([3.0, 0.0], [4.0, 1.0]), resolution)[2],
+        0
+    );
+}
+
+/// Converts a failure to schedule the depgraph into something that can be shown to the user
+fn order_calculation_error_to_py(err: OrderCalculationError<OperationId>) -> PyErr {
+    match err {
+        OrderCalculationError::ResourceLimitExceeded {
+            required,
+            peak_demand,
+            available,
+        } => pyo3::exceptions::PyRuntimeError::new_err(format!(
+            "Texture budget exceeded: an operation needs {} canvas textures at once but the budget is {}. \
+            Rendering the whole image without spilling to the CPU would need {}.",
+            required, available, peak_demand
+        )),
+        err => pyo3::exceptions::PyRuntimeError::new_err(format!(
+            "Computing order of operations failed: {:?}",
+            err
+        )),
+    }
+}
 
+impl PainterRenderer {
+    /// Draws the image into last_render. If redraw_area is set only that part of it is
+    /// drawn, which gives the same result as long as every operation only changes the
+    /// pixels it draws to.
+    fn render_image(&self, context: &EditContext) -> PyResult<()> {
+        let output_node = get_output_node(&context.image.operations).expect("No Output Node");
+        let order_of_operations = compute_execution(
+            &context.image.depgraph,
+            vec![output_node],
+            self.texture_budget,
+        )
+        .map_err(order_calculation_error_to_py)?;
+        self.free_textures_over_budget();
+
+        let resolution = context.image.metadata.preview_canvas_size;
+        if let Some(area) = &self.redraw_area {
+            let [x, y, width, height] = pixel_area(area, resolution);
+            unsafe {
+                self.gl.enable(glow::SCISSOR_TEST);
+                self.gl.scissor(x, y, width, height);
+            }
+        }
         default_executor(
             order_of_operations,
-            NUM_TEXTURES,
+            self.texture_budget,
             &mut |x| self.load_resource(context, x),
             &mut |x| self.unload_resource(context, x),
+            &mut |x| self.spill_resource(context, x),
+            &mut |x| self.restore_resource(context, x),
             &mut |x, dep, mut_dep| self.execute_op(context, x, dep, mut_dep),
-        ).expect("Execution Failed");
+        )
+        .expect("Execution Failed");
+        unsafe { self.gl.disable(glow::SCISSOR_TEST) };
+        Ok(())
+    }
 
-        self.output_framebuffer = None;
-        // // From here we coud in theory remove any operations that haven't changed since last time and are in cache.
-        // // but for now that isn't implemented.
-        // for operation_stage in order_of_operations.iter() {
-        //     match context.image.operations.get_unchecked(&operation_stage.operation.0.id) {
-        //         Operation::Stroke(stroke_data) => {
-        //             if let Some(glyph) = context.image.glyphs.get(&stroke_data.glyph) {
-        //                 self.brush_renderer.perform_stroke(
-        //                     &self.gl,
-        //                     stroke_data,
-        //                     &glyph,
-        //                     &self.tmp_canvas.as_ref().unwrap(),
-        //                 );
-        //             } else {
-        //                 warn!("Unable to find brush for stroke");
-        //             }
-        //         }
-        //         Operation::Output(_id) => {
-        //             let tmp_canvas = self.tmp_canvas.as_ref().unwrap();
-        //             self.output_renderer.render(
-        //                 &self.gl,
-        //                 context,
-        //                 &tmp_canvas.texture,
-        //                 &outp_framebuffer_state,
-        //             );
-        //         }
-        //         Operation::Tag(_name) => {}
-        //         Operation::Composite(_name) => {}
-        //     }
-        // }
+    /// If the texture budget has been lowered, frees the canvases that are no longer used
+    fn free_textures_over_budget(&self) {
+        let mut texture_cache = self.gpu_texture_cache.borrow_mut();
+        let over_budget: Vec<usize> = texture_cache
+            .keys()
+            .filter(|addr| **addr >= self.texture_budget)
+            .cloned()
+            .collect();
+        for addr in over_budget {
+            if let Some(canvas) = texture_cache.remove(&addr) {
+                canvas.delete(&self.gl);
+            }
+        }
     }
-}
 
-impl PainterRenderer {
-    fn load_resource(&self, context: &EditContext, op: LocatedOperation<OperationId>) {
-        let mut texture_cache = self.gpu_texture_cache.try_borrow_mut().expect("Borrow Tex Cache Failed");
+    /// Ensures there is a canvas of the correct size at the supplied address and makes it active
+    fn prepare_canvas(&self, context: &EditContext, addr: usize) {
+        let mut texture_cache = self
+            .gpu_texture_cache
+            .try_borrow_mut()
+            .expect("Borrow Tex Cache Failed");
 
-        if !texture_cache.contains_key(&op.addr) {
-            // If the canvas doesn't exist, create it
-            texture_cache.insert(op.addr, canvas::Canvas::new(
+        texture_cache.entry(addr).or_insert_with(|| {
+            canvas::Canvas::new(
                 &self.gl,
                 context.image.metadata.preview_canvas_size,
                 "tmp_canvas",
-            ).expect("Creating Canvas Failed"));
-        }
+            )
+            .expect("Creating Canvas Failed")
+        });
 
-        // Make sure canvas is ready to be drawn on
-        let canv = texture_cache.get_mut(&op.addr).expect("Still does not exist in cache");
+        let canv = texture_cache
+            .get_mut(&addr)
+            .expect("Still does not exist in cache");
         canv.resize(&self.gl, context.image.metadata.preview_canvas_size);
         canv.make_active(&self.gl);
+    }
+
+    fn load_resource(&self, context: &EditContext, op: LocatedOperation<OperationId>) {
+        // Make sure canvas is ready to be drawn on
+        self.prepare_canvas(context, op.addr);
         unsafe {
-            self.gl.clear_color(
-                0.0,
-                0.0,
-                0.0,
-                0.0,
-            );
+            self.gl.clear_color(0.0, 0.0, 0.0, 0.0);
             self.gl.clear(glow::COLOR_BUFFER_BIT);
         }
     }
@@ -197,39 +385,74 @@ impl PainterRenderer {
         // let texture_cache = self.gpu_texture_cache.borrow_mut();
     }
 
+    fn spill_resource(&self, _context: &EditContext, op: LocatedOperation<OperationId>) {
+        let texture_cache = self.gpu_texture_cache.borrow();
+        let canvas = texture_cache
+            .get(&op.addr)
+            .expect("Spilling canvas that does not exist");
+        let pixels = canvas.read_pixels(&self.gl);
+        info!("Spilling {:?} to the CPU ({} bytes)", op.id, pixels.len());
+        self.cpu_texture_store.borrow_mut().insert(op.id, pixels);
+    }
+
+    fn restore_resource(&self, context: &EditContext, op: LocatedOperation<OperationId>) {
+        let pixels = self
+            .cpu_texture_store
+            .borrow_mut()
+            .remove(&op.id)
+            .expect("Restoring canvas that was not spilled");
+        self.prepare_canvas(context, op.addr);
+        let texture_cache = self.gpu_texture_cache.borrow();
+        let canvas = texture_cache
+            .get(&op.addr)
+            .expect("Still does not exist in cache");
+        canvas.write_pixels(&self.gl, &pixels);
+    }
+
     fn execute_op(
         &self,
-        context: &EditContext, 
+        context: &EditContext,
         op: LocatedOperation<OperationId>,
         deps: Vec<LocatedOperation<OperationId>>,
         mutable_deps: Vec<LocatedOperation<OperationId>>,
     ) {
-
         match context.image.operations.get_unchecked(&op.id) {
             Operation::Stroke(stroke_data) => {
                 if deps.len() != 1 {
                     panic!("Stroke expects exactly one dependency")
                 }
-                let draw_on_dep = deps.get(0).expect("Output does not depend on anything!");
-                
+                let draw_on_dep = deps.first().expect("Output does not depend on anything!");
+
                 if mutable_deps.contains(draw_on_dep) {
-                    self.swap_items_in_tex_cache(&draw_on_dep, &op);
+                    self.swap_items_in_tex_cache(draw_on_dep, &op);
                 } else {
                     let texture_cache = self.gpu_texture_cache.borrow();
-                    let canvas_to_draw_on = texture_cache.get(&draw_on_dep.addr).expect("Canvas not created!");
-                    let output_canvas = texture_cache.get(&op.addr).expect("Texture not loaded in cache");
+                    let canvas_to_draw_on = texture_cache
+                        .get(&draw_on_dep.addr)
+                        .expect("Canvas not created!");
+                    let output_canvas = texture_cache
+                        .get(&op.addr)
+                        .expect("Texture not loaded in cache");
                     output_canvas.copy_from(&self.gl, canvas_to_draw_on);
                 }
                 let texture_cache = self.gpu_texture_cache.borrow();
-                let output_canvas = texture_cache.get(&op.addr).expect("Texture not loaded in cache");
-
+                let output_canvas = texture_cache
+                    .get(&op.addr)
+                    .expect("Texture not loaded in cache");
+
+                // Strokes that are entirely off the canvas, or outside the part of it
+                // being drawn again, don't need drawing
+                let viewport = Bounds::canvas(output_canvas.aspect_ratio());
+                if !context.spatial_index.may_intersect(&op.id, &viewport)
+                    || self
+                        .redraw_area
+                        .is_some_and(|area| !context.spatial_index.may_intersect(&op.id, &area))
+                {
+                    return;
+                }
                 if let Some(glyph) = context.image.glyphs.get(&stroke_data.glyph) {
-                    self.brush_renderer.perform_stroke(
-                        &self.gl,
-                        stroke_data,
-                        &glyph,
-                        &output_canvas,
-                    );
+                    self.brush_renderer
+                        .perform_stroke(&self.gl, stroke_data, glyph, output_canvas);
                 } else {
                     warn!("Unable to find brush for stroke");
                 }
@@ -239,60 +462,116 @@ impl PainterRenderer {
                     panic!("Output expects exactly one dependency")
                 }
                 let texture_cache = self.gpu_texture_cache.borrow();
-                let canvas_to_draw = texture_cache.get(&deps[0].addr).expect("Output does not depend on anythin!");
-                self.output_renderer.render(
-                    &self.gl,
-                    context,
-                    &canvas_to_draw.texture,
-                    self.output_framebuffer.as_ref().expect("No output framebuffer"),
-                );
+                let canvas_to_draw = texture_cache
+                    .get(&deps[0].addr)
+                    .expect("Output does not depend on anythin!");
+                // The result is kept so it can be shown without drawing it again
+                let last_render = self.last_render.as_ref().expect("No last render canvas");
+                let resolution = last_render.resolution();
+                let area = match &self.redraw_area {
+                    Some(area) => pixel_area(area, resolution),
+                    None => [0, 0, resolution[0] as i32, resolution[1] as i32],
+                };
+                last_render.copy_area_from(&self.gl, canvas_to_draw, area);
             }
             Operation::Tag(_name) => {
-                if deps.len() == 0 {
+                if deps.is_empty() {
                     // No Op
                 } else if deps.len() == 1 {
-                    let draw_on_dep = deps.get(0).expect("Tag internal error");
+                    let draw_on_dep = deps.first().expect("Tag internal error");
                     if mutable_deps.contains(draw_on_dep) {
-                        self.swap_items_in_tex_cache(&draw_on_dep, &op);
+                        self.swap_items_in_tex_cache(draw_on_dep, &op);
                     } else {
                         let texture_cache = self.gpu_texture_cache.borrow();
-                        let canvas_to_draw_on = texture_cache.get(&draw_on_dep.addr).expect("Canvas not created!");
-                        let output_canvas = texture_cache.get(&op.addr).expect("Texture not loaded in cache");
+                        let canvas_to_draw_on = texture_cache
+                            .get(&draw_on_dep.addr)
+                            .expect("Canvas not created!");
+                        let output_canvas = texture_cache
+                            .get(&op.addr)
+                            .expect("Texture not loaded in cache");
                         output_canvas.copy_from(&self.gl, canvas_to_draw_on);
                     }
                 } else {
                     panic!("Tag expects exactly one or zero dependencies")
                 }
-
             }
-            Operation::Composite(_name) => {
-                // For now all composites are a passthrough
-                if deps.len() != 2 {
-                    panic!("Composite expects exactly two dependencies")
+            Operation::Composite(blend_mode) => {
+                // The layer's contents are blended over whatever is below it, optionally
+                // clipped to the contents of another layer
+                if deps.len() != 2 && deps.len() != 3 {
+                    panic!("Composite expects two or three dependencies")
+                }
+                let hidden = context
+                    .image
+                    .layer_with_blend_operation(&op.id)
+                    .and_then(|layer_id| context.image.layers.get(&layer_id))
+                    .is_some_and(|layer| !layer.visible);
+                if hidden {
+                    // Hidden layers pass through what is below them
+                    self.copy_or_take_canvas(&deps[1], &op, &mutable_deps);
+                    return;
                 }
-                let texture_cache = self.gpu_texture_cache.borrow();
-                let output_canvas = texture_cache.get(&op.addr).expect("Texture not loaded in cache");
 
-                let canvas_underneath = texture_cache.get(&deps[0].addr).expect("Output does not depend on anythin!");
-                let _canvas_above = texture_cache.get(&deps[1].addr).expect("Output does not depend on anythin!");
-                output_canvas.copy_from(&self.gl, canvas_underneath);
+                let texture_cache = self.gpu_texture_cache.borrow();
+                let get_canvas = |dep: &LocatedOperation<OperationId>| {
+                    texture_cache
+                        .get(&dep.addr)
+                        .expect("Composite dependency not loaded in cache")
+                };
+                let output_canvas = texture_cache
+                    .get(&op.addr)
+                    .expect("Texture not loaded in cache");
+                self.composite_renderer.composite(
+                    &self.gl,
+                    blend_mode,
+                    get_canvas(&deps[0]),
+                    get_canvas(&deps[1]),
+                    deps.get(2).map(get_canvas),
+                    output_canvas,
+                );
             }
         }
     }
-    fn swap_items_in_tex_cache(&self, item1: &LocatedOperation<OperationId>, item2: &LocatedOperation<OperationId>) {
+
+    /// Makes the canvas for `op` a copy of the canvas for `dep`. If `dep` is not needed
+    /// by anything else the canvases are swapped rather than copied.
+    fn copy_or_take_canvas(
+        &self,
+        dep: &LocatedOperation<OperationId>,
+        op: &LocatedOperation<OperationId>,
+        mutable_deps: &[LocatedOperation<OperationId>],
+    ) {
+        if mutable_deps.contains(dep) {
+            self.swap_items_in_tex_cache(dep, op);
+        } else {
+            let texture_cache = self.gpu_texture_cache.borrow();
+            let canvas_to_copy = texture_cache.get(&dep.addr).expect("Canvas not created!");
+            let output_canvas = texture_cache
+                .get(&op.addr)
+                .expect("Texture not loaded in cache");
+            output_canvas.copy_from(&self.gl, canvas_to_copy);
+        }
+    }
+
+    fn swap_items_in_tex_cache(
+        &self,
+        item1: &LocatedOperation<OperationId>,
+        item2: &LocatedOperation<OperationId>,
+    ) {
         let mut texture_cache = self.gpu_texture_cache.borrow_mut();
 
-        let i1 = texture_cache.remove(&item1.addr).expect("Item not in texture cache");
-        let i2 = texture_cache.remove(&item2.addr).expect("Item not in texture cache");
+        let i1 = texture_cache
+            .remove(&item1.addr)
+            .expect("Item not in texture cache");
+        let i2 = texture_cache
+            .remove(&item2.addr)
+            .expect("Item not in texture cache");
 
         texture_cache.insert(item1.addr, i2);
         texture_cache.insert(item2.addr, i1);
     }
 }
 
-
-
-
 fn create_gl_context() -> glow::Context {
     info!("Attempting to grab openGL Context");
 
@@ -12,15 +12,16 @@ out vec4 color;
 
 
 void main() {
-        vec2 screen_pos = aVertexPosition * 2.0 - vec2(1.0);
-        screen_pos.y *= aspectRatio; // Future: needs to occur after rotation
-        
         vec2 offset = aStrokeData.xy;
         float size = aStrokeData.z;
-
-        screen_pos *= size;
-        offset.x /= aspectRatio;
-        screen_pos += offset;
+        float angle = aStrokeData.w;
+
+        // Place the stamp in canvas coordinates, where x is stretched by the aspect
+        // ratio, so that rotating it does not skew it.
+        vec2 local_pos = (aVertexPosition * 2.0 - vec2(1.0)) * size * aspectRatio;
+        local_pos = mat2(cos(angle), sin(angle), -sin(angle), cos(angle)) * local_pos;
+        vec2 canvas_pos = offset + local_pos;
+        vec2 screen_pos = vec2(canvas_pos.x / aspectRatio, canvas_pos.y);
         color = aColorData;
         
 
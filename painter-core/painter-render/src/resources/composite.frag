@@ -0,0 +1,48 @@
+#version 300 es
+// Blends one canvas over another. Canvases store straight (not premultiplied) alpha.
+
+precision highp float;
+out vec4 FragColor;
+
+uniform sampler2D aboveTexture;
+uniform sampler2D belowTexture;
+uniform sampler2D clipTexture;
+uniform bool useClip;
+uniform float opacity;
+
+// 0: Mix, 1: Multiply, 2: Screen, 3: Add
+uniform int blendMode;
+
+
+vec3 blend(vec3 below, vec3 above) {
+	if (blendMode == 1) {
+		return below * above;
+	} else if (blendMode == 2) {
+		return below + above - below * above;
+	} else if (blendMode == 3) {
+		return min(below + above, vec3(1.0));
+	}
+	return above;
+}
+
+
+void main() {
+	ivec2 pixel = ivec2(gl_FragCoord.xy);
+	vec4 above = texelFetch(aboveTexture, pixel, 0);
+	vec4 below = texelFetch(belowTexture, pixel, 0);
+
+	above.a *= opacity;
+	if (useClip) {
+		above.a *= texelFetch(clipTexture, pixel, 0).a;
+	}
+
+	// Where there is nothing below the layer it's own color is used
+	vec3 aboveColor = mix(above.rgb, blend(below.rgb, above.rgb), below.a);
+
+	float alpha = above.a + below.a * (1.0 - above.a);
+	vec3 color = aboveColor * above.a + below.rgb * below.a * (1.0 - above.a);
+	if (alpha > 0.0) {
+		color /= alpha;
+	}
+	FragColor = vec4(color, alpha);
+}
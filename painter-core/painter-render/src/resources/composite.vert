@@ -0,0 +1,11 @@
+#version 300 es
+precision lowp float;
+in vec2 aVertexPosition;
+
+void main() {
+        gl_Position = vec4(
+                aVertexPosition * 2.0 - vec2(1.0),
+                0.0,
+                1.0
+        );
+}
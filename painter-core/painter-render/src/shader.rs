@@ -1,5 +1,7 @@
 use glow::{Context, HasContext, Program, FRAGMENT_SHADER, VERTEX_SHADER};
 
+// Contents are only read through the Debug implementation when reporting errors
+#[allow(dead_code, clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum ShaderError {
     ShaderAllocError(String),
@@ -54,7 +56,7 @@ unsafe fn load_shader(
         gl.delete_shader(shader);
         return Err(ShaderError::ShaderCompileError {
             shader_type,
-            compiler_output: compiler_output,
+            compiler_output,
             shader_text: shader_text.to_string(),
         });
     }
@@ -0,0 +1,85 @@
+//! Works out how many canvas textures the renderer can keep on the GPU at once.
+//! The depgraph scheduler is told this number and spills to the CPU when it runs out.
+use glow::HasContext;
+use log::info;
+
+use super::gl_utils::TextureFormat;
+
+/// Number of canvas textures used when nothing else has been configured.
+pub const DEFAULT_TEXTURE_BUDGET: usize = 10;
+
+/// Fewer textures than this cannot render anything useful: a layer composite needs
+/// it's two inputs and somewhere to put the result.
+pub const MIN_TEXTURE_BUDGET: usize = 3;
+
+/// From GL_NVX_gpu_memory_info. Returns kilobytes of free video memory
+const GPU_MEMORY_INFO_CURRENT_AVAILABLE_VIDMEM_NVX: u32 = 0x9049;
+
+/// From GL_ATI_meminfo. Returns four values, the first of which is the kilobytes of free
+/// memory in the pool used for textures.
+const TEXTURE_FREE_MEMORY_ATI: u32 = 0x87FC;
+
+/// Size in bytes of a single canvas texture
+pub fn canvas_texture_bytes(canvas_size: [u32; 2], format: &TextureFormat) -> u64 {
+    canvas_size[0] as u64 * canvas_size[1] as u64 * format.bytes_per_pixel() as u64
+}
+
+/// How many canvas textures of the given size and format fit in `memory_budget` bytes.
+/// This never goes below MIN_TEXTURE_BUDGET as the image could not be rendered at all.
+pub fn texture_budget_from_memory(
+    canvas_size: [u32; 2],
+    format: &TextureFormat,
+    memory_budget: u64,
+) -> usize {
+    let texture_bytes = canvas_texture_bytes(canvas_size, format).max(1);
+    ((memory_budget / texture_bytes) as usize).max(MIN_TEXTURE_BUDGET)
+}
+
+/// Asks the driver how much video memory is free, in bytes. This is only possible
+/// on drivers supporting GL_NVX_gpu_memory_info (Nvidia) or GL_ATI_meminfo (AMD).
+pub fn query_free_video_memory(gl: &glow::Context) -> Option<u64> {
+    let extensions = gl.supported_extensions();
+    if extensions.contains("GL_NVX_gpu_memory_info") {
+        let kilobytes =
+            unsafe { gl.get_parameter_i32(GPU_MEMORY_INFO_CURRENT_AVAILABLE_VIDMEM_NVX) };
+        info!("NVX reports {}kb of free video memory", kilobytes);
+        Some(kilobytes.max(0) as u64 * 1024)
+    } else if extensions.contains("GL_ATI_meminfo") {
+        let mut meminfo = [0; 4];
+        unsafe { gl.get_parameter_i32_slice(TEXTURE_FREE_MEMORY_ATI, &mut meminfo) };
+        info!("ATI reports {}kb of free texture memory", meminfo[0]);
+        Some(meminfo[0].max(0) as u64 * 1024)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_texture_budget_from_memory() {
+    // A 4k RGBA32F canvas is 128MiB
+    let size = [3840, 2160];
+    let texture_bytes = canvas_texture_bytes(size, &TextureFormat::RGBA32F);
+    assert_eq!(texture_bytes, 3840 * 2160 * 16);
+
+    assert_eq!(
+        texture_budget_from_memory(size, &TextureFormat::RGBA32F, texture_bytes * 12),
+        12
+    );
+    assert_eq!(
+        texture_budget_from_memory(size, &TextureFormat::RGBA32F, texture_bytes * 12 - 1),
+        11
+    );
+    // Smaller textures mean more of them fit
+    assert_eq!(
+        texture_budget_from_memory(size, &TextureFormat::RGBA8, texture_bytes * 12),
+        48
+    );
+}
+
+#[test]
+fn test_texture_budget_minimum() {
+    assert_eq!(
+        texture_budget_from_memory([1920, 1080], &TextureFormat::RGBA32F, 0),
+        MIN_TEXTURE_BUDGET
+    );
+}
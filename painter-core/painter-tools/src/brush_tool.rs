@@ -3,13 +3,16 @@ use pyo3::prelude::*;
 
 use painter_data::color_primitives::BlendMode;
 // use painter_data::color_primitives::Color;
-use painter_data::brush::PressureSettings;
+use painter_data::brush::{Brush, PressureSettings};
 use painter_data::color_primitives::Color;
-use painter_data::id_map::{BrushId, GlyphId, IdMapBase, OperationId};
+use painter_data::id_map::{BrushId, IdMapBase, OperationId};
 use painter_data::operation::Operation;
 use painter_data::stroke::StrokeData;
 
 use super::context::EditContext;
+use super::input_samples::InputSample;
+use super::random::StrokeRng;
+use super::recording::RecordedEvent;
 
 #[pyclass]
 #[derive(Clone)]
@@ -19,7 +22,31 @@ pub struct BrushTool {
     brush_id: Option<BrushId>,
 
     #[pyo3(get, set)]
-    size: f32,
+    pub size: f32,
+
+    /// Keep the input each stroke is drawn from (see EditContext::input_samples). Only
+    /// takes effect from the next stroke.
+    #[pyo3(get, set)]
+    pub record_input: bool,
+
+    /// Whether the input of the current stroke is being kept
+    recording_current: bool,
+
+    /// The seed the next stroke's stamps are varied with. Chosen at random if not set,
+    /// and cleared once a stroke starts.
+    #[pyo3(get, set)]
+    pub next_stroke_seed: Option<u64>,
+
+    /// Where the seeds of strokes come from
+    seeds: StrokeRng,
+
+    /// The name of the device the input is coming from. The pressure it reports is
+    /// corrected by its curve in EditContext::tablet_curves before anything else, and
+    /// the corrected pressure is what is recorded.
+    #[pyo3(get, set)]
+    pub device: Option<String>,
+
+    placer: StampPlacer,
 }
 
 impl Default for BrushTool {
@@ -29,6 +56,52 @@ impl Default for BrushTool {
             current_operation_id: None,
             brush_id: None,
             size: 0.1,
+            record_input: true,
+            recording_current: false,
+            next_stroke_seed: None,
+            seeds: StrokeRng::new(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|time| time.as_nanos() as u64)
+                    .unwrap_or_default(),
+            ),
+            device: None,
+            placer: StampPlacer::new(0),
+        }
+    }
+}
+
+impl BrushTool {
+    /// Adds the stamp for an input sample to the current stroke
+    fn add_sample(&mut self, context: &mut EditContext, sample: InputSample) {
+        if let Some(operation_id) = &self.current_operation_id {
+            let brush = context
+                .image
+                .brushes
+                .get_unchecked(self.brush_id.as_ref().expect("No Active Brush"));
+            let aspect_ratio = context.image.metadata.aspect_ratio();
+            let stamps = self.placer.place(brush, self.size, aspect_ratio, sample);
+
+            match context.operation_mut(operation_id) {
+                Some(Operation::Stroke(stroke_data)) => {
+                    for stamp in stamps.iter() {
+                        stamp.add_to(stroke_data);
+                    }
+                    if self.recording_current {
+                        context.input_samples.record(*operation_id, sample);
+                    }
+                }
+                Some(_) => {
+                    warn!(target: "brush_tool", "Current operation is not stroke");
+                }
+                None => {
+                    // The stroke was undone part way through drawing it
+                    info!(target: "brush_tool", "Current stroke no longer exists");
+                    self.current_operation_id = None;
+                }
+            }
+        } else {
+            warn!(target: "brush_tool", "No stroke to draw into");
         }
     }
 }
@@ -44,30 +117,53 @@ impl BrushTool {
         self.brush_id = Some(brush_id);
     }
 
-    fn start_stroke(&mut self, context: &mut EditContext, x: f32, y: f32, pressure: f32) {
+    /// Starts drawing a stroke. `tilt` is how far the stylus leans along the x and y
+    /// axes (from -1 to 1), if the device reports it.
+    #[args(tilt = "None")]
+    pub fn start_stroke(
+        &mut self,
+        context: &mut EditContext,
+        x: f32,
+        y: f32,
+        pressure: f32,
+        tilt: Option<[f32; 2]>,
+    ) {
         if let Some(_op) = &self.current_operation_id {
             warn!(target: "brush_tool", "Starting stroke when one already exists");
+            self.end_stroke(context);
         }
+        let pressure = context
+            .tablet_curves
+            .calibrate(self.device.as_deref(), pressure);
 
         match &self.brush_id {
             Some(brush_id) => {
-                let brush = context.image.brushes.get_unchecked(brush_id);
-
-                // Find the glyph id
-                let glyph_id: GlyphId = {
-                    match context
-                        .image
-                        .glyphs
-                        .iter()
-                        .find(|(_id, gly)| *gly == &brush.glyph)
-                    {
-                        Some((id, _v)) => *id,
-                        None => {
-                            info!(target: "brush_tool", "Loading brush glyph to glyph array");
-                            context.image.glyphs.insert(brush.glyph.clone())
-                        }
-                    }
+                let seed = match self.next_stroke_seed.take() {
+                    Some(seed) => seed,
+                    None => self.seeds.next_u64(),
                 };
+                context.record_event(RecordedEvent::StartStroke {
+                    insert_onto: context.insert_operation_onto,
+                    brush_id: *brush_id,
+                    size: self.size,
+                    record_input: self.record_input,
+                    position: [x, y],
+                    pressure,
+                    tilt,
+                    seed,
+                });
+                let layer_id = context.current_layer();
+                let layer = layer_id.and_then(|layer_id| context.get_layer(layer_id));
+                if layer_id.is_some_and(|layer_id| context.locked_layer(&layer_id).is_some()) {
+                    warn!(target: "brush_tool", "Cannot draw on a locked layer");
+                    return;
+                }
+
+                // The whole stroke is undone in one go. The group is ended in end_stroke
+                context.begin_group("Brush Stroke");
+
+                let glyph = context.image.brushes.get_unchecked(brush_id).glyph.clone();
+                let glyph_id = context.find_or_insert_glyph(&glyph);
 
                 let operation = Operation::Stroke(StrokeData {
                     color: context.color.clone(),
@@ -78,10 +174,27 @@ impl BrushTool {
                     size: self.size,
                     size_array: Vec::new(),
                     blend_mode: self.blend_mode.clone(),
+                    alpha_locked: layer.is_some_and(|layer| layer.alpha_locked),
                 });
-                let operation_id = context.insert_operation(operation);
-                self.current_operation_id = Some(operation_id);
-                self.continue_stroke(context, x, y, pressure, 0.0);
+                match context.insert_operation(operation) {
+                    Ok(operation_id) => {
+                        self.current_operation_id = Some(operation_id);
+                        self.recording_current = self.record_input;
+                        self.placer = StampPlacer::new(seed);
+                        context.input_samples.set_seed(operation_id, seed);
+                        let sample = InputSample {
+                            position: [x, y],
+                            pressure,
+                            time: 0.0,
+                            tilt,
+                        };
+                        self.add_sample(context, sample);
+                    }
+                    Err(err) => {
+                        warn!(target: "brush_tool", "Unable to start stroke: {:?}", err);
+                        context.end_group();
+                    }
+                }
             }
             None => {
                 warn!(target: "brush_tool", "Brush tool does not have active brush")
@@ -89,46 +202,443 @@ impl BrushTool {
         }
     }
 
-    fn continue_stroke(
+    /// Continues the current stroke. `time_since_start` is in seconds, and `tilt` is as
+    /// for start_stroke.
+    #[args(tilt = "None")]
+    pub fn continue_stroke(
         &mut self,
         context: &mut EditContext,
         x: f32,
         y: f32,
         pressure: f32,
         time_since_start: f32,
+        tilt: Option<[f32; 2]>,
     ) {
-        if let Some(operation_id) = &self.current_operation_id {
-            let stroke = context.image.operations.get_mut_unchecked(operation_id);
-            let brush = context
-                .image
-                .brushes
-                .get_unchecked(self.brush_id.as_ref().expect("No Active Brush"));
+        let pressure = context
+            .tablet_curves
+            .calibrate(self.device.as_deref(), pressure);
+        context.record_event(RecordedEvent::ContinueStroke {
+            position: [x, y],
+            pressure,
+            time: time_since_start,
+            tilt,
+        });
+        self.add_sample(
+            context,
+            InputSample {
+                position: [x, y],
+                pressure,
+                time: time_since_start,
+                tilt,
+            },
+        );
+    }
 
-            if let Operation::Stroke(stroke_data) = stroke {
-                stroke_data.position_array.push([x, y]);
-                stroke_data.angle_array.push(0.0);
-                stroke_data.color_array.push(Color {
-                    r: 1.0,
-                    g: 1.0,
-                    b: 1.0,
-                    a: evaluate_pressure_setting(&brush.flow, pressure),
-                });
-                stroke_data
-                    .size_array
-                    .push(evaluate_pressure_setting(&brush.size, pressure));
-            } else {
-                warn!(target: "brush_tool", "Current operation is not stroke");
-            }
-        } else {
-            warn!(target: "brush_tool", "No stroke to draw into");
+    pub fn end_stroke(&mut self, context: &mut EditContext) {
+        context.record_event(RecordedEvent::EndStroke);
+        // If the stroke was undone while being drawn, the group has already been ended
+        if self.current_operation_id.take().is_some() && context.history.in_group() {
+            context.end_group();
         }
     }
+}
+
+/// What a brush draws for a single input sample
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stamp {
+    pub position: [f32; 2],
+    pub angle: f32,
+    pub size: f32,
+    pub color: Color,
+}
 
-    fn end_stroke(&mut self) {
-        self.current_operation_id = None;
+impl Stamp {
+    /// The stamp for a sample, with its size, flow and angle varied by the brush's
+    /// random settings. Always takes the same amount of numbers from `rng`.
+    pub fn from_sample(brush: &Brush, sample: &InputSample, rng: &mut StrokeRng) -> Self {
+        let size = evaluate_random_setting(&brush.size, sample.pressure, rng).max(0.0);
+        let flow = evaluate_random_setting(&brush.flow, sample.pressure, rng).clamp(0.0, 1.0);
+        let angle = evaluate_random_setting(&brush.angle, sample.pressure, rng);
+        Self {
+            position: sample.position,
+            angle,
+            size,
+            color: Color {
+                r: 1.0,
+                g: 1.0,
+                b: 1.0,
+                a: flow,
+            },
+        }
+    }
+
+    /// Adds the stamp to the end of a stroke
+    pub fn add_to(&self, stroke: &mut StrokeData) {
+        stroke.position_array.push(self.position);
+        stroke.angle_array.push(self.angle);
+        stroke.color_array.push(self.color.clone());
+        stroke.size_array.push(self.size);
+    }
+}
+
+/// Stamps are never placed closer together than this (in canvas units), so that tiny
+/// stamps don't turn a stroke into millions of stamps.
+const MIN_STAMP_SPACING: f32 = 1e-3;
+
+/// The most stamps that are placed between two input samples
+const MAX_STAMPS_PER_SAMPLE: usize = 4096;
+
+/// Places stamps along the path followed by the input samples of a stroke, so that the
+/// spacing of stamps doesn't depend on how fast the stroke was drawn. The distance from
+/// each stamp to the next is its width times the brush's gap at that point. A gap of
+/// zero places one stamp at each input sample.
+///
+/// Each stamp is then moved away from the path by up to its width times the brush's
+/// scatter. The random variation is taken from a generator with the stroke's seed, so
+/// placing the same samples with the same seed gives the same stamps.
+#[derive(Debug, Clone)]
+pub struct StampPlacer {
+    previous: Option<InputSample>,
+
+    /// How much further along the path the next stamp is. None to place it at the
+    /// next sample.
+    distance_to_next: Option<f32>,
+
+    rng: StrokeRng,
+}
+
+impl StampPlacer {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            previous: None,
+            distance_to_next: None,
+            rng: StrokeRng::new(seed),
+        }
+    }
+
+    /// The stamp at a point on the path. Also sets how far away the next one is.
+    fn stamp_at(
+        &mut self,
+        brush: &Brush,
+        stroke_size: f32,
+        aspect_ratio: f32,
+        sample: &InputSample,
+    ) -> Stamp {
+        let rng = &mut self.rng;
+        let mut stamp = Stamp::from_sample(brush, sample, rng);
+        let width = 2.0 * stroke_size * stamp.size * aspect_ratio;
+
+        let scatter = evaluate_random_setting(&brush.scatter, sample.pressure, rng).max(0.0);
+        let distance = scatter * width * rng.next_f32();
+        let direction = rng.next_f32() * std::f32::consts::TAU;
+        stamp.position[0] += distance * direction.cos();
+        stamp.position[1] += distance * direction.sin();
+
+        let gap = evaluate_random_setting(&brush.gap, sample.pressure, rng);
+        self.distance_to_next = match gap > 0.0 {
+            true => Some((gap * width).max(MIN_STAMP_SPACING)),
+            false => None,
+        };
+        stamp
+    }
+
+    /// The stamps to add to a stroke with the given size for its next input sample.
+    /// Stamps are scaled by the canvas aspect ratio when drawn, so their spacing is
+    /// too.
+    pub fn place(
+        &mut self,
+        brush: &Brush,
+        stroke_size: f32,
+        aspect_ratio: f32,
+        sample: InputSample,
+    ) -> Vec<Stamp> {
+        let previous = match self.previous.replace(sample) {
+            Some(previous) => previous,
+            None => return vec![self.stamp_at(brush, stroke_size, aspect_ratio, &sample)],
+        };
+
+        let length = ((sample.position[0] - previous.position[0]).powi(2)
+            + (sample.position[1] - previous.position[1]).powi(2))
+        .sqrt();
+        let mut travelled = 0.0;
+        let mut stamps = Vec::new();
+        loop {
+            match self.distance_to_next {
+                None => {
+                    stamps.push(self.stamp_at(brush, stroke_size, aspect_ratio, &sample));
+                    break;
+                }
+                // The rest of the segment is left empty, and spacing starts again from
+                // this sample
+                Some(_) if stamps.len() >= MAX_STAMPS_PER_SAMPLE => break,
+                Some(distance) if travelled + distance <= length => {
+                    travelled += distance;
+                    let point = previous.lerp(&sample, travelled / length);
+                    stamps.push(self.stamp_at(brush, stroke_size, aspect_ratio, &point));
+                }
+                Some(distance) => {
+                    self.distance_to_next = Some(distance - (length - travelled));
+                    break;
+                }
+            }
+        }
+        stamps
     }
 }
 
 fn evaluate_pressure_setting(setting: &PressureSettings, pressure: f32) -> f32 {
-    return setting.min_value + pressure * (setting.max_value - setting.min_value);
+    let fraction = setting.curve.evaluate(pressure);
+    setting.min_value + fraction * (setting.max_value - setting.min_value)
+}
+
+/// The value of a setting, moved by up to its `random` either way. Always takes one
+/// number from `rng`, even if the setting isn't random.
+fn evaluate_random_setting(setting: &PressureSettings, pressure: f32, rng: &mut StrokeRng) -> f32 {
+    evaluate_pressure_setting(setting, pressure) + setting.random * rng.next_signed()
+}
+
+#[test]
+fn test_stamp_spacing() {
+    use painter_data::brush::{Glyph, PressureCurve};
+
+    let setting = |min_value: f32, max_value: f32| PressureSettings {
+        min_value,
+        max_value,
+        random: 0.0,
+        curve: PressureCurve::linear(),
+    };
+    let brush = |size: PressureSettings, gap: PressureSettings| Brush {
+        name: "Test".to_string(),
+        glyph: Glyph::Png(Vec::new()),
+        size,
+        flow: setting(1.0, 1.0),
+        scatter: setting(0.0, 0.0),
+        gap,
+        angle: setting(0.0, 0.0),
+    };
+    let sample = |x: f32, y: f32, pressure: f32| InputSample {
+        position: [x, y],
+        pressure,
+        time: 0.0,
+        tilt: None,
+    };
+    let place_all = |brush: &Brush, samples: &[InputSample]| {
+        let mut placer = StampPlacer::new(0);
+        let mut stroke = super::context::test_stroke(Vec::new(), 0.1);
+        for sample in samples.iter() {
+            for stamp in placer.place(brush, stroke.size, 1.0, *sample) {
+                stamp.add_to(&mut stroke);
+            }
+        }
+        stroke
+    };
+    let assert_positions = |actual: &[[f32; 2]], expected: &[[f32; 2]]| {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (actual, expected) in actual.iter().zip(expected.iter()) {
+            assert!((actual[0] - expected[0]).abs() < 1e-5, "{:?}", actual);
+            assert!((actual[1] - expected[1]).abs() < 1e-5, "{:?}", actual);
+        }
+    };
+
+    // Stamps 0.2 wide, half a stamp apart. Spacing carries on around corners.
+    let spaced = brush(setting(1.0, 1.0), setting(0.5, 0.5));
+    let stroke = place_all(
+        &spaced,
+        &[
+            sample(0.0, 0.0, 1.0),
+            sample(0.35, 0.0, 1.0),
+            sample(0.35, 0.1, 1.0),
+        ],
+    );
+    assert_positions(
+        &stroke.position_array,
+        &[[0.0, 0.0], [0.1, 0.0], [0.2, 0.0], [0.3, 0.0], [0.35, 0.05]],
+    );
+
+    // Slow strokes don't pile up stamps
+    let slow: Vec<InputSample> = (0..100)
+        .map(|index| sample(index as f32 * 0.001, 0.0, 1.0))
+        .collect();
+    assert_eq!(place_all(&spaced, &slow).position_array.len(), 1);
+
+    // Pressure (and so the size and spacing) is interpolated between samples
+    let pressure_sized = brush(setting(0.0, 1.0), setting(1.0, 1.0));
+    let stroke = place_all(
+        &pressure_sized,
+        &[sample(0.0, 0.0, 1.0), sample(1.0, 0.0, 0.0)],
+    );
+    // Each stamp is 0.2 * pressure wide, and pressure falls by 1 per unit, so stamps
+    // get closer together towards the end
+    assert_positions(
+        &stroke.position_array[..4],
+        &[[0.0, 0.0], [0.2, 0.0], [0.36, 0.0], [0.488, 0.0]],
+    );
+    assert!((stroke.size_array[1] - 0.8).abs() < 1e-5);
+
+    // Without a gap there is a stamp at each sample
+    let ungapped = brush(setting(1.0, 1.0), setting(0.0, 0.0));
+    let stroke = place_all(&ungapped, &slow[..3]);
+    assert_positions(
+        &stroke.position_array,
+        &[[0.0, 0.0], [0.001, 0.0], [0.002, 0.0]],
+    );
+
+    // A segment too long to fill stops at the limit, and the next segment is spaced
+    // from where it ends
+    let tiny = brush(setting(0.01, 0.01), setting(0.5, 0.5));
+    let stroke = place_all(
+        &tiny,
+        &[
+            sample(0.0, 0.0, 1.0),
+            sample(10.0, 0.0, 1.0),
+            sample(10.0, 0.01, 1.0),
+        ],
+    );
+    let after_limit = 1 + MAX_STAMPS_PER_SAMPLE;
+    assert!(stroke.position_array[..after_limit]
+        .iter()
+        .all(|position| position[1] == 0.0));
+    assert_positions(
+        &stroke.position_array[after_limit..after_limit + 2],
+        &[[10.0, 0.001], [10.0, 0.002]],
+    );
+    assert!(stroke.position_array[after_limit..]
+        .iter()
+        .all(|position| position[0] == 10.0 && position[1] > 0.0 && position[1] <= 0.01));
+}
+
+#[test]
+fn test_stamp_jitter() {
+    use painter_data::brush::{Glyph, PressureCurve};
+
+    let setting = |min_value: f32, max_value: f32, random: f32| PressureSettings {
+        min_value,
+        max_value,
+        random,
+        curve: PressureCurve::linear(),
+    };
+    let brush = Brush {
+        name: "Test".to_string(),
+        glyph: Glyph::Png(Vec::new()),
+        size: setting(0.5, 0.5, 0.25),
+        flow: setting(0.5, 1.0, 0.5),
+        scatter: setting(1.0, 1.0, 0.0),
+        gap: setting(0.5, 0.5, 0.25),
+        angle: setting(0.0, 0.0, 1.0),
+    };
+    let place_all = |seed: u64| {
+        let mut placer = StampPlacer::new(seed);
+        let mut stamps = Vec::new();
+        for index in 0..20 {
+            let sample = InputSample {
+                position: [index as f32 * 0.05, 0.0],
+                pressure: 1.0,
+                time: 0.0,
+                tilt: None,
+            };
+            stamps.extend(placer.place(&brush, 0.1, 1.0, sample));
+        }
+        stamps
+    };
+
+    let stamps = place_all(7);
+    assert_eq!(stamps, place_all(7));
+    assert_ne!(stamps, place_all(8));
+    for stamp in stamps.iter() {
+        assert!((0.25..=0.75).contains(&stamp.size), "{:?}", stamp);
+        assert!((0.5..=1.0).contains(&stamp.color.a), "{:?}", stamp);
+        assert!((-1.0..=1.0).contains(&stamp.angle), "{:?}", stamp);
+        // Scattered by up to a stamp width from the path
+        assert!(stamp.position[1].abs() <= 0.2 * stamp.size, "{:?}", stamp);
+    }
+    assert!(stamps.iter().any(|stamp| stamp.position[1] != 0.0));
+
+    // The seed a stroke is drawn with is kept, so it can be drawn again
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let brush_id = context.image.brushes.insert(brush);
+    let mut brush_tool = BrushTool::default();
+    brush_tool.set_brush_id(brush_id);
+    brush_tool.next_stroke_seed = Some(7);
+    brush_tool.start_stroke(&mut context, 0.0, 0.0, 1.0, None);
+    brush_tool.end_stroke(&mut context);
+    assert!(brush_tool.next_stroke_seed.is_none());
+    let (operation_id, _) = context
+        .image
+        .operations
+        .iter()
+        .find(|(_, operation)| matches!(operation, Operation::Stroke(_)))
+        .unwrap();
+    assert_eq!(context.input_samples.seed(operation_id), Some(7));
+}
+
+#[test]
+fn test_pressure_curves() {
+    use super::context::EditError;
+    use painter_data::brush::PressureCurve;
+
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let brush_id = *context.image.brushes.iter().next().unwrap().0;
+    context
+        .set_brush_curve(
+            brush_id,
+            "size",
+            PressureCurve::from_samples(vec![0.0, 0.25, 1.0]),
+        )
+        .unwrap();
+    assert!(matches!(
+        context.set_brush_curve(brush_id, "colour", PressureCurve::linear()),
+        Err(EditError::UnknownBrushSetting(_))
+    ));
+    let size = &context.image.brushes.get(&brush_id).unwrap().size;
+    assert_eq!(size.max_value, 1.0);
+
+    // Changing a curve is undone like any other edit
+    let curve = size.curve.clone();
+    assert!(context.undo());
+    let size = &context.image.brushes.get(&brush_id).unwrap().size;
+    assert_eq!(size.curve, PressureCurve::linear());
+    assert!(context.redo());
+    let size = &context.image.brushes.get(&brush_id).unwrap().size;
+    assert_eq!(size.curve, curve);
+
+    let mut brush_tool = BrushTool::default();
+    brush_tool.set_brush_id(brush_id);
+    // This pen reports half the pressure it should
+    context.set_tablet_curve(
+        "Pen".to_string(),
+        PressureCurve::from_samples(vec![0.0, 1.0, 1.0]),
+    );
+    let draw = |context: &mut EditContext, brush_tool: &mut BrushTool, pressure: f32| {
+        brush_tool.start_stroke(context, 0.0, 0.0, pressure, None);
+        let operation_id = brush_tool.current_operation_id.unwrap();
+        brush_tool.end_stroke(context);
+        let stroke = match context.image.operations.get(&operation_id) {
+            Some(Operation::Stroke(stroke)) => stroke.size_array[0],
+            _ => panic!("Not a stroke"),
+        };
+        let sample = context.input_samples.get(&operation_id).unwrap()[0];
+        (stroke, sample.pressure)
+    };
+
+    assert_eq!(draw(&mut context, &mut brush_tool, 0.5), (0.25, 0.5));
+    brush_tool.device = Some("Pen".to_string());
+    assert_eq!(draw(&mut context, &mut brush_tool, 0.25), (0.25, 0.5));
+    assert_eq!(draw(&mut context, &mut brush_tool, 0.75), (1.0, 1.0));
+
+    // Every brush tool uses the curves of the context it draws into, and they are
+    // saved with the image
+    let mut other_tool = BrushTool::default();
+    other_tool.set_brush_id(brush_id);
+    other_tool.device = Some("Pen".to_string());
+    assert_eq!(draw(&mut context, &mut other_tool, 0.25), (0.25, 0.5));
+    let saved = EditContext::new_with_saved_image(context.image_with_history());
+    assert_eq!(saved.tablet_curves, context.tablet_curves);
+
+    context.remove_tablet_curve("Pen");
+    assert!(context.get_tablet_curve("Pen").is_none());
+    assert_eq!(draw(&mut context, &mut brush_tool, 0.5), (0.25, 0.5));
 }
@@ -1,13 +1,136 @@
 use log::warn;
 use pyo3::prelude::*;
 
-use painter_data::image::Image;
+use painter_data::bounds::Bounds;
+use painter_data::hit_test::HitShape;
+use painter_data::image::{GarbageReport, Image};
+use painter_data::layer::{Layer, LayerKind};
 use painter_data::template::create_default_image;
 
 use glam::Mat3;
-use painter_data::color_primitives::Color;
-use painter_data::id_map::{IdMapBase, LayerId, OperationId};
+use painter_data::brush::{Brush, Glyph, PressureCurve};
+use painter_data::color_primitives::{BlendMode, Color};
+use painter_data::id_map::{BrushId, GlyphId, IdMapBase, LayerId, OperationId};
 use painter_data::operation::Operation;
+use painter_data::spatial_index::SpatialIndex;
+use painter_data::stroke::StrokeData;
+use painter_depgraph::DepGraphError;
+
+use super::brush_tool::StampPlacer;
+use super::history::{Change, Command, History, SnapshotDiff, UNDO_HISTORY_EXTENSION};
+use super::input_samples::{InputSample, InputSamples, INPUT_SAMPLES_EXTENSION};
+use super::journal::{Journal, JournalError};
+use super::recording::{RecordedEvent, Recording, RecordingError};
+use super::tablet_curves::{TabletCurves, TABLET_CURVES_EXTENSION};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Why an edit to the image could not be made
+#[derive(Debug)]
+pub enum EditError {
+    UnknownLayer(LayerId),
+
+    /// The image must always have at least one layer
+    LastLayer,
+
+    /// Locked layers cannot be drawn on
+    LayerLocked(LayerId),
+
+    UnknownBlendMode(String),
+
+    UnknownTransformMode(String),
+
+    /// Layers can only be placed inside group layers
+    NotAGroup(LayerId),
+
+    /// A group cannot be moved inside itself or one of the groups it contains
+    GroupIntoItself(LayerId),
+
+    /// Branches are merged back into the layer they were forked from, so they must
+    /// be the tip of a chain of operations built on that layer's contents and not
+    /// used by anything else.
+    InvalidBranch(OperationId),
+
+    /// Groups only contain other layers, so cannot be drawn on directly
+    IsAGroup(LayerId),
+
+    /// Merging needs at least two different branches
+    NotEnoughBranches,
+
+    /// The operation does not exist or is not a stroke
+    NotAStroke(OperationId),
+
+    UnknownGlyph(GlyphId),
+
+    UnknownBrush(BrushId),
+
+    /// Not one of Brush::SETTING_NAMES
+    UnknownBrushSetting(String),
+
+    /// The depgraph does not have the structure described in Image::layer_stack
+    MalformedLayerStack,
+
+    DepGraphError(DepGraphError<OperationId>),
+}
+
+/// Which strokes an edit to many strokes at once applies to
+#[derive(Debug, Clone, PartialEq)]
+pub enum StrokeScope {
+    /// Every stroke in the image that is not on a locked layer
+    Image,
+
+    /// The strokes in a layer, including those in the layers of a group
+    Layer(LayerId),
+
+    Strokes(Vec<OperationId>),
+}
+
+impl StrokeScope {
+    /// The scope described by the optional arguments of python methods: the strokes if
+    /// given, otherwise the layer if given, otherwise the whole image.
+    fn from_args(layer: Option<LayerId>, strokes: Option<Vec<OperationId>>) -> Self {
+        match (layer, strokes) {
+            (_, Some(strokes)) => Self::Strokes(strokes),
+            (Some(layer_id), None) => Self::Layer(layer_id),
+            (None, None) => Self::Image,
+        }
+    }
+}
+
+/// The part of the canvas that may look different since the renderer last drew it
+/// (see EditContext::take_damage)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Damage {
+    Nothing,
+
+    /// Only strokes inside the area were changed, so the rest of the canvas is as it was
+    Area(Bounds),
+
+    Everything,
+}
+
+impl Damage {
+    fn add(&mut self, bounds: Bounds) {
+        *self = match self {
+            Damage::Nothing => Damage::Area(bounds),
+            Damage::Area(area) => Damage::Area(area.union(&bounds)),
+            Damage::Everything => Damage::Everything,
+        };
+    }
+}
+
+impl From<DepGraphError<OperationId>> for EditError {
+    fn from(err: DepGraphError<OperationId>) -> Self {
+        EditError::DepGraphError(err)
+    }
+}
+
+impl From<EditError> for PyErr {
+    fn from(err: EditError) -> Self {
+        pyo3::exceptions::PyValueError::new_err(format!("{:?}", err))
+    }
+}
 
 #[pyclass]
 #[derive(Clone)]
@@ -21,6 +144,32 @@ pub struct EditContext {
 
     #[pyo3(get, set)]
     pub canvas_transform: CanvasTransform,
+
+    /// Changes to the image that can be undone. Anything that modifies the image should
+    /// do so inside a group (see begin_group) and touch any operations/glyphs it modifies.
+    pub history: History,
+
+    /// If set, every change to the image is appended to this so it can be recovered
+    /// after a crash. Shared between clones of the context.
+    journal: Option<Arc<Mutex<Journal>>>,
+
+    /// Where each stroke is on the canvas. Kept up to date as changes to the image are
+    /// applied. Strokes modified in a group that has not yet ended are left out, so
+    /// anything using the index must treat strokes it does not contain as possibly
+    /// being anywhere (see SpatialIndex::may_intersect).
+    pub spatial_index: SpatialIndex,
+
+    /// The input each stroke drawn with the brush tool was drawn from
+    pub input_samples: InputSamples,
+
+    /// How the pressure reported by each tablet is corrected, shared by every tool
+    pub tablet_curves: TabletCurves,
+
+    /// If set, the input given to the tools is recorded so it can be replayed
+    recording: Option<Recording>,
+
+    /// What has changed since the image was last drawn
+    damage: Damage,
 }
 
 impl Default for EditContext {
@@ -32,7 +181,10 @@ impl Default for EditContext {
 
 impl EditContext {
     pub fn new_with_image(image: Image) -> Self {
+        let mut history = History::default();
+        history.set_record_applied(true);
         EditContext {
+            spatial_index: SpatialIndex::new(&image),
             image,
             insert_operation_onto: None,
             color: Color {
@@ -42,7 +194,767 @@ impl EditContext {
                 a: 1.0,
             },
             canvas_transform: CanvasTransform::default(),
+            history,
+            journal: None,
+            input_samples: InputSamples::default(),
+            tablet_curves: TabletCurves::default(),
+            recording: None,
+            damage: Damage::Everything,
+        }
+    }
+
+    /// Starts (or with None, stops) writing changes to a journal
+    pub fn set_journal(&mut self, journal: Option<Journal>) {
+        self.journal = journal.map(|journal| Arc::new(Mutex::new(journal)));
+    }
+
+    /// How many records have been written to the journal, if there is one
+    pub fn journal_record_count(&self) -> Option<usize> {
+        self.journal.as_ref().map(|journal| {
+            journal
+                .lock()
+                .expect("Journal lock poisoned")
+                .record_count()
+        })
+    }
+
+    /// Moves the journal onto a newly saved version of the document that contains the
+    /// first `saved_records` records of the journal. See Journal::rebase.
+    pub fn rebase_journal(
+        &mut self,
+        document_path: &Path,
+        document_fingerprint: u64,
+        saved_records: usize,
+    ) -> Result<(), JournalError> {
+        self.sync_applied_changes();
+        match &self.journal {
+            Some(journal) => journal.lock().expect("Journal lock poisoned").rebase(
+                document_path,
+                document_fingerprint,
+                saved_records,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Reapplies commands read from a journal, adding them to the history so that they
+    /// can be undone.
+    pub fn replay(&mut self, commands: Vec<Command>) {
+        self.history
+            .end_all_groups(&self.image, self.insert_operation_onto);
+        for command in commands {
+            command.redo(&mut self.image, &mut self.insert_operation_onto);
+            self.history.push(command);
+        }
+        self.sync_applied_changes();
+    }
+
+    /// Updates the spatial index and damage with anything that has changed, and
+    /// appends the changes to the journal
+    fn sync_applied_changes(&mut self) {
+        let applied = self.history.take_applied();
+        for change in applied.iter().flat_map(|command| command.changes.iter()) {
+            match change {
+                // A stroke that was edited in place only changes the area it covered
+                // before and after. Strokes changed with operation_mut have already
+                // had their old bounds added.
+                Change::Operation {
+                    id,
+                    before: Some(Operation::Stroke(_)),
+                    after: Some(Operation::Stroke(_)),
+                } => {
+                    if let Some(bounds) = self.spatial_index.bounds(id) {
+                        self.damage.add(bounds);
+                    }
+                    self.spatial_index.update(&self.image, id);
+                    if let Some(bounds) = self.spatial_index.bounds(id) {
+                        self.damage.add(bounds);
+                    }
+                }
+                Change::Operation { id, .. } => {
+                    self.spatial_index.update(&self.image, id);
+                    self.damage = Damage::Everything;
+                }
+                // None of these are drawn until something uses them
+                Change::Glyph { before: None, .. }
+                | Change::Brush { .. }
+                | Change::InsertTarget { .. } => {}
+                _ => self.damage = Damage::Everything,
+            }
+        }
+        // Stamps are scaled by the aspect ratio, so if it changes everything moves
+        if self.spatial_index.aspect_ratio() != self.image.metadata.aspect_ratio() {
+            self.spatial_index = SpatialIndex::new(&self.image);
+        }
+        if let Some(journal) = &self.journal {
+            let mut journal = journal.lock().expect("Journal lock poisoned");
+            for command in applied.iter() {
+                if let Err(err) = journal.append(command) {
+                    warn!("Failed to write to journal: {:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Creates a context for an image loaded from a file, restoring the undo history,
+    /// input samples and tablet curves if they were saved with it.
+    pub fn new_with_saved_image(mut image: Image) -> Self {
+        let history = image.remove_extension(UNDO_HISTORY_EXTENSION);
+        let input_samples = image.remove_extension(INPUT_SAMPLES_EXTENSION);
+        let tablet_curves = image.remove_extension(TABLET_CURVES_EXTENSION);
+        let mut context = Self::new_with_image(image);
+        if let Some(data) = tablet_curves {
+            match TabletCurves::from_bytes(&data) {
+                Ok(tablet_curves) => context.tablet_curves = tablet_curves,
+                Err(err) => warn!(
+                    "Discarding tablet curves that could not be loaded: {:?}",
+                    err
+                ),
+            }
+        }
+        if let Some(data) = input_samples {
+            match InputSamples::from_bytes(&data) {
+                Ok(input_samples) => context.input_samples = input_samples,
+                Err(err) => warn!(
+                    "Discarding input samples that could not be loaded: {:?}",
+                    err
+                ),
+            }
+        }
+        if let Some(data) = history {
+            match History::from_bytes(&data) {
+                Ok(mut history) => {
+                    history.set_record_applied(true);
+                    context.history = history;
+                }
+                Err(err) => warn!(
+                    "Discarding undo history that could not be loaded: {:?}",
+                    err
+                ),
+            }
+        }
+        context
+    }
+
+    /// A copy of the image with the undo history, input samples and tablet curves stored
+    /// in it, ready to be saved
+    pub fn image_with_history(&self) -> Image {
+        let mut image = self.image.clone();
+        image
+            .set_extension(UNDO_HISTORY_EXTENSION, self.history.to_bytes())
+            .expect("Undo history extension name is valid");
+        if !self.input_samples.is_empty() {
+            image
+                .set_extension(INPUT_SAMPLES_EXTENSION, self.input_samples.to_bytes())
+                .expect("Input samples extension name is valid");
+        }
+        if !self.tablet_curves.is_empty() {
+            image
+                .set_extension(TABLET_CURVES_EXTENSION, self.tablet_curves.to_bytes())
+                .expect("Tablet curves extension name is valid");
+        }
+        image
+    }
+
+    /// Adds an event to the recording, if one is being made
+    pub fn record_event(&mut self, event: RecordedEvent) {
+        if let Some(recording) = &mut self.recording {
+            recording.push(event);
+        }
+    }
+
+    /// Returns what has changed since this was last called, so the renderer only has to
+    /// draw that part of the canvas again. Changes in a group that has not ended are
+    /// not tracked, so could be anywhere, both now and once the group ends.
+    pub fn take_damage(&mut self) -> Damage {
+        if self.history.pending_has_changes() {
+            self.damage = Damage::Everything;
+            return Damage::Everything;
+        }
+        std::mem::replace(&mut self.damage, Damage::Nothing)
+    }
+
+    /// Gets an operation for modification, recording it's current state in the history
+    pub fn operation_mut(&mut self, operation_id: &OperationId) -> Option<&mut Operation> {
+        self.history
+            .touch_operation(*operation_id, self.image.operations.get(operation_id));
+        // It is added back once the change is applied, so the area it covered has to
+        // be remembered until then
+        if let Some(bounds) = self.spatial_index.bounds(operation_id) {
+            self.damage.add(bounds);
+        }
+        self.spatial_index.remove(operation_id);
+        self.image.operations.get_mut(operation_id)
+    }
+
+    /// Gets a layer for modification, recording it's current state in the history
+    fn layer_mut(&mut self, layer_id: &LayerId) -> Option<&mut Layer> {
+        self.history
+            .touch_layer(*layer_id, self.image.layers.get(layer_id));
+        self.image.layers.get_mut(layer_id)
+    }
+
+    /// Gets a brush for modification, recording it's current state in the history
+    fn brush_mut(&mut self, brush_id: &BrushId) -> Option<&mut Brush> {
+        self.history
+            .touch_brush(*brush_id, self.image.brushes.get(brush_id));
+        self.image.brushes.get_mut(brush_id)
+    }
+
+    /// Adds a layer to the image, recording it in the history
+    fn add_layer(&mut self, layer: Layer) -> LayerId {
+        let layer_id = self.image.layers.insert(layer);
+        self.history.touch_layer(layer_id, None);
+        layer_id
+    }
+
+    /// Records the current dependencies of operations in the history. Must be called
+    /// before changing what they depend on.
+    fn touch_dependencies(&mut self, ids: &[OperationId]) {
+        for operation_id in ids.iter() {
+            self.history
+                .touch_dependencies(*operation_id, self.image.depgraph.depends_on(operation_id));
+        }
+    }
+
+    /// Records the dependencies changed by operating on `base` with a new operation
+    /// (see DepGraph::operate_on)
+    fn touch_for_operate_on(&mut self, new_operation: OperationId, base: &OperationId) {
+        let mut touched: Vec<OperationId> = self
+            .image
+            .depgraph
+            .edges_to(base)
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect();
+        touched.push(new_operation);
+        self.touch_dependencies(&touched);
+    }
+
+    /// Applies an affine transform to a set of strokes after they were drawn (see
+    /// transform_stroke).
+    pub fn transform_operations(
+        &mut self,
+        ids: &[OperationId],
+        transform: Mat3,
+    ) -> Result<(), EditError> {
+        self.edit_strokes("Transform Strokes", ids, |stroke| {
+            transform_stroke(stroke, &transform)
+        })
+    }
+
+    /// The strokes within a scope
+    pub fn strokes_in_scope(&self, scope: &StrokeScope) -> Result<Vec<OperationId>, EditError> {
+        let is_stroke = |operation_id: &OperationId| {
+            matches!(
+                self.image.operations.get(operation_id),
+                Some(Operation::Stroke(_))
+            )
+        };
+        match scope {
+            StrokeScope::Image => {
+                let locked = self.locked_operations();
+                Ok(self
+                    .image
+                    .operations
+                    .iter()
+                    .map(|(operation_id, _)| *operation_id)
+                    .filter(|operation_id| is_stroke(operation_id))
+                    .filter(|operation_id| !locked.contains_key(operation_id))
+                    .collect())
+            }
+            StrokeScope::Layer(layer_id) => {
+                self.layer(layer_id)?;
+                Ok(self
+                    .image
+                    .layer_operations(layer_id)
+                    .into_iter()
+                    .filter(|operation_id| is_stroke(operation_id))
+                    .collect())
+            }
+            StrokeScope::Strokes(ids) => Ok(ids.clone()),
+        }
+    }
+
+    /// Changes the color of each stroke in a scope to the one returned by `recolor`,
+    /// leaving the stroke alone if it returns None. Returns the strokes that changed.
+    pub fn recolor_in_scope(
+        &mut self,
+        name: &str,
+        scope: &StrokeScope,
+        mut recolor: impl FnMut(&Color) -> Option<Color>,
+    ) -> Result<Vec<OperationId>, EditError> {
+        let ids = self.strokes_in_scope(scope)?;
+        self.check_strokes_editable(&ids)?;
+        let new_colors: Vec<(OperationId, Color)> = ids
+            .iter()
+            .filter_map(
+                |operation_id| match self.image.operations.get(operation_id) {
+                    Some(Operation::Stroke(stroke)) => recolor(&stroke.color)
+                        .filter(|color| *color != stroke.color)
+                        .map(|color| (*operation_id, color)),
+                    _ => None,
+                },
+            )
+            .collect();
+        self.edit_in_group(name, |context| {
+            for (operation_id, color) in new_colors.iter() {
+                if let Some(Operation::Stroke(stroke)) = context.operation_mut(operation_id) {
+                    stroke.color = color.clone();
+                }
+            }
+            Ok(())
+        })?;
+        Ok(new_colors
+            .into_iter()
+            .map(|(operation_id, _)| operation_id)
+            .collect())
+    }
+
+    /// Checks that all the operations are strokes that may be edited, ie are not on a
+    /// locked layer.
+    pub fn check_strokes_editable(&self, ids: &[OperationId]) -> Result<(), EditError> {
+        let locked = self.locked_operations();
+        for operation_id in ids.iter() {
+            if !matches!(
+                self.image.operations.get(operation_id),
+                Some(Operation::Stroke(_))
+            ) {
+                return Err(EditError::NotAStroke(*operation_id));
+            }
+            if let Some(layer_id) = locked.get(operation_id) {
+                return Err(EditError::LayerLocked(*layer_id));
+            }
+        }
+        Ok(())
+    }
+
+    /// The operations inside locked paint layers, or paint layers inside a locked
+    /// group, and the locked layer each one is under. Each locked layer is walked
+    /// once, rather than searching for the layer containing every operation.
+    fn locked_operations(&self) -> HashMap<OperationId, LayerId> {
+        let mut locked = HashMap::new();
+        for (layer_id, layer) in self.image.layers.iter() {
+            if layer.kind != LayerKind::Paint {
+                continue;
+            }
+            if let Some(locked_by) = self.locked_layer(layer_id) {
+                for operation_id in self.image.layer_operations(layer_id) {
+                    locked.insert(operation_id, locked_by);
+                }
+            }
+        }
+        locked
+    }
+
+    /// Fails with LayerLocked if the layer, or a group it is in, is locked.
+    fn check_layer_unlocked(&self, layer_id: &LayerId) -> Result<(), EditError> {
+        match self.locked_layer(layer_id) {
+            Some(locked_by) => Err(EditError::LayerLocked(locked_by)),
+            None => Ok(()),
+        }
+    }
+
+    /// The layer whose contents `operation_id` is part of. Unlike
+    /// Image::layer_containing this also finds the layer of a branch that has not
+    /// been merged yet, by following the branch down to where it was forked.
+    fn layer_owning(&self, operation_id: &OperationId) -> Option<LayerId> {
+        let mut current = *operation_id;
+        let mut visited = HashSet::new();
+        while visited.insert(current) {
+            if let Some(layer_id) = self.image.layer_containing(&current) {
+                return Some(layer_id);
+            }
+            current = *self.image.depgraph.depends_on(&current)?.first()?;
+        }
+        None
+    }
+
+    /// The layer that stops `layer_id` from being edited: either the layer itself if
+    /// it is locked, or the closest locked group it is inside.
+    pub(crate) fn locked_layer(&self, layer_id: &LayerId) -> Option<LayerId> {
+        let mut next = Some(*layer_id);
+        while let Some(layer_id) = next {
+            if self.image.layers.get(&layer_id)?.locked {
+                return Some(layer_id);
+            }
+            next = self.image.parent_layer(&layer_id);
+        }
+        None
+    }
+
+    /// Finds the ID of the glyph, adding it to the image if it is not already present
+    pub fn find_or_insert_glyph(&mut self, glyph: &Glyph) -> GlyphId {
+        if let Some((id, _)) = self.image.glyphs.iter().find(|(_id, gly)| *gly == glyph) {
+            return *id;
+        }
+        self.begin_group("Add Glyph");
+        let glyph_id = self.image.glyphs.insert(glyph.clone());
+        self.history.touch_glyph(glyph_id, None);
+        self.end_group();
+        glyph_id
+    }
+
+    fn layer(&self, layer_id: &LayerId) -> Result<&Layer, EditError> {
+        self.image
+            .layers
+            .get(layer_id)
+            .ok_or(EditError::UnknownLayer(*layer_id))
+    }
+
+    /// The edge in the layer stack that points at a layer's blend operation: either
+    /// edge 0 of the Output, edge 0 of the blend operation of the group it is the top
+    /// of, or edge 1 of the blend operation of the layer above.
+    fn layer_stack_edge(
+        &self,
+        blend_operation_id: &OperationId,
+    ) -> Result<(OperationId, usize), EditError> {
+        let output = self
+            .image
+            .output_operation()
+            .ok_or(EditError::MalformedLayerStack)?;
+        self.image
+            .depgraph
+            .edges_to(blend_operation_id)
+            .into_iter()
+            .find(|(node, edge_index)| {
+                let layer = self
+                    .image
+                    .layer_with_blend_operation(node)
+                    .and_then(|layer_id| self.image.layers.get(&layer_id));
+                match (layer, edge_index) {
+                    (None, 0) => *node == output,
+                    (Some(layer), 0) => layer.kind == LayerKind::Group,
+                    (Some(_), 1) => true,
+                    _ => false,
+                }
+            })
+            .ok_or(EditError::MalformedLayerStack)
+    }
+
+    /// The edge that a layer placed at `index` in the stack of layers inside `parent`
+    /// (or the top level stack if `parent` is None, 0 being the top) would be inserted
+    /// on.
+    fn layer_stack_edge_at(
+        &self,
+        parent: Option<LayerId>,
+        index: usize,
+    ) -> Result<(OperationId, usize), EditError> {
+        let stack = self.image.child_layers(parent);
+        let above = index
+            .checked_sub(1)
+            .and_then(|above_index| stack.get(above_index).or_else(|| stack.last()));
+        match (above, parent) {
+            (Some(above), _) => Ok((self.layer(above)?.blend_operation_id, 1)),
+            (None, Some(parent)) => Ok((self.layer(&parent)?.blend_operation_id, 0)),
+            (None, None) => Ok((
+                self.image
+                    .output_operation()
+                    .ok_or(EditError::MalformedLayerStack)?,
+                0,
+            )),
+        }
+    }
+
+    /// Places a blend operation into the layer stack on `edge`, so that it blends
+    /// `contents` over whatever the edge pointed at.
+    fn link_layer(
+        &mut self,
+        blend_operation_id: OperationId,
+        contents: OperationId,
+        edge: (OperationId, usize),
+    ) -> Result<(), EditError> {
+        let (node, edge_index) = edge;
+        let below = *self
+            .image
+            .depgraph
+            .depends_on(&node)
+            .and_then(|deps| deps.get(edge_index))
+            .ok_or(EditError::MalformedLayerStack)?;
+        self.touch_dependencies(&[blend_operation_id, node]);
+        self.image
+            .depgraph
+            .try_insert(blend_operation_id, vec![contents, below])?;
+        self.image
+            .depgraph
+            .set_dependency(&node, edge_index, blend_operation_id)?;
+        Ok(())
+    }
+
+    /// Takes a layer out of the layer stack, joining the layers above and below it.
+    /// The layer's blend operation is left in the depgraph.
+    fn unlink_layer(&mut self, blend_operation_id: OperationId) -> Result<(), EditError> {
+        let (node, edge_index) = self.layer_stack_edge(&blend_operation_id)?;
+        let below = *self
+            .image
+            .depgraph
+            .depends_on(&blend_operation_id)
+            .and_then(|deps| deps.get(1))
+            .ok_or(EditError::MalformedLayerStack)?;
+        self.touch_dependencies(&[node]);
+        self.image
+            .depgraph
+            .set_dependency(&node, edge_index, below)?;
+        Ok(())
+    }
+
+    /// Copies an operation and everything it depends on, returning a map from each
+    /// copied operation to it's copy.
+    fn copy_operations(
+        &mut self,
+        root: OperationId,
+    ) -> Result<HashMap<OperationId, OperationId>, EditError> {
+        let mut copies: HashMap<OperationId, OperationId> = HashMap::new();
+        // Depth first, copying an operation once all it's dependencies are copied
+        let mut to_visit = vec![root];
+        while let Some(operation_id) = to_visit.last().cloned() {
+            if copies.contains_key(&operation_id) {
+                to_visit.pop();
+                continue;
+            }
+            let deps = self
+                .image
+                .depgraph
+                .depends_on(&operation_id)
+                .ok_or(DepGraphError::UnknownNode(operation_id))?
+                .clone();
+            let uncopied: Vec<OperationId> = deps
+                .iter()
+                .filter(|dep| !copies.contains_key(dep))
+                .cloned()
+                .collect();
+            if !uncopied.is_empty() {
+                to_visit.extend(uncopied);
+                continue;
+            }
+            to_visit.pop();
+
+            let operation = self
+                .image
+                .operations
+                .get(&operation_id)
+                .ok_or(DepGraphError::UnknownNode(operation_id))?
+                .clone();
+            let copy_id = self.image.operations.insert(operation);
+            self.history.touch_operation(copy_id, None);
+            let copy_deps = deps.iter().map(|dep| copies[dep]).collect();
+            self.touch_dependencies(&[copy_id]);
+            self.image.depgraph.insert(copy_id, copy_deps);
+            copies.insert(operation_id, copy_id);
+        }
+        Ok(copies)
+    }
+
+    /// Points the third dependency of the blend operation of each clipped layer at the
+    /// contents of the layer it is clipped to, and removes it from layers that are not
+    /// clipped. Must be called after anything that changes the order or clipping of
+    /// layers.
+    fn update_clipping(&mut self) -> Result<(), EditError> {
+        let groups: Vec<LayerId> = self
+            .image
+            .all_layers()
+            .into_iter()
+            .filter(|layer_id| {
+                self.image.layers.get(layer_id).map(|layer| layer.kind) == Some(LayerKind::Group)
+            })
+            .collect();
+        let stacks = std::iter::once(None).chain(groups.into_iter().map(Some));
+        for parent in stacks {
+            self.update_stack_clipping(self.image.child_layers(parent))?;
+        }
+        Ok(())
+    }
+
+    /// Updates the clipping of the layers in a single stack (see update_clipping)
+    fn update_stack_clipping(&mut self, stack: Vec<LayerId>) -> Result<(), EditError> {
+        let mut layers = Vec::with_capacity(stack.len());
+        for layer_id in stack.iter() {
+            let layer = self.layer(layer_id)?;
+            let deps = self
+                .image
+                .depgraph
+                .depends_on(&layer.blend_operation_id)
+                .filter(|deps| deps.len() >= 2)
+                .ok_or(EditError::MalformedLayerStack)?;
+            layers.push((layer.blend_operation_id, layer.clip_to_below, deps.clone()));
+        }
+
+        for (index, (blend_operation_id, clip_to_below, deps)) in layers.iter().enumerate() {
+            let mut new_deps = deps[..2].to_vec();
+            if *clip_to_below && index + 1 < layers.len() {
+                // Clip to the nearest layer below that is not itself clipped
+                let base = layers[index + 1..]
+                    .iter()
+                    .find(|(_, clipped, _)| !clipped)
+                    .or_else(|| layers.last())
+                    .expect("There is a layer below");
+                new_deps.push(base.2[0]);
+            }
+            if new_deps != *deps {
+                self.touch_dependencies(&[*blend_operation_id]);
+                self.image.depgraph.insert(*blend_operation_id, new_deps);
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates an empty layer or group directly above `above`, or at the top if
+    /// `above` is None. Paint layers start from a LayerStart tag, groups from an empty
+    /// GroupBase that their children are stacked on.
+    fn insert_layer(
+        &mut self,
+        name: String,
+        above: Option<LayerId>,
+        kind: LayerKind,
+    ) -> Result<LayerId, EditError> {
+        let edge = match above {
+            Some(above) => self.layer_stack_edge(&self.layer(&above)?.blend_operation_id)?,
+            None => self.layer_stack_edge_at(None, 0)?,
+        };
+        let tag = match kind {
+            LayerKind::Paint => "LayerStart",
+            LayerKind::Group => "GroupBase",
+        };
+        let contents = self
+            .image
+            .operations
+            .insert(Operation::Tag(tag.to_string()));
+        self.history.touch_operation(contents, None);
+        self.touch_dependencies(&[contents]);
+        self.image.depgraph.insert(contents, vec![]);
+
+        let blend_operation_id = self
+            .image
+            .operations
+            .insert(Operation::Composite(BlendMode::Mix(1.0)));
+        self.history.touch_operation(blend_operation_id, None);
+        self.link_layer(blend_operation_id, contents, edge)?;
+
+        let layer_id = self.add_layer(Layer::new_with_kind(name, blend_operation_id, kind));
+        self.update_clipping()?;
+        Ok(layer_id)
+    }
+
+    /// The operation at the top of a paint layer's contents, which new operations on
+    /// the layer are drawn on top of
+    fn layer_contents(&self, layer_id: &LayerId) -> Result<OperationId, EditError> {
+        let layer = self.layer(layer_id)?;
+        if layer.kind == LayerKind::Group {
+            return Err(EditError::IsAGroup(*layer_id));
+        }
+        self.image
+            .depgraph
+            .depends_on(&layer.blend_operation_id)
+            .and_then(|deps| deps.first().cloned())
+            .ok_or(EditError::MalformedLayerStack)
+    }
+
+    /// The strokes covering `shape`, topmost first (see Image::strokes_hit)
+    fn strokes_hit(
+        &self,
+        shape: HitShape,
+        topmost_only: bool,
+        current_layer_only: bool,
+    ) -> Vec<OperationId> {
+        let layer = match (current_layer_only, self.current_layer()) {
+            (false, _) => None,
+            (true, Some(layer_id)) => Some(layer_id),
+            (true, None) => return Vec::new(),
+        };
+        let area = match shape.bounds() {
+            Some(area) => area,
+            None => return Vec::new(),
+        };
+        let mut hits = self
+            .image
+            .strokes_hit_filtered(&shape, layer, |operation_id| {
+                self.spatial_index.may_intersect(operation_id, &area)
+            });
+        if topmost_only {
+            hits.truncate(1);
+        }
+        hits
+    }
+
+    /// Changes a set of strokes in an undo group. The strokes are edited in place and the
+    /// depgraph is left alone, so only the area they cover before and after the edit is
+    /// drawn again (see take_damage). Nothing is changed if any of the operations is not
+    /// a stroke or is on a locked layer.
+    fn edit_strokes(
+        &mut self,
+        name: &str,
+        ids: &[OperationId],
+        mut edit: impl FnMut(&mut StrokeData),
+    ) -> Result<(), EditError> {
+        self.check_strokes_editable(ids)?;
+        self.edit_in_group(name, |context| {
+            for operation_id in ids.iter() {
+                if let Some(Operation::Stroke(stroke)) = context.operation_mut(operation_id) {
+                    edit(stroke);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Changes some of the properties of a layer in an undo group
+    fn edit_layer(
+        &mut self,
+        layer_id: LayerId,
+        name: &str,
+        edit: impl FnOnce(&mut Layer),
+    ) -> Result<(), EditError> {
+        self.layer(&layer_id)?;
+        self.edit_in_group(name, |context| {
+            edit(context.layer_mut(&layer_id).expect("Checked above"));
+            Ok(())
+        })
+    }
+
+    /// Changes the blend operation of a layer in an undo group
+    fn edit_layer_blend_mode(
+        &mut self,
+        layer_id: LayerId,
+        name: &str,
+        edit: impl FnOnce(&BlendMode) -> Result<BlendMode, EditError>,
+    ) -> Result<(), EditError> {
+        let blend_operation_id = self.layer(&layer_id)?.blend_operation_id;
+        let new_blend_mode = match self.image.operations.get(&blend_operation_id) {
+            Some(Operation::Composite(blend_mode)) => edit(blend_mode)?,
+            _ => return Err(EditError::MalformedLayerStack),
+        };
+        self.edit_in_group(name, |context| {
+            *context
+                .operation_mut(&blend_operation_id)
+                .expect("Checked above") = Operation::Composite(new_blend_mode);
+            Ok(())
+        })
+    }
+
+    /// Runs `edit` in an undo group. If it fails, anything it changed before failing
+    /// is put back, so the image is never left half edited.
+    fn edit_in_group<T>(
+        &mut self,
+        name: &str,
+        edit: impl FnOnce(&mut Self) -> Result<T, EditError>,
+    ) -> Result<T, EditError> {
+        self.begin_group(name);
+        let savepoint = self
+            .history
+            .savepoint(&self.image, self.insert_operation_onto);
+        let result = edit(self);
+        if let (Err(_), Some(savepoint)) = (&result, savepoint) {
+            let reverted =
+                self.history
+                    .rollback(savepoint, &mut self.image, &mut self.insert_operation_onto);
+            for change in reverted.iter() {
+                if let Change::Operation { id, .. } = change {
+                    self.spatial_index.update(&self.image, id);
+                }
+            }
         }
+        self.end_group();
+        result
     }
 }
 
@@ -53,37 +965,862 @@ impl EditContext {
         Ok(Self::default())
     }
 
-    pub fn insert_operation(&mut self, operation: Operation) -> OperationId {
+    /// Places an operation on top of the selected layer. Fails if the layer, or a group
+    /// it is in, is locked.
+    pub fn insert_operation(&mut self, operation: Operation) -> Result<OperationId, EditError> {
+        if let Some(layer_id) = self.current_layer() {
+            self.layer(&layer_id)?;
+            self.check_layer_unlocked(&layer_id)?;
+        }
+        self.begin_group("Insert Operation");
         let new_op_id = self.image.operations.insert(operation);
+        self.history.touch_operation(new_op_id, None);
         if let Some(op_onto) = self.insert_operation_onto {
+            self.touch_for_operate_on(new_op_id, &op_onto);
             self.image.depgraph.operate_on(new_op_id, op_onto);
             self.insert_operation_onto = Some(new_op_id);
         } else {
             warn!("Created orphan operation - no known location to place in depgraph")
         }
-        return new_op_id;
+        self.end_group();
+        Ok(new_op_id)
     }
 
-    pub fn select_layer(&mut self, layer_id: LayerId) {
-        let layer_blend_op_id = self
-            .image
-            .layers
-            .get_unchecked(&layer_id)
-            .blend_operation_id;
+    /// Starts grouping changes so that they are undone/redone as a single step. Groups
+    /// can be nested - the changes are recorded when the outermost group ends.
+    pub fn begin_group(&mut self, name: &str) {
+        self.history
+            .begin_group(name, &self.image, self.insert_operation_onto);
+    }
+
+    /// Ends a group started with begin_group. Returns true if something was added to
+    /// the undo history.
+    pub fn end_group(&mut self) -> bool {
+        let added = self
+            .history
+            .end_group(&self.image, self.insert_operation_onto);
+        self.sync_applied_changes();
+        added
+    }
+
+    /// Reverts the most recent change. If a group is open (eg a stroke is in progress)
+    /// it is ended first so that the changes so far are undone.
+    /// Returns false if there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        self.record_event(RecordedEvent::Undo);
+        let changed = self
+            .history
+            .undo(&mut self.image, &mut self.insert_operation_onto);
+        self.sync_applied_changes();
+        changed
+    }
+
+    /// Reapplies the most recently undone change. Returns false if there is nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        self.record_event(RecordedEvent::Redo);
+        let changed = self
+            .history
+            .redo(&mut self.image, &mut self.insert_operation_onto);
+        self.sync_applied_changes();
+        changed
+    }
+
+    /// Redoes along a particular branch of the history. Branches are created by undoing
+    /// and then making a different change. See list_redo_branches.
+    pub fn redo_branch(&mut self, branch: usize) -> bool {
+        let changed =
+            self.history
+                .redo_branch(branch, &mut self.image, &mut self.insert_operation_onto);
+        self.sync_applied_changes();
+        changed
+    }
+
+    /// The names of the changes that can be redone from here, oldest branch first
+    pub fn list_redo_branches(&self) -> Vec<String> {
+        self.history.redo_branch_names()
+    }
+
+    /// Names the current state of the image so that it can be returned to with
+    /// switch_to_snapshot, even after undoing and drawing something else.
+    pub fn create_snapshot(&mut self, name: &str) {
+        self.history
+            .end_all_groups(&self.image, self.insert_operation_onto);
+        self.sync_applied_changes();
+        self.history.create_snapshot(name);
+    }
+
+    pub fn delete_snapshot(&mut self, name: &str) -> bool {
+        self.history.delete_snapshot(name)
+    }
+
+    pub fn list_snapshots(&self) -> Vec<String> {
+        self.history.snapshot_names()
+    }
+
+    /// Returns the image to the state it was in when the snapshot was created.
+    /// Returns false if there is no snapshot with that name.
+    pub fn switch_to_snapshot(&mut self, name: &str) -> bool {
+        let changed = match self.history.snapshot(name) {
+            Some(node) => {
+                self.history
+                    .switch_to(node, &mut self.image, &mut self.insert_operation_onto)
+            }
+            None => false,
+        };
+        self.sync_applied_changes();
+        changed
+    }
+
+    /// Which operations were added, removed or changed going from one snapshot to
+    /// another. Returns None if either snapshot does not exist.
+    pub fn diff_snapshots(&self, from: &str, to: &str) -> Option<SnapshotDiff> {
+        self.history
+            .diff(self.history.snapshot(from)?, self.history.snapshot(to)?)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.history.can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.history.can_redo()
+    }
+
+    /// The maximum number of changes that can be undone
+    #[getter]
+    pub fn get_history_depth(&self) -> usize {
+        self.history.max_depth()
+    }
+
+    #[setter]
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.history.set_max_depth(depth);
+    }
+
+    /// Makes new operations draw onto a paint layer. Group layers can't be drawn onto.
+    pub fn select_layer(&mut self, layer_id: LayerId) -> Result<(), EditError> {
+        let layer = self.layer(&layer_id)?;
+        if layer.kind == LayerKind::Group {
+            return Err(EditError::IsAGroup(layer_id));
+        }
+        let layer_blend_op_id = layer.blend_operation_id;
         let layer_existing_tips = self
             .image
             .depgraph
             .depends_on(&layer_blend_op_id)
             .expect("Unable to find blend op in depsgraph");
-        if layer_existing_tips.len() != 2 {
+        if layer_existing_tips.len() < 2 {
             warn!("Malformed layer blend operation: incorrect number of dependencies");
             self.insert_operation_onto = None;
         } else {
             self.insert_operation_onto = Some(layer_existing_tips[0]);
         }
+        Ok(())
+    }
+
+    /// The layers directly inside the group `parent`, or the top level layers if
+    /// `parent` is None, in the order they are drawn, topmost first.
+    #[args(parent = "None")]
+    pub fn list_layers(&self, parent: Option<LayerId>) -> Vec<LayerId> {
+        self.image.child_layers(parent)
+    }
+
+    /// The group a layer is in, or None if it is a top level layer
+    pub fn layer_parent(&self, layer_id: LayerId) -> Option<LayerId> {
+        self.image.parent_layer(&layer_id)
+    }
+
+    pub fn layer_name(&self, layer_id: LayerId) -> Option<String> {
+        Some(self.image.layers.get(&layer_id)?.name.clone())
+    }
+
+    /// Creates an empty layer directly above `above` (in the same group), or at the
+    /// top if `above` is None, and selects it.
+    pub fn create_layer(
+        &mut self,
+        name: String,
+        above: Option<LayerId>,
+    ) -> Result<LayerId, EditError> {
+        self.edit_in_group("Create Layer", |context| {
+            let layer_id = context.insert_layer(name, above, LayerKind::Paint)?;
+            context.select_layer(layer_id)?;
+            Ok(layer_id)
+        })
+    }
+
+    /// Creates an empty group directly above `above` (in the same group), or at the
+    /// top if `above` is None. The layers inside a group are composited together, and
+    /// the result is blended onto the layers below the group.
+    pub fn create_group(
+        &mut self,
+        name: String,
+        above: Option<LayerId>,
+    ) -> Result<LayerId, EditError> {
+        self.edit_in_group("Create Group", |context| {
+            context.insert_layer(name, above, LayerKind::Group)
+        })
+    }
+
+    /// Removes a layer and everything drawn on it, including all the layers inside it
+    /// if it is a group. If the layer being drawn on is deleted, the nearest remaining
+    /// paint layer is selected.
+    pub fn delete_layer(&mut self, layer_id: LayerId) -> Result<(), EditError> {
+        let blend_operation_id = self.layer(&layer_id)?.blend_operation_id;
+        let parent = self.image.parent_layer(&layer_id);
+        let stack = self.image.child_layers(parent);
+        let index = stack.iter().position(|id| *id == layer_id);
+        if index.is_some() && parent.is_none() && stack.len() == 1 {
+            return Err(EditError::LastLayer);
+        }
+
+        self.edit_in_group("Delete Layer", |context| {
+            if index.is_some() {
+                context.unlink_layer(blend_operation_id)?;
+                // Layers clipped to this one must let go of it's contents
+                context.update_clipping()?;
+            }
+            let mut touched = context
+                .image
+                .depgraph
+                .subgraph_to_remove(&blend_operation_id)?;
+            touched.extend(
+                context
+                    .image
+                    .depgraph
+                    .edges_to(&blend_operation_id)
+                    .into_iter()
+                    .map(|(node, _)| node),
+            );
+            context.touch_dependencies(&touched);
+            let removed = context
+                .image
+                .depgraph
+                .remove_subgraph(&blend_operation_id)?;
+            for operation_id in removed.iter() {
+                context
+                    .history
+                    .touch_operation(*operation_id, context.image.operations.get(operation_id));
+                context.image.operations.remove(operation_id);
+            }
+            let removed_layers: Vec<LayerId> = context
+                .image
+                .layers
+                .iter()
+                .filter(|(_, layer)| removed.contains(&layer.blend_operation_id))
+                .map(|(id, _)| *id)
+                .collect();
+            for removed_layer_id in removed_layers.iter().chain(std::iter::once(&layer_id)) {
+                context.layer_mut(removed_layer_id);
+                context.image.layers.remove(removed_layer_id);
+            }
+
+            let insert_target_removed = context
+                .insert_operation_onto
+                .is_some_and(|target| removed.contains(&target));
+            if insert_target_removed {
+                // Prefer the layer that took it's place, then anything else in the
+                // same group, then anywhere in the image
+                let stack = context.image.child_layers(parent);
+                let index = index.unwrap_or(0).min(stack.len());
+                let replacement = stack[index..]
+                    .iter()
+                    .chain(stack[..index].iter().rev())
+                    .cloned()
+                    .chain(context.image.all_layers())
+                    .find(|id| {
+                        context.image.layers.get(id).map(|layer| layer.kind)
+                            == Some(LayerKind::Paint)
+                    });
+                match replacement {
+                    Some(replacement) => context.select_layer(replacement)?,
+                    None => context.insert_operation_onto = None,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn rename_layer(&mut self, layer_id: LayerId, name: String) -> Result<(), EditError> {
+        self.layer(&layer_id)?;
+        self.edit_in_group("Rename Layer", |context| {
+            context.layer_mut(&layer_id).expect("Checked above").name = name;
+            Ok(())
+        })
+    }
+
+    /// Copies a layer and everything drawn on it, including all the layers inside it if
+    /// it is a group. The copy is placed directly above the original.
+    pub fn duplicate_layer(&mut self, layer_id: LayerId) -> Result<LayerId, EditError> {
+        let layer = self.layer(&layer_id)?.clone();
+        let edge = self.layer_stack_edge(&layer.blend_operation_id)?;
+        let contents = *self
+            .image
+            .depgraph
+            .depends_on(&layer.blend_operation_id)
+            .and_then(|deps| deps.first())
+            .ok_or(EditError::MalformedLayerStack)?;
+        let blend_operation = self
+            .image
+            .operations
+            .get(&layer.blend_operation_id)
+            .ok_or(EditError::MalformedLayerStack)?
+            .clone();
+
+        self.edit_in_group("Duplicate Layer", |context| {
+            let copies = context.copy_operations(contents)?;
+            let blend_operation_id = context.image.operations.insert(blend_operation);
+            context.history.touch_operation(blend_operation_id, None);
+            context.link_layer(blend_operation_id, copies[&contents], edge)?;
+
+            // The layers inside a group are copied along with it's contents
+            let child_layers: Vec<Layer> = context
+                .image
+                .layers
+                .iter()
+                .filter_map(|(_, child)| {
+                    let blend_operation_id = *copies.get(&child.blend_operation_id)?;
+                    Some(Layer {
+                        blend_operation_id,
+                        ..child.clone()
+                    })
+                })
+                .collect();
+            for child in child_layers {
+                context.add_layer(child);
+            }
+
+            let layer_id = context.add_layer(Layer {
+                name: format!("{} Copy", layer.name),
+                blend_operation_id,
+                ..layer
+            });
+            context.update_clipping()?;
+            Ok(layer_id)
+        })
+    }
+
+    /// Moves a layer to `index` in the stack of layers it is in, where 0 is the top
+    /// (see list_layers). Indices past the bottom place the layer at the bottom.
+    pub fn move_layer(&mut self, layer_id: LayerId, index: usize) -> Result<(), EditError> {
+        let parent = self.image.parent_layer(&layer_id);
+        self.move_layer_into(layer_id, parent, index)
+    }
+
+    /// Moves a layer to `index` in the stack of layers inside the group `parent`, or
+    /// the top level stack if `parent` is None.
+    pub fn move_layer_into(
+        &mut self,
+        layer_id: LayerId,
+        parent: Option<LayerId>,
+        index: usize,
+    ) -> Result<(), EditError> {
+        let blend_operation_id = self.layer(&layer_id)?.blend_operation_id;
+        self.layer_stack_edge(&blend_operation_id)?;
+        let mut ancestor = parent;
+        while let Some(group_id) = ancestor {
+            if self.layer(&group_id)?.kind != LayerKind::Group {
+                return Err(EditError::NotAGroup(group_id));
+            }
+            if group_id == layer_id {
+                return Err(EditError::GroupIntoItself(layer_id));
+            }
+            ancestor = self.image.parent_layer(&group_id);
+        }
+        let contents = *self
+            .image
+            .depgraph
+            .depends_on(&blend_operation_id)
+            .and_then(|deps| deps.first())
+            .ok_or(EditError::MalformedLayerStack)?;
+
+        self.edit_in_group("Move Layer", |context| {
+            context.unlink_layer(blend_operation_id)?;
+            let edge = context.layer_stack_edge_at(parent, index)?;
+            context.link_layer(blend_operation_id, contents, edge)?;
+            context.update_clipping()
+        })
+    }
+
+    /// A copy of a layer's properties. Use the set_layer_ functions to change them.
+    pub fn get_layer(&self, layer_id: LayerId) -> Option<Layer> {
+        self.image.layers.get(&layer_id).cloned()
+    }
+
+    /// The layer that new operations are drawn onto
+    pub fn current_layer(&self) -> Option<LayerId> {
+        self.image.layer_containing(&self.insert_operation_onto?)
+    }
+
+    pub fn set_layer_visible(&mut self, layer_id: LayerId, visible: bool) -> Result<(), EditError> {
+        let name = if visible { "Show Layer" } else { "Hide Layer" };
+        self.edit_layer(layer_id, name, |layer| layer.visible = visible)
+    }
+
+    pub fn set_layer_locked(&mut self, layer_id: LayerId, locked: bool) -> Result<(), EditError> {
+        let name = if locked { "Lock Layer" } else { "Unlock Layer" };
+        self.edit_layer(layer_id, name, |layer| layer.locked = locked)
+    }
+
+    /// Alpha lock only affects strokes drawn while it is set
+    pub fn set_layer_alpha_locked(
+        &mut self,
+        layer_id: LayerId,
+        alpha_locked: bool,
+    ) -> Result<(), EditError> {
+        let name = if alpha_locked {
+            "Lock Layer Alpha"
+        } else {
+            "Unlock Layer Alpha"
+        };
+        self.edit_layer(layer_id, name, |layer| layer.alpha_locked = alpha_locked)
+    }
+
+    pub fn set_layer_clip_to_below(
+        &mut self,
+        layer_id: LayerId,
+        clip_to_below: bool,
+    ) -> Result<(), EditError> {
+        let name = if clip_to_below {
+            "Clip Layer"
+        } else {
+            "Unclip Layer"
+        };
+        self.layer(&layer_id)?;
+        self.edit_in_group(name, |context| {
+            context
+                .layer_mut(&layer_id)
+                .expect("Checked above")
+                .clip_to_below = clip_to_below;
+            context.update_clipping()
+        })
+    }
+
+    pub fn layer_opacity(&self, layer_id: LayerId) -> Option<f32> {
+        let layer = self.image.layers.get(&layer_id)?;
+        match self.image.operations.get(&layer.blend_operation_id)? {
+            Operation::Composite(blend_mode) => Some(blend_mode.opacity()),
+            _ => None,
+        }
+    }
+
+    /// Sets the opacity of a layer, from 0.0 (invisible) to 1.0
+    pub fn set_layer_opacity(&mut self, layer_id: LayerId, opacity: f32) -> Result<(), EditError> {
+        let opacity = opacity.clamp(0.0, 1.0);
+        self.edit_layer_blend_mode(layer_id, "Change Layer Opacity", |blend_mode| {
+            Ok(blend_mode.with_opacity(opacity))
+        })
+    }
+
+    /// The name of the layer's blend mode. See list_blend_modes.
+    pub fn layer_blend_mode(&self, layer_id: LayerId) -> Option<String> {
+        let layer = self.image.layers.get(&layer_id)?;
+        match self.image.operations.get(&layer.blend_operation_id)? {
+            Operation::Composite(blend_mode) => Some(blend_mode.name().to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn set_layer_blend_mode(&mut self, layer_id: LayerId, name: &str) -> Result<(), EditError> {
+        self.edit_layer_blend_mode(layer_id, "Change Layer Blend Mode", |blend_mode| {
+            BlendMode::from_name(name, blend_mode.opacity())
+                .ok_or_else(|| EditError::UnknownBlendMode(name.to_string()))
+        })
+    }
+
+    /// Splits the contents of a paint layer into `count` branches that operations can
+    /// be inserted onto independently (see insert_operation_on), returning the tip of
+    /// each branch. Branches are not drawn until they are merged back into the layer
+    /// with merge_branches, and are removed by collect_garbage until then. Fails if
+    /// the layer, or a group it is in, is locked.
+    pub fn fork_layer(
+        &mut self,
+        layer_id: LayerId,
+        count: usize,
+    ) -> Result<Vec<OperationId>, EditError> {
+        let contents = self.layer_contents(&layer_id)?;
+        self.check_layer_unlocked(&layer_id)?;
+        self.edit_in_group("Fork Layer", |context| {
+            let mut branches = Vec::with_capacity(count);
+            for _ in 0..count {
+                let branch = context
+                    .image
+                    .operations
+                    .insert(Operation::Tag("Branch".to_string()));
+                context.history.touch_operation(branch, None);
+                context.touch_dependencies(&[branch]);
+                context.image.depgraph.try_insert(branch, vec![contents])?;
+                branches.push(branch);
+            }
+            Ok(branches)
+        })
+    }
+
+    /// Inserts an operation on top of `base`, returning the id of the new operation.
+    /// Anything that used `base` uses the new operation instead. Unlike
+    /// insert_operation this does not change where new operations are drawn. Fails if
+    /// `base` is in a locked layer or on a branch forked from one.
+    pub fn insert_operation_on(
+        &mut self,
+        base: OperationId,
+        operation: Operation,
+    ) -> Result<OperationId, EditError> {
+        if !self.image.depgraph.contains(&base) {
+            return Err(DepGraphError::UnknownNode(base).into());
+        }
+        if let Some(layer_id) = self.layer_owning(&base) {
+            self.check_layer_unlocked(&layer_id)?;
+        }
+        self.edit_in_group("Insert Operation", |context| {
+            let new_op_id = context.image.operations.insert(operation);
+            context.history.touch_operation(new_op_id, None);
+            context.touch_for_operate_on(new_op_id, &base);
+            context.image.depgraph.try_operate_on(new_op_id, base)?;
+            if context.insert_operation_onto == Some(base) {
+                context.insert_operation_onto = Some(new_op_id);
+            }
+            Ok(new_op_id)
+        })
+    }
+
+    /// Combines branches created by fork_layer back into the layer they came from,
+    /// compositing each branch over the ones after it with the blend mode `name` at
+    /// `opacity`. The result becomes the layer's contents, so drawing on the layer
+    /// continues on top of the merged result. Returns the topmost composite.
+    pub fn merge_branches(
+        &mut self,
+        layer_id: LayerId,
+        branches: Vec<OperationId>,
+        name: &str,
+        opacity: f32,
+    ) -> Result<OperationId, EditError> {
+        let blend_mode = BlendMode::from_name(name, opacity.clamp(0.0, 1.0))
+            .ok_or_else(|| EditError::UnknownBlendMode(name.to_string()))?;
+        let contents = self.layer_contents(&layer_id)?;
+        self.check_layer_unlocked(&layer_id)?;
+        let blend_operation_id = self.layer(&layer_id)?.blend_operation_id;
+        for (index, branch) in branches.iter().enumerate() {
+            let is_tip = self.image.depgraph.contains(branch)
+                && self.image.depgraph.dependees(branch).is_empty();
+            if !is_tip
+                || branches[..index].contains(branch)
+                || !self.image.depgraph.depends_on_indirectly(branch, &contents)
+            {
+                return Err(EditError::InvalidBranch(*branch));
+            }
+        }
+        let (bottom, rest) = match branches.split_last() {
+            Some((bottom, rest)) if !rest.is_empty() => (*bottom, rest),
+            _ => return Err(EditError::NotEnoughBranches),
+        };
+
+        self.edit_in_group("Merge Branches", |context| {
+            let mut merged = bottom;
+            for branch in rest.iter().rev() {
+                let composite = context
+                    .image
+                    .operations
+                    .insert(Operation::Composite(blend_mode.clone()));
+                context.history.touch_operation(composite, None);
+                context.touch_dependencies(&[composite]);
+                context
+                    .image
+                    .depgraph
+                    .try_insert(composite, vec![*branch, merged])?;
+                merged = composite;
+            }
+            context.touch_dependencies(&[blend_operation_id]);
+            context
+                .image
+                .depgraph
+                .set_dependency(&blend_operation_id, 0, merged)?;
+            if context.insert_operation_onto == Some(contents) {
+                context.insert_operation_onto = Some(merged);
+            }
+            // Layers clipped to this one use it's new contents
+            context.update_clipping()?;
+            Ok(merged)
+        })
+    }
+
+    /// The strokes that cover a point in canvas coordinates (see
+    /// screen_coords_to_canvas_coords), topmost first. `topmost_only` returns just the
+    /// stroke on top, and `current_layer_only` ignores strokes on other layers.
+    #[args(topmost_only = "false", current_layer_only = "false")]
+    pub fn strokes_at_point(
+        &self,
+        x: f32,
+        y: f32,
+        topmost_only: bool,
+        current_layer_only: bool,
+    ) -> Vec<OperationId> {
+        self.strokes_hit(HitShape::Point([x, y]), topmost_only, current_layer_only)
+    }
+
+    /// The strokes that cover any part of a rectangle in canvas coordinates, topmost
+    /// first. See strokes_at_point for the filters.
+    #[args(topmost_only = "false", current_layer_only = "false")]
+    pub fn strokes_in_rect(
+        &self,
+        corner_a: [f32; 2],
+        corner_b: [f32; 2],
+        topmost_only: bool,
+        current_layer_only: bool,
+    ) -> Vec<OperationId> {
+        let min = [corner_a[0].min(corner_b[0]), corner_a[1].min(corner_b[1])];
+        let max = [corner_a[0].max(corner_b[0]), corner_a[1].max(corner_b[1])];
+        self.strokes_hit(
+            HitShape::Rect { min, max },
+            topmost_only,
+            current_layer_only,
+        )
+    }
+
+    /// The strokes entirely inside a polygon in canvas coordinates, topmost first. See
+    /// strokes_at_point for the filters.
+    #[args(topmost_only = "false", current_layer_only = "false")]
+    pub fn strokes_in_lasso(
+        &self,
+        polygon: Vec<[f32; 2]>,
+        topmost_only: bool,
+        current_layer_only: bool,
+    ) -> Vec<OperationId> {
+        self.strokes_hit(HitShape::Lasso(polygon), topmost_only, current_layer_only)
+    }
+
+    /// The corners (min, max) of the box around a stroke in canvas coordinates, or
+    /// None if the operation is not a stroke or draws nothing.
+    pub fn stroke_bounds(&self, operation_id: OperationId) -> Option<([f32; 2], [f32; 2])> {
+        let bounds = match self.spatial_index.contains(&operation_id) {
+            true => self.spatial_index.bounds(&operation_id),
+            false => match self.image.operations.get(&operation_id) {
+                Some(Operation::Stroke(stroke)) => {
+                    stroke.bounds(self.image.metadata.aspect_ratio())
+                }
+                _ => None,
+            },
+        }?;
+        Some((bounds.min, bounds.max))
+    }
+
+    /// Applies an affine transform to strokes that have already been drawn (see
+    /// transform_operations). `matrix` is a 3x3 matrix given as a list of rows.
+    pub fn transform_strokes(
+        &mut self,
+        ids: Vec<OperationId>,
+        matrix: [[f32; 3]; 3],
+    ) -> Result<(), EditError> {
+        let transform = Mat3::from_cols_array_2d(&matrix).transpose();
+        self.transform_operations(&ids, transform)
+    }
+
+    /// Changes the color of strokes that have already been drawn. Per-point colors
+    /// still multiply the new color.
+    pub fn recolor_strokes(
+        &mut self,
+        ids: Vec<OperationId>,
+        r: f32,
+        g: f32,
+        b: f32,
+        a: f32,
+    ) -> Result<(), EditError> {
+        let color = Color { r, g, b, a };
+        self.edit_strokes("Recolor Strokes", &ids, |stroke| {
+            stroke.color = color.clone();
+        })
+    }
+
+    /// Moves the color of every stroke in scope that is within `tolerance` (see
+    /// Color::rgb_distance) of `from` by the difference between `from` and `to`, so
+    /// slightly different shades stay slightly different. Alpha is left alone.
+    /// The scope is the given strokes, otherwise the given layer, otherwise the whole
+    /// image. Returns the strokes that changed.
+    #[args(layer = "None", strokes = "None")]
+    pub fn replace_color(
+        &mut self,
+        from: [f32; 3],
+        to: [f32; 3],
+        tolerance: f32,
+        layer: Option<LayerId>,
+        strokes: Option<Vec<OperationId>>,
+    ) -> Result<Vec<OperationId>, EditError> {
+        let from_color = Color {
+            r: from[0],
+            g: from[1],
+            b: from[2],
+            a: 1.0,
+        };
+        let scope = StrokeScope::from_args(layer, strokes);
+        self.recolor_in_scope("Replace Color", &scope, |color| {
+            if color.rgb_distance(&from_color) > tolerance {
+                return None;
+            }
+            let shift =
+                |value: f32, index: usize| (value + to[index] - from[index]).clamp(0.0, 1.0);
+            Some(Color {
+                r: shift(color.r, 0),
+                g: shift(color.g, 1),
+                b: shift(color.b, 2),
+                a: color.a,
+            })
+        })
+    }
+
+    /// Rotates the hue of every stroke in scope (see replace_color) by `degrees`
+    #[args(layer = "None", strokes = "None")]
+    pub fn shift_hue(
+        &mut self,
+        degrees: f32,
+        layer: Option<LayerId>,
+        strokes: Option<Vec<OperationId>>,
+    ) -> Result<Vec<OperationId>, EditError> {
+        let scope = StrokeScope::from_args(layer, strokes);
+        self.recolor_in_scope("Shift Hue", &scope, |color| {
+            let [hue, saturation, value] = color.to_hsv();
+            Some(Color::from_hsv([hue + degrees, saturation, value], color.a))
+        })
+    }
+
+    /// Multiplies the saturation of every stroke in scope (see replace_color) by
+    /// `factor`. 0 makes them grey.
+    #[args(layer = "None", strokes = "None")]
+    pub fn saturate(
+        &mut self,
+        factor: f32,
+        layer: Option<LayerId>,
+        strokes: Option<Vec<OperationId>>,
+    ) -> Result<Vec<OperationId>, EditError> {
+        let scope = StrokeScope::from_args(layer, strokes);
+        self.recolor_in_scope("Saturate", &scope, |color| {
+            let [hue, saturation, value] = color.to_hsv();
+            let saturation = (saturation * factor).clamp(0.0, 1.0);
+            Some(Color::from_hsv([hue, saturation, value], color.a))
+        })
+    }
+
+    /// Changes the size of strokes that have already been drawn. Per-point sizes still
+    /// scale the new size.
+    pub fn resize_strokes(&mut self, ids: Vec<OperationId>, size: f32) -> Result<(), EditError> {
+        self.edit_strokes("Resize Strokes", &ids, |stroke| {
+            stroke.size = size;
+        })
+    }
+
+    /// Changes the glyph that strokes that have already been drawn are stamped with
+    pub fn rebrush_strokes(
+        &mut self,
+        ids: Vec<OperationId>,
+        glyph: GlyphId,
+    ) -> Result<(), EditError> {
+        if self.image.glyphs.get(&glyph).is_none() {
+            return Err(EditError::UnknownGlyph(glyph));
+        }
+        self.edit_strokes("Rebrush Strokes", &ids, |stroke| {
+            stroke.glyph = glyph;
+        })
+    }
+
+    /// Draws strokes again with a different brush, replacing their glyph and stamps.
+    /// Strokes are redrawn from the input they were drawn from if it was kept, and
+    /// otherwise from their existing stamps (see InputSample::from_stamps).
+    pub fn redraw_strokes_with_brush(
+        &mut self,
+        ids: Vec<OperationId>,
+        brush_id: BrushId,
+    ) -> Result<(), EditError> {
+        let brush = self
+            .image
+            .brushes
+            .get(&brush_id)
+            .cloned()
+            .ok_or(EditError::UnknownBrush(brush_id))?;
+        let samples: HashMap<OperationId, Vec<InputSample>> = ids
+            .iter()
+            .filter_map(|operation_id| {
+                let samples = match (
+                    self.input_samples.get(operation_id),
+                    self.image.operations.get(operation_id),
+                ) {
+                    (Some(samples), _) => samples.to_vec(),
+                    (None, Some(Operation::Stroke(stroke))) => InputSample::from_stamps(stroke),
+                    (None, _) => return None,
+                };
+                Some((*operation_id, samples))
+            })
+            .collect();
+        self.check_strokes_editable(&ids)?;
+        let aspect_ratio = self.image.metadata.aspect_ratio();
+        self.edit_in_group("Redraw Strokes", |context| {
+            let glyph = context.find_or_insert_glyph(&brush.glyph);
+            for (operation_id, samples) in samples.iter() {
+                // Strokes drawn before seeds were kept are varied differently
+                let seed = context.input_samples.seed(operation_id).unwrap_or(0);
+                if let Some(Operation::Stroke(stroke)) = context.operation_mut(operation_id) {
+                    stroke.glyph = glyph;
+                    stroke.position_array.clear();
+                    stroke.angle_array.clear();
+                    stroke.size_array.clear();
+                    stroke.color_array.clear();
+                    let mut placer = StampPlacer::new(seed);
+                    for sample in samples.iter() {
+                        for stamp in placer.place(&brush, stroke.size, aspect_ratio, *sample) {
+                            stamp.add_to(stroke);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Sets how one of a brush's settings (see Brush::SETTING_NAMES) responds to
+    /// pressure.
+    pub fn set_brush_curve(
+        &mut self,
+        brush_id: BrushId,
+        setting: &str,
+        curve: PressureCurve,
+    ) -> Result<(), EditError> {
+        self.edit_in_group("Change Brush Curve", |context| {
+            let brush = context
+                .brush_mut(&brush_id)
+                .ok_or(EditError::UnknownBrush(brush_id))?;
+            let setting = brush
+                .setting_mut(setting)
+                .ok_or_else(|| EditError::UnknownBrushSetting(setting.to_string()))?;
+            setting.curve = curve;
+            Ok(())
+        })
+    }
+
+    /// Sets the calibration curve of a device, which maps the pressure it reports to
+    /// the pressure used by brushes. It is saved with the image.
+    pub fn set_tablet_curve(&mut self, device: String, curve: PressureCurve) {
+        self.tablet_curves.set(device, curve);
+    }
+
+    pub fn get_tablet_curve(&self, device: &str) -> Option<PressureCurve> {
+        self.tablet_curves.get(device).cloned()
+    }
+
+    /// Stops correcting the pressure a device reports
+    pub fn remove_tablet_curve(&mut self, device: &str) {
+        self.tablet_curves.remove(device);
+    }
+
+    #[staticmethod]
+    pub fn list_blend_modes() -> Vec<String> {
+        BlendMode::NAMES
+            .iter()
+            .map(|name| name.to_string())
+            .collect()
     }
 
     pub fn manipulate_canvas(&mut self, zoom: f32, angle: f32, translation: [f32; 2]) {
+        self.record_event(RecordedEvent::ManipulateCanvas {
+            zoom,
+            angle,
+            translation,
+        });
         self.canvas_transform.zoom = zoom;
         self.canvas_transform.angle = angle;
         self.canvas_transform.translation = translation;
@@ -104,6 +1841,58 @@ impl EditContext {
         self.color.g = g;
         self.color.b = b;
         self.color.a = a;
+        self.record_event(RecordedEvent::SetPrimaryColor(self.color.clone()));
+    }
+
+    /// Starts recording the input given to the tools, replacing any recording already
+    /// being made. See the recording module for what is recorded.
+    pub fn start_recording(&mut self) -> Result<(), RecordingError> {
+        self.recording = Some(Recording::start(self)?);
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Writes what has been recorded so far to a file. Recording continues.
+    pub fn save_recording(&self, path: &str) -> Result<(), RecordingError> {
+        self.recording
+            .as_ref()
+            .ok_or(RecordingError::NotRecording)?
+            .save(Path::new(path))
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recording = None;
+    }
+
+    /// Creates a context by replaying a recording saved with save_recording
+    #[staticmethod]
+    pub fn replay_recording(path: &str) -> Result<EditContext, RecordingError> {
+        Recording::load(Path::new(path))?.replay()
+    }
+
+    /// Removes operations, glyphs and layers that no longer affect the image.
+    /// See Image::collect_garbage
+    /// This clears the undo history, as undoing could otherwise bring back references
+    /// to the removed data.
+    pub fn collect_garbage(&mut self) -> GarbageReport {
+        self.history
+            .end_all_groups(&self.image, self.insert_operation_onto);
+        self.sync_applied_changes();
+        let report = self.image.collect_garbage();
+        if let Some(insert_onto) = self.insert_operation_onto {
+            if report.operations.contains(&insert_onto) {
+                self.insert_operation_onto = None;
+            }
+        }
+        if !report.is_empty() {
+            self.history.clear();
+            self.spatial_index = SpatialIndex::new(&self.image);
+            self.input_samples.retain_in(&self.image);
+        }
+        report
     }
 
     /// Generates a string representation of the depgraph that can be visualized using
@@ -115,16 +1904,16 @@ impl EditContext {
             let operation = self.image.operations.get_unchecked(operation_id);
             match operation {
                 Operation::Composite(_dat) => {
-                    return format!("Operation {} {:?}", operation_id.val(), operation);
+                    format!("Operation {} {:?}", operation_id.val(), operation)
                 }
                 Operation::Tag(str) => {
-                    return format!("Operation {} Tag({})", operation_id.val(), str);
+                    format!("Operation {} Tag({})", operation_id.val(), str)
                 }
                 Operation::Output(_id) => {
-                    return format!("Operation {} {:?}", operation_id.val(), operation);
+                    format!("Operation {} {:?}", operation_id.val(), operation)
                 }
                 Operation::Stroke(_dat) => {
-                    return format!("Operation {} Stroke", operation_id.val());
+                    format!("Operation {} Stroke", operation_id.val())
                 }
             }
         })
@@ -135,11 +1924,11 @@ impl EditContext {
 #[derive(Clone)]
 pub struct CanvasTransform {
     #[pyo3(get)]
-    zoom: f32,
+    pub zoom: f32,
     #[pyo3(get)]
-    angle: f32,
+    pub angle: f32,
     #[pyo3(get)]
-    translation: [f32; 2],
+    pub translation: [f32; 2],
 }
 
 #[pymethods]
@@ -173,3 +1962,797 @@ impl Default for CanvasTransform {
         }
     }
 }
+
+/// Applies an affine transform to a stroke. The rotation of the transform is added to
+/// each point's angle and the stroke size is scaled by the average scaling of the
+/// transform.
+pub fn transform_stroke(stroke: &mut StrokeData, transform: &Mat3) {
+    let x_axis = transform.x_axis.truncate();
+    let rotation = x_axis.y.atan2(x_axis.x);
+    let determinant = transform.determinant();
+    for position in stroke.position_array.iter_mut() {
+        let transformed = transform.transform_point2(glam::Vec2::from(*position));
+        *position = transformed.into();
+    }
+    for angle in stroke.angle_array.iter_mut() {
+        // Mirroring reverses the direction angles are measured in
+        *angle = if determinant < 0.0 {
+            rotation - *angle
+        } else {
+            rotation + *angle
+        };
+    }
+    stroke.size *= determinant.abs().sqrt();
+}
+
+/// A stroke with an opaque black, unrotated, full size stamp at each position
+#[cfg(test)]
+pub(crate) fn test_stroke(positions: Vec<[f32; 2]>, size: f32) -> StrokeData {
+    let black = Color {
+        r: 0.0,
+        g: 0.0,
+        b: 0.0,
+        a: 1.0,
+    };
+    StrokeData {
+        angle_array: positions.iter().map(|_| 0.0).collect(),
+        size_array: positions.iter().map(|_| 1.0).collect(),
+        color_array: positions.iter().map(|_| black.clone()).collect(),
+        position_array: positions,
+        size,
+        color: black,
+        glyph: GlyphId::default(),
+        blend_mode: BlendMode::Mix(1.0),
+        alpha_locked: false,
+    }
+}
+
+/// The current data of a stroke in the image
+#[cfg(test)]
+fn stroke_data(context: &EditContext, operation_id: OperationId) -> StrokeData {
+    match context.image.operations.get(&operation_id) {
+        Some(Operation::Stroke(stroke)) => stroke.clone(),
+        _ => panic!("Stroke missing"),
+    }
+}
+
+#[test]
+fn test_layer_management() {
+    let mut context = EditContext::default();
+    let background = context.list_layers(None)[0];
+    context.select_layer(background).unwrap();
+    context
+        .insert_operation(Operation::Tag("Background Stroke".to_string()))
+        .unwrap();
+
+    let top = context.create_layer("Top".to_string(), None).unwrap();
+    let middle = context
+        .create_layer("Middle".to_string(), Some(background))
+        .unwrap();
+    assert_eq!(context.list_layers(None), vec![top, middle, background]);
+
+    // New layers are selected so drawing goes onto them
+    let middle_stroke = context
+        .insert_operation(Operation::Tag("Middle Stroke".to_string()))
+        .unwrap();
+    let middle_blend = context
+        .image
+        .layers
+        .get(&middle)
+        .unwrap()
+        .blend_operation_id;
+    assert_eq!(
+        context.image.depgraph.depends_on(&middle_blend).unwrap()[0],
+        middle_stroke
+    );
+
+    context.rename_layer(top, "Renamed".to_string()).unwrap();
+    assert_eq!(context.layer_name(top), Some("Renamed".to_string()));
+
+    // The copy has it's own contents so drawing on it does not change the original
+    let copy = context.duplicate_layer(middle).unwrap();
+    assert_eq!(
+        context.list_layers(None),
+        vec![top, copy, middle, background]
+    );
+    assert_eq!(context.layer_name(copy), Some("Middle Copy".to_string()));
+    let copy_blend = context.image.layers.get(&copy).unwrap().blend_operation_id;
+    let copy_stroke = context.image.depgraph.depends_on(&copy_blend).unwrap()[0];
+    assert_ne!(copy_stroke, middle_stroke);
+    assert_eq!(
+        context.image.operations.get(&copy_stroke),
+        context.image.operations.get(&middle_stroke)
+    );
+
+    context.move_layer(background, 0).unwrap();
+    assert_eq!(
+        context.list_layers(None),
+        vec![background, top, copy, middle]
+    );
+    context.move_layer(background, 10).unwrap();
+    assert_eq!(
+        context.list_layers(None),
+        vec![top, copy, middle, background]
+    );
+
+    // Deleting the selected layer selects the one that replaces it
+    context.delete_layer(middle).unwrap();
+    assert_eq!(context.list_layers(None), vec![top, copy, background]);
+    assert!(context.image.operations.get(&middle_stroke).is_none());
+    assert!(!context.image.depgraph.contains(&middle_blend));
+    context.select_layer(background).unwrap();
+    assert!(context.image.depgraph.dangling_dependencies().is_empty());
+
+    context.delete_layer(top).unwrap();
+    context.delete_layer(copy).unwrap();
+    assert!(matches!(
+        context.delete_layer(background),
+        Err(EditError::LastLayer)
+    ));
+    assert!(matches!(
+        context.delete_layer(top),
+        Err(EditError::UnknownLayer(_))
+    ));
+
+    // Layer changes can be undone
+    context.undo();
+    context.undo();
+    context.undo();
+    assert_eq!(
+        context.list_layers(None),
+        vec![top, copy, middle, background]
+    );
+    assert_eq!(
+        context.image.depgraph.depends_on(&middle_blend).unwrap()[0],
+        middle_stroke
+    );
+}
+
+#[test]
+fn test_layer_properties() {
+    let mut context = EditContext::default();
+    let background = context.list_layers(None)[0];
+    let top = context.create_layer("Top".to_string(), None).unwrap();
+    assert_eq!(context.current_layer(), Some(top));
+
+    // Locked layers refuse edits
+    context.set_layer_locked(top, true).unwrap();
+    assert!(matches!(
+        context.insert_operation(Operation::Tag("Refused".to_string())),
+        Err(EditError::LayerLocked(_))
+    ));
+    context.set_layer_locked(top, false).unwrap();
+    context
+        .insert_operation(Operation::Tag("Allowed".to_string()))
+        .unwrap();
+
+    context.set_layer_opacity(top, 0.25).unwrap();
+    context.set_layer_blend_mode(top, "multiply").unwrap();
+    assert_eq!(context.layer_opacity(top), Some(0.25));
+    assert_eq!(context.layer_blend_mode(top), Some("multiply".to_string()));
+    assert!(matches!(
+        context.set_layer_blend_mode(top, "unknown"),
+        Err(EditError::UnknownBlendMode(_))
+    ));
+
+    // Clipped layers depend on the contents of the layer they are clipped to, and
+    // follow it as it is drawn on
+    let top_blend = context.get_layer(top).unwrap().blend_operation_id;
+    context.set_layer_clip_to_below(top, true).unwrap();
+    context.select_layer(background).unwrap();
+    let background_stroke = context
+        .insert_operation(Operation::Tag("Background".to_string()))
+        .unwrap();
+    assert_eq!(
+        context.image.depgraph.depends_on(&top_blend).unwrap()[2],
+        background_stroke
+    );
+
+    // Moving the clipped layer to the bottom leaves nothing to clip to
+    context.move_layer(top, 1).unwrap();
+    assert_eq!(
+        context.image.depgraph.depends_on(&top_blend).unwrap().len(),
+        2
+    );
+    context.move_layer(top, 0).unwrap();
+    assert_eq!(
+        context.image.depgraph.depends_on(&top_blend).unwrap().len(),
+        3
+    );
+
+    // Deleting the layer that is clipped to releases it's contents
+    context.delete_layer(background).unwrap();
+    assert!(context.image.operations.get(&background_stroke).is_none());
+    assert!(context.image.depgraph.dangling_dependencies().is_empty());
+
+    context.undo();
+    context.set_layer_visible(top, false).unwrap();
+    assert!(!context.get_layer(top).unwrap().visible);
+    context.undo();
+    assert!(context.get_layer(top).unwrap().visible);
+}
+
+#[test]
+fn test_layer_groups() {
+    let mut context = EditContext::default();
+    let background = context.list_layers(None)[0];
+    let group = context.create_group("Group".to_string(), None).unwrap();
+    let inner = context.create_group("Inner".to_string(), None).unwrap();
+    assert_eq!(context.list_layers(None), vec![inner, group, background]);
+    assert!(context.get_layer(group).unwrap().is_group());
+    assert!(context.list_layers(Some(group)).is_empty());
+
+    let paint = context.create_layer("Paint".to_string(), None).unwrap();
+    context.move_layer_into(inner, Some(group), 0).unwrap();
+    context.move_layer_into(paint, Some(inner), 0).unwrap();
+    assert_eq!(context.list_layers(None), vec![group, background]);
+    assert_eq!(context.list_layers(Some(group)), vec![inner]);
+    assert_eq!(context.list_layers(Some(inner)), vec![paint]);
+    assert_eq!(context.layer_parent(paint), Some(inner));
+    assert_eq!(context.layer_parent(inner), Some(group));
+    assert_eq!(context.layer_parent(group), None);
+    assert_eq!(
+        context.image.all_layers(),
+        vec![group, inner, paint, background]
+    );
+
+    // Drawing goes into the selected layer inside the group
+    context.select_layer(paint).unwrap();
+    let stroke = context
+        .insert_operation(Operation::Tag("Stroke".to_string()))
+        .unwrap();
+    assert_eq!(context.current_layer(), Some(paint));
+    assert_eq!(context.image.layer_containing(&stroke), Some(paint));
+
+    // Locking a group locks everything inside it
+    context.set_layer_locked(group, true).unwrap();
+    assert!(matches!(
+        context.insert_operation(Operation::Tag("Refused".to_string())),
+        Err(EditError::LayerLocked(locked)) if locked == group
+    ));
+    assert_eq!(context.locked_operations().get(&stroke), Some(&group));
+    context.set_layer_locked(group, false).unwrap();
+    assert!(context.locked_operations().is_empty());
+
+    // Groups can't be drawn onto, so selecting one leaves the selection unchanged
+    assert!(matches!(
+        context.select_layer(group),
+        Err(EditError::IsAGroup(_))
+    ));
+    assert_eq!(context.current_layer(), Some(paint));
+
+    // Layers created above a layer in a group go into the same group
+    let sibling = context
+        .create_layer("Sibling".to_string(), Some(paint))
+        .unwrap();
+    assert_eq!(context.list_layers(Some(inner)), vec![sibling, paint]);
+    context.move_layer(sibling, 1).unwrap();
+    assert_eq!(context.list_layers(Some(inner)), vec![paint, sibling]);
+
+    assert!(matches!(
+        context.move_layer_into(group, Some(inner), 0),
+        Err(EditError::GroupIntoItself(_))
+    ));
+    assert!(matches!(
+        context.move_layer_into(background, Some(paint), 0),
+        Err(EditError::NotAGroup(_))
+    ));
+
+    // Duplicating a group copies the layers inside it
+    let copy = context.duplicate_layer(group).unwrap();
+    assert_eq!(context.list_layers(None), vec![copy, group, background]);
+    let inner_copy = context.list_layers(Some(copy))[0];
+    assert_ne!(inner_copy, inner);
+    assert_eq!(context.list_layers(Some(inner_copy)).len(), 2);
+
+    // Deleting a group deletes everything inside it, and the selection moves to a
+    // remaining paint layer
+    context.select_layer(paint).unwrap();
+    context.delete_layer(group).unwrap();
+    assert_eq!(context.list_layers(None), vec![copy, background]);
+    assert!(context.get_layer(paint).is_none());
+    assert!(context.image.operations.get(&stroke).is_none());
+    assert!(matches!(
+        context.select_layer(paint),
+        Err(EditError::UnknownLayer(_))
+    ));
+    assert!(context.current_layer().is_some());
+    assert!(context.image.depgraph.dangling_dependencies().is_empty());
+
+    context.undo();
+    assert_eq!(context.list_layers(None), vec![copy, group, background]);
+    assert_eq!(context.list_layers(Some(inner)), vec![paint, sibling]);
+    assert_eq!(context.image.layer_containing(&stroke), Some(paint));
+}
+
+#[test]
+fn test_fork_and_merge_branches() {
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let base = context
+        .insert_operation(Operation::Tag("Base".to_string()))
+        .unwrap();
+
+    let branches = context.fork_layer(layer, 2).unwrap();
+    assert_eq!(branches.len(), 2);
+    let left = context
+        .insert_operation_on(branches[0], Operation::Tag("Left".to_string()))
+        .unwrap();
+    let right = context
+        .insert_operation_on(branches[1], Operation::Tag("Right".to_string()))
+        .unwrap();
+    assert!(context.image.depgraph.depends_on_indirectly(&left, &base));
+    assert!(!context.image.depgraph.depends_on_indirectly(&left, &right));
+
+    // Only the tips of branches from the layer's contents can be merged
+    assert!(matches!(
+        context.merge_branches(layer, vec![left, branches[1]], "mix", 1.0),
+        Err(EditError::InvalidBranch(_))
+    ));
+    assert!(matches!(
+        context.merge_branches(layer, vec![left, left], "mix", 1.0),
+        Err(EditError::InvalidBranch(_))
+    ));
+    assert!(matches!(
+        context.merge_branches(layer, vec![left], "mix", 1.0),
+        Err(EditError::NotEnoughBranches)
+    ));
+    assert!(matches!(
+        context.merge_branches(layer, vec![left, right], "smudge", 1.0),
+        Err(EditError::UnknownBlendMode(_))
+    ));
+
+    let merged = context
+        .merge_branches(layer, vec![left, right], "multiply", 1.0)
+        .unwrap();
+    assert_eq!(
+        context.image.operations.get(&merged),
+        Some(&Operation::Composite(BlendMode::Multiply(1.0)))
+    );
+    assert_eq!(
+        context.image.depgraph.depends_on(&merged),
+        Some(&vec![left, right])
+    );
+    let blend = context.get_layer(layer).unwrap().blend_operation_id;
+    assert_eq!(
+        context.image.depgraph.depends_on(&blend).unwrap()[0],
+        merged
+    );
+    assert_eq!(context.image.layer_containing(&left), Some(layer));
+
+    // Drawing continues on top of the merged result
+    let after = context
+        .insert_operation(Operation::Tag("After".to_string()))
+        .unwrap();
+    assert_eq!(
+        context.image.depgraph.depends_on(&after),
+        Some(&vec![merged])
+    );
+
+    // Operations can still be added to a branch once it is merged
+    let extra = context
+        .insert_operation_on(left, Operation::Tag("Extra".to_string()))
+        .unwrap();
+    assert_eq!(
+        context.image.depgraph.depends_on(&merged).unwrap()[0],
+        extra
+    );
+
+    context.undo();
+    context.undo();
+    context.undo();
+    assert_eq!(context.image.depgraph.depends_on(&blend).unwrap()[0], base);
+    assert!(context.image.depgraph.dependees(&left).is_empty());
+    assert!(context.image.operations.get(&merged).is_none());
+}
+
+#[test]
+fn test_edit_strokes() {
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let glyph = context.find_or_insert_glyph(&Glyph::Png(vec![1]));
+    let stroke = context
+        .insert_operation(Operation::Stroke(StrokeData {
+            angle_array: vec![0.0, 0.5],
+            glyph,
+            ..test_stroke(vec![[1.0, 0.0], [0.0, 1.0]], 1.0)
+        }))
+        .unwrap();
+    let tag = context
+        .insert_operation(Operation::Tag("Not a stroke".to_string()))
+        .unwrap();
+    let edges_before = context.image.depgraph.edges_to(&stroke);
+
+    // Rotate a quarter turn, double in size and move right
+    let transform = Mat3::from_scale_angle_translation(
+        glam::Vec2::new(2.0, 2.0),
+        std::f32::consts::FRAC_PI_2,
+        glam::Vec2::new(1.0, 0.0),
+    );
+    context.transform_operations(&[stroke], transform).unwrap();
+    let transformed = stroke_data(&context, stroke);
+    let expected = [[1.0, 2.0], [-1.0, 0.0]];
+    for (position, expected) in transformed.position_array.iter().zip(expected.iter()) {
+        assert!((position[0] - expected[0]).abs() < 1e-5);
+        assert!((position[1] - expected[1]).abs() < 1e-5);
+    }
+    assert!((transformed.angle_array[1] - (0.5 + std::f32::consts::FRAC_PI_2)).abs() < 1e-5);
+    assert!((transformed.size - 2.0).abs() < 1e-5);
+
+    context
+        .recolor_strokes(vec![stroke], 1.0, 0.0, 0.0, 1.0)
+        .unwrap();
+    context.resize_strokes(vec![stroke], 0.5).unwrap();
+    assert_eq!(stroke_data(&context, stroke).color.r, 1.0);
+    assert_eq!(stroke_data(&context, stroke).size, 0.5);
+
+    // Nothing changes if any of the operations can't be edited
+    assert!(matches!(
+        context.resize_strokes(vec![stroke, tag], 3.0),
+        Err(EditError::NotAStroke(_))
+    ));
+    assert_eq!(stroke_data(&context, stroke).size, 0.5);
+    let missing_glyph = context.image.glyphs.insert(Glyph::Png(vec![]));
+    context.image.glyphs.remove(&missing_glyph);
+    assert!(matches!(
+        context.rebrush_strokes(vec![stroke], missing_glyph),
+        Err(EditError::UnknownGlyph(_))
+    ));
+    context.set_layer_locked(layer, true).unwrap();
+    assert!(matches!(
+        context.resize_strokes(vec![stroke], 3.0),
+        Err(EditError::LayerLocked(_))
+    ));
+    context.undo();
+
+    // Edits are made in place so the depgraph is unchanged
+    assert_eq!(context.image.depgraph.edges_to(&stroke), edges_before);
+    assert_eq!(context.image.depgraph.depends_on(&tag), Some(&vec![stroke]));
+    context.undo();
+    context.undo();
+    context.undo();
+    assert_eq!(
+        stroke_data(&context, stroke).position_array,
+        vec![[1.0, 0.0], [0.0, 1.0]]
+    );
+    assert_eq!(stroke_data(&context, stroke).size, 1.0);
+}
+
+#[test]
+fn test_strokes_hit() {
+    let mut context = EditContext::default();
+    // A glyph that can't be decoded covers the whole of each stamp
+    let glyph = context.find_or_insert_glyph(&Glyph::Png(vec![]));
+    let stroke_at = |position: [f32; 2]| {
+        Operation::Stroke(StrokeData {
+            glyph,
+            ..test_stroke(vec![position], 0.1)
+        })
+    };
+    let background = context.list_layers(None)[0];
+    context.select_layer(background).unwrap();
+    let bottom = context.insert_operation(stroke_at([0.0, 0.0])).unwrap();
+    let far = context.insert_operation(stroke_at([0.8, 0.8])).unwrap();
+    let top_layer = context.create_layer("Top".to_string(), None).unwrap();
+    let top = context.insert_operation(stroke_at([0.05, 0.0])).unwrap();
+
+    assert_eq!(
+        context.strokes_at_point(0.0, 0.0, false, false),
+        vec![top, bottom]
+    );
+    assert_eq!(context.strokes_at_point(0.0, 0.0, true, false), vec![top]);
+    context.select_layer(background).unwrap();
+    assert_eq!(context.strokes_at_point(0.0, 0.0, true, true), vec![bottom]);
+    assert!(context.strokes_at_point(0.5, -0.5, false, false).is_empty());
+
+    assert_eq!(
+        context.strokes_in_rect([0.7, 0.7], [1.0, 1.0], false, false),
+        vec![far]
+    );
+    assert_eq!(
+        context.strokes_in_lasso(
+            vec![[-0.5, -0.5], [0.5, -0.5], [0.5, 0.5], [-0.5, 0.5]],
+            false,
+            false
+        ),
+        vec![top, bottom]
+    );
+
+    // Hidden strokes can't be selected
+    context.set_layer_visible(top_layer, false).unwrap();
+    assert_eq!(
+        context.strokes_at_point(0.0, 0.0, false, false),
+        vec![bottom]
+    );
+}
+
+#[test]
+fn test_spatial_index_follows_changes() {
+    use painter_data::bounds::Bounds;
+
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let glyph = context.find_or_insert_glyph(&Glyph::Png(vec![]));
+    let stroke = Operation::Stroke(StrokeData {
+        glyph,
+        ..test_stroke(vec![[0.0, 0.0]], 0.01)
+    });
+    let stroke = context.insert_operation(stroke).unwrap();
+    let origin = Bounds::point([0.0, 0.0]);
+    let elsewhere = Bounds::point([0.5, 0.5]);
+    assert!(context.spatial_index.query(&origin).contains(&stroke));
+    assert!(context.stroke_bounds(stroke).is_some());
+
+    // While a stroke is being changed it could be anywhere
+    context.begin_group("Move");
+    if let Some(Operation::Stroke(data)) = context.operation_mut(&stroke) {
+        data.position_array[0] = [0.5, 0.5];
+    }
+    assert!(context.spatial_index.may_intersect(&stroke, &origin));
+    context.end_group();
+    assert!(!context.spatial_index.may_intersect(&stroke, &origin));
+    assert!(context.spatial_index.query(&elsewhere).contains(&stroke));
+
+    context.undo();
+    assert!(context.spatial_index.query(&origin).contains(&stroke));
+    context.undo();
+    assert!(!context.spatial_index.contains(&stroke));
+    assert!(context.stroke_bounds(stroke).is_none());
+}
+
+#[test]
+fn test_recolor_in_scope() {
+    let mut context = EditContext::default();
+    let bottom = context.list_layers(None)[0];
+    context.select_layer(bottom).unwrap();
+    let stroke = |r: f32, g: f32, b: f32| {
+        Operation::Stroke(StrokeData {
+            color: Color { r, g, b, a: 0.5 },
+            ..test_stroke(vec![[0.0, 0.0]], 0.1)
+        })
+    };
+    let blue = context.insert_operation(stroke(0.0, 0.0, 1.0)).unwrap();
+    let light_blue = context.insert_operation(stroke(0.1, 0.1, 1.0)).unwrap();
+    let red = context.insert_operation(stroke(1.0, 0.0, 0.0)).unwrap();
+    let top = context
+        .create_layer("Top".to_string(), Some(bottom))
+        .unwrap();
+    let top_blue = context.insert_operation(stroke(0.0, 0.0, 1.0)).unwrap();
+    let rgb = |r: f32, g: f32, b: f32| Color { r, g, b, a: 0.5 };
+    let color = |context: &EditContext, id: OperationId| stroke_data(context, id).color;
+
+    // Similar shades move together and keep their differences
+    let changed = context
+        .replace_color([0.0, 0.0, 1.0], [0.0, 0.5, 0.5], 0.2, Some(bottom), None)
+        .unwrap();
+    assert_eq!(changed.len(), 2);
+    assert!(color(&context, blue).rgb_distance(&rgb(0.0, 0.5, 0.5)) < 1e-5);
+    assert!(color(&context, light_blue).rgb_distance(&rgb(0.1, 0.6, 0.5)) < 1e-5);
+    assert_eq!(color(&context, light_blue).a, 0.5);
+    assert_eq!(color(&context, red), rgb(1.0, 0.0, 0.0));
+    assert_eq!(color(&context, top_blue).b, 1.0);
+
+    // The whole recolor is a single undo step
+    assert!(context.undo());
+    assert_eq!(color(&context, blue).b, 1.0);
+    assert_eq!(color(&context, light_blue).g, 0.1);
+
+    // Locked layers are skipped when recoloring the whole image...
+    context.set_layer_locked(top, true).unwrap();
+    let changed = context.shift_hue(120.0, None, None).unwrap();
+    assert!(!changed.contains(&top_blue));
+    assert!(color(&context, red).rgb_distance(&rgb(0.0, 1.0, 0.0)) < 1e-5);
+    // ...but can't be recolored directly
+    assert!(matches!(
+        context.saturate(0.0, None, Some(vec![top_blue])),
+        Err(EditError::LayerLocked(_))
+    ));
+
+    context.saturate(0.0, None, Some(vec![red])).unwrap();
+    let grey = color(&context, red);
+    assert!(grey.r == grey.g && grey.g == grey.b);
+    context.set_layer_locked(top, false).unwrap();
+    context.delete_layer(top).unwrap();
+    assert!(matches!(
+        context.saturate(0.0, Some(top), None),
+        Err(EditError::UnknownLayer(_))
+    ));
+}
+
+#[test]
+fn test_redraw_strokes_with_brush() {
+    use painter_data::brush::{Brush, PressureSettings};
+
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let setting = |min_value: f32, max_value: f32| PressureSettings {
+        min_value,
+        max_value,
+        random: 0.0,
+        curve: PressureCurve::linear(),
+    };
+    let brush = context.image.brushes.insert(Brush {
+        name: "Test".to_string(),
+        glyph: Glyph::Png(vec![7]),
+        size: setting(0.0, 2.0),
+        flow: setting(0.25, 0.25),
+        scatter: setting(0.0, 0.0),
+        gap: setting(0.0, 0.0),
+        angle: setting(0.0, 0.0),
+    });
+    let stroke = |size_array: Vec<f32>| {
+        Operation::Stroke(StrokeData {
+            size_array: size_array.clone(),
+            ..test_stroke(size_array.iter().map(|_| [0.0, 0.0]).collect(), 0.1)
+        })
+    };
+    let recorded = context.insert_operation(stroke(vec![1.0, 1.0])).unwrap();
+    for (index, pressure) in [0.0, 0.5, 1.0].iter().enumerate() {
+        context.input_samples.record(
+            recorded,
+            InputSample {
+                position: [index as f32, 0.0],
+                pressure: *pressure,
+                time: index as f32 * 0.25,
+                tilt: None,
+            },
+        );
+    }
+    let unrecorded = context.insert_operation(stroke(vec![0.5, 1.0])).unwrap();
+
+    context
+        .redraw_strokes_with_brush(vec![recorded, unrecorded], brush)
+        .unwrap();
+    // Recorded strokes are redrawn from their input...
+    let redrawn = stroke_data(&context, recorded);
+    assert_eq!(redrawn.size_array, vec![0.0, 1.0, 2.0]);
+    assert_eq!(redrawn.position_array[2], [2.0, 0.0]);
+    assert_eq!(redrawn.color_array[0].a, 0.25);
+    assert_eq!(redrawn.size, 0.1);
+    assert_eq!(
+        context.image.glyphs.get(&redrawn.glyph),
+        Some(&Glyph::Png(vec![7]))
+    );
+    // ...others from their stamps
+    assert_eq!(stroke_data(&context, unrecorded).size_array, vec![1.0, 2.0]);
+
+    assert!(context.undo());
+    assert_eq!(stroke_data(&context, recorded).size_array, vec![1.0, 1.0]);
+
+    // The samples are saved with the image
+    let loaded = EditContext::new_with_saved_image(context.image_with_history());
+    assert_eq!(loaded.input_samples, context.input_samples);
+    assert!(loaded.image.extension(INPUT_SAMPLES_EXTENSION).is_none());
+
+    context.image.brushes.remove(&brush);
+    assert!(matches!(
+        context.redraw_strokes_with_brush(vec![recorded], brush),
+        Err(EditError::UnknownBrush(_))
+    ));
+}
+
+#[test]
+fn test_failed_edit_rolled_back() {
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let operations_before = context.image.operations.iter().count();
+
+    let result: Result<(), EditError> = context.edit_in_group("Fails", |context| {
+        context.insert_operation(Operation::Tag("Half done".to_string()))?;
+        context.rename_layer(layer, "Renamed".to_string())?;
+        context.create_layer("New".to_string(), None)?;
+        Err(EditError::MalformedLayerStack)
+    });
+    assert!(result.is_err());
+    assert_eq!(context.image.operations.iter().count(), operations_before);
+    assert_eq!(context.layer_name(layer), Some("Background".to_string()));
+    assert_eq!(context.list_layers(None), vec![layer]);
+    assert_eq!(context.current_layer(), Some(layer));
+    assert!(context.image.depgraph.dangling_dependencies().is_empty());
+    assert!(!context.can_undo());
+
+    // Only what changed inside the failed edit is put back
+    context.begin_group("Outer");
+    let kept = context
+        .insert_operation(Operation::Tag("Kept".to_string()))
+        .unwrap();
+    let result: Result<(), EditError> = context.edit_in_group("Fails", |context| {
+        context.insert_operation(Operation::Tag("Removed".to_string()))?;
+        Err(EditError::MalformedLayerStack)
+    });
+    assert!(result.is_err());
+    context.end_group();
+    assert!(context.image.operations.get(&kept).is_some());
+    assert_eq!(
+        context.image.operations.iter().count(),
+        operations_before + 1
+    );
+    assert!(context.undo());
+    assert!(context.image.operations.get(&kept).is_none());
+}
+
+#[test]
+fn test_branches_of_locked_layers() {
+    let mut context = EditContext::default();
+    let group = context.create_group("Group".to_string(), None).unwrap();
+    let layer = context.create_layer("Paint".to_string(), None).unwrap();
+    context.move_layer_into(layer, Some(group), 0).unwrap();
+    context.select_layer(layer).unwrap();
+    let base = context
+        .insert_operation(Operation::Tag("Base".to_string()))
+        .unwrap();
+
+    context.set_layer_locked(group, true).unwrap();
+    assert!(matches!(
+        context.fork_layer(layer, 2),
+        Err(EditError::LayerLocked(locked)) if locked == group
+    ));
+    assert!(matches!(
+        context.insert_operation_on(base, Operation::Tag("Refused".to_string())),
+        Err(EditError::LayerLocked(_))
+    ));
+
+    context.set_layer_locked(group, false).unwrap();
+    let branches = context.fork_layer(layer, 2).unwrap();
+
+    // Unmerged branches belong to the layer they were forked from
+    context.set_layer_locked(layer, true).unwrap();
+    assert!(matches!(
+        context.insert_operation_on(branches[0], Operation::Tag("Refused".to_string())),
+        Err(EditError::LayerLocked(locked)) if locked == layer
+    ));
+    assert!(matches!(
+        context.merge_branches(layer, branches.clone(), "mix", 1.0),
+        Err(EditError::LayerLocked(_))
+    ));
+    assert!(context.image.depgraph.dependees(&branches[0]).is_empty());
+
+    context.set_layer_locked(layer, false).unwrap();
+    let allowed = context
+        .insert_operation_on(branches[0], Operation::Tag("Allowed".to_string()))
+        .unwrap();
+    context
+        .merge_branches(layer, vec![allowed, branches[1]], "mix", 1.0)
+        .unwrap();
+}
+
+#[test]
+fn test_damage_from_edits() {
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let stroke = context
+        .insert_operation(Operation::Stroke(test_stroke(vec![[0.0, 0.0]], 0.1)))
+        .unwrap();
+    assert_eq!(context.take_damage(), Damage::Everything);
+    assert_eq!(context.take_damage(), Damage::Nothing);
+
+    // Moving a stroke damages where it was and where it is now
+    let before = context.spatial_index.bounds(&stroke).unwrap();
+    let transform = Mat3::from_translation(glam::Vec2::new(0.5, 0.0));
+    context.transform_operations(&[stroke], transform).unwrap();
+    let after = context.spatial_index.bounds(&stroke).unwrap();
+    assert_ne!(before, after);
+    assert_eq!(context.take_damage(), Damage::Area(before.union(&after)));
+
+    // As does undoing the move
+    context.undo();
+    assert_eq!(context.take_damage(), Damage::Area(after.union(&before)));
+
+    // Anything other than editing strokes may change the whole canvas
+    context.set_layer_visible(layer, false).unwrap();
+    assert_eq!(context.take_damage(), Damage::Everything);
+
+    // Changes in a group that is still open could be anywhere
+    context.begin_group("Open");
+    context.resize_strokes(vec![stroke], 0.2).unwrap();
+    assert_eq!(context.take_damage(), Damage::Everything);
+    context.end_group();
+    assert_eq!(context.take_damage(), Damage::Everything);
+    assert_eq!(context.take_damage(), Damage::Nothing);
+}
@@ -0,0 +1,1305 @@
+//! Undo/Redo support.
+//!
+//! Every change to the image is recorded as a command made up of a list of `Change`s,
+//! each of which stores the state of one part of the image before and after the command.
+//! Undoing a command puts back all the "before" states, redoing puts back the "after".
+//!
+//! Operations, glyphs, brushes, layers and the dependencies of each node in the depgraph
+//! must be explicitly "touched" before they are modified, so that only the ones that
+//! change are copied and compared. The metadata is small, so a copy is taken when the command begins
+//! and compared with the image when it ends.
+//!
+//! The history can be saved inside the image file as the `_sp_undo_history` extension.
+use log::warn;
+use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use painter_data::brush::{Brush, Glyph};
+use painter_data::id_map::{BrushId, GlyphId, IdMapBase, LayerId, OperationId};
+use painter_data::image::{Image, MetaData};
+use painter_data::layer::Layer;
+use painter_data::operation::Operation;
+
+/// How many commands are kept if not otherwise specified.
+pub const DEFAULT_HISTORY_DEPTH: usize = 256;
+
+/// The name of the image extension the history is saved in
+pub const UNDO_HISTORY_EXTENSION: &str = "_sp_undo_history";
+
+/// Increment if the way the history is stored changes. Histories stored in another
+/// version are discarded rather than failing to load the image.
+const HISTORY_FORMAT_VERSION: u32 = 1;
+
+/// A single reversible change to one part of the image. `None` means that the item
+/// did not exist.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Change {
+    Operation {
+        id: OperationId,
+        before: Option<Operation>,
+        after: Option<Operation>,
+    },
+    Glyph {
+        id: GlyphId,
+        before: Option<Glyph>,
+        after: Option<Glyph>,
+    },
+    Layer {
+        id: LayerId,
+        before: Option<Layer>,
+        after: Option<Layer>,
+    },
+    Dependencies {
+        id: OperationId,
+        before: Option<Vec<OperationId>>,
+        after: Option<Vec<OperationId>>,
+    },
+    MetaData {
+        before: MetaData,
+        after: MetaData,
+    },
+    /// Where in the depgraph new operations are placed. This is not part of the image,
+    /// but has to follow along so that (for example) after undoing a stroke the next
+    /// stroke does not try to operate on the one that was removed.
+    InsertTarget {
+        before: Option<OperationId>,
+        after: Option<OperationId>,
+    },
+    /// Brushes are much larger than anything else, so are boxed to keep changes small
+    Brush {
+        id: BrushId,
+        before: Option<Box<Brush>>,
+        after: Option<Box<Brush>>,
+    },
+}
+
+impl Change {
+    /// Puts the before (if `undo` is set) or after state back into the image
+    fn apply(&self, image: &mut Image, insert_target: &mut Option<OperationId>, undo: bool) {
+        match self {
+            Change::Operation { id, before, after } => match if undo { before } else { after } {
+                Some(operation) => image.operations.force(*id, operation.clone()),
+                None => {
+                    image.operations.remove(id);
+                }
+            },
+            Change::Glyph { id, before, after } => match if undo { before } else { after } {
+                Some(glyph) => image.glyphs.force(*id, glyph.clone()),
+                None => {
+                    image.glyphs.remove(id);
+                }
+            },
+            Change::Brush { id, before, after } => match if undo { before } else { after } {
+                Some(brush) => image.brushes.force(*id, brush.as_ref().clone()),
+                None => {
+                    image.brushes.remove(id);
+                }
+            },
+            Change::Layer { id, before, after } => match if undo { before } else { after } {
+                Some(layer) => image.layers.force(*id, layer.clone()),
+                None => {
+                    image.layers.remove(id);
+                }
+            },
+            Change::Dependencies { id, before, after } => match if undo { before } else { after } {
+                Some(depends_on) => image.depgraph.insert(*id, depends_on.clone()),
+                None => {
+                    image.depgraph.remove(id);
+                }
+            },
+            Change::MetaData { before, after } => {
+                image.metadata = if undo { before } else { after }.clone();
+            }
+            Change::InsertTarget { before, after } => {
+                *insert_target = if undo { *before } else { *after };
+            }
+        }
+    }
+}
+
+/// A group of changes that are undone and redone together
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Command {
+    pub name: String,
+    pub changes: Vec<Change>,
+}
+
+impl Change {
+    /// A change that does the opposite of this one
+    pub fn inverse(&self) -> Self {
+        match self.clone() {
+            Change::Operation { id, before, after } => Change::Operation {
+                id,
+                before: after,
+                after: before,
+            },
+            Change::Glyph { id, before, after } => Change::Glyph {
+                id,
+                before: after,
+                after: before,
+            },
+            Change::Brush { id, before, after } => Change::Brush {
+                id,
+                before: after,
+                after: before,
+            },
+            Change::Layer { id, before, after } => Change::Layer {
+                id,
+                before: after,
+                after: before,
+            },
+            Change::Dependencies { id, before, after } => Change::Dependencies {
+                id,
+                before: after,
+                after: before,
+            },
+            Change::MetaData { before, after } => Change::MetaData {
+                before: after,
+                after: before,
+            },
+            Change::InsertTarget { before, after } => Change::InsertTarget {
+                before: after,
+                after: before,
+            },
+        }
+    }
+}
+
+impl Command {
+    /// A command that, when redone, undoes this one
+    pub fn inverse(&self) -> Self {
+        Self {
+            name: format!("Undo {}", self.name),
+            changes: self.changes.iter().rev().map(Change::inverse).collect(),
+        }
+    }
+
+    pub fn undo(&self, image: &mut Image, insert_target: &mut Option<OperationId>) {
+        for change in self.changes.iter().rev() {
+            change.apply(image, insert_target, true);
+        }
+    }
+
+    pub fn redo(&self, image: &mut Image, insert_target: &mut Option<OperationId>) {
+        for change in self.changes.iter() {
+            change.apply(image, insert_target, false);
+        }
+    }
+}
+
+/// The state of the image when a command began
+#[derive(Debug, Clone)]
+struct PendingCommand {
+    name: String,
+    metadata: MetaData,
+    insert_target: Option<OperationId>,
+
+    /// The state of each touched item before it was first touched
+    operations: HashMap<OperationId, Option<Operation>>,
+    glyphs: HashMap<GlyphId, Option<Glyph>>,
+    brushes: HashMap<BrushId, Option<Brush>>,
+    layers: HashMap<LayerId, Option<Layer>>,
+    dependencies: HashMap<OperationId, Option<Vec<OperationId>>>,
+}
+
+/// The state of everything touched so far in an open group, so that the changes made
+/// after it can be rolled back. Created by History::savepoint.
+#[derive(Debug, Clone)]
+pub struct Savepoint(PendingCommand);
+
+/// Identifies a point in the history tree
+pub type HistoryNodeId = usize;
+
+/// A state the image has been in. The root is the oldest state that can be returned to,
+/// and every other node is reached by applying it's command to it's parent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HistoryNode {
+    parent: Option<HistoryNodeId>,
+
+    /// Takes the image from the parent's state to this one. None for the root.
+    command: Option<Command>,
+
+    /// Oldest first
+    children: Vec<HistoryNodeId>,
+
+    /// The child that redo moves to: the most recently created or visited one
+    active_child: Option<HistoryNodeId>,
+}
+
+/// The parts of the history that are saved. Open groups are not saved. The nodes are
+/// ordered so that saving the same history always gives the same bytes.
+#[derive(Serialize, Deserialize)]
+struct StoredHistory {
+    nodes: BTreeMap<HistoryNodeId, HistoryNode>,
+    root: HistoryNodeId,
+    current: HistoryNodeId,
+    next_node_id: HistoryNodeId,
+    snapshots: BTreeMap<String, HistoryNodeId>,
+    max_depth: usize,
+}
+
+#[derive(Debug)]
+pub enum HistoryLoadError {
+    /// Not even long enough to contain a version number
+    Truncated,
+    UnknownVersion(u32),
+    DeserializeError(std::boxed::Box<bincode::ErrorKind>),
+}
+
+/// How the operations differ between two points in the history
+#[pyclass]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SnapshotDiff {
+    /// Operations present in the second snapshot but not the first
+    #[pyo3(get)]
+    pub added: Vec<OperationId>,
+
+    /// Operations present in the first snapshot but not the second
+    #[pyo3(get)]
+    pub removed: Vec<OperationId>,
+
+    /// Operations present in both, but with different contents
+    #[pyo3(get)]
+    pub changed: Vec<OperationId>,
+}
+
+/// The undo history is a tree, so drawing something after undoing does not lose the
+/// changes that were undone - they are kept in another branch. Redo follows the most
+/// recently used branch, and any state can be returned to with `switch_to`.
+#[derive(Debug, Clone)]
+pub struct History {
+    nodes: HashMap<HistoryNodeId, HistoryNode>,
+    root: HistoryNodeId,
+    current: HistoryNodeId,
+    next_node_id: HistoryNodeId,
+
+    /// Named points in the history
+    snapshots: BTreeMap<String, HistoryNodeId>,
+
+    pending: Option<PendingCommand>,
+
+    /// How many begin_group calls have not yet had a matching end_group
+    group_depth: usize,
+
+    max_depth: usize,
+
+    /// If set, every command applied to the image (including undoing and redoing) is
+    /// stored in `applied` until collected with take_applied. This is used to keep
+    /// the journal and spatial index up to date.
+    record_applied: bool,
+    applied: Vec<Command>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_DEPTH)
+    }
+}
+
+impl History {
+    pub fn new(max_depth: usize) -> Self {
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            0,
+            HistoryNode {
+                parent: None,
+                command: None,
+                children: Vec::new(),
+                active_child: None,
+            },
+        );
+        Self {
+            nodes,
+            root: 0,
+            current: 0,
+            next_node_id: 1,
+            snapshots: BTreeMap::new(),
+            pending: None,
+            group_depth: 0,
+            max_depth,
+            record_applied: false,
+            applied: Vec::new(),
+        }
+    }
+
+    /// Starts recording a command. Groups can be nested, in which case everything up
+    /// until the outermost group ends becomes a single command named after the outermost
+    /// group.
+    pub fn begin_group(&mut self, name: &str, image: &Image, insert_target: Option<OperationId>) {
+        if self.group_depth == 0 {
+            self.pending = Some(PendingCommand {
+                name: name.to_string(),
+                metadata: image.metadata.clone(),
+                insert_target,
+                operations: HashMap::new(),
+                glyphs: HashMap::new(),
+                brushes: HashMap::new(),
+                layers: HashMap::new(),
+                dependencies: HashMap::new(),
+            });
+        }
+        self.group_depth += 1;
+    }
+
+    /// Finishes recording a command. Returns true if a command was added to the history,
+    /// which only happens when the outermost group ends and something actually changed.
+    pub fn end_group(&mut self, image: &Image, insert_target: Option<OperationId>) -> bool {
+        if self.group_depth == 0 {
+            warn!(target: "history", "Ending an undo group that was never started");
+            return false;
+        }
+        self.group_depth -= 1;
+        if self.group_depth > 0 {
+            return false;
+        }
+
+        let pending = self.pending.take().expect("Pending command for open group");
+        let name = pending.name.clone();
+        let changes = diff(pending, image, insert_target);
+        if changes
+            .iter()
+            .all(|change| matches!(change, Change::InsertTarget { .. }))
+        {
+            // Nothing in the image changed
+            return false;
+        }
+
+        self.push(Command { name, changes });
+        true
+    }
+
+    /// Records the current state of everything touched so far in the open group. None if
+    /// no group is open.
+    pub fn savepoint(
+        &self,
+        image: &Image,
+        insert_target: Option<OperationId>,
+    ) -> Option<Savepoint> {
+        let pending = self.pending.as_ref()?;
+        Some(Savepoint(PendingCommand {
+            name: pending.name.clone(),
+            metadata: image.metadata.clone(),
+            insert_target,
+            operations: pending
+                .operations
+                .keys()
+                .map(|id| (*id, image.operations.get(id).cloned()))
+                .collect(),
+            glyphs: pending
+                .glyphs
+                .keys()
+                .map(|id| (*id, image.glyphs.get(id).cloned()))
+                .collect(),
+            brushes: pending
+                .brushes
+                .keys()
+                .map(|id| (*id, image.brushes.get(id).cloned()))
+                .collect(),
+            layers: pending
+                .layers
+                .keys()
+                .map(|id| (*id, image.layers.get(id).cloned()))
+                .collect(),
+            dependencies: pending
+                .dependencies
+                .keys()
+                .map(|id| (*id, image.depgraph.depends_on(id).cloned()))
+                .collect(),
+        }))
+    }
+
+    /// Puts back everything changed since `savepoint` was taken. The group stays open.
+    /// Returns the changes that were reverted.
+    pub fn rollback(
+        &self,
+        savepoint: Savepoint,
+        image: &mut Image,
+        insert_target: &mut Option<OperationId>,
+    ) -> Vec<Change> {
+        let pending = match self.pending.as_ref() {
+            Some(pending) => pending,
+            None => return Vec::new(),
+        };
+        // Anything first touched after the savepoint goes back to how it was before the
+        // group started
+        let mut target = savepoint.0;
+        for (id, before) in pending.operations.iter() {
+            target
+                .operations
+                .entry(*id)
+                .or_insert_with(|| before.clone());
+        }
+        for (id, before) in pending.glyphs.iter() {
+            target.glyphs.entry(*id).or_insert_with(|| before.clone());
+        }
+        for (id, before) in pending.brushes.iter() {
+            target.brushes.entry(*id).or_insert_with(|| before.clone());
+        }
+        for (id, before) in pending.layers.iter() {
+            target.layers.entry(*id).or_insert_with(|| before.clone());
+        }
+        for (id, before) in pending.dependencies.iter() {
+            target
+                .dependencies
+                .entry(*id)
+                .or_insert_with(|| before.clone());
+        }
+        let command = Command {
+            name: target.name.clone(),
+            changes: diff(target, image, *insert_target),
+        };
+        command.undo(image, insert_target);
+        command.changes
+    }
+
+    /// Ends all open groups
+    pub fn end_all_groups(&mut self, image: &Image, insert_target: Option<OperationId>) {
+        while self.group_depth > 0 {
+            self.end_group(image, insert_target);
+        }
+    }
+
+    pub fn in_group(&self) -> bool {
+        self.group_depth > 0
+    }
+
+    /// Must be called before modifying or creating an operation so that it's previous
+    /// state is known.
+    pub fn touch_operation(&mut self, id: OperationId, current: Option<&Operation>) {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending
+                    .operations
+                    .entry(id)
+                    .or_insert_with(|| current.cloned());
+            }
+            None => warn!(target: "history", "Operation modified outside of an undo group"),
+        }
+    }
+
+    /// Must be called before modifying or creating a glyph so that it's previous state
+    /// is known.
+    pub fn touch_glyph(&mut self, id: GlyphId, current: Option<&Glyph>) {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending.glyphs.entry(id).or_insert_with(|| current.cloned());
+            }
+            None => warn!(target: "history", "Glyph modified outside of an undo group"),
+        }
+    }
+
+    /// Must be called before modifying or creating a brush so that it's previous state
+    /// is known.
+    pub fn touch_brush(&mut self, id: BrushId, current: Option<&Brush>) {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending
+                    .brushes
+                    .entry(id)
+                    .or_insert_with(|| current.cloned());
+            }
+            None => warn!(target: "history", "Brush modified outside of an undo group"),
+        }
+    }
+
+    /// Must be called before modifying or creating a layer so that it's previous state
+    /// is known.
+    pub fn touch_layer(&mut self, id: LayerId, current: Option<&Layer>) {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending.layers.entry(id).or_insert_with(|| current.cloned());
+            }
+            None => warn!(target: "history", "Layer modified outside of an undo group"),
+        }
+    }
+
+    /// Must be called before changing what an operation depends on (including adding
+    /// or removing it from the depgraph) so that it's previous dependencies are known.
+    pub fn touch_dependencies(&mut self, id: OperationId, current: Option<&Vec<OperationId>>) {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending
+                    .dependencies
+                    .entry(id)
+                    .or_insert_with(|| current.cloned());
+            }
+            None => warn!(target: "history", "Depgraph modified outside of an undo group"),
+        }
+    }
+
+    /// Adds a command that has already been applied to the image. If some commands
+    /// have been undone, this starts a new branch rather than discarding them.
+    pub fn push(&mut self, command: Command) {
+        let new_node = self.next_node_id;
+        self.next_node_id += 1;
+        self.nodes.insert(
+            new_node,
+            HistoryNode {
+                parent: Some(self.current),
+                command: Some(command),
+                children: Vec::new(),
+                active_child: None,
+            },
+        );
+        let current = self.node_mut(self.current);
+        current.children.push(new_node);
+        current.active_child = Some(new_node);
+        self.current = new_node;
+        self.record(new_node, false);
+        self.trim();
+    }
+
+    /// Starts or stops recording the commands applied to the image
+    pub fn set_record_applied(&mut self, record_applied: bool) {
+        self.record_applied = record_applied;
+        if !record_applied {
+            self.applied.clear();
+        }
+    }
+
+    /// The commands applied to the image since this was last called, in the order they
+    /// were applied. Only recorded if enabled with set_record_applied.
+    pub fn take_applied(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.applied)
+    }
+
+    /// Records that the command of `node` has been applied (or undone if `inverse`)
+    fn record(&mut self, node: HistoryNodeId, inverse: bool) {
+        if !self.record_applied {
+            return;
+        }
+        if let Some(command) = &self.nodes[&node].command {
+            let applied = if inverse {
+                command.inverse()
+            } else {
+                command.clone()
+            };
+            self.applied.push(applied);
+        }
+    }
+
+    /// Undoes the most recent command. Any open groups are ended first, so undoing
+    /// part-way through a stroke undoes the stroke so far.
+    /// Returns false if there was nothing to undo.
+    pub fn undo(&mut self, image: &mut Image, insert_target: &mut Option<OperationId>) -> bool {
+        self.end_all_groups(image, *insert_target);
+        let node = &self.nodes[&self.current];
+        match (node.parent, &node.command) {
+            (Some(parent), Some(command)) => {
+                command.undo(image, insert_target);
+                let undone = self.current;
+                self.record(undone, true);
+                self.node_mut(parent).active_child = Some(undone);
+                self.current = parent;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Redoes the most recently undone command on the active branch.
+    /// Returns false if there was nothing to redo.
+    pub fn redo(&mut self, image: &mut Image, insert_target: &mut Option<OperationId>) -> bool {
+        self.end_all_groups(image, *insert_target);
+        match self.nodes[&self.current].active_child {
+            Some(child) => {
+                self.redo_into(child, image, insert_target);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Redoes the command leading to the `branch`th (oldest first) child of the current
+    /// state. Returns false if there is no such branch.
+    pub fn redo_branch(
+        &mut self,
+        branch: usize,
+        image: &mut Image,
+        insert_target: &mut Option<OperationId>,
+    ) -> bool {
+        self.end_all_groups(image, *insert_target);
+        match self.nodes[&self.current].children.get(branch).cloned() {
+            Some(child) => {
+                self.redo_into(child, image, insert_target);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Names of the commands that redo_branch can redo, oldest branch first
+    pub fn redo_branch_names(&self) -> Vec<String> {
+        self.nodes[&self.current]
+            .children
+            .iter()
+            .map(|child| self.command_name(*child))
+            .collect()
+    }
+
+    /// Moves the image to the state it was in at some point in the history, undoing
+    /// back to where the branches split and then redoing along the other branch.
+    pub fn switch_to(
+        &mut self,
+        target: HistoryNodeId,
+        image: &mut Image,
+        insert_target: &mut Option<OperationId>,
+    ) -> bool {
+        if !self.nodes.contains_key(&target) {
+            return false;
+        }
+        self.end_all_groups(image, *insert_target);
+
+        let common = self.common_ancestor(self.current, target);
+        while self.current != common {
+            self.undo(image, insert_target);
+        }
+        let mut path = self.path_to_ancestor(target, common);
+        path.reverse();
+        for node in path {
+            self.redo_into(node, image, insert_target);
+        }
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.nodes[&self.current].parent.is_some() || self.pending_has_changes()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.nodes[&self.current].active_child.is_some()
+    }
+
+    /// Names of the commands that can be undone, most recent last
+    pub fn undo_names(&self) -> Vec<String> {
+        let mut path = self.path_to_ancestor(self.current, self.root);
+        path.reverse();
+        path.iter().map(|node| self.command_name(*node)).collect()
+    }
+
+    /// Names of the commands that redo will step through, the next to be redone last
+    pub fn redo_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut node = self.nodes[&self.current].active_child;
+        while let Some(next) = node {
+            names.push(self.command_name(next));
+            node = self.nodes[&next].active_child;
+        }
+        names.reverse();
+        names
+    }
+
+    /// The point in the history that the image is currently at
+    pub fn current(&self) -> HistoryNodeId {
+        self.current
+    }
+
+    /// Names the current point in the history so it can be returned to later. If a
+    /// snapshot with this name already exists it is moved. Open groups should be ended
+    /// first, or their changes will not be part of the snapshot.
+    pub fn create_snapshot(&mut self, name: &str) {
+        self.snapshots.insert(name.to_string(), self.current);
+    }
+
+    pub fn delete_snapshot(&mut self, name: &str) -> bool {
+        self.snapshots.remove(name).is_some()
+    }
+
+    pub fn snapshot(&self, name: &str) -> Option<HistoryNodeId> {
+        self.snapshots.get(name).cloned()
+    }
+
+    /// Names of all the snapshots in alphabetical order
+    pub fn snapshot_names(&self) -> Vec<String> {
+        self.snapshots.keys().cloned().collect()
+    }
+
+    /// Works out which operations differ between two points in the history
+    pub fn diff(&self, from: HistoryNodeId, to: HistoryNodeId) -> Option<SnapshotDiff> {
+        if !self.nodes.contains_key(&from) || !self.nodes.contains_key(&to) {
+            return None;
+        }
+        let common = self.common_ancestor(from, to);
+
+        // Going up from `from` the first change seen for an operation is nearest to
+        // `from`, and the last is nearest to the common ancestor.
+        let mut at_from: HashMap<OperationId, Option<&Operation>> = HashMap::new();
+        let mut at_common: HashMap<OperationId, Option<&Operation>> = HashMap::new();
+        for node in self.path_to_ancestor(from, common) {
+            for (id, before, after) in self.operation_changes(node) {
+                at_from.entry(id).or_insert(after);
+                at_common.insert(id, before);
+            }
+        }
+
+        let mut path = self.path_to_ancestor(to, common);
+        path.reverse();
+        let mut at_to: HashMap<OperationId, Option<&Operation>> = HashMap::new();
+        for node in path {
+            for (id, before, after) in self.operation_changes(node) {
+                at_common.entry(id).or_insert(before);
+                at_to.insert(id, after);
+            }
+        }
+
+        let mut result = SnapshotDiff::default();
+        for (id, common_state) in at_common.iter() {
+            let before = at_from.get(id).unwrap_or(common_state);
+            let after = at_to.get(id).unwrap_or(common_state);
+            match (before, after) {
+                (None, Some(_)) => result.added.push(*id),
+                (Some(_), None) => result.removed.push(*id),
+                (Some(before), Some(after)) if before != after => result.changed.push(*id),
+                _ => {}
+            }
+        }
+        Some(result)
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Sets how many commands are kept, discarding the oldest if there are more.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+        self.trim();
+    }
+
+    /// Encodes the history so it can be stored in an image extension
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let stored = StoredHistory {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|(node_id, node)| (*node_id, node.clone()))
+                .collect(),
+            root: self.root,
+            current: self.current,
+            next_node_id: self.next_node_id,
+            snapshots: self.snapshots.clone(),
+            max_depth: self.max_depth,
+        };
+        let mut data = HISTORY_FORMAT_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut data, &stored).expect("Serializing history failed");
+        data
+    }
+
+    /// Decodes a history created by to_bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self, HistoryLoadError> {
+        let mut version = [0u8; 4];
+        if data.len() < version.len() {
+            return Err(HistoryLoadError::Truncated);
+        }
+        version.copy_from_slice(&data[..4]);
+        match u32::from_le_bytes(version) {
+            HISTORY_FORMAT_VERSION => {
+                let stored: StoredHistory =
+                    bincode::deserialize(&data[4..]).map_err(HistoryLoadError::DeserializeError)?;
+                Ok(Self {
+                    nodes: stored.nodes.into_iter().collect(),
+                    root: stored.root,
+                    current: stored.current,
+                    next_node_id: stored.next_node_id,
+                    snapshots: stored.snapshots,
+                    max_depth: stored.max_depth,
+                    ..Self::default()
+                })
+            }
+            number => Err(HistoryLoadError::UnknownVersion(number)),
+        }
+    }
+
+    /// Forgets all commands and snapshots. Open groups are left open.
+    pub fn clear(&mut self) {
+        let pending = self.pending.take();
+        let applied = std::mem::take(&mut self.applied);
+        *self = Self {
+            pending,
+            group_depth: self.group_depth,
+            record_applied: self.record_applied,
+            applied,
+            ..Self::new(self.max_depth)
+        };
+    }
+
+    fn node_mut(&mut self, node: HistoryNodeId) -> &mut HistoryNode {
+        self.nodes.get_mut(&node).expect("History node exists")
+    }
+
+    fn command_name(&self, node: HistoryNodeId) -> String {
+        self.nodes[&node]
+            .command
+            .as_ref()
+            .map(|command| command.name.clone())
+            .unwrap_or_default()
+    }
+
+    /// Applies the command of a child of the current node
+    fn redo_into(
+        &mut self,
+        child: HistoryNodeId,
+        image: &mut Image,
+        insert_target: &mut Option<OperationId>,
+    ) {
+        let node = &self.nodes[&child];
+        debug_assert_eq!(node.parent, Some(self.current));
+        if let Some(command) = &node.command {
+            command.redo(image, insert_target);
+        }
+        self.record(child, false);
+        self.node_mut(self.current).active_child = Some(child);
+        self.current = child;
+    }
+
+    /// The nodes from `node` (inclusive) up to `ancestor` (exclusive)
+    fn path_to_ancestor(&self, node: HistoryNodeId, ancestor: HistoryNodeId) -> Vec<HistoryNodeId> {
+        let mut path = Vec::new();
+        let mut node = node;
+        while node != ancestor {
+            path.push(node);
+            match self.nodes[&node].parent {
+                Some(parent) => node = parent,
+                None => break,
+            }
+        }
+        path
+    }
+
+    fn common_ancestor(&self, a: HistoryNodeId, b: HistoryNodeId) -> HistoryNodeId {
+        let mut ancestors_of_a: HashSet<HistoryNodeId> =
+            self.path_to_ancestor(a, self.root).into_iter().collect();
+        ancestors_of_a.insert(self.root);
+        let mut node = b;
+        while !ancestors_of_a.contains(&node) {
+            node = self.nodes[&node]
+                .parent
+                .expect("Root is an ancestor of everything");
+        }
+        node
+    }
+
+    /// (operation, before, after) for each operation changed by the node's command
+    fn operation_changes(
+        &self,
+        node: HistoryNodeId,
+    ) -> Vec<(OperationId, Option<&Operation>, Option<&Operation>)> {
+        let command = match &self.nodes[&node].command {
+            Some(command) => command,
+            None => return Vec::new(),
+        };
+        command
+            .changes
+            .iter()
+            .filter_map(|change| match change {
+                Change::Operation { id, before, after } => {
+                    Some((*id, before.as_ref(), after.as_ref()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Discards the oldest states until there are at most max_depth commands. The
+    /// current state and named snapshots (and the states leading to them) are kept, so
+    /// there may be more commands than max_depth if there are a lot of snapshots.
+    fn trim(&mut self) {
+        while self.nodes.len() > self.max_depth + 1 {
+            let mut protected: HashSet<HistoryNodeId> = self
+                .path_to_ancestor(self.current, self.root)
+                .into_iter()
+                .collect();
+            for snapshot in self.snapshots.values() {
+                protected.extend(self.path_to_ancestor(*snapshot, self.root));
+            }
+            let root_is_snapshot = self.snapshots.values().any(|node| *node == self.root);
+
+            // Prefer dropping the oldest state. This is only possible when there is a
+            // single branch leading from it.
+            let root_children = self.nodes[&self.root].children.clone();
+            if root_children.len() == 1 && self.current != self.root && !root_is_snapshot {
+                let old_root = self.root;
+                self.root = root_children[0];
+                self.nodes.remove(&old_root);
+                let new_root = self.node_mut(self.root);
+                new_root.parent = None;
+                new_root.command = None;
+                continue;
+            }
+
+            // Otherwise drop the oldest branch that isn't being used
+            let oldest_unused_leaf = self
+                .nodes
+                .iter()
+                .filter(|(id, node)| node.children.is_empty() && !protected.contains(id))
+                .map(|(id, _)| *id)
+                .min();
+            match oldest_unused_leaf {
+                Some(leaf) => {
+                    let parent = self.nodes.remove(&leaf).and_then(|node| node.parent);
+                    if let Some(parent) = parent {
+                        let parent = self.node_mut(parent);
+                        parent.children.retain(|child| *child != leaf);
+                        if parent.active_child == Some(leaf) {
+                            parent.active_child = parent.children.last().cloned();
+                        }
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Whether anything has been changed in a group that has not ended yet
+    pub fn pending_has_changes(&self) -> bool {
+        self.pending
+            .as_ref()
+            .map(|pending| {
+                !pending.operations.is_empty()
+                    || !pending.glyphs.is_empty()
+                    || !pending.brushes.is_empty()
+                    || !pending.layers.is_empty()
+                    || !pending.dependencies.is_empty()
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Compares the state at the start of a command with the current state of the image
+fn diff(pending: PendingCommand, image: &Image, insert_target: Option<OperationId>) -> Vec<Change> {
+    let PendingCommand {
+        metadata,
+        insert_target: previous_insert_target,
+        operations,
+        glyphs,
+        brushes,
+        layers,
+        dependencies,
+        ..
+    } = pending;
+    let mut changes = Vec::new();
+
+    for (id, before) in glyphs {
+        let after = image.glyphs.get(&id).cloned();
+        if before != after {
+            changes.push(Change::Glyph { id, before, after });
+        }
+    }
+    for (id, before) in brushes {
+        let after = image.brushes.get(&id).cloned();
+        if before != after {
+            changes.push(Change::Brush {
+                id,
+                before: before.map(Box::new),
+                after: after.map(Box::new),
+            });
+        }
+    }
+    for (id, before) in operations {
+        let after = image.operations.get(&id).cloned();
+        if before != after {
+            changes.push(Change::Operation { id, before, after });
+        }
+    }
+
+    for (id, before) in dependencies {
+        let after = image.depgraph.depends_on(&id).cloned();
+        if before != after {
+            changes.push(Change::Dependencies { id, before, after });
+        }
+    }
+    for (id, before) in layers {
+        let after = image.layers.get(&id).cloned();
+        if before != after {
+            changes.push(Change::Layer { id, before, after });
+        }
+    }
+
+    if metadata != image.metadata {
+        changes.push(Change::MetaData {
+            before: metadata,
+            after: image.metadata.clone(),
+        });
+    }
+
+    if previous_insert_target != insert_target {
+        changes.push(Change::InsertTarget {
+            before: previous_insert_target,
+            after: insert_target,
+        });
+    }
+
+    changes
+}
+
+#[cfg(test)]
+fn test_context() -> super::context::EditContext {
+    let mut context = super::context::EditContext::default();
+    let layer_id = *context.image.layers.iter().next().unwrap().0;
+    context.select_layer(layer_id).unwrap();
+    context
+}
+
+/// The operations in the image. IDs are never reused, so the map itself will differ
+#[cfg(test)]
+fn operations_of(context: &super::context::EditContext) -> HashMap<OperationId, Operation> {
+    context
+        .image
+        .operations
+        .iter()
+        .map(|(id, operation)| (*id, operation.clone()))
+        .collect()
+}
+
+#[test]
+fn test_undo_redo_insert() {
+    let mut context = test_context();
+    let original_operations = operations_of(&context);
+    let original_target = context.insert_operation_onto.unwrap();
+    let blend_op = *context.image.depgraph.dependees(&original_target)[0];
+
+    assert!(!context.can_undo());
+    let new_op = context
+        .insert_operation(Operation::Tag("New".to_string()))
+        .unwrap();
+    assert!(context.can_undo());
+    assert_eq!(
+        context.image.depgraph.depends_on(&blend_op).unwrap()[0],
+        new_op
+    );
+
+    assert!(context.undo());
+    assert_eq!(operations_of(&context), original_operations);
+    assert!(!context.image.depgraph.contains(&new_op));
+    assert_eq!(
+        context.image.depgraph.depends_on(&blend_op).unwrap()[0],
+        original_target
+    );
+    assert_eq!(context.insert_operation_onto, Some(original_target));
+    assert!(!context.undo());
+
+    assert!(context.redo());
+    assert!(context.image.operations.get(&new_op).is_some());
+    assert_eq!(
+        context.image.depgraph.depends_on(&blend_op).unwrap()[0],
+        new_op
+    );
+    assert_eq!(context.insert_operation_onto, Some(new_op));
+    assert!(!context.can_redo());
+}
+
+#[test]
+fn test_group_undone_together() {
+    let mut context = test_context();
+    let original_operations = operations_of(&context);
+
+    context.begin_group("Two Tags");
+    let first = context
+        .insert_operation(Operation::Tag("First".to_string()))
+        .unwrap();
+    context
+        .insert_operation(Operation::Tag("Second".to_string()))
+        .unwrap();
+    if let Some(Operation::Tag(name)) = context.operation_mut(&first) {
+        *name = "Renamed".to_string();
+    }
+    assert!(context.end_group());
+
+    assert_eq!(context.history.undo_names(), vec!["Two Tags".to_string()]);
+    assert!(context.undo());
+    assert_eq!(operations_of(&context), original_operations);
+
+    assert!(context.redo());
+    assert_eq!(
+        context.image.operations.get(&first),
+        Some(&Operation::Tag("Renamed".to_string()))
+    );
+}
+
+#[test]
+fn test_undo_while_grouping() {
+    let mut context = test_context();
+    context.begin_group("Stroke");
+    let op = context
+        .insert_operation(Operation::Tag("Partial".to_string()))
+        .unwrap();
+
+    // Undoing part way through ends the group and undoes what was done so far
+    assert!(context.undo());
+    assert!(!context.history.in_group());
+    assert!(context.image.operations.get(&op).is_none());
+    assert!(context.operation_mut(&op).is_none());
+}
+
+#[test]
+fn test_history_depth() {
+    let mut context = test_context();
+    context.set_history_depth(2);
+    for i in 0..5 {
+        context
+            .insert_operation(Operation::Tag(format!("{}", i)))
+            .unwrap();
+    }
+    assert!(context.undo());
+    assert!(context.undo());
+    assert!(!context.undo());
+
+    // New changes discard anything that could be redone
+    context
+        .insert_operation(Operation::Tag("New".to_string()))
+        .unwrap();
+    assert!(!context.can_redo());
+}
+
+#[test]
+fn test_history_saved_in_image() {
+    use painter_data::{load_from_reader, write_into};
+
+    let mut context = test_context();
+    let op = context
+        .insert_operation(Operation::Tag("Saved".to_string()))
+        .unwrap();
+    let undone_op = context
+        .insert_operation(Operation::Tag("Undone".to_string()))
+        .unwrap();
+    context.undo();
+
+    let mut data = Vec::new();
+    write_into(&context.image_with_history(), &mut data).expect("Failed to write");
+    let image = load_from_reader(data.as_slice()).expect("Failed to load");
+    let mut loaded = super::context::EditContext::new_with_saved_image(image);
+
+    // The history is not left lying around in the image
+    assert!(loaded.image.extension(UNDO_HISTORY_EXTENSION).is_none());
+
+    assert!(loaded.redo());
+    assert!(loaded.image.operations.get(&undone_op).is_some());
+    assert!(loaded.undo());
+    assert!(loaded.undo());
+    assert!(loaded.image.operations.get(&op).is_none());
+    assert!(!loaded.undo());
+
+    // The nodes are iterated in a different order, but store the same bytes
+    let stored = context.history.to_bytes();
+    assert_eq!(History::from_bytes(&stored).unwrap().to_bytes(), stored);
+}
+
+#[test]
+fn test_corrupt_history_ignored() {
+    let mut context = test_context();
+    context
+        .insert_operation(Operation::Tag("Saved".to_string()))
+        .unwrap();
+    let mut image = context.image_with_history();
+    image
+        .set_extension(UNDO_HISTORY_EXTENSION, vec![1, 0, 0, 0, 255])
+        .unwrap();
+
+    let loaded = super::context::EditContext::new_with_saved_image(image);
+    assert!(!loaded.can_undo());
+}
+
+#[test]
+fn test_undo_keeps_branches() {
+    let mut context = test_context();
+    let first = context
+        .insert_operation(Operation::Tag("First".to_string()))
+        .unwrap();
+    context.undo();
+    let second = context
+        .insert_operation(Operation::Tag("Second".to_string()))
+        .unwrap();
+    context.undo();
+
+    assert_eq!(
+        context.list_redo_branches(),
+        vec![
+            "Insert Operation".to_string(),
+            "Insert Operation".to_string()
+        ]
+    );
+    // Redo goes down the most recently used branch
+    assert!(context.redo());
+    assert!(context.image.operations.get(&second).is_some());
+    assert!(context.image.operations.get(&first).is_none());
+
+    context.undo();
+    assert!(context.redo_branch(0));
+    assert!(context.image.operations.get(&first).is_some());
+    assert!(context.image.operations.get(&second).is_none());
+    assert!(!context.redo_branch(5));
+}
+
+#[test]
+fn test_snapshots() {
+    let mut context = test_context();
+    context.create_snapshot("Start");
+    let base = context
+        .insert_operation(Operation::Tag("Base".to_string()))
+        .unwrap();
+    context.create_snapshot("Base");
+
+    context.begin_group("Red");
+    let red = context
+        .insert_operation(Operation::Tag("Red".to_string()))
+        .unwrap();
+    if let Some(Operation::Tag(name)) = context.operation_mut(&base) {
+        *name = "Red Base".to_string();
+    }
+    context.end_group();
+    context.create_snapshot("Red");
+
+    context.switch_to_snapshot("Base");
+    let blue = context
+        .insert_operation(Operation::Tag("Blue".to_string()))
+        .unwrap();
+    context.create_snapshot("Blue");
+
+    assert!(context.switch_to_snapshot("Red"));
+    assert!(context.image.operations.get(&red).is_some());
+    assert!(context.image.operations.get(&blue).is_none());
+    assert_eq!(
+        context.image.operations.get(&base),
+        Some(&Operation::Tag("Red Base".to_string()))
+    );
+
+    let diff = context.diff_snapshots("Red", "Blue").unwrap();
+    assert_eq!(diff.added, vec![blue]);
+    assert_eq!(diff.removed, vec![red]);
+    assert_eq!(diff.changed, vec![base]);
+
+    let diff = context.diff_snapshots("Start", "Base").unwrap();
+    assert_eq!(diff.added, vec![base]);
+    assert!(diff.removed.is_empty() && diff.changed.is_empty());
+
+    assert!(context.diff_snapshots("Start", "Missing").is_none());
+    assert!(!context.switch_to_snapshot("Missing"));
+    assert_eq!(
+        context.list_snapshots(),
+        vec!["Base", "Blue", "Red", "Start"]
+    );
+
+    // Snapshots survive saving
+    let image = context.image_with_history();
+    let mut loaded = super::context::EditContext::new_with_saved_image(image);
+    assert!(loaded.switch_to_snapshot("Blue"));
+    assert!(loaded.image.operations.get(&blue).is_some());
+}
+
+#[test]
+fn test_trim_keeps_snapshots() {
+    let mut context = test_context();
+    context.set_history_depth(3);
+    let first = context
+        .insert_operation(Operation::Tag("First".to_string()))
+        .unwrap();
+    context.create_snapshot("First");
+    for i in 0..5 {
+        context
+            .insert_operation(Operation::Tag(format!("{}", i)))
+            .unwrap();
+    }
+    assert!(context.switch_to_snapshot("First"));
+    assert!(context.image.operations.get(&first).is_some());
+}
@@ -0,0 +1,426 @@
+//! The input a stroke was drawn from. Strokes store their stamps, which depend on the
+//! brush they were drawn with, so the input is kept alongside them so that a stroke can
+//! be drawn again with a different brush. The seed each stroke's stamps were varied
+//! with (see the random module) is kept too, whether or not its input was.
+//!
+//! Samples are saved inside the image file as the `_sp_input_samples` extension. They
+//! are not part of the undo history: samples for strokes that no longer exist are
+//! simply ignored.
+//!
+//! A stroke can have thousands of samples, so they are stored compactly: each value is
+//! rounded to a multiple of a small step, and stored as the (zigzag varint encoded)
+//! number of steps it changed by since the previous sample. Consecutive samples are
+//! close together, so most values take a single byte.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use painter_data::id_map::{IdMapBase, IncrId, OperationId};
+use painter_data::image::Image;
+use painter_data::stroke::StrokeData;
+
+/// The name of the image extension the samples are saved in
+pub const INPUT_SAMPLES_EXTENSION: &str = "_sp_input_samples";
+
+/// Increment if the way the samples are stored changes. Samples stored in another
+/// version are discarded rather than failing to load the image.
+const INPUT_SAMPLES_FORMAT_VERSION: u32 = 1;
+
+/// The steps each value is rounded to when stored. They are powers of two so that
+/// values that are already multiples of them are stored exactly.
+/// A position step is about a quarter of a pixel on a canvas 4096 pixels tall.
+const POSITION_STEP: f32 = 1.0 / 8192.0;
+const PRESSURE_STEP: f32 = 1.0 / 4096.0;
+/// About a millisecond
+const TIME_STEP: f32 = 1.0 / 1024.0;
+const TILT_STEP: f32 = 1.0 / 4096.0;
+
+/// Set in the flags of a stored stroke if any of its samples include the tilt. Each
+/// sample then says whether it has one.
+const HAS_TILT: u8 = 1;
+
+/// A single input event of a stroke
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct InputSample {
+    pub position: [f32; 2],
+    pub pressure: f32,
+
+    /// Seconds since the stroke started
+    pub time: f32,
+
+    /// How far the stylus leans along the x and y axes, from -1 to 1. None if the
+    /// device does not report it.
+    pub tilt: Option<[f32; 2]>,
+}
+
+impl InputSample {
+    /// The sample `fraction` of the way from this sample to `other`. The tilt is only
+    /// interpolated if both samples have one.
+    pub fn lerp(&self, other: &Self, fraction: f32) -> Self {
+        let lerp = |from: f32, to: f32| from + (to - from) * fraction;
+        Self {
+            position: [
+                lerp(self.position[0], other.position[0]),
+                lerp(self.position[1], other.position[1]),
+            ],
+            pressure: lerp(self.pressure, other.pressure),
+            time: lerp(self.time, other.time),
+            tilt: match (self.tilt, other.tilt) {
+                (Some(from), Some(to)) => Some([lerp(from[0], to[0]), lerp(from[1], to[1])]),
+                (from, to) => to.or(from),
+            },
+        }
+    }
+
+    /// Samples at the stamps of a stroke, for strokes whose input was not kept. The
+    /// pressure is how large each stamp is compared to the largest stamp.
+    pub fn from_stamps(stroke: &StrokeData) -> Vec<Self> {
+        let largest = stroke.size_array.iter().cloned().fold(0.0, f32::max);
+        stroke
+            .position_array
+            .iter()
+            .enumerate()
+            .map(|(index, position)| Self {
+                position: *position,
+                pressure: match stroke.size_array.get(index) {
+                    Some(size) if largest > 0.0 => size / largest,
+                    _ => 1.0,
+                },
+                time: 0.0,
+                tilt: None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum InputSamplesLoadError {
+    /// Not even long enough to contain a version number
+    Truncated,
+    UnknownVersion(u32),
+
+    /// A stroke's samples end part way through a sample
+    MalformedStroke(OperationId),
+    DeserializeError(std::boxed::Box<bincode::ErrorKind>),
+}
+
+/// How the samples are stored: the encoded samples of each stroke, then the seed of
+/// each stroke
+type StoredInputSamples = (Vec<(OperationId, Vec<u8>)>, Vec<(OperationId, u64)>);
+
+/// The input samples of each stroke that was drawn with them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputSamples {
+    strokes: HashMap<OperationId, Vec<InputSample>>,
+    seeds: HashMap<OperationId, u64>,
+}
+
+impl InputSamples {
+    /// Adds a sample to the end of a stroke's input
+    pub fn record(&mut self, operation_id: OperationId, sample: InputSample) {
+        self.strokes.entry(operation_id).or_default().push(sample);
+    }
+
+    /// The samples a stroke was drawn from, if they were kept
+    pub fn get(&self, operation_id: &OperationId) -> Option<&[InputSample]> {
+        self.strokes
+            .get(operation_id)
+            .map(|samples| samples.as_slice())
+    }
+
+    pub fn set_seed(&mut self, operation_id: OperationId, seed: u64) {
+        self.seeds.insert(operation_id, seed);
+    }
+
+    /// The seed a stroke's stamps were varied with, if it was kept
+    pub fn seed(&self, operation_id: &OperationId) -> Option<u64> {
+        self.seeds.get(operation_id).copied()
+    }
+
+    /// Forgets the samples of strokes that are no longer in the image
+    pub fn retain_in(&mut self, image: &Image) {
+        self.strokes
+            .retain(|operation_id, _| image.operations.get(operation_id).is_some());
+        self.seeds
+            .retain(|operation_id, _| image.operations.get(operation_id).is_some());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strokes.is_empty() && self.seeds.is_empty()
+    }
+
+    /// Encodes the samples so they can be stored in an image extension. Values are
+    /// rounded (see the module documentation). Strokes are ordered by ID so that the
+    /// same samples always give the same bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut strokes: Vec<(OperationId, Vec<u8>)> = self
+            .strokes
+            .iter()
+            .map(|(operation_id, samples)| (*operation_id, encode_stroke(samples)))
+            .collect();
+        strokes.sort_by_key(|(operation_id, _)| operation_id.val());
+        let mut seeds: Vec<(OperationId, u64)> = self
+            .seeds
+            .iter()
+            .map(|(operation_id, seed)| (*operation_id, *seed))
+            .collect();
+        seeds.sort_by_key(|(operation_id, _)| operation_id.val());
+        let mut data = INPUT_SAMPLES_FORMAT_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut data, &(strokes, seeds)).expect("Serializing samples failed");
+        data
+    }
+
+    /// Decodes samples created by to_bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self, InputSamplesLoadError> {
+        let mut version = [0u8; 4];
+        if data.len() < version.len() {
+            return Err(InputSamplesLoadError::Truncated);
+        }
+        version.copy_from_slice(&data[..4]);
+        match u32::from_le_bytes(version) {
+            INPUT_SAMPLES_FORMAT_VERSION => {
+                let (stored, seeds): StoredInputSamples = bincode::deserialize(&data[4..])
+                    .map_err(InputSamplesLoadError::DeserializeError)?;
+                Ok(Self {
+                    strokes: decode_strokes(stored)?,
+                    seeds: seeds.into_iter().collect(),
+                })
+            }
+            number => Err(InputSamplesLoadError::UnknownVersion(number)),
+        }
+    }
+}
+
+fn decode_strokes(
+    stored: Vec<(OperationId, Vec<u8>)>,
+) -> Result<HashMap<OperationId, Vec<InputSample>>, InputSamplesLoadError> {
+    let mut strokes = HashMap::new();
+    for (operation_id, data) in stored {
+        let samples =
+            decode_stroke(&data).ok_or(InputSamplesLoadError::MalformedStroke(operation_id))?;
+        strokes.insert(operation_id, samples);
+    }
+    Ok(strokes)
+}
+
+/// Encodes the samples of a single stroke. The first byte is flags, followed by the
+/// change in each value of each sample.
+fn encode_stroke(samples: &[InputSample]) -> Vec<u8> {
+    let has_tilt = samples.iter().any(|sample| sample.tilt.is_some());
+    let mut data = vec![if has_tilt { HAS_TILT } else { 0 }];
+    let mut previous = [0i64; 6];
+    let mut write_values = |data: &mut Vec<u8>, values: &[i64], first: usize| {
+        for (value, previous) in values.iter().zip(previous[first..].iter_mut()) {
+            write_varint(data, zigzag(value - *previous));
+            *previous = *value;
+        }
+    };
+    for sample in samples.iter() {
+        let values = [
+            to_steps(sample.position[0], POSITION_STEP),
+            to_steps(sample.position[1], POSITION_STEP),
+            to_steps(sample.pressure, PRESSURE_STEP),
+            to_steps(sample.time, TIME_STEP),
+        ];
+        write_values(&mut data, &values, 0);
+        if has_tilt {
+            data.push(sample.tilt.is_some() as u8);
+            if let Some(tilt) = sample.tilt {
+                let tilt = [to_steps(tilt[0], TILT_STEP), to_steps(tilt[1], TILT_STEP)];
+                write_values(&mut data, &tilt, 4);
+            }
+        }
+    }
+    data
+}
+
+/// Decodes the samples of a stroke encoded by encode_stroke, or None if they are
+/// malformed.
+fn decode_stroke(data: &[u8]) -> Option<Vec<InputSample>> {
+    let (flags, mut data) = data.split_first()?;
+    let has_tilt = flags & HAS_TILT != 0;
+    let mut values = [0i64; 6];
+    let mut samples = Vec::new();
+    while !data.is_empty() {
+        for value in values[..4].iter_mut() {
+            *value += unzigzag(read_varint(&mut data)?);
+        }
+        let sample_has_tilt = match has_tilt {
+            true => {
+                let (flag, rest) = data.split_first()?;
+                data = rest;
+                *flag != 0
+            }
+            false => false,
+        };
+        if sample_has_tilt {
+            for value in values[4..].iter_mut() {
+                *value += unzigzag(read_varint(&mut data)?);
+            }
+        }
+        samples.push(InputSample {
+            position: [
+                from_steps(values[0], POSITION_STEP),
+                from_steps(values[1], POSITION_STEP),
+            ],
+            pressure: from_steps(values[2], PRESSURE_STEP),
+            time: from_steps(values[3], TIME_STEP),
+            tilt: match sample_has_tilt {
+                true => Some([
+                    from_steps(values[4], TILT_STEP),
+                    from_steps(values[5], TILT_STEP),
+                ]),
+                false => None,
+            },
+        });
+    }
+    Some(samples)
+}
+
+/// The number of steps closest to a value. Kept within the range of an i32 so that the
+/// difference between two values always fits in an i64.
+fn to_steps(value: f32, step: f32) -> i64 {
+    (value / step)
+        .round()
+        .clamp(i32::MIN as f32, i32::MAX as f32) as i64
+}
+
+fn from_steps(steps: i64, step: f32) -> f32 {
+    steps as f32 * step
+}
+
+/// Maps signed numbers to unsigned ones so that small negative numbers stay small
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Writes a number 7 bits at a time, least significant first, with the top bit of
+/// each byte set if more follow.
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+/// Reads a number written by write_varint from the start of `data`, advancing past it
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[test]
+fn test_input_samples_round_trip() {
+    use painter_data::operation::Operation;
+
+    let mut image = painter_data::template::create_default_image();
+    let kept = image.operations.insert(Operation::Tag("Kept".to_string()));
+    let removed = image
+        .operations
+        .insert(Operation::Tag("Removed".to_string()));
+    let mut samples = InputSamples::default();
+    for (operation_id, tilt) in [(kept, Some([0.25, -0.5])), (removed, None)].iter() {
+        samples.record(
+            *operation_id,
+            InputSample {
+                position: [0.5, -0.5],
+                pressure: 0.25,
+                time: 0.125,
+                tilt: *tilt,
+            },
+        );
+    }
+
+    // A sample without a tilt in a stroke that otherwise has one
+    samples.record(
+        kept,
+        InputSample {
+            position: [0.75, -0.5],
+            pressure: 0.5,
+            time: 0.25,
+            tilt: None,
+        },
+    );
+
+    samples.set_seed(kept, u64::MAX);
+    samples.set_seed(removed, 3);
+
+    let loaded = InputSamples::from_bytes(&samples.to_bytes()).unwrap();
+    assert_eq!(loaded, samples);
+    assert_eq!(loaded.get(&kept).unwrap()[1].tilt, None);
+    // The maps are iterated in a different order, but store the same bytes
+    assert_eq!(loaded.to_bytes(), samples.to_bytes());
+    assert!(matches!(
+        InputSamples::from_bytes(&[2, 0]),
+        Err(InputSamplesLoadError::Truncated)
+    ));
+    assert!(matches!(
+        InputSamples::from_bytes(&[99, 0, 0, 0]),
+        Err(InputSamplesLoadError::UnknownVersion(99))
+    ));
+
+    image.operations.remove(&removed);
+    samples.retain_in(&image);
+    assert_eq!(samples.get(&kept).map(|samples| samples.len()), Some(2));
+    assert!(samples.get(&removed).is_none());
+    assert_eq!(samples.seed(&kept), Some(u64::MAX));
+    assert!(samples.seed(&removed).is_none());
+}
+
+#[test]
+fn test_input_samples_encoding() {
+    use painter_data::id_map::OperationIdMap;
+    use painter_data::operation::Operation;
+
+    let mut operations = OperationIdMap::default();
+    let stroke = operations.insert(Operation::Tag("Stroke".to_string()));
+    let mut samples = InputSamples::default();
+    for index in 0..1000 {
+        let t = index as f32 / 1000.0;
+        samples.record(
+            stroke,
+            InputSample {
+                position: [t.sin() * 0.7, -t * 0.9],
+                pressure: 0.3 + 0.4 * t,
+                time: t * 2.0,
+                tilt: Some([0.1, -0.2]),
+            },
+        );
+    }
+    let data = samples.to_bytes();
+    // A smooth stroke takes about a byte per value
+    assert!(data.len() < 1000 * 8, "{} bytes", data.len());
+    let loaded = InputSamples::from_bytes(&data).unwrap();
+    for (loaded, sample) in loaded
+        .get(&stroke)
+        .unwrap()
+        .iter()
+        .zip(samples.get(&stroke).unwrap())
+    {
+        assert!((loaded.position[0] - sample.position[0]).abs() <= POSITION_STEP / 2.0);
+        assert!((loaded.position[1] - sample.position[1]).abs() <= POSITION_STEP / 2.0);
+        assert!((loaded.pressure - sample.pressure).abs() <= PRESSURE_STEP / 2.0);
+        assert!((loaded.time - sample.time).abs() <= TIME_STEP / 2.0);
+        assert!((loaded.tilt.unwrap()[1] - -0.2).abs() <= TILT_STEP / 2.0);
+    }
+
+    for value in [0, 1, -1, 63, -64, i32::MAX as i64, i32::MIN as i64].iter() {
+        let mut data = Vec::new();
+        write_varint(&mut data, zigzag(*value));
+        assert_eq!(unzigzag(read_varint(&mut data.as_slice()).unwrap()), *value);
+    }
+    // A sample cut off part way through
+    assert!(decode_stroke(&[0, 2, 2, 2]).is_none());
+}
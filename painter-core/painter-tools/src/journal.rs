@@ -0,0 +1,462 @@
+//! An append-only log of the changes made to an image since it was last saved, so that
+//! they can be recovered after a crash.
+//!
+//! The journal lives next to the document (`picture.sveg` has `picture.sveg.journal`) and
+//! starts with a fingerprint of the document it applies to. Each command applied to the
+//! image is appended as a record:
+//! ```ignore
+//! [length: u32] [bincode encoded Command] [checksum of the command: u64]
+//! ```
+//! A crash part way through writing a record leaves a partial record at the end, which
+//! is ignored when reading.
+//!
+//! Writes go straight to the OS, but syncing to disk is slow, so it is only done every
+//! few records or after a short time.
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::history::Command;
+
+const JOURNAL_MAGIC_STR: &[u8] = b"PAINTER_JOURNAL";
+
+/// Journals from other versions are not recovered
+const JOURNAL_FORMAT_VERSION: u32 = 1;
+
+/// Sync to disk after this many records...
+const SYNC_BATCH_SIZE: usize = 16;
+
+/// ... or when this long has passed since the last sync, whichever comes first
+const SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum JournalError {
+    IoError(std::io::Error),
+    SerializeError(std::boxed::Box<bincode::ErrorKind>),
+    InvalidMagicString,
+    UnknownVersion(u32),
+}
+
+impl From<std::io::Error> for JournalError {
+    fn from(err: std::io::Error) -> Self {
+        JournalError::IoError(err)
+    }
+}
+
+/// Identifies the exact contents of a document, so that a journal is only replayed
+/// onto the document it was written for.
+pub fn document_fingerprint(data: &[u8]) -> u64 {
+    checksum(data)
+}
+
+/// FNV-1a, which (unlike std's DefaultHasher) is stable between builds
+fn checksum(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Where the journal for a document is stored
+pub fn journal_path(document_path: &Path) -> PathBuf {
+    let mut path = document_path.as_os_str().to_owned();
+    path.push(".journal");
+    PathBuf::from(path)
+}
+
+/// What was read back from a journal file
+#[derive(Debug)]
+pub struct JournalContents {
+    /// The fingerprint of the document the journal applies to
+    pub document_fingerprint: u64,
+
+    /// The complete records in the journal, oldest first
+    pub commands: Vec<Command>,
+
+    /// How many bytes of the file contain the header and complete records
+    valid_length: u64,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+    document_path: PathBuf,
+    records: usize,
+    unsynced_records: usize,
+    last_sync: Instant,
+}
+
+impl Journal {
+    /// Starts a new, empty journal for a document, replacing any existing one
+    pub fn create(document_path: &Path, document_fingerprint: u64) -> Result<Self, JournalError> {
+        let mut file = File::create(journal_path(document_path))?;
+        file.write_all(JOURNAL_MAGIC_STR)?;
+        file.write_all(&JOURNAL_FORMAT_VERSION.to_le_bytes())?;
+        file.write_all(&document_fingerprint.to_le_bytes())?;
+        file.sync_data()?;
+        Ok(Self {
+            file,
+            document_path: document_path.to_path_buf(),
+            records: 0,
+            unsynced_records: 0,
+            last_sync: Instant::now(),
+        })
+    }
+
+    /// Continues appending to a journal that was read with `read`, discarding any partial
+    /// record at the end of it.
+    pub fn resume(document_path: &Path, contents: &JournalContents) -> Result<Self, JournalError> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(journal_path(document_path))?;
+        file.set_len(contents.valid_length)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            document_path: document_path.to_path_buf(),
+            records: contents.commands.len(),
+            unsynced_records: 0,
+            last_sync: Instant::now(),
+        })
+    }
+
+    /// Reads the journal for a document. Returns None if there is no journal.
+    pub fn read(document_path: &Path) -> Result<Option<JournalContents>, JournalError> {
+        let mut data = Vec::new();
+        match File::open(journal_path(document_path)) {
+            Ok(mut file) => file.read_to_end(&mut data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let header_length = JOURNAL_MAGIC_STR.len() + 4 + 8;
+        if data.len() < header_length || &data[..JOURNAL_MAGIC_STR.len()] != JOURNAL_MAGIC_STR {
+            return Err(JournalError::InvalidMagicString);
+        }
+        let mut position = JOURNAL_MAGIC_STR.len();
+        let version = u32::from_le_bytes(read_array(&data, &mut position).expect("Header length"));
+        if version != JOURNAL_FORMAT_VERSION {
+            return Err(JournalError::UnknownVersion(version));
+        }
+        let fingerprint =
+            u64::from_le_bytes(read_array(&data, &mut position).expect("Header length"));
+
+        let mut commands = Vec::new();
+        let mut valid_length = position;
+        while let Some(command) = read_record(&data, &mut position) {
+            commands.push(command);
+            valid_length = position;
+        }
+
+        Ok(Some(JournalContents {
+            document_fingerprint: fingerprint,
+            commands,
+            valid_length: valid_length as u64,
+        }))
+    }
+
+    /// Removes the journal for a document if there is one
+    pub fn delete(document_path: &Path) -> Result<(), JournalError> {
+        match std::fs::remove_file(journal_path(document_path)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds a command to the end of the journal
+    pub fn append(&mut self, command: &Command) -> Result<(), JournalError> {
+        let payload = bincode::serialize(command).map_err(JournalError::SerializeError)?;
+        let mut record = Vec::with_capacity(payload.len() + 12);
+        record.extend((payload.len() as u32).to_le_bytes());
+        record.extend(&payload);
+        record.extend(checksum(&payload).to_le_bytes());
+        self.file.write_all(&record)?;
+
+        self.records += 1;
+        self.unsynced_records += 1;
+        if self.unsynced_records >= SYNC_BATCH_SIZE || self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// How many records are in the journal, including any there when it was resumed
+    pub fn record_count(&self) -> usize {
+        self.records
+    }
+
+    /// Switches to a journal for a newly saved version of the document. The saved
+    /// document contains the changes from the first `saved_records` records, so only
+    /// the records after those are carried over. Used when the document was saved in
+    /// the background while changes continued to be made.
+    pub fn rebase(
+        &mut self,
+        document_path: &Path,
+        document_fingerprint: u64,
+        saved_records: usize,
+    ) -> Result<(), JournalError> {
+        self.sync()?;
+        let commands = match Self::read(&self.document_path)? {
+            Some(contents) => contents.commands,
+            None => Vec::new(),
+        };
+        let mut rebased = Self::create(document_path, document_fingerprint)?;
+        for command in commands.iter().skip(saved_records) {
+            rebased.append(command)?;
+        }
+        rebased.sync()?;
+        *self = rebased;
+        Ok(())
+    }
+
+    /// Makes sure everything appended so far is on disk
+    pub fn sync(&mut self) -> Result<(), JournalError> {
+        if self.unsynced_records > 0 {
+            self.file.sync_data()?;
+            self.unsynced_records = 0;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        // Nothing useful can be done with an error here
+        let _ = self.sync();
+    }
+}
+
+fn read_array<const N: usize>(data: &[u8], position: &mut usize) -> Option<[u8; N]> {
+    let bytes = data.get(*position..*position + N)?;
+    *position += N;
+    let mut out = [0u8; N];
+    out.copy_from_slice(bytes);
+    Some(out)
+}
+
+/// Reads a single record, returning None (and leaving `position` alone) if the record is
+/// incomplete or corrupt.
+fn read_record(data: &[u8], position: &mut usize) -> Option<Command> {
+    let mut cursor = *position;
+    let length = u32::from_le_bytes(read_array(data, &mut cursor)?) as usize;
+    let payload = data.get(cursor..cursor + length)?;
+    cursor += length;
+    let checksum = u64::from_le_bytes(read_array(data, &mut cursor)?);
+    if checksum != self::checksum(payload) {
+        return None;
+    }
+    let command = bincode::deserialize(payload).ok()?;
+    *position = cursor;
+    Some(command)
+}
+
+#[cfg(test)]
+fn test_document_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "painter_journal_test_{}_{}.sveg",
+        name,
+        std::process::id()
+    ));
+    Journal::delete(&path).unwrap();
+    path
+}
+
+#[cfg(test)]
+fn test_command(name: &str) -> Command {
+    use super::history::Change;
+    Command {
+        name: name.to_string(),
+        changes: vec![Change::InsertTarget {
+            before: None,
+            after: None,
+        }],
+    }
+}
+
+#[test]
+fn test_journal_round_trip() {
+    let path = test_document_path("round_trip");
+    assert!(Journal::read(&path).unwrap().is_none());
+
+    let mut journal = Journal::create(&path, 1234).unwrap();
+    journal.append(&test_command("First")).unwrap();
+    journal.append(&test_command("Second")).unwrap();
+    drop(journal);
+
+    let contents = Journal::read(&path).unwrap().unwrap();
+    assert_eq!(contents.document_fingerprint, 1234);
+    assert_eq!(
+        contents.commands,
+        vec![test_command("First"), test_command("Second")]
+    );
+
+    // Resuming appends after the existing records
+    let mut journal = Journal::resume(&path, &contents).unwrap();
+    journal.append(&test_command("Third")).unwrap();
+    drop(journal);
+    assert_eq!(Journal::read(&path).unwrap().unwrap().commands.len(), 3);
+
+    Journal::delete(&path).unwrap();
+}
+
+#[test]
+fn test_journal_torn_write() {
+    let path = test_document_path("torn_write");
+    let mut journal = Journal::create(&path, 1).unwrap();
+    journal.append(&test_command("Complete")).unwrap();
+    journal.append(&test_command("Torn")).unwrap();
+    drop(journal);
+
+    // Chop the end off the last record as if the app crashed while writing it
+    let file_length = std::fs::metadata(journal_path(&path)).unwrap().len();
+    let file = OpenOptions::new()
+        .write(true)
+        .open(journal_path(&path))
+        .unwrap();
+    file.set_len(file_length - 3).unwrap();
+    drop(file);
+
+    let contents = Journal::read(&path).unwrap().unwrap();
+    assert_eq!(contents.commands, vec![test_command("Complete")]);
+
+    // The partial record is removed when resuming
+    let mut journal = Journal::resume(&path, &contents).unwrap();
+    journal.append(&test_command("After")).unwrap();
+    drop(journal);
+    assert_eq!(
+        Journal::read(&path).unwrap().unwrap().commands,
+        vec![test_command("Complete"), test_command("After")]
+    );
+
+    Journal::delete(&path).unwrap();
+}
+
+#[test]
+fn test_journal_rebase() {
+    let path = test_document_path("rebase");
+    let mut journal = Journal::create(&path, 1).unwrap();
+    journal.append(&test_command("Saved")).unwrap();
+    journal.append(&test_command("Not Saved")).unwrap();
+    assert_eq!(journal.record_count(), 2);
+
+    journal.rebase(&path, 2, 1).unwrap();
+    assert_eq!(journal.record_count(), 1);
+    journal.append(&test_command("After")).unwrap();
+    drop(journal);
+
+    let contents = Journal::read(&path).unwrap().unwrap();
+    assert_eq!(contents.document_fingerprint, 2);
+    assert_eq!(
+        contents.commands,
+        vec![test_command("Not Saved"), test_command("After")]
+    );
+
+    Journal::delete(&path).unwrap();
+}
+
+#[test]
+fn test_journal_replay() {
+    use super::context::EditContext;
+    use painter_data::id_map::IdMapBase;
+    use painter_data::operation::Operation;
+
+    let path = test_document_path("replay");
+    let new_context = || {
+        let mut context = EditContext::default();
+        let layer_id = *context.image.layers.iter().next().unwrap().0;
+        context.select_layer(layer_id).unwrap();
+        context
+    };
+
+    let mut context = new_context();
+    context.set_journal(Some(Journal::create(&path, 0).unwrap()));
+    let kept = context
+        .insert_operation(Operation::Tag("Kept".to_string()))
+        .unwrap();
+    let undone = context
+        .insert_operation(Operation::Tag("Undone".to_string()))
+        .unwrap();
+    context.undo();
+    context.begin_group("Unfinished");
+    context
+        .insert_operation(Operation::Tag("Unfinished".to_string()))
+        .unwrap();
+    context.set_journal(None);
+
+    let mut recovered = new_context();
+    recovered.replay(Journal::read(&path).unwrap().unwrap().commands);
+
+    assert!(recovered.image.operations.get(&kept).is_some());
+    assert!(recovered.image.operations.get(&undone).is_none());
+    assert_eq!(recovered.insert_operation_onto, Some(kept));
+    // The unfinished group was never written
+    assert_eq!(recovered.image.operations.iter().count(), 5);
+    let blend_op = recovered
+        .image
+        .layers
+        .iter()
+        .next()
+        .unwrap()
+        .1
+        .blend_operation_id;
+    assert_eq!(
+        recovered.image.depgraph.depends_on(&blend_op).unwrap()[0],
+        kept
+    );
+    // Everything that was recovered can be undone
+    assert!(recovered.undo());
+    assert!(recovered.undo());
+    assert!(recovered.undo());
+    assert!(recovered.image.operations.get(&kept).is_none());
+
+    Journal::delete(&path).unwrap();
+}
+
+#[test]
+fn test_journal_replay_then_insert() {
+    use super::context::EditContext;
+    use painter_data::brush::{Glyph, PressureCurve};
+    use painter_data::id_map::IdMapBase;
+    use painter_data::operation::Operation;
+
+    let path = test_document_path("replay_then_insert");
+    let mut context = EditContext::default();
+    context.set_journal(Some(Journal::create(&path, 0).unwrap()));
+    let layer_id = context.create_layer("Replayed".to_string(), None).unwrap();
+    let operation_id = context
+        .insert_operation(Operation::Tag("Replayed".to_string()))
+        .unwrap();
+    let glyph_id = context.find_or_insert_glyph(&Glyph::Png(vec![1]));
+    let brush_id = *context.image.brushes.iter().next().unwrap().0;
+    let curve = PressureCurve::from_samples(vec![0.0, 0.25, 1.0]);
+    context
+        .set_brush_curve(brush_id, "size", curve.clone())
+        .unwrap();
+    context.set_journal(None);
+
+    let mut recovered = EditContext::default();
+    recovered.replay(Journal::read(&path).unwrap().unwrap().commands);
+    recovered.select_layer(layer_id).unwrap();
+
+    // New IDs must not collide with the replayed ones
+    let new_layer_id = recovered.create_layer("New".to_string(), None).unwrap();
+    assert_ne!(new_layer_id, layer_id);
+    let new_operation_id = recovered
+        .insert_operation(Operation::Tag("New".to_string()))
+        .unwrap();
+    assert_ne!(new_operation_id, operation_id);
+    let new_glyph_id = recovered.find_or_insert_glyph(&Glyph::Png(vec![2]));
+    assert_ne!(new_glyph_id, glyph_id);
+    assert!(recovered.image.operations.get(&operation_id).is_some());
+    assert!(recovered.image.layers.get(&layer_id).is_some());
+    assert_eq!(
+        recovered.image.brushes.get(&brush_id).unwrap().size.curve,
+        curve
+    );
+
+    Journal::delete(&path).unwrap();
+}
@@ -1,2 +1,9 @@
 pub mod brush_tool;
 pub mod context;
+pub mod history;
+pub mod input_samples;
+pub mod journal;
+pub mod random;
+pub mod recording;
+pub mod selection_tool;
+pub mod tablet_curves;
@@ -0,0 +1,58 @@
+//! The random numbers used to vary stamps. Strokes store the stamps they were drawn
+//! with, so the randomness is baked into the image, but the seed of each stroke is kept
+//! so that it can be drawn again with the same result.
+//!
+//! The generator is SplitMix64 (as described by Steele, Lea and Flood in "Fast
+//! Splittable Pseudorandom Number Generators"), which only uses integer arithmetic so
+//! gives the same numbers on every platform and in every version. Do not change it:
+//! doing so changes how strokes are redrawn.
+
+/// Added to the state before each number is generated
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrokeRng {
+    state: u64,
+}
+
+impl StrokeRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        let mut value = self.state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        value ^ (value >> 31)
+    }
+
+    /// A number from 0 up to (but not including) 1. Uses the top 24 bits, which is all
+    /// an f32 can represent exactly in this range.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// A number from -1 up to (but not including) 1
+    pub fn next_signed(&mut self) -> f32 {
+        self.next_f32() * 2.0 - 1.0
+    }
+}
+
+#[test]
+fn test_stroke_rng() {
+    // The first outputs for seed 1234567 from the reference implementation
+    let mut rng = StrokeRng::new(1234567);
+    assert_eq!(rng.next_u64(), 6457827717110365317);
+    assert_eq!(rng.next_u64(), 3203168211198807973);
+
+    let mut rng = StrokeRng::new(0);
+    for _ in 0..1000 {
+        let value = rng.next_f32();
+        assert!((0.0..1.0).contains(&value));
+        let value = rng.next_signed();
+        assert!((-1.0..1.0).contains(&value));
+    }
+    assert_eq!(StrokeRng::new(5).next_u64(), StrokeRng::new(5).next_u64());
+}
@@ -0,0 +1,286 @@
+//! Records the input given to the brush tool and the edit context, so that a session can
+//! be replayed later to produce the same image. This is used to reproduce bug reports
+//! and to check that changes to the brush engine don't change existing paintings.
+//!
+//! A recording file contains the image as it was when recording started (including its
+//! undo history), followed by the events:
+//! ```ignore
+//! [magic string] [version: u32] [bincode encoded (image bytes, events)]
+//! ```
+//! Only the events listed in RecordedEvent are recorded, so replaying a session that
+//! made other edits (such as creating layers) will not give the same image.
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use painter_data::color_primitives::Color;
+use painter_data::id_map::{BrushId, OperationId};
+use painter_data::PainterDataError;
+
+use super::brush_tool::BrushTool;
+use super::context::EditContext;
+
+const RECORDING_MAGIC_STR: &[u8] = b"PAINTER_RECORDING";
+
+/// Recordings from other versions cannot be replayed
+const RECORDING_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum RecordingError {
+    IoError(std::io::Error),
+    SerializeError(std::boxed::Box<bincode::ErrorKind>),
+    InvalidMagicString,
+    UnknownVersion(u32),
+    ImageError(PainterDataError),
+
+    /// Saving a recording when none was started
+    NotRecording,
+}
+
+impl From<std::io::Error> for RecordingError {
+    fn from(err: std::io::Error) -> Self {
+        RecordingError::IoError(err)
+    }
+}
+
+impl From<PainterDataError> for RecordingError {
+    fn from(err: PainterDataError) -> Self {
+        RecordingError::ImageError(err)
+    }
+}
+
+impl From<RecordingError> for pyo3::PyErr {
+    fn from(err: RecordingError) -> Self {
+        pyo3::exceptions::PyIOError::new_err(format!("{:?}", err))
+    }
+}
+
+/// A single call that changed the image or the state the tools draw with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RecordedEvent {
+    /// BrushTool::start_stroke, along with the settings of the tool, where in the image
+    /// the stroke was inserted (ie the selected layer) at the time, and the seed its
+    /// stamps were varied with
+    StartStroke {
+        insert_onto: Option<OperationId>,
+        brush_id: BrushId,
+        size: f32,
+        record_input: bool,
+        position: [f32; 2],
+        pressure: f32,
+        tilt: Option<[f32; 2]>,
+        seed: u64,
+    },
+    ContinueStroke {
+        position: [f32; 2],
+        pressure: f32,
+        time: f32,
+        tilt: Option<[f32; 2]>,
+    },
+    EndStroke,
+    ManipulateCanvas {
+        zoom: f32,
+        angle: f32,
+        translation: [f32; 2],
+    },
+    SetPrimaryColor(Color),
+    Undo,
+    Redo,
+}
+
+#[derive(Debug, Clone)]
+pub struct Recording {
+    /// The image (with its undo history) when recording started, encoded as a file
+    image: Vec<u8>,
+    events: Vec<RecordedEvent>,
+}
+
+impl Recording {
+    /// Starts recording edits to the image in a context. The state the tools draw
+    /// with is recorded as the first events.
+    pub fn start(context: &EditContext) -> Result<Self, RecordingError> {
+        let transform = &context.canvas_transform;
+        Ok(Self {
+            image: painter_data::to_bytes(&context.image_with_history(), &mut |_| {})?,
+            events: vec![
+                RecordedEvent::ManipulateCanvas {
+                    zoom: transform.zoom,
+                    angle: transform.angle,
+                    translation: transform.translation,
+                },
+                RecordedEvent::SetPrimaryColor(context.color.clone()),
+            ],
+        })
+    }
+
+    pub fn push(&mut self, event: RecordedEvent) {
+        self.events.push(event);
+    }
+
+    pub fn events(&self) -> &[RecordedEvent] {
+        &self.events
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), RecordingError> {
+        let mut data = RECORDING_MAGIC_STR.to_vec();
+        data.extend_from_slice(&RECORDING_FORMAT_VERSION.to_le_bytes());
+        bincode::serialize_into(&mut data, &(&self.image, &self.events))
+            .map_err(RecordingError::SerializeError)?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, RecordingError> {
+        let data = std::fs::read(path)?;
+        let header_length = RECORDING_MAGIC_STR.len() + 4;
+        if data.len() < header_length || !data.starts_with(RECORDING_MAGIC_STR) {
+            return Err(RecordingError::InvalidMagicString);
+        }
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&data[RECORDING_MAGIC_STR.len()..header_length]);
+        match u32::from_le_bytes(version) {
+            RECORDING_FORMAT_VERSION => {
+                let (image, events) = bincode::deserialize(&data[header_length..])
+                    .map_err(RecordingError::SerializeError)?;
+                Ok(Self { image, events })
+            }
+            number => Err(RecordingError::UnknownVersion(number)),
+        }
+    }
+
+    /// Creates a context from the image recording started with and performs every
+    /// recorded event on it.
+    pub fn replay(&self) -> Result<EditContext, RecordingError> {
+        let image = painter_data::from_bytes(&self.image, &mut |_| {})?;
+        let mut context = EditContext::new_with_saved_image(image);
+        let mut brush_tool = BrushTool::default();
+        for event in self.events.iter() {
+            match event {
+                RecordedEvent::StartStroke {
+                    insert_onto,
+                    brush_id,
+                    size,
+                    record_input,
+                    position,
+                    pressure,
+                    tilt,
+                    seed,
+                } => {
+                    context.insert_operation_onto = *insert_onto;
+                    brush_tool.next_stroke_seed = Some(*seed);
+                    brush_tool.set_brush_id(*brush_id);
+                    brush_tool.size = *size;
+                    brush_tool.record_input = *record_input;
+                    brush_tool.start_stroke(
+                        &mut context,
+                        position[0],
+                        position[1],
+                        *pressure,
+                        *tilt,
+                    );
+                }
+                RecordedEvent::ContinueStroke {
+                    position,
+                    pressure,
+                    time,
+                    tilt,
+                } => brush_tool.continue_stroke(
+                    &mut context,
+                    position[0],
+                    position[1],
+                    *pressure,
+                    *time,
+                    *tilt,
+                ),
+                RecordedEvent::EndStroke => brush_tool.end_stroke(&mut context),
+                RecordedEvent::ManipulateCanvas {
+                    zoom,
+                    angle,
+                    translation,
+                } => context.manipulate_canvas(*zoom, *angle, *translation),
+                RecordedEvent::SetPrimaryColor(color) => {
+                    context.set_primary_color(color.r, color.g, color.b, color.a)
+                }
+                RecordedEvent::Undo => {
+                    context.undo();
+                }
+                RecordedEvent::Redo => {
+                    context.redo();
+                }
+            }
+        }
+        Ok(context)
+    }
+}
+
+#[test]
+fn test_record_and_replay() {
+    use painter_data::id_map::IdMapBase;
+    use painter_data::image::Image;
+
+    let path = std::env::temp_dir().join(format!(
+        "painter_recording_test_{}.recording",
+        std::process::id()
+    ));
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let brush_id = *context.image.brushes.iter().next().unwrap().0;
+    let mut brush_tool = BrushTool::default();
+    brush_tool.set_brush_id(brush_id);
+
+    // Drawn before recording started, so part of the recorded image
+    brush_tool.start_stroke(&mut context, -0.5, -0.5, 0.5, None);
+    brush_tool.end_stroke(&mut context);
+    context.set_primary_color(1.0, 0.0, 0.0, 1.0);
+
+    context.start_recording().unwrap();
+    let mut draw = |context: &mut EditContext, offset: f32| {
+        brush_tool.start_stroke(context, offset, 0.0, 0.2, Some([0.1, 0.2]));
+        for index in 1..10 {
+            let t = index as f32 * 0.1;
+            brush_tool.continue_stroke(context, offset + t, t, 0.2 + t, t, None);
+        }
+        brush_tool.end_stroke(context);
+    };
+    draw(&mut context, 0.0);
+    context.manipulate_canvas(2.0, 0.5, [0.1, 0.0]);
+    context.set_primary_color(0.0, 1.0, 0.0, 0.5);
+    draw(&mut context, 0.3);
+    context.undo();
+    context.redo();
+    context.undo();
+    draw(&mut context, -0.3);
+    context.save_recording(path.to_str().unwrap()).unwrap();
+
+    let replayed = EditContext::replay_recording(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let operations = |image: &Image| {
+        let mut operations: Vec<_> = image
+            .operations
+            .iter()
+            .map(|(id, operation)| (*id, operation.clone()))
+            .collect();
+        operations.sort_by_key(|(id, _)| format!("{:?}", id));
+        operations
+    };
+    assert_eq!(operations(&replayed.image), operations(&context.image));
+    for (id, _) in context.image.operations.iter() {
+        assert_eq!(
+            replayed.image.depgraph.depends_on(id),
+            context.image.depgraph.depends_on(id)
+        );
+    }
+    assert_eq!(replayed.input_samples, context.input_samples);
+    assert_eq!(replayed.color, context.color);
+    assert_eq!(replayed.canvas_transform.zoom, 2.0);
+
+    assert!(matches!(
+        Recording::load(Path::new("/nonexistent/recording")),
+        Err(RecordingError::IoError(_))
+    ));
+    let stopped = EditContext::default();
+    assert!(matches!(
+        stopped.save_recording(path.to_str().unwrap()),
+        Err(RecordingError::NotRecording)
+    ));
+}
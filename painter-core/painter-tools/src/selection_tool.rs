@@ -0,0 +1,358 @@
+use glam::{Mat3, Vec2};
+use log::{info, warn};
+use pyo3::prelude::*;
+use std::collections::HashMap;
+
+use painter_data::bounds::Bounds;
+use painter_data::id_map::{IdMapBase, OperationId};
+use painter_data::operation::Operation;
+use painter_data::stroke::StrokeData;
+
+use super::context::{transform_stroke, EditContext, EditError};
+
+/// Drags that start or end closer than this to the pivot are too short to measure a
+/// rotation or scale from.
+const MIN_PIVOT_DISTANCE: f32 = 1e-4;
+
+/// What dragging the selection does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransformMode {
+    Move,
+    Rotate,
+    Scale,
+}
+
+impl TransformMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "move" => Some(Self::Move),
+            "rotate" => Some(Self::Rotate),
+            "scale" => Some(Self::Scale),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Move => "move",
+            Self::Rotate => "rotate",
+            Self::Scale => "scale",
+        }
+    }
+}
+
+/// A transform that is being dragged out
+#[derive(Debug, Clone)]
+struct Drag {
+    start: Vec2,
+    pivot: Vec2,
+
+    /// The selected strokes as they were when the drag started. The preview is redrawn
+    /// from these each time the drag moves.
+    originals: HashMap<OperationId, StrokeData>,
+
+    transform: Mat3,
+}
+
+/// Moves, rotates and scales the selected strokes. While dragging, the strokes in the
+/// image are replaced with transformed copies so that they are drawn where they will
+/// end up. The whole drag is undone in one go.
+#[pyclass]
+#[derive(Clone)]
+pub struct SelectionTool {
+    selection: Vec<OperationId>,
+    mode: TransformMode,
+    drag: Option<Drag>,
+
+    /// The point to rotate and scale around. None to use the center of the selection.
+    #[pyo3(get, set)]
+    pivot: Option<[f32; 2]>,
+
+    /// Scale the selection by the same amount horizontally and vertically
+    #[pyo3(get, set)]
+    constrain_proportions: bool,
+}
+
+impl Default for SelectionTool {
+    fn default() -> Self {
+        Self {
+            selection: Vec::new(),
+            mode: TransformMode::Move,
+            drag: None,
+            pivot: None,
+            constrain_proportions: true,
+        }
+    }
+}
+
+impl SelectionTool {
+    /// The box around all of the selected strokes as they currently are
+    fn bounds(&self, context: &EditContext) -> Option<Bounds> {
+        self.selection
+            .iter()
+            .filter_map(|operation_id| context.stroke_bounds(*operation_id))
+            .map(|(min, max)| Bounds::new(min, max))
+            .reduce(|bounds, other| bounds.union(&other))
+    }
+
+    /// The transform for dragging from the start of the drag to `point`
+    fn drag_transform(&self, drag: &Drag, point: Vec2) -> Mat3 {
+        let from = drag.start - drag.pivot;
+        let to = point - drag.pivot;
+        let around_pivot = |transform: Mat3| {
+            Mat3::from_translation(drag.pivot) * transform * Mat3::from_translation(-drag.pivot)
+        };
+        match self.mode {
+            TransformMode::Move => Mat3::from_translation(point - drag.start),
+            TransformMode::Rotate => {
+                if from.length() < MIN_PIVOT_DISTANCE || to.length() < MIN_PIVOT_DISTANCE {
+                    return Mat3::IDENTITY;
+                }
+                let angle = to.y.atan2(to.x) - from.y.atan2(from.x);
+                around_pivot(Mat3::from_angle(angle))
+            }
+            TransformMode::Scale => {
+                let scale = if self.constrain_proportions {
+                    Vec2::splat(scale_ratio(to.length(), from.length()))
+                } else {
+                    Vec2::new(scale_ratio(to.x, from.x), scale_ratio(to.y, from.y))
+                };
+                around_pivot(Mat3::from_scale(scale))
+            }
+        }
+    }
+
+    /// Replaces the selected strokes with their originals transformed by the drag
+    fn apply_drag(context: &mut EditContext, drag: &Drag) {
+        for (operation_id, original) in drag.originals.iter() {
+            if let Some(Operation::Stroke(stroke)) = context.operation_mut(operation_id) {
+                *stroke = original.clone();
+                transform_stroke(stroke, &drag.transform);
+            }
+        }
+    }
+
+    /// Whether the drag's group was ended from elsewhere, eg by undoing part way
+    /// through a drag.
+    fn drag_interrupted(&mut self, context: &EditContext) -> bool {
+        if self.drag.is_some() && !context.history.in_group() {
+            info!(target: "selection_tool", "Transform was interrupted");
+            self.drag = None;
+        }
+        self.drag.is_none()
+    }
+}
+
+#[pymethods]
+impl SelectionTool {
+    #[new]
+    pub fn new() -> PyResult<Self> {
+        Ok(Self::default())
+    }
+
+    /// Sets the strokes that will be transformed. Does nothing while dragging.
+    pub fn set_selection(&mut self, ids: Vec<OperationId>) {
+        if self.drag.is_some() {
+            warn!(target: "selection_tool", "Cannot change the selection while transforming it");
+            return;
+        }
+        self.selection = ids;
+    }
+
+    pub fn get_selection(&self) -> Vec<OperationId> {
+        self.selection.clone()
+    }
+
+    /// Sets what dragging does: "move", "rotate" or "scale"
+    pub fn set_mode(&mut self, name: &str) -> Result<(), EditError> {
+        self.mode = TransformMode::from_name(name)
+            .ok_or_else(|| EditError::UnknownTransformMode(name.to_string()))?;
+        Ok(())
+    }
+
+    pub fn get_mode(&self) -> String {
+        self.mode.name().to_string()
+    }
+
+    pub fn is_transforming(&self) -> bool {
+        self.drag.is_some()
+    }
+
+    /// The corners (min, max) of the box around the selected strokes, including any
+    /// transform being previewed.
+    pub fn selection_bounds(&self, context: &EditContext) -> Option<([f32; 2], [f32; 2])> {
+        self.bounds(context).map(|bounds| (bounds.min, bounds.max))
+    }
+
+    /// The transform currently being previewed as a list of rows, or None if not
+    /// transforming.
+    pub fn preview_transform(&self) -> Option<([f32; 3], [f32; 3], [f32; 3])> {
+        let drag = self.drag.as_ref()?;
+        let [x, y, z] = drag.transform.transpose().to_cols_array_2d();
+        Some((x, y, z))
+    }
+
+    fn start_transform(&mut self, context: &mut EditContext, x: f32, y: f32) {
+        if self.drag.is_some() {
+            warn!(target: "selection_tool", "Starting transform when one already exists");
+            self.end_transform(context);
+        }
+
+        // Strokes that have since been undone or deleted are dropped from the selection
+        self.selection.retain(|operation_id| {
+            matches!(
+                context.image.operations.get(operation_id),
+                Some(Operation::Stroke(_))
+            )
+        });
+        if self.selection.is_empty() {
+            warn!(target: "selection_tool", "Nothing selected to transform");
+            return;
+        }
+        if let Err(err) = context.check_strokes_editable(&self.selection) {
+            warn!(target: "selection_tool", "Unable to transform selection: {:?}", err);
+            return;
+        }
+
+        let start = Vec2::new(x, y);
+        let pivot = match (self.pivot, self.bounds(context)) {
+            (Some(pivot), _) => pivot.into(),
+            (None, Some(bounds)) => (Vec2::from(bounds.min) + Vec2::from(bounds.max)) * 0.5,
+            (None, None) => start,
+        };
+        let originals = self
+            .selection
+            .iter()
+            .filter_map(
+                |operation_id| match context.image.operations.get(operation_id) {
+                    Some(Operation::Stroke(stroke)) => Some((*operation_id, stroke.clone())),
+                    _ => None,
+                },
+            )
+            .collect();
+
+        // The group is ended in end_transform so the whole drag is a single undo step
+        context.begin_group("Transform Selection");
+        self.drag = Some(Drag {
+            start,
+            pivot,
+            originals,
+            transform: Mat3::IDENTITY,
+        });
+    }
+
+    fn continue_transform(&mut self, context: &mut EditContext, x: f32, y: f32) {
+        if self.drag_interrupted(context) {
+            return;
+        }
+        if let Some(mut drag) = self.drag.take() {
+            drag.transform = self.drag_transform(&drag, Vec2::new(x, y));
+            Self::apply_drag(context, &drag);
+            self.drag = Some(drag);
+        }
+    }
+
+    fn end_transform(&mut self, context: &mut EditContext) {
+        if self.drag_interrupted(context) {
+            return;
+        }
+        self.drag = None;
+        context.end_group();
+    }
+
+    /// Puts the selection back where it was before the drag started, without adding
+    /// anything to the undo history.
+    fn cancel_transform(&mut self, context: &mut EditContext) {
+        if self.drag_interrupted(context) {
+            return;
+        }
+        if let Some(mut drag) = self.drag.take() {
+            drag.transform = Mat3::IDENTITY;
+            Self::apply_drag(context, &drag);
+            context.end_group();
+            // Nothing changed, so there is no applied change to re-index the strokes from
+            for operation_id in drag.originals.keys() {
+                context.spatial_index.update(&context.image, operation_id);
+            }
+        }
+    }
+}
+
+/// How much to scale by to get from `from` to `to`, or 1 if `from` is too small to tell
+fn scale_ratio(to: f32, from: f32) -> f32 {
+    if from.abs() < MIN_PIVOT_DISTANCE {
+        1.0
+    } else {
+        to / from
+    }
+}
+
+#[test]
+fn test_selection_tool() {
+    let mut context = EditContext::default();
+    let layer = context.list_layers(None)[0];
+    context.select_layer(layer).unwrap();
+    let stroke = context
+        .insert_operation(Operation::Stroke(super::context::test_stroke(
+            vec![[0.0, 0.0], [1.0, 0.0]],
+            0.1,
+        )))
+        .unwrap();
+    let positions = |context: &EditContext| match context.image.operations.get(&stroke) {
+        Some(Operation::Stroke(stroke)) => stroke.position_array.clone(),
+        _ => panic!("Stroke missing"),
+    };
+    let assert_near = |actual: Vec<[f32; 2]>, expected: &[[f32; 2]]| {
+        for (actual, expected) in actual.iter().zip(expected.iter()) {
+            assert!((actual[0] - expected[0]).abs() < 1e-5, "{:?}", actual);
+            assert!((actual[1] - expected[1]).abs() < 1e-5, "{:?}", actual);
+        }
+    };
+    let mut tool = SelectionTool::default();
+    tool.set_selection(vec![stroke]);
+
+    // The image shows the preview while dragging, and the drag is undone in one step
+    tool.start_transform(&mut context, 0.0, 0.0);
+    tool.continue_transform(&mut context, 0.5, 0.0);
+    tool.continue_transform(&mut context, 0.0, 1.0);
+    assert_near(positions(&context), &[[0.0, 1.0], [1.0, 1.0]]);
+    assert!(tool.is_transforming());
+    tool.end_transform(&mut context);
+    assert!(!tool.is_transforming());
+    assert!(context.undo());
+    assert_near(positions(&context), &[[0.0, 0.0], [1.0, 0.0]]);
+
+    // Rotating a quarter turn around a pivot at the start of the stroke
+    tool.set_mode("rotate").unwrap();
+    tool.pivot = Some([0.0, 0.0]);
+    tool.start_transform(&mut context, 1.0, 0.0);
+    tool.continue_transform(&mut context, 0.0, 2.0);
+    tool.end_transform(&mut context);
+    assert_near(positions(&context), &[[0.0, 0.0], [0.0, 1.0]]);
+    assert!(context.undo());
+
+    // Scaling around the center of the selection, with and without keeping proportions
+    tool.set_mode("scale").unwrap();
+    tool.pivot = None;
+    tool.start_transform(&mut context, 1.0, 0.0);
+    tool.continue_transform(&mut context, 1.5, 0.0);
+    assert_near(positions(&context), &[[-0.5, 0.0], [1.5, 0.0]]);
+    tool.constrain_proportions = false;
+    tool.continue_transform(&mut context, 1.5, 1.0);
+    assert_near(positions(&context), &[[-0.5, 0.0], [1.5, 0.0]]);
+
+    // Cancelling puts the strokes back without adding to the history
+    tool.cancel_transform(&mut context);
+    assert_near(positions(&context), &[[0.0, 0.0], [1.0, 0.0]]);
+    assert!(context.can_redo());
+    assert!(context.spatial_index.contains(&stroke));
+
+    // Undoing part way through a drag ends it
+    tool.start_transform(&mut context, 0.0, 0.0);
+    tool.continue_transform(&mut context, 0.5, 0.0);
+    context.undo();
+    tool.continue_transform(&mut context, 1.0, 0.0);
+    assert!(!tool.is_transforming());
+
+    assert!(tool.set_mode("shear").is_err());
+}
@@ -0,0 +1,98 @@
+//! The calibration curve of each tablet, which maps the pressure it reports to the
+//! pressure used by brushes. The curves are kept on the EditContext so that every tool
+//! drawing into it uses the same ones.
+//!
+//! Curves are saved inside the image file as the `_sp_tablet_curves` extension. They
+//! are not part of the undo history.
+use std::collections::BTreeMap;
+
+use painter_data::brush::PressureCurve;
+
+/// The name of the image extension the curves are saved in
+pub const TABLET_CURVES_EXTENSION: &str = "_sp_tablet_curves";
+
+/// Increment if the way the curves are stored changes. Curves stored in another
+/// version are discarded rather than failing to load the image.
+const TABLET_CURVES_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum TabletCurvesLoadError {
+    /// Not even long enough to contain a version number
+    Truncated,
+    UnknownVersion(u32),
+    DeserializeError(std::boxed::Box<bincode::ErrorKind>),
+}
+
+/// The calibration curve of each device, by name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TabletCurves {
+    curves: BTreeMap<String, PressureCurve>,
+}
+
+impl TabletCurves {
+    pub fn set(&mut self, device: String, curve: PressureCurve) {
+        self.curves.insert(device, curve);
+    }
+
+    pub fn get(&self, device: &str) -> Option<&PressureCurve> {
+        self.curves.get(device)
+    }
+
+    pub fn remove(&mut self, device: &str) {
+        self.curves.remove(device);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.curves.is_empty()
+    }
+
+    /// The pressure reported by `device`, corrected by its curve if it has one
+    pub fn calibrate(&self, device: Option<&str>, pressure: f32) -> f32 {
+        match device.and_then(|device| self.get(device)) {
+            Some(curve) => curve.evaluate(pressure).clamp(0.0, 1.0),
+            None => pressure,
+        }
+    }
+
+    /// Encodes the curves so they can be stored in an image extension
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = TABLET_CURVES_FORMAT_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut data, &self.curves).expect("Serializing curves failed");
+        data
+    }
+
+    /// Decodes curves created by to_bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self, TabletCurvesLoadError> {
+        let mut version = [0u8; 4];
+        if data.len() < version.len() {
+            return Err(TabletCurvesLoadError::Truncated);
+        }
+        version.copy_from_slice(&data[..4]);
+        match u32::from_le_bytes(version) {
+            TABLET_CURVES_FORMAT_VERSION => Ok(Self {
+                curves: bincode::deserialize(&data[4..])
+                    .map_err(TabletCurvesLoadError::DeserializeError)?,
+            }),
+            number => Err(TabletCurvesLoadError::UnknownVersion(number)),
+        }
+    }
+}
+
+#[test]
+fn test_tablet_curves_saved() {
+    let mut curves = TabletCurves::default();
+    curves.set(
+        "Pen".to_string(),
+        PressureCurve::from_samples(vec![0.0, 1.0, 1.0]),
+    );
+    assert_eq!(curves.calibrate(Some("Pen"), 0.25), 0.5);
+    assert_eq!(curves.calibrate(Some("Mouse"), 0.25), 0.25);
+    assert_eq!(curves.calibrate(None, 0.25), 0.25);
+
+    let loaded = TabletCurves::from_bytes(&curves.to_bytes()).unwrap();
+    assert_eq!(loaded, curves);
+    assert!(matches!(
+        TabletCurves::from_bytes(&[99, 0, 0, 0]),
+        Err(TabletCurvesLoadError::UnknownVersion(99))
+    ));
+}
@@ -13,7 +13,7 @@ graphics tablet using python and GTK4.
 # Load Gtk
 import gi
 gi.require_version('Gtk', '4.0')
-from gi.repository import Gtk, Gdk
+from gi.repository import Gtk, Gdk, GLib
 import os
 
 import painter_core
@@ -65,18 +65,45 @@ class Painter():
 
     def save_image(self):
         print("Saving")
-        self.core.save(self.context, "test.sveg")
-
+        # Saving happens in the background so painting can continue
+        job = self.core.save_in_background(self.context, "test.sveg")
+        context = self.context
+
+        def check_save():
+            if not job.is_finished():
+                return GLib.SOURCE_CONTINUE
+            try:
+                self.core.finish_save(context, job)
+                print("Saved")
+            except IOError as err:
+                print("Failed to save: {}".format(err))
+            return GLib.SOURCE_REMOVE
+
+        GLib.timeout_add(100, check_save)
         print(self.context.generate_dotgraph())
     
     def load_image(self):
         print("Loading")
-        self.context = self.core.load("test.sveg")
+        recoverable = self.core.recoverable_changes("test.sveg")
+        if recoverable > 0:
+            print("Recovering {} unsaved changes".format(recoverable))
+            self.context = self.core.recover("test.sveg")
+        else:
+            self.context = self.core.load("test.sveg")
         self._context_changed()
     
+    def undo(self):
+        self.context.undo()
+        self.canvas.queue_draw()
+
+    def redo(self):
+        self.context.redo()
+        self.canvas.queue_draw()
+
     def _context_changed(self):
         self.brush_tool.set_brush_id(self.context.image.brushes.list_ids()[0]) # TODO: Is there a better way to do this binding between tools and context?
-        self.context.select_layer(self.context.image.layers.list_ids()[0]) # TODO: Is there a better way to select a layer?
+        paint_layers = [l for l in self.context.list_layers() if not self.context.get_layer(l).is_group()]
+        self.context.select_layer(paint_layers[0]) # TODO: Is there a better way to select a layer?
         self.canvas.queue_draw()
 
     def toggle_ui(self):
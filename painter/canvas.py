@@ -141,7 +141,7 @@ class Canvas(Gtk.GLArea):
         self.queue_draw()
     
     def stylus_up(self, event, x, y):
-        self.painter.brush_tool.end_stroke()
+        self.painter.brush_tool.end_stroke(self.painter.context)
         self.time_at_stroke_start = None
         self.queue_draw()
 
@@ -149,6 +149,8 @@ class Canvas(Gtk.GLArea):
         ctx.make_current()
         if self.renderer is None:
             self.renderer = painter_core.PainterRenderer()
+            # Leave the rest of the video memory for the compositor and other apps
+            self.renderer.auto_texture_budget(self.painter.context, 0.5)
         self.renderer.render(self.painter.context)
         return True
 
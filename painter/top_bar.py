@@ -13,10 +13,14 @@ class TopBar(Gtk.Box):
         load_button = Gtk.Button.new_with_label('Open')
         save_button = Gtk.Button.new_with_label('Save')
         new_button = Gtk.Button.new_with_label('New')
+        undo_button = Gtk.Button.new_with_label('Undo')
+        redo_button = Gtk.Button.new_with_label('Redo')
         
         new_button.connect("clicked", lambda _: painter.new_image())
         save_button.connect("clicked", lambda _: painter.save_image())
         load_button.connect("clicked", lambda _: painter.load_image())
+        undo_button.connect("clicked", lambda _: painter.undo())
+        redo_button.connect("clicked", lambda _: painter.redo())
         self.set_halign(Gtk.Align.CENTER)
         self.set_hexpand(False)
         self.set_valign(Gtk.Align.START)
@@ -25,6 +29,8 @@ class TopBar(Gtk.Box):
         self.append(save_button)
         self.append(load_button)
         self.append(new_button)
+        self.append(undo_button)
+        self.append(redo_button)
         
         
 